@@ -0,0 +1,27 @@
+CREATE TABLE planets (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    type TEXT,
+    moons INTEGER
+);
+
+CREATE TABLE moons (
+    id INTEGER PRIMARY KEY,
+    planet_id INTEGER,
+    name TEXT
+);
+
+INSERT INTO planets (name, type, moons)
+SELECT 'p' || value, CASE WHEN value % 10 = 0 THEN 'gas' ELSE 'rock' END, value % 7
+FROM generate_series(1, 2000);
+
+INSERT INTO moons (planet_id, name)
+SELECT value % 2000 + 1, 'm' || value
+FROM generate_series(1, 300);
+
+CREATE INDEX by_type ON planets (type);
+CREATE INDEX by_name ON planets (name);
+CREATE INDEX by_moons ON planets (moons, type);
+CREATE INDEX moons_planet ON moons (planet_id);
+
+ANALYZE;
@@ -0,0 +1,15 @@
+PRAGMA page_size = 512;
+-- Must be set before the first table, it decides the layout of the file
+PRAGMA auto_vacuum = INCREMENTAL;
+
+CREATE TABLE moons (id INTEGER PRIMARY KEY, name TEXT, planet TEXT);
+CREATE INDEX moons_by_planet ON moons (planet);
+INSERT INTO moons (name, planet) VALUES ('Io', 'Jupiter'), ('Europa', 'Jupiter'), ('Moon', 'Earth');
+INSERT INTO moons (name, planet) VALUES ('Phobos', 'Mars'), ('Deimos', 'Mars'), ('Titan', 'Saturn');
+
+-- Enough pages for a second pointer map page, half of them freed again but
+-- left on the freelist until an incremental vacuum
+CREATE TABLE log (entry TEXT);
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 400)
+INSERT INTO log SELECT printf('%04d %.100c', i, '*') FROM n;
+DELETE FROM log WHERE rowid > 200;
@@ -0,0 +1,11 @@
+PRAGMA page_size = 512;
+-- Pointer maps have entries for overflow pages too
+PRAGMA auto_vacuum = FULL;
+
+CREATE TABLE notes (id INTEGER PRIMARY KEY, title TEXT, body TEXT);
+CREATE INDEX notes_by_body ON notes (body);
+
+-- Bodies from small enough for the page to several overflow pages long, with
+-- index keys spilling sooner than rows
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 40)
+INSERT INTO notes (title, body) SELECT printf('Note %d', i), printf('%d %.*c', i, i * 60, 'x') FROM n;
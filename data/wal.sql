@@ -0,0 +1,20 @@
+PRAGMA page_size = 1024;
+PRAGMA journal_mode = WAL;
+PRAGMA wal_autocheckpoint = 0;
+
+CREATE TABLE moons (id INTEGER PRIMARY KEY, name TEXT, planet TEXT);
+INSERT INTO moons (name, planet) VALUES ('Io', 'Jupiter');
+INSERT INTO moons (name, planet) VALUES ('Europa', 'Jupiter');
+INSERT INTO moons (name, planet) VALUES ('Ganymede', 'Jupiter');
+INSERT INTO moons (name, planet) VALUES ('Callisto', 'Jupiter');
+
+-- Copies the WAL into the database, the next commit starts it over with new
+-- salts and leaves the frames after it behind
+PRAGMA wal_checkpoint;
+
+INSERT INTO moons (name, planet) VALUES ('Moon', 'Earth');
+INSERT INTO moons (name, planet) VALUES ('Phobos', 'Mars'), ('Deimos', 'Mars');
+UPDATE moons SET name = 'Luna' WHERE name = 'Moon';
+
+-- Closing the database would checkpoint the WAL, so keep copies of the files
+.system cp data/wal-build.db data/wal.db && cp data/wal-build.db-wal data/wal.db-wal && cp data/wal-build.db-shm data/wal.db-shm
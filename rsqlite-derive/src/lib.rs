@@ -0,0 +1,82 @@
+//! # Derive `FromRow`
+//!
+//! `#[derive(FromRow)]` for structs with named fields, see `rsqlite::row`.
+//!
+//! Each field reads the column with the same name, converted with
+//! `rsqlite::row::FromValue`. Field attributes
+//!
+//! - `#[row(rename = "name")]` reads another column
+//! - `#[row(timestamp)]` reads text dates or unix times as unix seconds
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Fields, LitStr};
+
+#[proc_macro_derive(FromRow, attributes(row))]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Options from the `#[row(..)]` attributes of a field
+#[derive(Default)]
+struct Options {
+    rename: Option<LitStr>,
+    timestamp: bool,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new(input.span(), "FromRow needs a struct with named fields")),
+        },
+        _ => return Err(Error::new(input.span(), "FromRow can only be derived for structs")),
+    };
+
+    let mut values = vec![];
+    for field in fields {
+        let options = options(field)?;
+        let ident = field.ident.as_ref().expect("named field");
+        let column = match options.rename {
+            Some(rename) => rename.value(),
+            None => ident.to_string().trim_start_matches("r#").to_string(),
+        };
+        let read = match options.timestamp {
+            true => quote!(::rsqlite::row::timestamp),
+            false => quote!(::rsqlite::row::column),
+        };
+        values.push(quote!(#ident: #read(columns, row, #column)?));
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rsqlite::row::FromRow for #name #ty_generics #where_clause {
+            fn from_row<S: ::core::convert::AsRef<str>>(
+                columns: &[S],
+                row: &[::rsqlite::schema::SerialValue],
+            ) -> ::rsqlite::de::Result<Self> {
+                ::core::result::Result::Ok(#name { #(#values,)* })
+            }
+        }
+    })
+}
+
+fn options(field: &syn::Field) -> syn::Result<Options> {
+    let mut options = Options::default();
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("row")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                options.rename = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("timestamp") {
+                options.timestamp = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `rename = \"..\"` or `timestamp`"))
+            }
+        })?;
+    }
+    Ok(options)
+}
@@ -0,0 +1,359 @@
+//! # Online backup
+//!
+//! A [Backup] copies a snapshot of a database into a new file page by page,
+//! like the backup API of SQLite, either all at once or a few pages at a time
+//! with [Backup::step]. The snapshot is of a [Database] in memory, or of a
+//! database file as of when the backup starts, with the pages committed to
+//! its WAL by then on top. Its pages are read as they are copied, holding the
+//! [locks](crate::lock) of a reader until the copy is done, so none of the
+//! changes other connections make meanwhile end up half in it: commits to
+//! the WAL go on and checkpoints stop at the snapshot, and other writers wait.
+//!
+//! - [Backup API docs](https://www.sqlite.org/backup.html)
+//! - [VACUUM INTO docs](https://www.sqlite.org/lang_vacuum.html#vacuuminto)
+//!
+//! The new file is held with an EXCLUSIVE lock until the copy is done, and its
+//! first page, with the header, is written last, so it is never read as a
+//! database before it is complete. A backup dropped before it is done leaves
+//! an empty file behind. [Database::vacuum_into] writes a copy rebuilt like
+//! [Database::vacuum] does instead, like `VACUUM INTO`.
+//!
+//! ```
+//! use rsqlite::{backup::Backup, schema::Database};
+//!
+//! let path = std::env::temp_dir().join(format!("rsqlite-backup-doc-{}.db", std::process::id()));
+//! let mut backup = Backup::open("data/planets.db", &path).unwrap();
+//! assert_eq!((backup.page_count(), backup.remaining()), (2, 2));
+//!
+//! assert!(!backup.step(1).unwrap());
+//! assert!(backup.step(1).unwrap());
+//! assert_eq!(backup.remaining(), 0);
+//!
+//! let db = Database::open(&path).unwrap();
+//! assert_eq!(db.table_rows(2).len(), 8);
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+
+use crate::{
+    journal::{self, sync_directory},
+    lock::{FileLock, LockLevel, ShmLock},
+    schema::{error, lock_byte_page, Database, Header},
+    wal,
+};
+use binrw::{BinRead, BinResult, BinWrite};
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// A copy of a database snapshot being written to a new file
+pub struct Backup {
+    lock: FileLock,
+    path: PathBuf,
+    source: Source,
+    page_size: u32,
+    page_count: u32,
+    copied: u32,
+    done: bool,
+}
+
+/// Where the pages of a [Backup] are read from
+enum Source {
+    /// A serialized database
+    Bytes(Vec<u8>),
+    /// A database file locked like a reader, see [wal::lock_reader], and the
+    /// data of the newest committed frame of each page in its WAL
+    File {
+        lock: FileLock,
+        shm: Option<ShmLock>,
+        frames: BTreeMap<u32, Vec<u8>>,
+    },
+}
+
+impl Backup {
+    /**
+     * Start a backup of the database file at `source` into a new file at
+     * `path`, which can also be an empty file. A transaction a crash left
+     * half written is rolled back first, like [Database::open] does.
+     */
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(source: P, path: Q) -> BinResult<Backup> {
+        journal::rollback(source.as_ref())?;
+        let (mut lock, shm, wal) = wal::lock_reader(source.as_ref())?;
+        let mut frames = BTreeMap::new();
+        let mut last = None;
+        if let Some(wal) = wal {
+            let end = wal.committed().len();
+            for frame in wal.frames.into_iter().take(end) {
+                last = Some((wal.header.page_size, frame.header.commit_size));
+                frames.insert(frame.header.page_number, frame.data);
+            }
+        }
+
+        // The snapshot has the size of the last commit to the WAL, if any
+        let size = lock.file().metadata()?.len();
+        let (page_size, page_count) = match last {
+            Some(sizes) => sizes,
+            None if size == 0 => (1, 0),
+            None => {
+                let mut header = vec![];
+                lock.file().seek(SeekFrom::Start(0))?;
+                lock.file().take(100).read_to_end(&mut header)?;
+                let page_size = Header::read(&mut Cursor::new(header))?.page_size_bytes();
+                (page_size, (size / page_size as u64) as u32)
+            }
+        };
+        let source = Source::File { lock, shm, frames };
+        Backup::new(source, page_size, page_count, path.as_ref())
+    }
+
+    fn new(source: Source, page_size: u32, page_count: u32, path: &Path) -> BinResult<Backup> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut lock = FileLock::new(file);
+        lock.lock(LockLevel::Exclusive)?;
+        if lock.file().metadata()?.len() > 0 {
+            return Err(error(format!("{} already exists", path.display())));
+        }
+        Ok(Backup {
+            lock,
+            path: path.to_path_buf(),
+            source,
+            page_size,
+            page_count,
+            copied: 0,
+            done: false,
+        })
+    }
+
+    /// Number of pages in the snapshot
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    /// Number of pages not copied yet
+    pub fn remaining(&self) -> u32 {
+        self.page_count() - self.copied
+    }
+
+    /**
+     * Copy up to `pages` more pages, or all of them when `pages` is 0.
+     * Returns `Ok(true)` once the copy is complete, synced and unlocked.
+     *
+     * Pages are copied from the second one on, and the first one last. The
+     * lock-byte page is never written, it stays a hole in the new file.
+     */
+    pub fn step(&mut self, pages: u32) -> BinResult<bool> {
+        if self.done {
+            return Ok(true);
+        }
+        let count = match pages {
+            0 => self.remaining(),
+            n => n.min(self.remaining()),
+        };
+        let lock_byte = lock_byte_page(self.page_size);
+        let size = self.page_size as u64;
+
+        for _ in 0..count {
+            let number = match self.copied + 1 == self.page_count {
+                true => 1,
+                false => self.copied + 2,
+            };
+            self.copied += 1;
+            if number == lock_byte {
+                continue;
+            }
+            let page = self.source.page(number, self.page_size)?;
+            let file = self.lock.file();
+            file.seek(SeekFrom::Start((number - 1) as u64 * size))?;
+            file.write_all(&page)?;
+        }
+
+        if self.remaining() > 0 {
+            return Ok(false);
+        }
+        let file = self.lock.file();
+        file.set_len(self.page_count as u64 * size)?;
+        file.sync_all()?;
+        sync_directory(&self.path)?;
+        self.lock.unlock(LockLevel::None)?;
+        self.source.unlock()?;
+        self.done = true;
+        Ok(true)
+    }
+}
+
+impl Source {
+    /// Page `number` of the snapshot, zeros past the end of a database file
+    /// that only its WAL made longer
+    fn page(&mut self, number: u32, page_size: u32) -> BinResult<Vec<u8>> {
+        let start = (number - 1) as u64 * page_size as u64;
+        match self {
+            Source::Bytes(bytes) => Ok(bytes[start as usize..][..page_size as usize].to_vec()),
+            Source::File { frames, .. } if frames.contains_key(&number) => Ok(frames[&number].clone()),
+            Source::File { lock, .. } => {
+                let mut page = vec![];
+                lock.file().seek(SeekFrom::Start(start))?;
+                lock.file().take(page_size as u64).read_to_end(&mut page)?;
+                page.resize(page_size as usize, 0);
+                Ok(page)
+            }
+        }
+    }
+
+    /// Release the locks on a database file once the copy is done
+    fn unlock(&mut self) -> BinResult<()> {
+        if let Source::File { lock, shm, .. } = self {
+            *shm = None;
+            lock.unlock(LockLevel::None)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Backup {
+    fn drop(&mut self) {
+        if !self.done {
+            // Errors can't be returned here, an unfinished copy is no database
+            let _ = self.lock.file().set_len(0);
+        }
+    }
+}
+
+impl Database {
+    /// Start a backup of the database as it is in memory into a new file at
+    /// `path`, see [Backup::open]
+    pub fn backup<P: AsRef<Path>>(&self, path: P) -> BinResult<Backup> {
+        let mut bytes = Cursor::new(vec![]);
+        self.write_be(&mut bytes)?;
+        let bytes = bytes.into_inner();
+        let page_size = self.db_header.page_size_bytes();
+        let page_count = (bytes.len() / page_size as usize) as u32;
+        Backup::new(Source::Bytes(bytes), page_size, page_count, path.as_ref())
+    }
+
+    /**
+     * Write a copy of the database rebuilt like [Database::vacuum] does into a
+     * new file at `path`, like `VACUUM INTO`. The database itself is not
+     * changed, and the copy is not in WAL mode, like in SQLite.
+     */
+    pub fn vacuum_into<P: AsRef<Path>>(&self, path: P) -> BinResult<()> {
+        let mut db = self.vacuumed(None)?;
+        db.db_header.write_format = 1;
+        db.db_header.read_format = 1;
+        db.backup(path)?.step(0)?;
+        Ok(())
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        testing::{temp_copy, temp_path},
+        wal::{checkpoint, CheckpointMode},
+    };
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn backup() {
+        // Pages copied at each step, and how many steps that takes
+        let t = vec![
+            ("data/planets.db", 1, 2),
+            ("data/analyze.db", 3, 14),
+            ("data/autovacuum.db", 10, 11),
+            ("data/wal.db", 0, 1),
+        ];
+        for (source, pages, steps) in t.into_iter() {
+            let path = temp_path("backup-copy");
+            let mut backup = Backup::open(source, &path).unwrap();
+            let mut taken = 1;
+            while !backup.step(pages).unwrap() {
+                taken += 1;
+            }
+            assert_eq!((taken, backup.remaining()), (steps, 0), "{}", source);
+            drop(backup);
+
+            let copy = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(copy, wal::read_with_wal(Path::new(source)).unwrap(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn partial() {
+        let path = temp_path("backup-partial");
+        let mut backup = Backup::open("data/analyze.db", &path).unwrap();
+        assert!(!backup.step(5).unwrap());
+        assert_eq!(backup.remaining(), backup.page_count() - 5);
+
+        // The header is written last, and the file is locked until then
+        let header = std::fs::read(&path).unwrap()[..100].to_vec();
+        assert_eq!(header, vec![0; 100]);
+        let mut reader = FileLock::open(&path).unwrap().with_timeout(std::time::Duration::ZERO);
+        assert!(reader.lock(LockLevel::Shared).is_err());
+
+        // Dropping it leaves an empty file, which a new backup can use
+        drop(backup);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        Backup::open("data/planets.db", &path).unwrap().step(0).unwrap();
+        let err = Backup::open("data/planets.db", &path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+    }
+
+    #[test]
+    fn snapshot() {
+        let source = temp_copy("data/wal.db", "backup-snapshot");
+        std::fs::copy("data/wal.db-wal", wal::wal_path(&source)).unwrap();
+        assert_eq!(checkpoint(&source, CheckpointMode::Passive).unwrap(), (3, 3));
+        let before = wal::read_with_wal(&source).unwrap();
+
+        // Commits go on while the copy is made, and checkpoints stop at it
+        let path = temp_path("backup-snapshot-copy");
+        let mut backup = Backup::open(&source, &path).unwrap();
+        assert!(!backup.step(1).unwrap());
+        let mut db = Database::open(&source).unwrap();
+        db.execute("CREATE TABLE stars (name TEXT)").unwrap();
+        db.save(&source).unwrap();
+        assert_eq!(checkpoint(&source, CheckpointMode::Passive).unwrap(), (5, 3));
+        assert!(checkpoint(&source, CheckpointMode::Truncate).is_err());
+
+        assert!(backup.step(0).unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert_eq!(checkpoint(&source, CheckpointMode::Truncate).unwrap(), (0, 0));
+        assert_eq!(Database::open(&source).unwrap().table_rows(1), db.table_rows(1));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(wal::wal_path(&source)).unwrap();
+        std::fs::remove_file(crate::shm::shm_path(&source)).unwrap();
+        std::fs::remove_file(&source).unwrap();
+    }
+
+    #[test]
+    fn vacuum_into() {
+        let path = temp_path("backup-vacuum-into");
+        let mut db = Database::open("data/analyze.db").unwrap();
+        db.execute(&format!("VACUUM INTO '{}'", path.display())).unwrap();
+        let copy = Database::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(copy.db_header.database_page_count, 42);
+        assert_eq!(copy.db_header.freelist_page_count, 0);
+        assert_eq!(db.db_header, Database::open("data/analyze.db").unwrap().db_header);
+
+        // Not in WAL mode, and not over an existing file
+        let db = Database::open("data/wal.db").unwrap();
+        db.vacuum_into(&path).unwrap();
+        let copy = Database::open(&path).unwrap();
+        assert_eq!((copy.db_header.write_format, copy.db_header.read_format), (1, 1));
+        assert!(db.vacuum_into(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(copy.table_rows(2).len(), db.table_rows(2).len());
+    }
+}
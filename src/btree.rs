@@ -0,0 +1,1608 @@
+//! # Writing b-trees
+//!
+//! Rows are inserted into the [Page]s of a [Database] in memory, which can then
+//! be saved back to a file whole.
+//!
+//! A new cell goes into the leaf its key belongs in. When the leaf is full it
+//! is balanced with up to 2 of its siblings by spreading their cells over as
+//! many pages as needed, which adds dividers to the parent page that may then
+//! overflow in turn. A full root page moves its cells down into a new child, so
+//! the tree grows from the top and stays balanced. This follows
+//! `balance_nonroot()`, `balance_quick()` and `balance_deeper()` in SQLite's
+//! [btree.c](https://github.com/sqlite/sqlite/blob/e69b4d7/src/btree.c).
+//!
+//! Removed cells leave [Freeblock]s behind, which new cells reuse before the
+//! page is defragmented. Pages left less than a third full are balanced with
+//! their siblings the same way, which can free pages and make the tree
+//! shallower again.
+//!
+//! A record too large to share a page with 3 others keeps only its start in
+//! the cell, and the rest goes on a list of new [OverflowPage]s, like
+//! `fillInCell()` does. The overflow pages stay where they are as the cell
+//! moves between pages, and are freed with it.
+//!
+//! New pages are taken from the freelist first, or added at the end of the file.
+//! Auto-vacuum databases also keep their [pointer maps][crate::ptrmap] up to
+//! date as pages move.
+//!
+//! ```
+//! use rsqlite::schema::{CreateOptions, Database};
+//!
+//! let mut db = Database::new(CreateOptions { page_size: 512, ..Default::default() }).unwrap();
+//! // Page 1 is the root of the `sqlite_schema` table, itself a table b-tree
+//! for row_id in 1..=100 {
+//!     db.insert(1, row_id, vec!["table".into(), format!("t{}", row_id).as_str().into()]).unwrap();
+//! }
+//! assert_eq!(db.table_rows(1).len(), 100);
+//! assert!(db.db_header.database_page_count > 1);
+//! ```
+
+use crate::{ddl::TableIndex, schema::*};
+use binrw::{BinResult, BinWrite};
+use std::{cmp::Ordering, io::Cursor};
+
+/// Key of a cell, which orders the cells of a b-tree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Key<'a> {
+    RowId(i64),
+    Record(&'a [SerialValue]),
+}
+
+type Order<'a> = &'a dyn Fn(&Key, &Key) -> Ordering;
+
+/// Page numbers of the interior pages from the root down to a page, with the
+/// index of the child followed on each.
+type Ancestors = Vec<(u32, usize)>;
+
+/**
+ * A cell of a b-tree page that can move between pages while balancing.
+ *
+ * Table leaves keep all their cells and put a copy of the last key of a page
+ * in the parent as the divider. Everywhere else the divider is moved up into
+ * the parent, and moved back down when the pages are balanced again.
+ */
+pub(crate) trait Node: Cell + Sized {
+    /// Cells of the interior pages above pages of this cell
+    type Parent: Node<Parent = Self::Parent>;
+
+    /// Whether dividers are copies of the last key of the pages before them
+    const COPY_UP: bool = false;
+
+    fn key(&self) -> Key<'_>;
+
+    fn left_child(&self) -> Option<u32> {
+        None
+    }
+
+    fn btree_page(page: &mut Page) -> Option<&mut BTreePage<Self>>;
+
+    fn into_page(page: BTreePage<Self>) -> Page;
+
+    /// Divider for page `left_child` ending with this cell, with [Self::COPY_UP]
+    fn copy_up(&self, _left_child: u32) -> Option<Self::Parent> {
+        None
+    }
+
+    /// Divider for page `left_child` ending before this cell, and the right
+    /// most pointer that page gets from the cell.
+    fn move_up(self, left_child: u32) -> (Self::Parent, Option<u32>);
+
+    /// Cell for a divider moved down into its left page, which had `right_most`
+    /// as its right most pointer.
+    fn move_down(divider: Self::Parent, right_most: Option<u32>) -> Self;
+}
+
+impl Node for TableLeafCell {
+    type Parent = TableInteriorCell;
+
+    const COPY_UP: bool = true;
+
+    fn key(&self) -> Key<'_> {
+        Key::RowId(self.row_id.value as i64)
+    }
+
+    fn btree_page(page: &mut Page) -> Option<&mut BTreePage<Self>> {
+        match page {
+            Page::TableLeaf(page) => Some(page),
+            _ => None,
+        }
+    }
+
+    fn into_page(page: BTreePage<Self>) -> Page {
+        Page::TableLeaf(page)
+    }
+
+    fn copy_up(&self, left_child: u32) -> Option<TableInteriorCell> {
+        Some(TableInteriorCell {
+            left_child,
+            row_id: self.row_id,
+        })
+    }
+
+    fn move_up(self, _: u32) -> (TableInteriorCell, Option<u32>) {
+        unreachable!("Table leaves copy their dividers up")
+    }
+
+    fn move_down(_: TableInteriorCell, _: Option<u32>) -> Self {
+        unreachable!("Table leaves copy their dividers up")
+    }
+}
+
+impl Node for TableInteriorCell {
+    type Parent = TableInteriorCell;
+
+    fn key(&self) -> Key<'_> {
+        Key::RowId(self.row_id.value as i64)
+    }
+
+    fn left_child(&self) -> Option<u32> {
+        Some(self.left_child)
+    }
+
+    fn btree_page(page: &mut Page) -> Option<&mut BTreePage<Self>> {
+        match page {
+            Page::TableInterior(page) => Some(page),
+            _ => None,
+        }
+    }
+
+    fn into_page(page: BTreePage<Self>) -> Page {
+        Page::TableInterior(page)
+    }
+
+    fn move_up(self, left_child: u32) -> (TableInteriorCell, Option<u32>) {
+        let right_most = self.left_child;
+        (TableInteriorCell { left_child, ..self }, Some(right_most))
+    }
+
+    fn move_down(divider: TableInteriorCell, right_most: Option<u32>) -> Self {
+        TableInteriorCell {
+            left_child: right_most.expect("Interior pages have a right most pointer"),
+            ..divider
+        }
+    }
+}
+
+impl Node for IndexLeafCell {
+    type Parent = IndexInteriorCell;
+
+    fn key(&self) -> Key<'_> {
+        Key::Record(&self.record.payload)
+    }
+
+    fn btree_page(page: &mut Page) -> Option<&mut BTreePage<Self>> {
+        match page {
+            Page::IndexLeaf(page) => Some(page),
+            _ => None,
+        }
+    }
+
+    fn into_page(page: BTreePage<Self>) -> Page {
+        Page::IndexLeaf(page)
+    }
+
+    fn move_up(self, left_child: u32) -> (IndexInteriorCell, Option<u32>) {
+        let divider = IndexInteriorCell {
+            left_child,
+            size: self.size,
+            overflow: self.overflow,
+            record: self.record,
+        };
+        (divider, None)
+    }
+
+    fn move_down(divider: IndexInteriorCell, _: Option<u32>) -> Self {
+        IndexLeafCell {
+            size: divider.size,
+            overflow: divider.overflow,
+            record: divider.record,
+        }
+    }
+}
+
+impl Node for IndexInteriorCell {
+    type Parent = IndexInteriorCell;
+
+    fn key(&self) -> Key<'_> {
+        Key::Record(&self.record.payload)
+    }
+
+    fn left_child(&self) -> Option<u32> {
+        Some(self.left_child)
+    }
+
+    fn btree_page(page: &mut Page) -> Option<&mut BTreePage<Self>> {
+        match page {
+            Page::IndexInterior(page) => Some(page),
+            _ => None,
+        }
+    }
+
+    fn into_page(page: BTreePage<Self>) -> Page {
+        Page::IndexInterior(page)
+    }
+
+    fn move_up(self, left_child: u32) -> (IndexInteriorCell, Option<u32>) {
+        let right_most = self.left_child;
+        (IndexInteriorCell { left_child, ..self }, Some(right_most))
+    }
+
+    fn move_down(divider: IndexInteriorCell, right_most: Option<u32>) -> Self {
+        IndexInteriorCell {
+            left_child: right_most.expect("Interior pages have a right most pointer"),
+            ..divider
+        }
+    }
+}
+
+impl<C: Cell> BTreePage<C> {
+    /// An empty page, a `usable_size` of 65536 is stored as 0
+    pub fn empty(usable_size: u32, right_most_pointer: Option<u32>) -> BTreePage<C> {
+        BTreePage {
+            db_header: None,
+            page_header: BTreePageHeader {
+                page_type: C::PAGE_TYPE,
+                first_freeblock: 0,
+                num_cells: 0,
+                cell_content_start: usable_size as u16,
+                fragmented_free_bytes: 0,
+                right_most_pointer,
+            },
+            cell_pointers: vec![],
+            cells: vec![],
+            freeblocks: vec![],
+        }
+    }
+
+    /// Offset of the cell pointer array, after the database and page headers
+    fn header_end(&self) -> usize {
+        let header = match self.page_header.right_most_pointer {
+            Some(_) => 12,
+            None => 8,
+        };
+        self.db_header.map_or(0, |_| 100) + header
+    }
+
+    fn content_start(&self) -> usize {
+        match self.page_header.cell_content_start {
+            0 => 65536,
+            n => n as usize,
+        }
+    }
+
+    /// Bytes free if the page was defragmented, negative if the cells don't fit
+    fn free_space(&self, usable_size: u32) -> isize {
+        let cells: usize = self.cells.iter().map(|cell| cell_size(cell) + 2).sum();
+        usable_size as isize - (self.header_end() + cells) as isize
+    }
+
+    /// Would all cells fit on the page if it was defragmented?
+    fn fits(&self, usable_size: u32) -> bool {
+        self.free_space(usable_size) >= 0
+    }
+
+    /// Is less than a third of the page used? SQLite balances such pages with
+    /// their siblings after removing cells.
+    fn underfull(&self, usable_size: u32) -> bool {
+        self.free_space(usable_size) * 3 > usable_size as isize * 2
+    }
+
+    /**
+     * Give a place in the cell content area to cells with a 0 cell pointer,
+     * which is never a valid offset.
+     *
+     * Like `allocateSpace()` in SQLite, a cell goes in the first freeblock big
+     * enough for it, or else in the unallocated space between the cell
+     * pointers and the content area. When neither has room the page is
+     * defragmented, moving all cells to the end of the page.
+     *
+     * The cells must [fit](Self::fits).
+     */
+    fn layout(&mut self, usable_size: u32) {
+        self.page_header.num_cells = self.cells.len() as u16;
+        let pointers_end = self.header_end() + 2 * self.cells.len();
+
+        for i in 0..self.cells.len() {
+            if self.cell_pointers[i] != 0 {
+                continue;
+            }
+            let size = cell_size(&self.cells[i]);
+            if let Some(offset) = self.take_freeblock(size) {
+                self.cell_pointers[i] = offset;
+            } else if pointers_end + size <= self.content_start() {
+                self.page_header.cell_content_start = (self.content_start() - size) as u16;
+                self.cell_pointers[i] = self.page_header.cell_content_start;
+            } else {
+                return self.defragment(usable_size);
+            }
+        }
+    }
+
+    /// Move all cells to the end of the page in order, with the first cell
+    /// last like SQLite, leaving no free space between them.
+    fn defragment(&mut self, usable_size: u32) {
+        let mut start = usable_size as usize;
+        for (cell, pointer) in self.cells.iter().zip(self.cell_pointers.iter_mut()) {
+            start -= cell_size(cell);
+            *pointer = start as u16;
+        }
+        self.page_header.cell_content_start = start as u16;
+        self.page_header.fragmented_free_bytes = 0;
+        self.freeblocks.clear();
+        self.page_header.first_freeblock = 0;
+    }
+
+    /// Offset of `size` bytes taken from the end of the first freeblock big
+    /// enough. Less than 4 bytes left over can't be a freeblock, and becomes
+    /// fragmented bytes instead.
+    fn take_freeblock(&mut self, size: usize) -> Option<u16> {
+        let i = self.freeblocks.iter().position(|block| block.size as usize >= size)?;
+        let block = &mut self.freeblocks[i];
+        let left = block.size as usize - size;
+        let offset = block.offset + left as u16;
+        if left >= 4 {
+            block.size = left as u16;
+        } else if self.page_header.fragmented_free_bytes as usize + left <= 60 {
+            self.page_header.fragmented_free_bytes += left as u8;
+            self.freeblocks.remove(i);
+            self.page_header.first_freeblock = self.freeblocks.first().map_or(0, |block| block.offset);
+        } else {
+            // Too fragmented, the page is defragmented instead
+            return None;
+        }
+        Some(offset)
+    }
+
+    /**
+     * Add the space of a removed cell to the freeblocks, like `freeSpace()` in
+     * SQLite. Freeblocks next to each other or with only fragmented bytes
+     * between them are merged, and a freeblock at the start of the cell content
+     * area becomes part of the unallocated space instead.
+     */
+    fn release(&mut self, offset: u16, size: usize) {
+        let i = self.freeblocks.partition_point(|block| block.offset < offset);
+        self.freeblocks.insert(i, Freeblock { offset, size: size as u16 });
+
+        // Merge with the next freeblock, then with the previous one
+        self.merge_freeblocks(i);
+        if i > 0 {
+            self.merge_freeblocks(i - 1);
+        }
+
+        if let Some(first) = self.freeblocks.first().copied() {
+            if first.offset as usize == self.content_start() {
+                // A page of 65536 bytes that is now empty wraps around to 0
+                self.page_header.cell_content_start = first.offset.wrapping_add(first.size);
+                self.freeblocks.remove(0);
+            }
+        }
+        self.page_header.first_freeblock = self.freeblocks.first().map_or(0, |block| block.offset);
+    }
+
+    /// Merge freeblocks `i` and `i + 1` if only fragmented bytes are between
+    fn merge_freeblocks(&mut self, i: usize) {
+        let (Some(left), Some(right)) = (self.freeblocks.get(i).copied(), self.freeblocks.get(i + 1).copied()) else {
+            return;
+        };
+        let gap = right.offset as usize - (left.offset as usize + left.size as usize);
+        if gap <= 3 {
+            let fragmented = &mut self.page_header.fragmented_free_bytes;
+            *fragmented = fragmented.saturating_sub(gap as u8);
+            self.freeblocks[i].size = (right.offset as usize + right.size as usize - left.offset as usize) as u16;
+            self.freeblocks.remove(i + 1);
+        }
+    }
+
+    /// Remove cell `i`, freeing its space
+    fn remove(&mut self, i: usize) -> C {
+        let cell = self.cells.remove(i);
+        let pointer = self.cell_pointers.remove(i);
+        if pointer != 0 {
+            self.release(pointer, cell_size(&cell));
+        }
+        self.page_header.num_cells = self.cells.len() as u16;
+        cell
+    }
+
+    /// Page number of child `i`, the right most pointer after the last cell
+    fn child(&self, i: usize) -> Option<u32>
+    where
+        C: Node,
+    {
+        match self.cells.get(i) {
+            Some(cell) => cell.left_child(),
+            None => self.page_header.right_most_pointer,
+        }
+    }
+}
+
+impl Database {
+    /// Insert a row into the table b-tree rooted at page `root`, and its key
+    /// into each index of the table.
+    ///
+    /// Fails if a row with the same `row_id` exists, or another row has the
+    /// same values in the columns of a `UNIQUE` index or constraint.
+    pub fn insert(&mut self, root: u32, row_id: i64, payload: Vec<SerialValue>) -> BinResult<()> {
+        let indexes = self.table_indexes(root).map_err(|err| error(err.to_string()))?;
+        let keys: Vec<_> = indexes.iter().map(|index| index.key(row_id, &payload)).collect();
+        for (index, key) in indexes.iter().zip(&keys) {
+            self.check_unique(index, key)?;
+        }
+
+        let cell = self.table_cell(row_id, payload);
+        if !self.insert_cell(root, cell, &by_row_id)? {
+            return Err(error(format!(
+                "Row {} already exists in the table rooted at page {}",
+                row_id, root
+            )));
+        }
+        for (index, key) in indexes.iter().zip(keys) {
+            self.insert_key(index, key)?;
+        }
+        Ok(())
+    }
+
+    /// Delete a row from the table b-tree rooted at page `root` and its key
+    /// from each index of the table, returning false if there is no row with
+    /// `row_id`.
+    ///
+    /// Its space on the page becomes a freeblock, and pages left less than a
+    /// third full are merged with their siblings, freeing pages emptied.
+    pub fn delete(&mut self, root: u32, row_id: i64) -> BinResult<bool> {
+        let indexes = self.table_indexes(root).map_err(|err| error(err.to_string()))?;
+        let Some(cell) = self.delete_cell::<TableLeafCell>(root, Key::RowId(row_id), &by_row_id)? else {
+            return Ok(false);
+        };
+        for index in &indexes {
+            self.delete_key(index, &index.key(row_id, &cell.record.payload))?;
+        }
+        Ok(true)
+    }
+
+    /// Replace the values of a row in the table b-tree rooted at page `root`,
+    /// and its key in each index where it changes, returning false if there
+    /// is no row with `row_id`.
+    ///
+    /// A row of the same size is changed in place, otherwise it is moved like
+    /// SQLite does, which may split the page. Fails like [Database::insert]
+    /// when the new values break a `UNIQUE` constraint.
+    pub fn update(&mut self, root: u32, row_id: i64, payload: Vec<SerialValue>) -> BinResult<bool> {
+        let indexes = self.table_indexes(root).map_err(|err| error(err.to_string()))?;
+        let (mut path, number, i) = self.seek::<TableLeafCell>(root, Key::RowId(row_id), &by_row_id)?;
+        let Ok(i) = i else {
+            return Ok(false);
+        };
+
+        // Only keys that change are replaced, and they are checked first
+        let old = &self.btree_page::<TableLeafCell>(number)?.cells[i].record.payload;
+        let changes: Vec<_> = indexes
+            .iter()
+            .map(|index| (index, index.key(row_id, old), index.key(row_id, &payload)))
+            .filter(|(_, old, new)| old != new)
+            .collect();
+        for (index, old, new) in &changes {
+            if index.order(&Key::Record(old), &Key::Record(new)) != Ordering::Equal {
+                self.check_unique(index, new)?;
+            }
+        }
+
+        // The new overflow pages are taken before the old ones are freed
+        let cell = self.table_cell(row_id, payload);
+        let overflow = cell.overflow();
+        let leaf = self.btree_page::<TableLeafCell>(number)?;
+        let old = leaf.cells[i].overflow();
+        if cell_size(&cell) == cell_size(&leaf.cells[i]) {
+            leaf.cells[i] = cell;
+        } else {
+            leaf.remove(i);
+            leaf.cells.insert(i, cell);
+            leaf.cell_pointers.insert(i, 0);
+        }
+        if let Some((first, _)) = old {
+            self.free_overflow(first);
+        }
+        if let Some((first, _)) = overflow {
+            self.set_pointer(first, PointerType::Overflow1, number);
+        }
+        self.balance::<TableLeafCell>(&mut path, number, false)?;
+
+        for (index, old, new) in changes {
+            self.delete_key(index, &old)?;
+            self.insert_key(index, new)?;
+        }
+        Ok(true)
+    }
+
+    /// Fail if another row has the values of `key` in a unique index
+    fn check_unique(&mut self, index: &TableIndex, key: &[SerialValue]) -> BinResult<()> {
+        let Some(values) = index.unique_values(key) else {
+            return Ok(());
+        };
+        let order = |a: &Key, b: &Key| index.order(a, b);
+        match self.contains::<IndexLeafCell>(index.root, Key::Record(values), &order)? {
+            true => Err(error(index.conflict().to_string())),
+            false => Ok(()),
+        }
+    }
+
+    fn insert_key(&mut self, index: &TableIndex, key: Vec<SerialValue>) -> BinResult<()> {
+        let cell = self.index_cell(key);
+        let order = |a: &Key, b: &Key| index.order(a, b);
+        match self.insert_cell(index.root, cell, &order)? {
+            true => Ok(()),
+            false => Err(error(format!("Index {} already has the key of the row", index.name))),
+        }
+    }
+
+    fn delete_key(&mut self, index: &TableIndex, key: &[SerialValue]) -> BinResult<()> {
+        let order = |a: &Key, b: &Key| index.order(a, b);
+        match self.delete_cell::<IndexLeafCell>(index.root, Key::Record(key), &order)? {
+            Some(_) => Ok(()),
+            None => Err(error(format!("Index {} is missing the key of the row", index.name))),
+        }
+    }
+
+    /// A table leaf cell for the row, with what doesn't fit on a page on
+    /// overflow pages
+    pub(crate) fn table_cell(&mut self, row_id: i64, payload: Vec<SerialValue>) -> TableLeafCell {
+        let mut cell = TableLeafCell::new(row_id, payload);
+        cell.overflow = self.spill(PageType::LeafTable, &cell.record);
+        cell
+    }
+
+    /// An index leaf cell for the key, with what doesn't fit on a page on
+    /// overflow pages
+    pub(crate) fn index_cell(&mut self, key: Vec<SerialValue>) -> IndexLeafCell {
+        let mut cell = IndexLeafCell::new(key);
+        cell.overflow = self.spill(PageType::LeafIndex, &cell.record);
+        cell
+    }
+
+    /**
+     * Put the part of `record` that doesn't fit in a cell on a `page_type`
+     * page on new overflow pages, returning where it continues. Each overflow
+     * page after the first has the one before it as its parent in the pointer
+     * map, the first gets the b-tree page once the cell is on one.
+     */
+    fn spill(&mut self, page_type: PageType, record: &Record) -> Option<Overflow> {
+        let usable_size = self.usable_size();
+        let local = page_type.local_size(record.size(), usable_size);
+        if local == record.size() {
+            return None;
+        }
+
+        let mut payload = Cursor::new(vec![]);
+        record
+            .write_be(&mut payload)
+            .expect("Records have values of their serial types");
+        let contents: Vec<&[u8]> = payload.get_ref()[local as usize..]
+            .chunks(usable_size as usize - 4)
+            .collect();
+        let numbers: Vec<u32> = contents.iter().map(|_| self.allocate()).collect();
+        for (k, content) in contents.into_iter().enumerate() {
+            let next = numbers.get(k + 1).copied().unwrap_or(0);
+            self.pages[numbers[k] as usize - 1] = Page::Overflow(OverflowPage {
+                next,
+                content: content.to_vec(),
+            });
+            if k > 0 {
+                self.set_pointer(numbers[k], PointerType::Overflow2, numbers[k - 1]);
+            }
+        }
+        Some(Overflow { local, page: numbers[0] })
+    }
+
+    /// Put the overflow pages from page `first` on the freelist
+    pub(crate) fn free_overflow(&mut self, first: u32) {
+        for number in self.overflow_pages(first) {
+            self.free(number);
+        }
+    }
+
+    /// Page numbers of the list of overflow pages starting at page `number`
+    pub(crate) fn overflow_pages(&self, mut number: u32) -> Vec<u32> {
+        let mut numbers = vec![];
+        // A corrupt list could loop, but it can't be longer than the file
+        while let Some(Page::Overflow(page)) = self.page(number) {
+            if numbers.len() == self.pages.len() {
+                break;
+            }
+            numbers.push(number);
+            number = page.next;
+        }
+        numbers
+    }
+
+    /// Page size without the bytes reserved at the end of each page
+    pub fn usable_size(&self) -> u32 {
+        self.db_header.usable_size()
+    }
+
+    fn page_mut(&mut self, number: u32) -> BinResult<&mut Page> {
+        number
+            .checked_sub(1)
+            .and_then(|i| self.pages.get_mut(i as usize))
+            .ok_or_else(|| error(format!("Page {} is out of range", number)))
+    }
+
+    fn btree_page<C: Node>(&mut self, number: u32) -> BinResult<&mut BTreePage<C>> {
+        C::btree_page(self.page_mut(number)?)
+            .ok_or_else(|| error(format!("Page {} is not a {:?} page", number, C::PAGE_TYPE)))
+    }
+
+    /**
+     * Find the leaf where `key` is or belongs in the b-tree rooted at `root`.
+     *
+     * Returns the interior pages on the way, the leaf page number and the
+     * index of the cell with the key, or of where it would be inserted. Keys
+     * in interior pages of index b-trees are not found, see [Database::contains].
+     */
+    fn seek<L: Node>(
+        &mut self,
+        root: u32,
+        key: Key,
+        order: Order,
+    ) -> BinResult<(Ancestors, u32, Result<usize, usize>)> {
+        let mut path = Ancestors::new();
+        let mut number = root;
+        loop {
+            let page = self.page_mut(number)?;
+            if let Some(node) = L::Parent::btree_page(page) {
+                let i = node
+                    .cells
+                    .partition_point(|c| order(&key, &c.key()) == Ordering::Greater);
+                path.push((number, i));
+                number = node.child(i).ok_or_else(|| error("Interior page without children"))?;
+            } else if let Some(leaf) = L::btree_page(page) {
+                let i = leaf
+                    .cells
+                    .partition_point(|c| order(&key, &c.key()) == Ordering::Greater);
+                let found = leaf
+                    .cells
+                    .get(i)
+                    .is_some_and(|c| order(&key, &c.key()) == Ordering::Equal);
+                return Ok((path, number, if found { Ok(i) } else { Err(i) }));
+            } else {
+                return Err(error(format!("Page {} is not part of a b-tree", number)));
+            }
+        }
+    }
+
+    /// Insert a cell into the b-tree rooted at `root`, and balance the pages
+    /// on the way from its leaf to the root. Returns false if a cell with the
+    /// same key exists, freeing the overflow pages of `cell`.
+    pub(crate) fn insert_cell<L: Node>(&mut self, root: u32, cell: L, order: Order) -> BinResult<bool> {
+        let overflow = cell.overflow();
+        let (mut path, number, i) = self.seek::<L>(root, cell.key(), order)?;
+        let Err(i) = i else {
+            if let Some((first, _)) = overflow {
+                self.free_overflow(first);
+            }
+            return Ok(false);
+        };
+        let leaf = self.btree_page::<L>(number)?;
+        leaf.cells.insert(i, cell);
+        leaf.cell_pointers.insert(i, 0);
+        if let Some((first, _)) = overflow {
+            self.set_pointer(first, PointerType::Overflow1, number);
+        }
+        self.balance::<L>(&mut path, number, false)?;
+        Ok(true)
+    }
+
+    /// Remove the cell with `key` from the b-tree rooted at `root`, and balance
+    /// the pages on the way from its leaf to the root. Returns the cell, whose
+    /// overflow pages are freed.
+    pub(crate) fn delete_cell<L: Node>(&mut self, root: u32, key: Key, order: Order) -> BinResult<Option<L>> {
+        let (mut path, number, i) = self.seek::<L>(root, key, order)?;
+        let i = match i {
+            Ok(i) => i,
+            Err(_) => return self.delete_divider::<L>(root, path, number, key, order),
+        };
+        let cell = self.btree_page::<L>(number)?.remove(i);
+        if let Some((first, _)) = cell.overflow() {
+            self.free_overflow(first);
+        }
+        self.balance::<L>(&mut path, number, true)?;
+        Ok(Some(cell))
+    }
+
+    /**
+     * Remove the divider with `key` from an interior page on the `path` to
+     * `leaf`, like `sqlite3BtreeDelete()` does. The last cell of the leaf is
+     * the key before it, and is moved up in its place before both pages are
+     * balanced.
+     */
+    fn delete_divider<L: Node>(
+        &mut self,
+        root: u32,
+        path: Ancestors,
+        leaf: u32,
+        key: Key,
+        order: Order,
+    ) -> BinResult<Option<L>> {
+        let Some(k) = self.find_divider::<L>(&path, key, order)? else {
+            return Ok(None);
+        };
+        let page = self.btree_page::<L>(leaf)?;
+        let last = page
+            .cells
+            .len()
+            .checked_sub(1)
+            .ok_or_else(|| error(format!("Leaf page {} is empty", leaf)))?;
+        let before = page.remove(last);
+        let Key::Record(payload) = before.key() else {
+            unreachable!("Only index b-trees move their dividers up");
+        };
+        let payload = payload.to_vec();
+        let overflow = before.overflow();
+
+        let (number, i) = path[k];
+        let node = self.btree_page::<L::Parent>(number)?;
+        let cell = node.remove(i);
+        let left_child = cell.left_child().expect("Interior cells have a left child");
+        node.cells.insert(i, before.move_up(left_child).0);
+        node.cell_pointers.insert(i, 0);
+        if let Some((first, _)) = cell.overflow() {
+            self.free_overflow(first);
+        }
+        if let Some((first, _)) = overflow {
+            self.set_pointer(first, PointerType::Overflow1, number);
+        }
+
+        // Balancing the interior page can move the leaf, which is found again
+        // by the key now above it
+        self.balance::<L::Parent>(&mut path[..k].to_vec(), number, false)?;
+        let (mut path, leaf, _) = self.seek::<L>(root, Key::Record(&payload), order)?;
+        self.balance::<L>(&mut path, leaf, true)?;
+        Ok(Some(L::move_down(cell, None)))
+    }
+
+    /// Whether the b-tree rooted at `root` has a cell with `key`, on a leaf or
+    /// as a divider of an index b-tree
+    pub(crate) fn contains<L: Node>(&mut self, root: u32, key: Key, order: Order) -> BinResult<bool> {
+        let (path, _, i) = self.seek::<L>(root, key, order)?;
+        Ok(i.is_ok() || self.find_divider::<L>(&path, key, order)?.is_some())
+    }
+
+    /// Position on `path` of the interior page with `key` as the divider
+    /// followed. Table b-trees only copy keys up, and keep them once the rows
+    /// are deleted, so their dividers are never found.
+    fn find_divider<L: Node>(&mut self, path: &Ancestors, key: Key, order: Order) -> BinResult<Option<usize>> {
+        if L::COPY_UP {
+            return Ok(None);
+        }
+        for (k, &(number, i)) in path.iter().enumerate() {
+            let node = self.btree_page::<L::Parent>(number)?;
+            if node
+                .cells
+                .get(i)
+                .is_some_and(|c| order(&key, &c.key()) == Ordering::Equal)
+            {
+                return Ok(Some(k));
+            }
+        }
+        Ok(None)
+    }
+
+    /**
+     * Lay out page `number`, balancing it with its siblings when its cells
+     * don't fit. Pages that are `underfull` are balanced too, which SQLite
+     * only does after removing cells or for pages above the one changed.
+     */
+    fn balance<C: Node>(&mut self, path: &mut Ancestors, number: u32, underfull: bool) -> BinResult<()> {
+        let usable_size = self.usable_size();
+        let page = self.btree_page::<C>(number)?;
+        let fits = page.fits(usable_size);
+        if fits && (path.is_empty() || !(underfull && page.underfull(usable_size))) {
+            page.layout(usable_size);
+            return Ok(());
+        }
+
+        match path.pop() {
+            None => {
+                let child = self.balance_deeper::<C>(number)?;
+                path.push((number, 0));
+                self.balance::<C>(path, child, false)
+            }
+            Some((parent, i)) => match self.balance_nonroot::<C>(parent, i, path.is_empty())? {
+                true => Ok(()),
+                false => self.balance::<C::Parent>(path, parent, true),
+            },
+        }
+    }
+
+    /// Move the cells of the root page into a new child, leaving the root with
+    /// only a right most pointer to it. Returns the page number of the child.
+    fn balance_deeper<C: Node>(&mut self, root: u32) -> BinResult<u32> {
+        let usable_size = self.usable_size();
+        let child = self.allocate();
+        let page = self.btree_page::<C>(root)?;
+
+        let mut node = BTreePage::<C::Parent>::empty(usable_size, Some(child));
+        node.db_header = page.db_header.take();
+        // Cells keep their offsets, only the page header moves
+        let leaf = std::mem::replace(page, BTreePage::empty(usable_size, None));
+
+        self.pages[root as usize - 1] = C::Parent::into_page(node);
+        self.pages[child as usize - 1] = C::into_page(leaf);
+        self.set_parent_of_children(root);
+        self.set_parent_of_children(child);
+        Ok(child)
+    }
+
+    /**
+     * Spread the cells of child `i` of page `parent` and up to 2 of its
+     * siblings over as many pages as they need.
+     *
+     * A `root` parent left with one child takes its cells when they fit, so
+     * the tree gets shallower. Returns true when that happened.
+     */
+    fn balance_nonroot<C: Node>(&mut self, parent: u32, i: usize, root: bool) -> BinResult<bool> {
+        let usable_size = self.usable_size();
+        let node = self.btree_page::<C::Parent>(parent)?;
+        let children = node.cells.len() + 1;
+        let count = children.min(3);
+        let first = i.saturating_sub(1).min(children - count);
+        let siblings: Vec<u32> = (first..first + count)
+            .map(|j| node.child(j).ok_or_else(|| error("Interior page without children")))
+            .collect::<BinResult<_>>()?;
+
+        if parent != 1 && i == node.cells.len() && self.balance_quick::<C>(parent, siblings[count - 1])? {
+            return Ok(false);
+        }
+
+        // Take all cells of the siblings, with the dividers between them
+        let node = self.btree_page::<C::Parent>(parent)?;
+        let mut dividers: Vec<C::Parent> = (first..first + count - 1).map(|_| node.remove(first)).collect();
+        dividers.reverse();
+
+        let mut cells = vec![];
+        let mut right_most = None;
+        for (k, &number) in siblings.iter().enumerate() {
+            let page = self.btree_page::<C>(number)?;
+            cells.extend(std::mem::take(&mut page.cells));
+            right_most = page.page_header.right_most_pointer;
+            if k + 1 < count {
+                let divider = dividers.pop().expect("A divider between siblings");
+                if !C::COPY_UP {
+                    cells.push(C::move_down(divider, right_most));
+                }
+            }
+        }
+
+        let (pages, dividers) = distribute::<C>(cells, usable_size, right_most.is_some());
+
+        // Reuse the pages of the siblings in order, then allocate or free
+        let mut numbers = siblings.clone();
+        while numbers.len() < pages.len() {
+            numbers.push(self.allocate());
+        }
+        for number in numbers.drain(pages.len()..) {
+            self.free(number);
+        }
+
+        let mut new_dividers = vec![];
+        let mut dividers = dividers.into_iter();
+        for (k, cells) in pages.into_iter().enumerate() {
+            let mut page = BTreePage::<C>::empty(usable_size, right_most);
+            if k + 1 < numbers.len() {
+                let copy = cells.last().and_then(|cell| cell.copy_up(numbers[k]));
+                let divider = match copy {
+                    Some(divider) => divider,
+                    None => {
+                        let divider = dividers.next().expect("A divider after every page but the last");
+                        let (divider, pointer) = divider.move_up(numbers[k]);
+                        page.page_header.right_most_pointer = pointer;
+                        divider
+                    }
+                };
+                new_dividers.push(divider);
+            }
+            page.cell_pointers = vec![0; cells.len()];
+            page.cells = cells;
+            page.layout(usable_size);
+            self.pages[numbers[k] as usize - 1] = C::into_page(page);
+            self.set_parent_of_children(numbers[k]);
+        }
+
+        // Point the parent at the new pages
+        let last = *numbers.last().expect("At least one page");
+        let node = self.btree_page::<C::Parent>(parent)?;
+        let end = first + new_dividers.len();
+        node.cell_pointers.splice(first..first, vec![0; new_dividers.len()]);
+        node.cells.splice(first..first, new_dividers);
+        if first + count == children {
+            node.page_header.right_most_pointer = Some(last);
+        } else {
+            // Same size, so it keeps its place on the page
+            let cell = node.cells.remove(end);
+            node.cells.insert(end, C::Parent::move_down(cell, Some(last)));
+        }
+        let shallower = root && node.cells.is_empty();
+        self.set_parent_of_children(parent);
+
+        match shallower {
+            true => self.balance_shallower::<C>(parent, last),
+            false => Ok(false),
+        }
+    }
+
+    /// Move the cells of the only child of the root into the root if they fit,
+    /// returning true if they did.
+    fn balance_shallower<C: Node>(&mut self, root: u32, child: u32) -> BinResult<bool> {
+        let usable_size = self.usable_size();
+        let db_header = self.btree_page::<C::Parent>(root)?.db_header;
+        let page = self.btree_page::<C>(child)?;
+        let mut node = std::mem::replace(page, BTreePage::empty(usable_size, None));
+        node.db_header = db_header;
+        if !node.fits(usable_size) {
+            node.db_header = None;
+            *self.btree_page::<C>(child)? = node;
+            return Ok(false);
+        }
+
+        // The page header grows on page 1, so cells may have to move
+        if db_header.is_some() {
+            node.defragment(usable_size);
+        }
+        self.pages[root as usize - 1] = C::into_page(node);
+        self.free(child);
+        self.set_parent_of_children(root);
+        Ok(true)
+    }
+
+    /**
+     * Rows are often added in rowid order, which always adds the new cell at
+     * the end of the right most leaf. Instead of splitting the leaf in half,
+     * the new cell goes alone on a new page so the leaves stay full.
+     *
+     * Returns false when the last cell of the right most leaf is not new, or
+     * the leaf is not a table leaf.
+     */
+    fn balance_quick<C: Node>(&mut self, parent: u32, leaf: u32) -> BinResult<bool> {
+        let usable_size = self.usable_size();
+        let page = self.btree_page::<C>(leaf)?;
+        if !C::COPY_UP || page.cells.len() < 2 || page.cell_pointers.last() != Some(&0) {
+            return Ok(false);
+        }
+        let cell = page.cells.pop().expect("A new cell");
+        page.cell_pointers.pop();
+        let divider = page
+            .cells
+            .last()
+            .and_then(|last| last.copy_up(leaf))
+            .expect("A table leaf cell");
+        page.layout(usable_size);
+
+        let number = self.allocate();
+        let mut page = BTreePage::<C>::empty(usable_size, None);
+        page.cells.push(cell);
+        page.cell_pointers.push(0);
+        page.layout(usable_size);
+        self.pages[number as usize - 1] = C::into_page(page);
+
+        let node = self.btree_page::<C::Parent>(parent)?;
+        node.cells.push(divider);
+        node.cell_pointers.push(0);
+        node.page_header.right_most_pointer = Some(number);
+        self.set_pointer(number, PointerType::BTree, parent);
+        self.set_parent_of_children(number);
+        Ok(true)
+    }
+
+    /// Number of a page that is free to use, from the freelist or at the end
+    /// of the file. The page is a [Page::FreelistLeaf] until it is replaced.
+    pub(crate) fn allocate(&mut self) -> u32 {
+        let header = &mut self.db_header;
+        let trunk = header.freelist_trunk_page;
+        if let Some(Page::FreelistTrunk(page)) = trunk.checked_sub(1).and_then(|i| self.pages.get_mut(i as usize)) {
+            header.freelist_page_count -= 1;
+            if let Some(leaf) = page.leaves.pop() {
+                page.leaf_count -= 1;
+                return leaf;
+            }
+            // An empty trunk page is used itself
+            header.freelist_trunk_page = page.next;
+            self.pages[trunk as usize - 1] = Page::FreelistLeaf;
+            return trunk;
+        }
+        self.grow()
+    }
+
+    /// Add a page at the end of the file, after the [lock-byte
+    /// page][lock_byte_page] and the next [PointerMap] page if it is there.
+    pub(crate) fn grow(&mut self) -> u32 {
+        let lock_byte = lock_byte_page(self.db_header.page_size_bytes());
+        loop {
+            let number = self.pages.len() as u32 + 1;
+            let page = match number == lock_byte {
+                true => Page::LockByte,
+                false if self.db_header.is_pointer_map_page(number) => {
+                    Page::PointerMap(PointerMap::new(self.usable_size()))
+                }
+                false => break,
+            };
+            self.pages.push(page);
+        }
+        self.pages.push(Page::FreelistLeaf);
+        self.db_header.database_page_count = self.pages.len() as u32;
+        self.pages.len() as u32
+    }
+
+    /// Allocate the root page of a new, empty b-tree of `C` cells. Auto-vacuum
+    /// databases keep them at the start of the file, see [crate::ptrmap].
+    pub(crate) fn create_btree<C: Node>(&mut self) -> BinResult<u32> {
+        let number = match self.db_header.is_auto_vacuum() {
+            true => self.allocate_root()?,
+            false => self.allocate(),
+        };
+        self.pages[number as usize - 1] = C::into_page(BTreePage::empty(self.usable_size(), None));
+        Ok(number)
+    }
+
+    /// Put every page of the b-tree rooted at `root` on the freelist, children
+    /// and overflow pages before their parents like `clearDatabasePage()` in
+    /// SQLite.
+    pub(crate) fn drop_btree(&mut self, root: u32) -> BinResult<()> {
+        let page = self.page_mut(root)?;
+        if !matches!(
+            page,
+            Page::TableInterior(_) | Page::IndexInterior(_) | Page::TableLeaf(_) | Page::IndexLeaf(_)
+        ) {
+            return Err(error(format!("Page {} is not part of a b-tree", root)));
+        }
+        let overflows = page.overflows();
+        for child in children(page) {
+            self.drop_btree(child)?;
+        }
+        for (first, _) in overflows {
+            self.free_overflow(first);
+        }
+        self.free(root);
+        Ok(())
+    }
+
+    /// Put page `number` on the freelist
+    pub(crate) fn free(&mut self, number: u32) {
+        // SQLite before 3.6.0 wrongly used 2 less entries, and still does
+        let capacity = self.usable_size() as usize / 4 - 8;
+        self.set_pointer(number, PointerType::FreePage, 0);
+        let header = &mut self.db_header;
+        header.freelist_page_count += 1;
+
+        let trunk = header.freelist_trunk_page;
+        if let Some(Page::FreelistTrunk(page)) = trunk.checked_sub(1).and_then(|i| self.pages.get_mut(i as usize)) {
+            if page.leaves.len() < capacity {
+                page.leaves.push(number);
+                page.leaf_count += 1;
+                self.pages[number as usize - 1] = Page::FreelistLeaf;
+                return;
+            }
+        }
+
+        header.freelist_trunk_page = number;
+        self.pages[number as usize - 1] = Page::FreelistTrunk(FreelistTrunk {
+            next: trunk,
+            leaf_count: 0,
+            leaves: vec![],
+        });
+    }
+}
+
+/// Page numbers of the children of an interior page, none for other pages
+pub(crate) fn children(page: &Page) -> Vec<u32> {
+    match page {
+        Page::TableInterior(node) => (0..=node.cells.len()).filter_map(|i| node.child(i)).collect(),
+        Page::IndexInterior(node) => (0..=node.cells.len()).filter_map(|i| node.child(i)).collect(),
+        _ => vec![],
+    }
+}
+
+/// Point an interior page at child page `to` instead of `from`, returning
+/// false if `from` is not one of its children.
+pub(crate) fn replace_child(page: &mut Page, from: u32, to: u32) -> bool {
+    fn replace<C: Node>(node: &mut BTreePage<C>, from: u32, to: u32, left_child: fn(&mut C) -> &mut u32) -> bool {
+        if let Some(cell) = node.cells.iter_mut().find(|cell| cell.left_child() == Some(from)) {
+            *left_child(cell) = to;
+        } else if node.page_header.right_most_pointer == Some(from) {
+            node.page_header.right_most_pointer = Some(to);
+        } else {
+            return false;
+        }
+        true
+    }
+    match page {
+        Page::TableInterior(node) => replace(node, from, to, |cell| &mut cell.left_child),
+        Page::IndexInterior(node) => replace(node, from, to, |cell| &mut cell.left_child),
+        _ => false,
+    }
+}
+
+/// Point a b-tree page with a cell, or an overflow page, at overflow page `to`
+/// instead of `from`, returning false if it doesn't point at `from`.
+pub(crate) fn replace_overflow(page: &mut Page, from: u32, to: u32) -> bool {
+    fn replace<C: Cell>(
+        node: &mut BTreePage<C>,
+        from: u32,
+        to: u32,
+        overflow: fn(&mut C) -> &mut Option<Overflow>,
+    ) -> bool {
+        match node
+            .cells
+            .iter_mut()
+            .find_map(|cell| overflow(cell).as_mut().filter(|o| o.page == from))
+        {
+            Some(overflow) => {
+                overflow.page = to;
+                true
+            }
+            None => false,
+        }
+    }
+    match page {
+        Page::TableLeaf(node) => replace(node, from, to, |cell| &mut cell.overflow),
+        Page::IndexLeaf(node) => replace(node, from, to, |cell| &mut cell.overflow),
+        Page::IndexInterior(node) => replace(node, from, to, |cell| &mut cell.overflow),
+        Page::Overflow(page) if page.next == from => {
+            page.next = to;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Order of the keys of table b-trees
+fn by_row_id(a: &Key, b: &Key) -> Ordering {
+    match (a, b) {
+        (Key::RowId(a), Key::RowId(b)) => a.cmp(b),
+        _ => unreachable!("Table b-trees are keyed by rowid"),
+    }
+}
+
+/**
+ * Split cells into pages, and the dividers between them when they are moved
+ * up. Table leaves keep all their cells and have no dividers.
+ *
+ * Pages are filled from the left, then cells move right until the right page
+ * of each pair is about as full as the left one.
+ */
+fn distribute<C: Node>(cells: Vec<C>, usable_size: u32, interior: bool) -> (Vec<Vec<C>>, Vec<C>) {
+    let capacity = usable_size as usize - if interior { 12 } else { 8 };
+    let sizes: Vec<usize> = cells.iter().map(|cell| cell_size(cell) + 2).collect();
+    // Cells between pages when dividers move up
+    let gap = usize::from(!C::COPY_UP);
+
+    // Page k has the cells up to ends[k], and its divider is at ends[k]
+    let mut ends = vec![];
+    let (mut i, mut size) = (0, 0);
+    while i < cells.len() {
+        if size + sizes[i] > capacity {
+            ends.push(i);
+            (i, size) = (i + gap, 0);
+        } else {
+            size += sizes[i];
+            i += 1;
+        }
+    }
+    ends.push(cells.len());
+
+    let start = |ends: &[usize], k: usize| if k == 0 { 0 } else { ends[k - 1] + gap };
+    for k in (1..ends.len()).rev() {
+        loop {
+            let left: usize = sizes[start(&ends, k - 1)..ends[k - 1]].iter().sum();
+            let right: usize = sizes[start(&ends, k)..ends[k]].iter().sum();
+            // The last cell of the left page either moves right, or becomes
+            // the divider and the old divider moves right.
+            let last = ends[k - 1] - 1;
+            let (lost, gained) = (sizes[last], sizes[last + gap]);
+            // Like SQLite, the last page may end up a cell pointer fuller
+            let lost = if k + 1 == ends.len() { lost - 2 } else { lost };
+            if last == start(&ends, k - 1) || right + gained > capacity || right != 0 && right + gained > left - lost {
+                break;
+            }
+            ends[k - 1] -= 1;
+        }
+    }
+
+    let mut pages = vec![];
+    let mut dividers = vec![];
+    let mut cells = cells.into_iter();
+    for k in 0..ends.len() {
+        pages.push(cells.by_ref().take(ends[k] - start(&ends, k)).collect());
+        if gap == 1 && k + 1 < ends.len() {
+            dividers.extend(cells.next());
+        }
+    }
+    (pages, dividers)
+}
+
+/// Size of a cell on the page, not counting its cell pointer
+fn cell_size<C: Cell>(cell: &C) -> usize {
+    let mut buffer = Cursor::new(vec![]);
+    cell.write_be(&mut buffer)
+        .expect("Cells have values of their serial types");
+    // SQLite never uses less than 4 bytes, so the space can become a freeblock
+    buffer.into_inner().len().max(4)
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{integrity_check, planets, reread, temp_path};
+    use binrw::{BinReaderExt, BinWrite};
+    use pretty_assertions::assert_eq;
+    use std::{
+        fs::File,
+        io::{Seek, SeekFrom, Write},
+    };
+
+    fn row_ids(db: &Database, root: u32) -> Vec<i64> {
+        db.table_rows(root)
+            .iter()
+            .map(|cell| cell.row_id.value as i64)
+            .collect()
+    }
+
+    #[test]
+    fn insert() {
+        let t = vec![
+            ("ascending", (9..=1000).collect::<Vec<i64>>()),
+            ("descending", (9..=1000).rev().collect()),
+            (
+                "odd then even",
+                (9..=1000).step_by(2).chain((10..=1000).step_by(2)).collect(),
+            ),
+        ];
+
+        for (name, ids) in t.into_iter() {
+            let mut db = planets();
+            for &id in &ids {
+                let name = format!("Planet {}", id);
+                db.insert(2, id, vec![().into(), name.as_str().into(), "Dwarf".into(), id.into()])
+                    .expect("Failed to insert");
+            }
+
+            let db = reread(&db);
+            assert_eq!(row_ids(&db, 2), (1..=1000).collect::<Vec<_>>(), "{}", name);
+            assert_eq!(db.pages.len() as u32, db.db_header.database_page_count, "{}", name);
+            assert!(matches!(db.page(2), Some(Page::TableInterior(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn insert_errors() {
+        let mut db = planets();
+
+        let err = db.insert(2, 3, vec!["Earth".into()]).unwrap_err();
+        assert!(err.to_string().contains("Row 3 already exists"), "{}", err);
+
+        // Overflow pages of a row that isn't inserted are freed again
+        let err = db.insert(2, 3, vec![vec![0u8; 5000].into()]).unwrap_err();
+        assert!(err.to_string().contains("Row 3 already exists"), "{}", err);
+        assert_eq!(db.db_header.freelist_page_count, db.db_header.database_page_count - 2);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut db = Database::new(CreateOptions {
+            page_size: 512,
+            ..Default::default()
+        })
+        .unwrap();
+        db.execute("CREATE TABLE notes (body TEXT)").unwrap();
+        let body = |id: i64, size: i64| format!("{} {}", id, "x".repeat((id * size) as usize));
+        for id in 1..=60 {
+            db.insert(2, id, vec![body(id, 50).as_str().into()]).unwrap();
+        }
+        let pages = db.db_header.database_page_count;
+
+        // Rows shrink, grow and go, and their overflow pages with them
+        for id in (1..=60).step_by(2) {
+            assert!(db.update(2, id, vec![body(id, 60 - id).as_str().into()]).unwrap());
+        }
+        for id in (3..=60).step_by(3) {
+            assert!(db.delete(2, id).unwrap());
+        }
+        assert!(db.db_header.freelist_page_count > 0);
+        db.execute("CREATE INDEX notes_by_body ON notes (body)").unwrap();
+        let db = reread(&db);
+        let ids: Vec<i64> = (1..=60).filter(|id| id % 3 != 0).collect();
+        let mut bodies: Vec<String> = ids
+            .iter()
+            .map(|&id| body(id, if id % 2 == 1 { 60 - id } else { 50 }))
+            .collect();
+        let value = |body: &String| SerialValue::from(body.as_str());
+        assert_eq!(row_ids(&db, 2), ids);
+        let rows: Vec<_> = db
+            .table_rows(2)
+            .into_iter()
+            .map(|cell| cell.record.payload[0].clone())
+            .collect();
+        assert_eq!(rows, bodies.iter().map(value).collect::<Vec<_>>());
+        assert!(db.table_rows(2).iter().any(|cell| cell.overflow.is_some()));
+
+        let index = &crate::catalog::Catalog::read(&db).indexes[0];
+        let keys: Vec<_> = db
+            .index_keys(index.root)
+            .into_iter()
+            .map(|key| key.payload[0].clone())
+            .collect();
+        bodies.sort();
+        assert_eq!(keys, bodies.iter().map(value).collect::<Vec<_>>());
+
+        // Dropping the table frees all of them
+        let mut db = db;
+        db.execute("DROP TABLE notes").unwrap();
+        assert_eq!(db.db_header.freelist_page_count, db.db_header.database_page_count - 1);
+        assert!(db.db_header.database_page_count >= pages);
+    }
+
+    #[test]
+    fn delete_and_update() {
+        let mut db = planets();
+        for id in 9..=1000 {
+            db.insert(2, id, vec![().into(), format!("Planet {}", id).as_str().into()])
+                .expect("Failed to insert");
+        }
+        let pages = db.db_header.database_page_count;
+
+        for id in (1..=1000).filter(|id| id % 3 != 0) {
+            assert!(db.delete(2, id).expect("Failed to delete"), "{}", id);
+        }
+        assert!(!db.delete(2, 1).expect("Failed to delete"));
+        for id in (3..=1000).step_by(6) {
+            let name = "x".repeat(id as usize % 50);
+            assert!(db
+                .update(2, id, vec![().into(), name.as_str().into()])
+                .expect("Failed to update"));
+        }
+        assert!(!db.update(2, 1, vec![]).expect("Failed to update"));
+
+        let mut db = reread(&db);
+        assert_eq!(row_ids(&db, 2), (3..=1000).step_by(3).collect::<Vec<_>>());
+        let rows = db.table_rows(2);
+        assert_eq!(rows[0].record.payload[1], "xxx".into());
+        assert_eq!(rows[3].record.payload[1], "Planet 12".into());
+        assert!(db.db_header.freelist_page_count > 0);
+        assert_eq!(db.db_header.database_page_count, pages);
+
+        // Deleting every row leaves an empty root, with all other pages free
+        for id in (3..=1000).step_by(3) {
+            assert!(db.delete(2, id).expect("Failed to delete"), "{}", id);
+        }
+        let db = reread(&db);
+        assert!(matches!(db.page(2), Some(Page::TableLeaf(leaf)) if leaf.cells.is_empty()));
+        assert_eq!(db.db_header.freelist_page_count, pages - 2);
+    }
+
+    #[test]
+    fn insert_indexes() {
+        let mut db = planets();
+        db.execute("CREATE TABLE moons (id INTEGER PRIMARY KEY, name TEXT UNIQUE, planet INT, UNIQUE (planet, name))")
+            .unwrap();
+        db.execute("CREATE INDEX by_planet ON moons (planet DESC)").unwrap();
+        let moon = |name: &str, planet: i64| vec![().into(), name.into(), planet.into()];
+        for id in 1..=500 {
+            db.insert(3, id, moon(&format!("Moon {}", id), id % 8 + 1)).unwrap();
+        }
+        // NULLs are distinct from each other
+        db.insert(3, 501, vec![().into(), ().into(), 3.into()]).unwrap();
+        db.insert(3, 502, vec![().into(), ().into(), 3.into()]).unwrap();
+
+        let t = vec![
+            (503, moon("Moon 7", 1), "UNIQUE constraint failed: moons.name"),
+            (1, moon("Moon 1", 2), "UNIQUE constraint failed: moons.name"),
+            (1, moon("Luna", 3), "Row 1 already exists"),
+        ];
+        for (id, row, exp) in t.into_iter() {
+            let err = db.insert(3, id, row).unwrap_err();
+            assert!(err.to_string().contains(exp), "{}", err);
+        }
+
+        let db = reread(&db);
+        assert_eq!(integrity_check(&db), Vec::<String>::new());
+        let catalog = crate::catalog::Catalog::read(&db);
+        let keys: Vec<usize> = catalog
+            .indexes_on("moons")
+            .map(|index| db.index_keys(index.root).len())
+            .collect();
+        assert_eq!(keys, vec![502, 502, 502]);
+        assert_eq!(db.table_rows(3).len(), 502);
+
+        // Custom collations are only known to the statement creating the index
+        let mut collations = crate::collation::Collations::default();
+        collations.register("custom", |a, b| a.cmp(b));
+        let mut db = planets();
+        db.execute_with("CREATE INDEX by_name ON planets (name COLLATE custom)", &collations)
+            .unwrap();
+        let err = db.insert(2, 9, vec![().into(), "Pluto".into()]).unwrap_err();
+        assert!(
+            err.to_string().contains("no such collation sequence: custom"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn delete_and_update_indexes() {
+        let mut db = Database::new(CreateOptions {
+            page_size: 512,
+            ..Default::default()
+        })
+        .unwrap();
+        db.execute("CREATE TABLE moons (id INTEGER PRIMARY KEY, name TEXT UNIQUE, planet TEXT COLLATE NOCASE)")
+            .unwrap();
+        db.execute("CREATE INDEX by_planet ON moons (planet, name DESC)")
+            .unwrap();
+        let moon = |name: &str, planet: &str| vec![().into(), name.into(), planet.into()];
+        let planets = ["Earth", "Mars", "Jupiter"];
+        for id in 1..=600 {
+            db.insert(2, id, moon(&format!("Moon {}", id), planets[id as usize % 3]))
+                .unwrap();
+        }
+        assert!(matches!(db.page(3), Some(Page::IndexInterior(_))));
+
+        // Keys that are dividers of interior pages go too
+        for id in (1..=600).filter(|id| id % 4 != 0) {
+            assert!(db.delete(2, id).unwrap(), "{}", id);
+        }
+        assert_eq!(integrity_check(&db), Vec::<String>::new());
+        for id in (4..=600).step_by(8) {
+            assert!(db.update(2, id, moon(&format!("Renamed {}", id), "EARTH")).unwrap());
+        }
+        // Only in case, which the index ignores but keeps
+        assert!(db.update(2, 8, moon("Moon 8", "MARS")).unwrap());
+        assert!(db.update(2, 12, moon("Moon 12", "jupiter")).unwrap());
+
+        // The row itself has the same name, another row doesn't
+        assert!(db.update(2, 4, moon("Renamed 4", "Mars")).unwrap());
+        let err = db.update(2, 16, moon("Renamed 4", "Mars")).unwrap_err();
+        assert!(
+            err.to_string().contains("UNIQUE constraint failed: moons.name"),
+            "{}",
+            err
+        );
+
+        let mut db = reread(&db);
+        assert_eq!(integrity_check(&db), Vec::<String>::new());
+        let keys = db.index_keys(4);
+        assert_eq!(keys.len(), 150);
+        assert_eq!(keys[0].payload, vec!["EARTH".into(), "Renamed 92".into(), 92.into()]);
+        assert!(keys.iter().any(|key| key.payload[0] == "jupiter".into()));
+
+        // Deleting every row leaves empty roots, with all other pages free
+        let pages = db.db_header.database_page_count;
+        for id in (4..=600).step_by(4) {
+            assert!(db.delete(2, id).unwrap(), "{}", id);
+        }
+        let db = reread(&db);
+        assert_eq!(integrity_check(&db), Vec::<String>::new());
+        for root in 2..=4 {
+            assert_eq!(db.page(root).map(children), Some(vec![]), "{}", root);
+        }
+        assert_eq!(db.db_header.freelist_page_count, pages - 4);
+    }
+
+    #[test]
+    fn freeblocks() {
+        let mut db = planets();
+        let Some(Page::TableLeaf(page)) = db.pages.get_mut(1) else {
+            panic!("Page 2 is a table leaf");
+        };
+        let block = |offset, size| Freeblock { offset, size };
+
+        // Cells 1 and 2 are next to each other, and merge into one freeblock
+        page.remove(2);
+        assert_eq!(page.freeblocks, vec![block(4001, 31)]);
+        page.remove(1);
+        assert_eq!(page.freeblocks, vec![block(4001, 62)]);
+        assert_eq!(page.page_header.first_freeblock, 4001);
+
+        // The last cell is at the start of the cell content area
+        page.remove(5);
+        assert_eq!(page.page_header.cell_content_start, 3871);
+
+        // New cells are taken from the end of the freeblock
+        page.cells.push(TableLeafCell::new(9, vec![1.into()]));
+        page.cell_pointers.push(0);
+        page.layout(4096);
+        assert_eq!(page.cell_pointers.last(), Some(&4059));
+        assert_eq!(page.freeblocks, vec![block(4001, 58)]);
+
+        let db = reread(&db);
+        let Some(Page::TableLeaf(page)) = db.page(2) else {
+            panic!("Page 2 is a table leaf");
+        };
+        assert_eq!(page.freeblocks, vec![block(4001, 58)]);
+        assert_eq!(db.table_rows(2).len(), 6);
+    }
+
+    #[test]
+    fn freelist() {
+        let mut db = Database::new(CreateOptions::default()).unwrap();
+        let pages: Vec<u32> = (0..4).map(|_| db.allocate()).collect();
+        assert_eq!(pages, vec![2, 3, 4, 5]);
+
+        // The first page freed is a trunk, the others its leaves
+        pages.iter().for_each(|number| db.free(*number));
+        assert_eq!(
+            (db.db_header.freelist_trunk_page, db.db_header.freelist_page_count),
+            (2, 4)
+        );
+        let db = reread(&db);
+        assert_eq!(
+            db.page(2),
+            Some(&Page::FreelistTrunk(FreelistTrunk {
+                next: 0,
+                leaf_count: 3,
+                leaves: vec![3, 4, 5],
+            }))
+        );
+
+        let mut db = db;
+        let pages: Vec<u32> = (0..5).map(|_| db.allocate()).collect();
+        assert_eq!(pages, vec![5, 4, 3, 2, 6]);
+        assert_eq!(
+            (db.db_header.freelist_trunk_page, db.db_header.freelist_page_count),
+            (0, 0)
+        );
+    }
+
+    /// Writes zeros as holes, so a file over 1 GiB takes no space
+    struct Sparse(File);
+
+    impl Write for Sparse {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match buf.iter().all(|&b| b == 0) {
+                true => self.0.seek(SeekFrom::Current(buf.len() as i64)).map(|_| buf.len()),
+                false => self.0.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Seek for Sparse {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn lock_byte_page() {
+        assert_eq!(super::lock_byte_page(512), 2097153);
+        assert_eq!(super::lock_byte_page(4096), 262145);
+        assert_eq!(super::lock_byte_page(65536), 16385);
+
+        // Growing the file skips the page holding byte 2^30
+        let mut db = Database::new(CreateOptions {
+            page_size: 65536,
+            ..Default::default()
+        })
+        .unwrap();
+        let pages: Vec<u32> = (0..16385).map(|_| db.allocate()).collect();
+        assert_eq!(pages[16381..], [16383, 16384, 16386, 16387]);
+        assert_eq!(db.page(16385), Some(&Page::LockByte));
+        assert_eq!(db.db_header.database_page_count, 16387);
+
+        // Read back, it isn't parsed and it isn't on the freelist
+        pages.iter().for_each(|number| db.free(*number));
+        let path = temp_path("lock-byte-page");
+        let mut file = Sparse(File::create(&path).unwrap());
+        db.write_be(&mut file).expect("Failed to write database");
+        file.0.set_len(16387 * 65536).unwrap();
+        let mut db: Database = File::open(&path).unwrap().read_be().expect("Failed to read database");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(db.page(16385), Some(&Page::LockByte));
+        assert_eq!(db.db_header.freelist_page_count, 16385);
+
+        let pages: Vec<u32> = (0..16386).map(|_| db.allocate()).collect();
+        assert!(!pages.contains(&16385));
+        assert_eq!(pages.last(), Some(&16388));
+    }
+}
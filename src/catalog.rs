@@ -0,0 +1,610 @@
+//! # Catalog
+//!
+//! The [Catalog] describes the tables and indexes in a [Database], read from
+//! the `sqlite_schema` table on page 1, along with the statistics `ANALYZE`
+//! stores in the `sqlite_stat1` and `sqlite_stat4` tables.
+//!
+//! - [Schema table docs](https://www.sqlite.org/schematab.html)
+//! - [Statistics docs](https://www.sqlite.org/fileformat2.html#the_sqlite_stat1_table)
+//!
+//! Table columns are found by a naive parse of the `CREATE TABLE` statements,
+//! which is good enough for the statements SQLite writes but not a full SQL
+//! parser. Index columns are read with the same tokenizer as [crate::ddl].
+
+use crate::{
+    ddl,
+    schema::{Database, Record, SerialValue, TableLeafCell},
+};
+use binrw::BinRead;
+use std::{collections::HashMap, io::Cursor};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Catalog {
+    pub tables: Vec<Table>,
+    pub indexes: Vec<Index>,
+    pub stats: Stats,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub name: String,
+    /// Page number of the root of the table b-tree
+    pub root: u32,
+    pub columns: Vec<Column>,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    /// Declared type, empty if none
+    pub decl_type: String,
+    /// Declared with `COLLATE name`
+    pub collation: Option<String>,
+    /// An `INTEGER PRIMARY KEY` column is an alias for the rowid, and is
+    /// stored as NULL in the record.
+    pub rowid_alias: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Index {
+    pub name: String,
+    pub table: String,
+    /// Page number of the root of the index b-tree
+    pub root: u32,
+    /// Indexed columns in order, empty for internal indexes like
+    /// `sqlite_autoindex_*` that have no SQL and for indexes on expressions.
+    pub columns: Vec<String>,
+    /// Collation of each indexed column, declared in the index or else on
+    /// the table column, `BINARY` by default
+    pub collations: Vec<String>,
+    /// Whether each indexed column is sorted in descending order
+    pub desc: Vec<bool>,
+    pub unique: bool,
+    /// A partial index only has the rows matching its `WHERE` clause
+    pub partial: bool,
+    pub sql: String,
+}
+
+/// Statistics from `ANALYZE`, keyed by lower case names
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// `sqlite_stat1` rows by (table, index)
+    pub stat1: HashMap<(String, Option<String>), Stat1>,
+    /// `sqlite_stat4` samples by index, sorted by key
+    pub stat4: HashMap<String, Vec<Sample>>,
+}
+
+/**
+ * A row of `sqlite_stat1`
+ *
+ * The stat column is a list of integers, the number of rows in the table
+ * followed by the average number of rows matching each prefix of the index
+ * columns. So `"2000 1000 1"` for an index on `(type, name)` means 2 distinct
+ * types and unique names.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat1 {
+    pub rows: u64,
+    pub avg_eq: Vec<u64>,
+    /// The index can't be used for sorting
+    pub unordered: bool,
+}
+
+/**
+ * A row of `sqlite_stat4`, a sample of an index key
+ *
+ * For each prefix of the key columns: `neq` is the number of rows equal to the
+ * sample, `nlt` the number of rows less than it and `ndlt` the number of
+ * distinct keys less than it.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub neq: Vec<u64>,
+    pub nlt: Vec<u64>,
+    pub ndlt: Vec<u64>,
+    pub key: Vec<SerialValue>,
+}
+
+impl Catalog {
+    pub fn read(db: &Database) -> Catalog {
+        let mut catalog = Catalog {
+            tables: vec![],
+            indexes: vec![],
+            stats: Stats::default(),
+        };
+
+        // CREATE TABLE sqlite_schema(type text, name text, tbl_name text, rootpage integer, sql text);
+        for cell in db.table_rows(1) {
+            let text = |i: usize| match cell.record.payload.get(i) {
+                Some(SerialValue::String(s)) => s.clone(),
+                _ => String::new(),
+            };
+            let root = match cell.record.payload.get(3) {
+                Some(SerialValue::Number(n)) => *n as u32,
+                _ => 0,
+            };
+
+            match text(0).as_str() {
+                "table" => catalog.tables.push(Table {
+                    name: text(1),
+                    root,
+                    columns: table_columns(&text(4)),
+                    sql: text(4),
+                }),
+                "index" => {
+                    let (columns, partial) = ddl::index_definition(&text(4)).unwrap_or_default();
+                    let columns = match columns.iter().any(|c| c.expression) {
+                        true => vec![],
+                        false => columns,
+                    };
+                    catalog.indexes.push(Index {
+                        name: text(1),
+                        table: text(2),
+                        root,
+                        columns: columns.iter().map(|c| c.name.clone()).collect(),
+                        collations: columns
+                            .iter()
+                            .map(|c| c.collation.clone().unwrap_or_default())
+                            .collect(),
+                        desc: columns.iter().map(|c| c.desc).collect(),
+                        unique: text(4).to_uppercase().starts_with("CREATE UNIQUE"),
+                        partial,
+                        sql: text(4),
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        // Tables come before their indexes, but only usually in rowid order
+        for index in catalog.indexes.iter_mut() {
+            let table = catalog
+                .tables
+                .iter()
+                .find(|t| t.name.eq_ignore_ascii_case(&index.table));
+            for (name, collation) in index.columns.iter().zip(index.collations.iter_mut()) {
+                if collation.is_empty() {
+                    *collation = table
+                        .and_then(|t| t.column(name).and_then(|i| t.columns[i].collation.clone()))
+                        .unwrap_or("BINARY".to_string());
+                }
+            }
+        }
+
+        catalog.stats = Stats::read(db, &catalog);
+        catalog
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|t| t.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn index(&self, name: &str) -> Option<&Index> {
+        self.indexes.iter().find(|i| i.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Indexes on a table, in the order they were created
+    pub fn indexes_on<'a>(&'a self, table: &'a str) -> impl Iterator<Item = &'a Index> + 'a {
+        self.indexes.iter().filter(move |i| i.table.eq_ignore_ascii_case(table))
+    }
+}
+
+impl Table {
+    /// Position of a column, also used as its position in the record
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn rowid_alias(&self) -> Option<usize> {
+        self.columns.iter().position(|c| c.rowid_alias)
+    }
+
+    /// Every column of a row in order, with the rowid in place of its alias.
+    /// Columns added by `ALTER TABLE` are missing from older records and NULL.
+    pub fn row(&self, cell: &TableLeafCell) -> Vec<SerialValue> {
+        self.columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| match column.rowid_alias {
+                true => SerialValue::Number(cell.row_id.value as i64),
+                false => cell.record.payload.get(i).cloned().unwrap_or(SerialValue::Null),
+            })
+            .collect()
+    }
+
+    /// Is `name` the rowid, either by one of its names or an alias?
+    pub fn is_rowid(&self, name: &str) -> bool {
+        match self.column(name) {
+            Some(i) => self.columns[i].rowid_alias,
+            None => ["rowid", "oid", "_rowid_"].iter().any(|r| r.eq_ignore_ascii_case(name)),
+        }
+    }
+}
+
+impl Stats {
+    fn read(db: &Database, catalog: &Catalog) -> Stats {
+        let mut stats = Stats::default();
+        let key = |s: &SerialValue| match s {
+            SerialValue::String(s) => Some(s.to_lowercase()),
+            _ => None,
+        };
+
+        // CREATE TABLE sqlite_stat1(tbl, idx, stat)
+        if let Some(table) = catalog.table("sqlite_stat1") {
+            for cell in db.table_rows(table.root) {
+                let [tbl, idx, SerialValue::String(stat)] = &cell.record.payload[..] else {
+                    continue;
+                };
+                let numbers: Vec<u64> = stat.split_whitespace().map_while(|w| w.parse().ok()).collect();
+                let Some((&rows, avg_eq)) = numbers.split_first() else {
+                    continue;
+                };
+                let unordered = stat.split_whitespace().any(|w| w == "unordered");
+                if let Some(tbl) = key(tbl) {
+                    stats.stat1.insert(
+                        (tbl, key(idx)),
+                        Stat1 {
+                            rows,
+                            avg_eq: avg_eq.to_vec(),
+                            unordered,
+                        },
+                    );
+                }
+            }
+        }
+
+        // CREATE TABLE sqlite_stat4(tbl, idx, neq, nlt, ndlt, sample)
+        if let Some(table) = catalog.table("sqlite_stat4") {
+            for cell in db.table_rows(table.root) {
+                let [_, idx, neq, nlt, ndlt, SerialValue::Blob(sample)] = &cell.record.payload[..] else {
+                    continue;
+                };
+                let numbers = |v: &SerialValue| {
+                    v.to_string()
+                        .split_whitespace()
+                        .filter_map(|n| n.parse().ok())
+                        .collect()
+                };
+                let Ok(record) = Record::read_be(&mut Cursor::new(sample)) else {
+                    continue;
+                };
+                if let Some(idx) = key(idx) {
+                    stats.stat4.entry(idx).or_default().push(Sample {
+                        neq: numbers(neq),
+                        nlt: numbers(nlt),
+                        ndlt: numbers(ndlt),
+                        key: record.payload,
+                    });
+                }
+            }
+        }
+
+        for samples in stats.stat4.values_mut() {
+            samples.sort_by_key(|s| s.nlt.first().copied().unwrap_or_default());
+        }
+
+        stats
+    }
+
+    pub fn table(&self, table: &str) -> Option<&Stat1> {
+        let table = table.to_lowercase();
+        // Tables without an index have a row with a NULL index, otherwise
+        // every index row starts with the row count.
+        self.stat1
+            .get(&(table.clone(), None))
+            .or_else(|| self.stat1.iter().find(|((t, _), _)| *t == table).map(|(_, s)| s))
+    }
+
+    pub fn index(&self, table: &str, index: &str) -> Option<&Stat1> {
+        self.stat1.get(&(table.to_lowercase(), Some(index.to_lowercase())))
+    }
+
+    pub fn samples(&self, index: &str) -> &[Sample] {
+        self.stat4.get(&index.to_lowercase()).map_or(&[], |s| s.as_slice())
+    }
+}
+
+// * Helper functions * //
+
+/// Split the parenthesized list of a CREATE statement on top level commas
+fn definitions(sql: &str) -> Vec<&str> {
+    let Some(start) = sql.find('(') else {
+        return vec![];
+    };
+
+    let mut parts = vec![];
+    let (mut depth, mut quote, mut from) = (0, None, start + 1);
+    for (i, c) in sql.char_indices().skip_while(|(i, _)| *i <= start) {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, '(') => depth += 1,
+            (None, ')') if depth == 0 => {
+                parts.push(sql[from..i].trim());
+                break;
+            }
+            (None, ')') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(sql[from..i].trim());
+                from = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts
+}
+
+fn unquote(name: &str) -> String {
+    name.trim_matches(['"', '\'', '`', '[', ']']).to_string()
+}
+
+/// Split the leading, possibly quoted, name from the rest of a definition
+fn split_name(def: &str) -> (String, &str) {
+    let close = match def.chars().next() {
+        Some(c @ ('"' | '\'' | '`')) => c,
+        Some('[') => ']',
+        _ => {
+            let end = def.find(char::is_whitespace).unwrap_or(def.len());
+            return (def[..end].to_string(), &def[end..]);
+        }
+    };
+    match def[1..].find(close) {
+        Some(end) => (def[1..end + 1].to_string(), &def[end + 2..]),
+        None => (unquote(def), ""),
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Split an unquoted leading word from the rest of a definition, the word is
+/// empty when it starts with a quote or a symbol
+fn leading_word(def: &str) -> (&str, &str) {
+    let def = def.trim_start();
+    let end = def.find(|c: char| !is_word_char(c)).unwrap_or(def.len());
+    (&def[..end], def[end..].trim_start())
+}
+
+/**
+ * Does a definition start a table constraint, like `PRIMARY KEY (a, b)`,
+ * rather than a column with a name like `checksum` or `primary_email`?
+ */
+fn is_table_constraint(def: &str) -> bool {
+    let (first, rest) = leading_word(def);
+    let (second, rest) = leading_word(rest);
+    match first.to_uppercase().as_str() {
+        "CONSTRAINT" => true,
+        "PRIMARY" | "FOREIGN" => second.eq_ignore_ascii_case("KEY"),
+        "UNIQUE" | "CHECK" => second.is_empty() && rest.starts_with('('),
+        _ => false,
+    }
+}
+
+/// Column definitions of `CREATE TABLE name (a INTEGER PRIMARY KEY, b TEXT)`
+pub(crate) fn table_columns(sql: &str) -> Vec<Column> {
+    const COLUMN_CONSTRAINTS: [&str; 11] = [
+        "CONSTRAINT",
+        "PRIMARY",
+        "NOT",
+        "NULL",
+        "UNIQUE",
+        "CHECK",
+        "DEFAULT",
+        "COLLATE",
+        "REFERENCES",
+        "GENERATED",
+        "AS",
+    ];
+
+    let mut columns = vec![];
+    let mut primary_key = vec![];
+
+    for def in definitions(sql) {
+        if is_table_constraint(def) {
+            // Table constraint `PRIMARY KEY (a, b)`
+            let words: Vec<&str> = def
+                .split(|c: char| !is_word_char(c))
+                .filter(|w| !w.is_empty())
+                .collect();
+            if words
+                .windows(2)
+                .any(|w| w[0].eq_ignore_ascii_case("PRIMARY") && w[1].eq_ignore_ascii_case("KEY"))
+            {
+                primary_key = index_columns(def);
+            }
+            continue;
+        }
+
+        let (name, rest) = split_name(def);
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        let upper: Vec<String> = words.iter().map(|w| w.to_uppercase()).collect();
+
+        let type_len = upper
+            .iter()
+            .take_while(|w| !COLUMN_CONSTRAINTS.contains(&w.as_str()))
+            .count();
+        let decl_type = words[..type_len].join(" ");
+        let collation = upper
+            .iter()
+            .position(|w| w == "COLLATE")
+            .and_then(|i| words.get(i + 1))
+            .map(|c| unquote(c));
+        let primary = upper.windows(2).any(|w| w[0] == "PRIMARY" && w[1] == "KEY");
+        let desc = upper.iter().any(|w| w == "DESC");
+
+        columns.push(Column {
+            name,
+            rowid_alias: primary && !desc && decl_type.eq_ignore_ascii_case("INTEGER"),
+            decl_type,
+            collation,
+        });
+    }
+
+    if let [key] = &primary_key[..] {
+        for column in columns.iter_mut() {
+            if column.name.eq_ignore_ascii_case(key) && column.decl_type.eq_ignore_ascii_case("INTEGER") {
+                column.rowid_alias = true;
+            }
+        }
+    }
+
+    columns
+}
+
+/// Column names in a list like `PRIMARY KEY (a, b COLLATE NOCASE DESC)`
+fn index_columns(sql: &str) -> Vec<String> {
+    definitions(sql).iter().map(|def| split_name(def).0).collect()
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs::File;
+
+    #[test]
+    fn parse_columns() {
+        let sql =
+            "CREATE TABLE t (\"id\" integer, [name] TEXT COLLATE nocase NOT NULL, price decimal(10, 2) DEFAULT 0, \
+                   PRIMARY KEY (id), CHECK (price > 0))";
+        let columns = table_columns(sql);
+
+        let names: Vec<(&str, &str, bool)> = columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.decl_type.as_str(), c.rowid_alias))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("id", "integer", true),
+                ("name", "TEXT", false),
+                ("price", "decimal(10, 2)", false)
+            ]
+        );
+        assert_eq!(columns[1].collation, Some("nocase".into()));
+
+        // Column names that start like a table constraint
+        let sql = "CREATE TABLE files (id INTEGER PRIMARY KEY, checksum TEXT, unique_id INT, primary_email TEXT, \
+                   constraint_x, foreign_key TEXT, name TEXT, CONSTRAINT pk UNIQUE(checksum), CHECK(id > 0))";
+        let names: Vec<String> = table_columns(sql).into_iter().map(|c| c.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "id",
+                "checksum",
+                "unique_id",
+                "primary_email",
+                "constraint_x",
+                "foreign_key",
+                "name"
+            ]
+        );
+        let sql = "CREATE TABLE t (a INTEGER, b TEXT, CONSTRAINT pk PRIMARY KEY (a))";
+        assert!(table_columns(sql)[0].rowid_alias);
+
+        let sql = "PRIMARY KEY (a COLLATE NOCASE, \"b c\" DESC)";
+        assert_eq!(index_columns(sql), vec!["a", "b c"]);
+    }
+
+    #[test]
+    fn read_planets() {
+        let mut file = File::open("data/planets.db").expect("Failed to open planets.db");
+        let db = Database::read(&mut file).expect("Failed to read database");
+        let catalog = Catalog::read(&db);
+
+        let planets = catalog.table("PLANETS").expect("planets table");
+        assert_eq!(planets.root, 2);
+        let names: Vec<&str> = planets.columns.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["id", "name", "type", "diameter", "distance", "moons"]);
+        assert!(planets.is_rowid("id") && planets.is_rowid("rowid") && !planets.is_rowid("name"));
+        assert_eq!(catalog.stats, Stats::default());
+    }
+
+    #[test]
+    fn read_indexes() {
+        let mut db = Database::new(Default::default()).unwrap();
+        let schema = [
+            ("table", "t", "CREATE TABLE t (a TEXT COLLATE nocase, b INT, \"c d\")"),
+            ("index", "ab", "CREATE UNIQUE INDEX ab ON t (a, b COLLATE rtrim DESC)"),
+            (
+                "index",
+                "part",
+                "CREATE INDEX part ON t (\"c d\" ASC) WHERE b IN (1, 2)",
+            ),
+            ("index", "expr", "CREATE INDEX expr ON t (lower(a), b)"),
+        ];
+        for (i, (kind, name, sql)) in schema.into_iter().enumerate() {
+            let row = vec![
+                kind.into(),
+                name.into(),
+                "t".into(),
+                ((i + 2) as i64).into(),
+                sql.into(),
+            ];
+            db.insert(1, i as i64 + 1, row).unwrap();
+        }
+        let catalog = Catalog::read(&db);
+
+        let index = |name: &str| {
+            let index = catalog.index(name).unwrap();
+            (
+                index.columns.clone(),
+                index.collations.clone(),
+                index.desc.clone(),
+                index.unique,
+                index.partial,
+            )
+        };
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            index("ab"),
+            (
+                strings(&["a", "b"]),
+                strings(&["nocase", "rtrim"]),
+                vec![false, true],
+                true,
+                false
+            )
+        );
+        assert_eq!(
+            index("part"),
+            (strings(&["c d"]), strings(&["BINARY"]), vec![false], false, true)
+        );
+        assert_eq!(index("expr"), (vec![], vec![], vec![], false, false));
+    }
+
+    #[test]
+    fn read_analyze() {
+        let mut file = File::open("data/analyze.db").expect("Failed to open analyze.db");
+        let db = Database::read(&mut file).expect("Failed to read database");
+        let catalog = Catalog::read(&db);
+
+        let by_moons = catalog.index("by_moons").expect("by_moons index");
+        assert_eq!(by_moons.columns, vec!["moons", "type"]);
+        assert_eq!(catalog.indexes_on("moons").count(), 1);
+
+        // $ sqlite3 data/analyze.db "select * from sqlite_stat1"
+        assert_eq!(
+            catalog.stats.index("planets", "by_moons"),
+            Some(&Stat1 {
+                rows: 2000,
+                avg_eq: vec![286, 143],
+                unordered: false
+            })
+        );
+        assert_eq!(catalog.stats.table("moons").map(|s| s.rows), Some(300));
+
+        // Walks interior pages, and the index keys end with the rowid
+        assert_eq!(db.table_rows(catalog.table("planets").unwrap().root).len(), 2000);
+        let keys = db.index_keys(catalog.index("by_name").unwrap().root);
+        assert_eq!(keys.len(), 2000);
+        assert_eq!(keys[0].payload, vec!["p1".into(), 1.into()]);
+        assert_eq!(keys[1].payload, vec!["p10".into(), 10.into()]);
+    }
+}
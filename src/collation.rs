@@ -0,0 +1,296 @@
+//! # Collating sequences
+//!
+//! A collation decides how two strings compare, and so the order of text in
+//! `ORDER BY`, comparisons and in indexes declared with `COLLATE name`.
+//! Searching such an index with any other collation gives wrong results, so
+//! custom collations used by an application have to be registered here.
+//!
+//! [Docs](https://www.sqlite.org/datatype3.html#collating_sequences)
+//!
+//! ```
+//! use rsqlite::collation::Collations;
+//! use std::cmp::Ordering;
+//!
+//! let mut collations = Collations::default();
+//! collations.register("length", |a, b| a.len().cmp(&b.len()));
+//!
+//! assert_eq!(collations.compare_str("LENGTH", "bb", "a"), Ok(Ordering::Greater));
+//! ```
+
+use crate::{ddl, schema::SerialValue};
+use std::{cmp::Ordering, collections::HashMap, fmt};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownCollation(pub String);
+
+impl fmt::Display for UnknownCollation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no such collation sequence: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCollation {}
+
+type Comparator = Box<dyn Fn(&str, &str) -> Ordering + Send + Sync>;
+
+/**
+ * Registry of collations by case insensitive name.
+ *
+ * The default registry has the 3 built in collations
+ *
+ * 1. `BINARY` compares bytes with `memcmp()`
+ * 2. `NOCASE` folds the 26 ASCII upper case letters to lower case first
+ * 3. `RTRIM` ignores trailing spaces
+ */
+pub struct Collations {
+    comparators: HashMap<String, Comparator>,
+}
+
+impl Collations {
+    /// Register a collation, replacing any previous one with the same name.
+    ///
+    /// The comparator must be a total order, or index seeks won't find rows.
+    pub fn register<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: Fn(&str, &str) -> Ordering + Send + Sync + 'static,
+    {
+        self.comparators.insert(name.to_lowercase(), Box::new(f));
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.comparators.contains_key(&name.to_lowercase())
+    }
+
+    /// Compare two strings with the named collation
+    pub fn compare_str(&self, name: &str, a: &str, b: &str) -> Result<Ordering, UnknownCollation> {
+        let f = self
+            .comparators
+            .get(&name.to_lowercase())
+            .ok_or_else(|| UnknownCollation(name.to_string()))?;
+        Ok(f(a, b))
+    }
+
+    /**
+     * Compare two values in SQLite sort order, using the collation for text.
+     *
+     * NULLs come first, then numbers, then text and blobs last. Integers and
+     * floats compare by their exact value, even where the integer has no
+     * exact float, and blobs byte by byte.
+     */
+    pub fn compare(&self, name: &str, a: &SerialValue, b: &SerialValue) -> Result<Ordering, UnknownCollation> {
+        use SerialValue as V;
+
+        let class = |v: &SerialValue| match v {
+            V::Null | V::Reserved => 0,
+            V::Number(_) | V::Float(_) => 1,
+            V::String(_) => 2,
+            V::Blob(_) => 3,
+        };
+
+        Ok(match (a, b) {
+            (V::Number(x), V::Number(y)) => x.cmp(y),
+            (V::Number(x), V::Float(y)) => int_float_cmp(*x, *y),
+            (V::Float(x), V::Number(y)) => int_float_cmp(*y, *x).reverse(),
+            // -0.0 equals 0.0
+            (V::Float(x), V::Float(y)) => x.partial_cmp(y).unwrap_or_else(|| x.total_cmp(y)),
+            (V::String(x), V::String(y)) => self.compare_str(name, x, y)?,
+            (V::Blob(x), V::Blob(y)) => x.cmp(y),
+            _ => class(a).cmp(&class(b)),
+        })
+    }
+
+    /// Compare two index keys column by column, each with its own collation.
+    ///
+    /// Columns without a collation use `BINARY`, extra columns are ignored
+    /// like in SQLite when one key is a prefix of the other.
+    pub fn compare_keys<S: AsRef<str>>(
+        &self,
+        collations: &[S],
+        a: &[SerialValue],
+        b: &[SerialValue],
+    ) -> Result<Ordering, UnknownCollation> {
+        for (i, (x, y)) in a.iter().zip(b).enumerate() {
+            let name = collations.get(i).map_or("binary", |c| c.as_ref());
+            match self.compare(name, x, y)? {
+                Ordering::Equal => continue,
+                ord => return Ok(ord),
+            }
+        }
+        Ok(Ordering::Equal)
+    }
+}
+
+impl Default for Collations {
+    fn default() -> Self {
+        let mut collations = Collations { comparators: HashMap::new() };
+        collations
+            .register("binary", |a, b| a.as_bytes().cmp(b.as_bytes()))
+            .register("nocase", |a, b| {
+                let fold = |s: &str| s.bytes().map(|c| c.to_ascii_lowercase()).collect::<Vec<_>>();
+                fold(a).cmp(&fold(b))
+            })
+            .register("rtrim", |a, b| a.trim_end_matches(' ').cmp(b.trim_end_matches(' ')));
+        collations
+    }
+}
+
+/**
+ * Compare an integer with a float like `sqlite3IntFloatCompare()`, without
+ * rounding the integer to the nearest float first, which would make integers
+ * above 2^53 equal to their neighbours.
+ */
+fn int_float_cmp(i: i64, r: f64) -> Ordering {
+    // 2^63 is exact as a float, unlike i64::MAX
+    let max = -(i64::MIN as f64);
+    if r < -max {
+        return Ordering::Greater;
+    }
+    if r >= max {
+        return Ordering::Less;
+    }
+    match i.cmp(&(r as i64)) {
+        // Same integer part, so the float may only be larger by a fraction
+        Ordering::Equal => (i as f64).partial_cmp(&r).unwrap_or(Ordering::Equal),
+        ord => ord,
+    }
+}
+
+/**
+ * Collation of every column of an index from its `CREATE INDEX` statement as
+ * stored in `sqlite_schema`, `BINARY` unless declared otherwise. Empty if the
+ * statement can't be parsed.
+ *
+ * ```
+ * use rsqlite::collation::index_collations;
+ *
+ * let sql = "CREATE INDEX by_name ON planets (name COLLATE NOCASE, type DESC) WHERE type IN ('a', 'b')";
+ * assert_eq!(index_collations(sql), vec!["NOCASE", "BINARY"]);
+ * ```
+ *
+ * Collations declared on the table columns are inherited by the index, which
+ * only [Index::collations](crate::catalog::Index::collations) knows about.
+ */
+pub fn index_collations(sql: &str) -> Vec<String> {
+    let (columns, _) = ddl::index_definition(sql).unwrap_or_default();
+    columns
+        .into_iter()
+        .map(|column| column.collation.unwrap_or("BINARY".to_string()))
+        .collect()
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use SerialValue as V;
+
+    #[test]
+    fn builtins() {
+        let collations = Collations::default();
+
+        let t = vec![
+            ("BINARY", "a", "B", Ordering::Greater),
+            ("NOCASE", "a", "B", Ordering::Less),
+            ("nocase", "ABC", "abc", Ordering::Equal),
+            ("BINARY", "abc  ", "abc", Ordering::Greater),
+            ("RTRIM", "abc  ", "abc", Ordering::Equal),
+        ];
+
+        for (name, a, b, exp) in t.into_iter() {
+            assert_eq!(collations.compare_str(name, a, b), Ok(exp), "{} {:?} {:?}", name, a, b);
+        }
+
+        assert_eq!(
+            collations.compare_str("missing", "a", "b"),
+            Err(UnknownCollation("missing".into()))
+        );
+    }
+
+    #[test]
+    fn sort_values() {
+        let mut collations = Collations::default();
+        collations.register("reverse", |a, b| b.cmp(a));
+
+        let mut values = vec![
+            "a".into(),
+            V::Blob(vec![0]),
+            V::Float(1.5),
+            "b".into(),
+            V::Null,
+            V::Number(1),
+        ];
+        values.sort_by(|a, b| collations.compare("reverse", a, b).unwrap());
+
+        assert_eq!(
+            values,
+            vec![
+                V::Null,
+                V::Number(1),
+                V::Float(1.5),
+                "b".into(),
+                "a".into(),
+                V::Blob(vec![0])
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_numbers() {
+        let collations = Collations::default();
+        let big = 1 << 53;
+
+        // Expected from `sqlite3 :memory: "select a < b, a = b, a > b"`
+        let t = vec![
+            (V::Number(big + 1), V::Float(big as f64), Ordering::Greater),
+            (V::Float(big as f64), V::Number(big + 1), Ordering::Less),
+            (V::Number(big), V::Float(big as f64), Ordering::Equal),
+            (V::Number(i64::MAX), V::Float(i64::MAX as f64), Ordering::Less),
+            (V::Number(i64::MIN), V::Float(i64::MIN as f64), Ordering::Equal),
+            (V::Number(i64::MIN), V::Float(-1e19), Ordering::Greater),
+            (V::Number(2), V::Float(2.5), Ordering::Less),
+            (V::Number(-2), V::Float(-2.5), Ordering::Greater),
+            (V::Number(0), V::Float(-0.0), Ordering::Equal),
+            (V::Float(-0.0), V::Float(0.0), Ordering::Equal),
+            (V::Float(1.5), V::Float(1.25), Ordering::Greater),
+        ];
+        for (a, b, exp) in t.into_iter() {
+            assert_eq!(collations.compare("binary", &a, &b), Ok(exp), "{:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn index_keys() {
+        let mut collations = Collations::default();
+        collations.register("natural", |a, b| {
+            let num = |s: &str| s.trim_start_matches(char::is_alphabetic).parse::<u64>().unwrap_or(0);
+            num(a).cmp(&num(b))
+        });
+
+        let sql = "CREATE INDEX idx ON files (\"dir\" COLLATE nocase, name COLLATE natural)";
+        let keys = index_collations(sql);
+        assert_eq!(keys, vec!["nocase", "natural"]);
+
+        // Commas inside expressions and the WHERE clause of a partial index
+        let sql = "CREATE INDEX idx ON files (substr(name, 1, 2) COLLATE nocase DESC, size) WHERE size IN (1, 2)";
+        assert_eq!(index_collations(sql), vec!["nocase", "BINARY"]);
+
+        // A binary search over an index sorted with the declared collations
+        let index = [
+            ["SRC".into(), "file2".into()],
+            ["src".into(), "file10".into()],
+            ["Tests".into(), "file1".into()],
+        ];
+        let target: [SerialValue; 2] = ["src".into(), "file10".into()];
+        let found = index.binary_search_by(|key| collations.compare_keys(&keys, key, &target).unwrap());
+        assert_eq!(found, Ok(1));
+
+        // Prefix keys match any suffix
+        assert_eq!(
+            collations.compare_keys(&keys, &["Src".into()], &index[0]),
+            Ok(Ordering::Equal)
+        );
+    }
+}
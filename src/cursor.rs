@@ -0,0 +1,340 @@
+//! # Cursors
+//!
+//! A [TableCursor] walks the rows of a table b-tree in `rowid` order like
+//! [Database::table_rows], and an [IndexCursor] the keys of an index b-tree in
+//! index order like [Database::index_keys], but one leaf page at a time,
+//! keeping only the path from the root down to the current leaf. Rows are found
+//! as they are asked for, so a statement that stops early never reads the rest
+//! of the table.
+//!
+//! Both can seek to the first entry at or after a key by following a single
+//! path down from the root, which is how a `SEARCH` of a
+//! [query plan](crate::plan) finds its rows.
+//!
+//! [Docs](https://www.sqlite.org/fileformat2.html#b_tree_pages)
+//!
+//! ```
+//! use rsqlite::{cursor::TableCursor, schema::Database};
+//!
+//! let db = Database::open("data/planets.db").unwrap();
+//! let mut cursor = TableCursor::new(&db, 2);
+//! assert_eq!(cursor.next().map(|cell| cell.row_id.value), Some(1));
+//! assert_eq!(cursor.count(), 7);
+//!
+//! let mut cursor = TableCursor::new(&db, 2);
+//! cursor.seek(7);
+//! assert_eq!(cursor.map(|cell| cell.row_id.value).collect::<Vec<_>>(), vec![7, 8]);
+//! ```
+
+use crate::schema::{Database, IndexInterior, IndexLeafCell, Page, Record, SerialValue, TableInterior, TableLeafCell};
+use std::{cmp::Ordering, slice};
+
+/// Rows of a table b-tree read as they are needed
+pub struct TableCursor<'db> {
+    db: &'db Database,
+    root: u32,
+    /// Interior pages from the root down to the current leaf, with the index
+    /// of the next child to visit on each
+    path: Vec<(&'db TableInterior, usize)>,
+    /// Cells of the current leaf not returned yet
+    cells: slice::Iter<'db, TableLeafCell>,
+}
+
+impl<'db> TableCursor<'db> {
+    /// A cursor before the first row of the table rooted at page `root`,
+    /// which has no rows if that isn't a table page
+    pub fn new(db: &'db Database, root: u32) -> TableCursor<'db> {
+        let mut cursor = TableCursor {
+            db,
+            root,
+            path: vec![],
+            cells: [].iter(),
+        };
+        cursor.descend(root);
+        cursor
+    }
+
+    /// Move before the first row with a rowid of at least `rowid`
+    pub fn seek(&mut self, rowid: i64) {
+        self.path.clear();
+        self.cells = [].iter();
+        let mut number = self.root;
+        loop {
+            match self.db.page(number) {
+                Some(Page::TableInterior(node)) => {
+                    // Dividers are the largest rowid of the child on their left
+                    let i = node.cells.partition_point(|cell| (cell.row_id.value as i64) < rowid);
+                    self.path.push((node, i + 1));
+                    match child(node, i) {
+                        Some(next) => number = next,
+                        None => return,
+                    }
+                }
+                Some(Page::TableLeaf(leaf)) => {
+                    let i = leaf.cells.partition_point(|cell| (cell.row_id.value as i64) < rowid);
+                    self.cells = leaf.cells[i..].iter();
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Follow the first children down from page `number` to a leaf
+    fn descend(&mut self, mut number: u32) {
+        loop {
+            match self.db.page(number) {
+                Some(Page::TableInterior(node)) => {
+                    self.path.push((node, 1));
+                    match child(node, 0) {
+                        Some(first) => number = first,
+                        None => return,
+                    }
+                }
+                Some(Page::TableLeaf(leaf)) => {
+                    self.cells = leaf.cells.iter();
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+impl<'db> Iterator for TableCursor<'db> {
+    type Item = &'db TableLeafCell;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(cell) = self.cells.next() {
+                return Some(cell);
+            }
+            let (node, next) = self.path.last_mut()?;
+            match child(node, *next) {
+                Some(number) => {
+                    *next += 1;
+                    self.descend(number);
+                }
+                None => {
+                    self.path.pop();
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Keys of an index b-tree read as they are needed.
+ *
+ * The keys of an interior page sit between its children, so each interior
+ * page on the path keeps a position that counts both: child `i` is at `2 * i`
+ * and the key after it at `2 * i + 1`.
+ */
+pub struct IndexCursor<'db> {
+    db: &'db Database,
+    root: u32,
+    path: Vec<(&'db IndexInterior, usize)>,
+    cells: slice::Iter<'db, IndexLeafCell>,
+}
+
+impl<'db> IndexCursor<'db> {
+    /// A cursor before the first key of the index rooted at page `root`,
+    /// which has no keys if that isn't an index page
+    pub fn new(db: &'db Database, root: u32) -> IndexCursor<'db> {
+        let mut cursor = IndexCursor {
+            db,
+            root,
+            path: vec![],
+            cells: [].iter(),
+        };
+        cursor.descend(root);
+        cursor
+    }
+
+    /**
+     * Move before the first key that `target` doesn't order as less than the
+     * key being searched for, so `target` returns [Ordering::Less] for every
+     * key before the first one wanted.
+     */
+    pub fn seek(&mut self, target: impl Fn(&[SerialValue]) -> Ordering) {
+        self.path.clear();
+        self.cells = [].iter();
+        let mut number = self.root;
+        loop {
+            match self.db.page(number) {
+                Some(Page::IndexInterior(node)) => {
+                    let i = node
+                        .cells
+                        .partition_point(|cell| target(&cell.record.payload) == Ordering::Less);
+                    self.path.push((node, 2 * i + 1));
+                    match index_child(node, i) {
+                        Some(next) => number = next,
+                        None => return,
+                    }
+                }
+                Some(Page::IndexLeaf(leaf)) => {
+                    let i = leaf
+                        .cells
+                        .partition_point(|cell| target(&cell.record.payload) == Ordering::Less);
+                    self.cells = leaf.cells[i..].iter();
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn descend(&mut self, mut number: u32) {
+        loop {
+            match self.db.page(number) {
+                Some(Page::IndexInterior(node)) => {
+                    self.path.push((node, 1));
+                    match index_child(node, 0) {
+                        Some(first) => number = first,
+                        None => return,
+                    }
+                }
+                Some(Page::IndexLeaf(leaf)) => {
+                    self.cells = leaf.cells.iter();
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+impl<'db> Iterator for IndexCursor<'db> {
+    type Item = &'db Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(cell) = self.cells.next() {
+                return Some(&cell.record);
+            }
+            let (node, next) = self.path.last_mut()?;
+            let (node, at) = (*node, *next);
+            *next += 1;
+            match (at % 2, index_child(node, at / 2)) {
+                (1, _) => match node.cells.get(at / 2) {
+                    Some(cell) => return Some(&cell.record),
+                    None => {
+                        self.path.pop();
+                    }
+                },
+                (_, Some(number)) => self.descend(number),
+                (_, None) => {
+                    self.path.pop();
+                }
+            }
+        }
+    }
+}
+
+// * Helper functions * //
+
+/// Page number of child `i` of an interior page, the right-most pointer last
+fn child(node: &TableInterior, i: usize) -> Option<u32> {
+    match node.cells.get(i) {
+        Some(cell) => Some(cell.left_child),
+        None if i == node.cells.len() => node.page_header.right_most_pointer,
+        None => None,
+    }
+}
+
+fn index_child(node: &IndexInterior, i: usize) -> Option<u32> {
+    match node.cells.get(i) {
+        Some(cell) => Some(cell.left_child),
+        None if i == node.cells.len() => node.page_header.right_most_pointer,
+        None => None,
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        collation::Collations,
+        schema::CreateOptions,
+        testing::{planets, reread},
+    };
+    use pretty_assertions::assert_eq;
+
+    /// A table of rowids 2, 4, .. 4000 deep enough to have interior pages
+    fn deep() -> Database {
+        let mut db = Database::new(CreateOptions {
+            page_size: 512,
+            ..Default::default()
+        })
+        .unwrap();
+        for row_id in (1..=2000).rev() {
+            db.insert(1, row_id * 2, vec![row_id.into()]).unwrap();
+        }
+        let db = reread(&db);
+        assert!(matches!(db.page(1), Some(Page::TableInterior(_))));
+        db
+    }
+
+    #[test]
+    fn table_cursor() {
+        let t = vec![("planets", planets(), 2), ("schema", planets(), 1), ("deep", deep(), 1)];
+        for (name, db, root) in t.into_iter() {
+            let rows: Vec<_> = TableCursor::new(&db, root).collect();
+            assert_eq!(rows, db.table_rows(root), "{}", name);
+        }
+
+        // Not a table page
+        let db = planets();
+        assert_eq!(TableCursor::new(&db, 100).count(), 0);
+    }
+
+    #[test]
+    fn table_seek() {
+        let db = deep();
+        let t = vec![
+            (-5, Some(2)),
+            (1, Some(2)),
+            (2, Some(2)),
+            (1001, Some(1002)),
+            (4000, Some(4000)),
+            (4001, None),
+        ];
+        for (rowid, exp) in t.into_iter() {
+            let mut cursor = TableCursor::new(&db, 1);
+            cursor.seek(rowid);
+            let found: Vec<i64> = cursor.map(|cell| cell.row_id.value as i64).collect();
+            assert_eq!(found.first().copied(), exp, "{}", rowid);
+            assert_eq!(
+                found.len(),
+                exp.map_or(0, |first| (4000 - first) / 2 + 1) as usize,
+                "{}",
+                rowid
+            );
+        }
+    }
+
+    #[test]
+    fn index_cursor() {
+        let db = Database::open("data/analyze.db").unwrap();
+        let collations = Collations::default();
+        for index in crate::catalog::Catalog::read(&db).indexes {
+            let keys: Vec<_> = IndexCursor::new(&db, index.root).collect();
+            assert_eq!(keys, db.index_keys(index.root), "{}", index.name);
+
+            // Seek to every 7th key, to the first of any equal ones
+            for (i, key) in keys.iter().enumerate().step_by(7) {
+                let first = keys.iter().position(|k| k.payload[0] == key.payload[0]).unwrap();
+                let mut cursor = IndexCursor::new(&db, index.root);
+                cursor.seek(|k| collations.compare("binary", &k[0], &key.payload[0]).unwrap());
+                assert_eq!(cursor.count(), keys.len() - first, "{} {}", index.name, i);
+            }
+
+            // Past the end
+            let mut cursor = IndexCursor::new(&db, index.root);
+            cursor.seek(|_| Ordering::Less);
+            assert_eq!(cursor.next(), None);
+        }
+    }
+}
@@ -0,0 +1,569 @@
+//! # Date and time functions
+//!
+//! Implements `date`, `time`, `datetime`, `julianday`, `unixepoch` and
+//! `strftime` as described [here](https://www.sqlite.org/lang_datefunc.html).
+//!
+//! Every function takes a time value followed by zero or more modifiers and
+//! returns a [SerialValue]. Just like SQLite, invalid inputs produce
+//! [SerialValue::Null] instead of an error.
+//!
+//! Internally a point in time is a Julian day number stored as an integer
+//! number of milliseconds, which is exactly how `date.c` does it.
+//!
+//! ```
+//! use rsqlite::{datetime, schema::SerialValue};
+//!
+//! let args = ["2024-01-31".into(), "+1 month".into()];
+//! assert_eq!(datetime::date(&args), SerialValue::String("2024-03-02".into()));
+//! ```
+
+use crate::schema::SerialValue;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds in a day
+const DAY: i64 = 86_400_000;
+
+/// Julian day of 1970-01-01 00:00:00 in milliseconds
+const UNIX_EPOCH_JD: i64 = 210_866_760_000_000;
+
+/// Largest valid Julian day, 9999-12-31 23:59:59.999
+const MAX_JD: i64 = 464_269_060_799_999;
+
+/**
+ * A point in time as a Julian day number in milliseconds, along with the
+ * state the modifiers need while being applied.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DateTime {
+    jd: i64,
+    /// The raw numeric input, valid only until the first modifier is applied.
+    /// Used by `unixepoch`, `julianday` and `auto`.
+    raw: Option<f64>,
+    /// Print fractional seconds, set by the `subsec` modifier.
+    subsec: bool,
+}
+
+impl DateTime {
+    fn new(jd: i64) -> Self {
+        DateTime { jd, raw: None, subsec: false }
+    }
+
+    fn valid(&self) -> bool {
+        (0..=MAX_JD).contains(&self.jd)
+    }
+
+    /// Julian day in milliseconds from calendar date and time of day
+    fn jd_from_parts(ymd: (i64, i64, i64), hms: (i64, i64, f64)) -> i64 {
+        let (mut y, mut m, d) = ymd;
+        let (h, min, s) = hms;
+
+        // Algorithm from Meeus "Astronomical Algorithms", as used in date.c
+        if m <= 2 {
+            y -= 1;
+            m += 12;
+        }
+        let a = y.div_euclid(100);
+        let b = 2 - a + a.div_euclid(4);
+        let x1 = (36525 * (y + 4716)).div_euclid(100);
+        let x2 = 306001 * (m + 1) / 10000;
+        let jd = ((x1 + x2 + d + b) as f64 - 1524.5) * DAY as f64;
+
+        jd as i64 + h * 3_600_000 + min * 60_000 + (s * 1000.0).round() as i64
+    }
+
+    /// Calendar date as (year, month, day)
+    fn ymd(&self) -> (i64, i64, i64) {
+        let z = (self.jd + DAY / 2) / DAY;
+        let a = ((z as f64 - 1867216.25) / 36524.25) as i64;
+        let a = z + 1 + a - a / 4;
+        let b = a + 1524;
+        let c = ((b as f64 - 122.1) / 365.25) as i64;
+        let d = (36525 * (c & 32767)) / 100;
+        let e = ((b - d) as f64 / 30.6001) as i64;
+        let x1 = (30.6001 * e as f64) as i64;
+
+        let day = b - d - x1;
+        let month = if e < 14 { e - 1 } else { e - 13 };
+        let year = if month > 2 { c - 4716 } else { c - 4715 };
+        (year, month, day)
+    }
+
+    /// Time of the day as (hour, minute, second with millisecond fraction)
+    fn hms(&self) -> (i64, i64, f64) {
+        let ms = (self.jd + DAY / 2) % DAY;
+        (ms / 3_600_000, ms / 60_000 % 60, (ms % 60_000) as f64 / 1000.0)
+    }
+
+    /// Day of the week, 0 is Sunday
+    fn weekday(&self) -> i64 {
+        ((self.jd + 129_600_000) / DAY) % 7
+    }
+
+    /// Zero based day of the year
+    fn yearday(&self) -> i64 {
+        let (y, _, _) = self.ymd();
+        (self.jd - DateTime::jd_from_parts((y, 1, 1), self.hms()) + DAY / 2) / DAY
+    }
+
+    fn unixepoch(&self) -> i64 {
+        self.jd / 1000 - UNIX_EPOCH_JD / 1000
+    }
+
+    /// Parse the first argument of every date time function.
+    fn parse(value: &SerialValue) -> Option<Self> {
+        match value {
+            SerialValue::Number(n) => DateTime::from_number(*n as f64),
+            SerialValue::Float(x) => DateTime::from_number(*x),
+            SerialValue::String(s) => DateTime::from_str(s.trim()),
+            _ => None,
+        }
+    }
+
+    /// Numbers are Julian day numbers unless a modifier says otherwise, so the
+    /// value may be out of range until then.
+    fn from_number(x: f64) -> Option<Self> {
+        x.is_finite().then_some(DateTime {
+            raw: Some(x),
+            ..DateTime::new((x * DAY as f64).round() as i64)
+        })
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        if s.eq_ignore_ascii_case("now") {
+            let ms = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                Ok(d) => d.as_millis() as i64,
+                Err(e) => -(e.duration().as_millis() as i64),
+            };
+            return Some(DateTime::new(UNIX_EPOCH_JD + ms));
+        }
+
+        if let Ok(x) = s.parse::<f64>() {
+            return DateTime::from_number(x);
+        }
+
+        let (ymd, rest) = match parse_ymd(s) {
+            Some((ymd, rest)) => (ymd, rest.trim_start_matches(|c: char| c.is_whitespace() || c == 'T')),
+            // A time without a date is on 2000-01-01
+            None => ((2000, 1, 1), s),
+        };
+
+        let t = if rest.is_empty() {
+            DateTime::new(DateTime::jd_from_parts(ymd, (0, 0, 0.0)))
+        } else {
+            let (hms, tz) = parse_hms(rest)?;
+            DateTime::new(DateTime::jd_from_parts(ymd, hms) - tz * 60_000)
+        };
+
+        t.valid().then_some(t)
+    }
+
+    /// Apply a single modifier, returns None if the modifier is invalid.
+    fn modify(mut self, modifier: &str) -> Option<Self> {
+        let modifier = modifier.trim().to_ascii_lowercase();
+        let raw = self.raw.take();
+
+        match modifier.as_str() {
+            // Only valid right after a numeric time value
+            "julianday" => {
+                raw?;
+            }
+            "unixepoch" => self.jd = UNIX_EPOCH_JD + (raw? * 1000.0).round() as i64,
+            "auto" => {
+                let x = raw?;
+                if !(0.0..5373484.5).contains(&x) {
+                    self.jd = UNIX_EPOCH_JD + (x * 1000.0).round() as i64;
+                }
+            }
+            _ if !self.valid() => return None,
+            "localtime" => self.jd += local_offset(self.unixepoch()) * 1000,
+            "utc" => {
+                // The offset depends on the UTC time we are looking for, so guess first
+                let guess = DateTime::new(self.jd - local_offset(self.unixepoch()) * 1000);
+                self.jd -= local_offset(guess.unixepoch()) * 1000;
+            }
+            "subsec" | "subsecond" => self.subsec = true,
+            "start of day" => self.jd -= (self.jd + DAY / 2) % DAY,
+            "start of month" => {
+                let (y, m, _) = self.ymd();
+                self.jd = DateTime::jd_from_parts((y, m, 1), (0, 0, 0.0));
+            }
+            "start of year" => {
+                let (y, _, _) = self.ymd();
+                self.jd = DateTime::jd_from_parts((y, 1, 1), (0, 0, 0.0));
+            }
+            m if m.starts_with("weekday ") => {
+                let n: f64 = m["weekday ".len()..].trim().parse().ok()?;
+                if n.fract() != 0.0 || !(0.0..7.0).contains(&n) {
+                    return None;
+                }
+                self.jd += (n as i64 - self.weekday()).rem_euclid(7) * DAY;
+            }
+            m => self.jd = self.shift(m)?,
+        }
+
+        self.valid().then_some(self)
+    }
+
+    /// Handle `±NNN unit` and `±HH:MM[:SS.SSS]` modifiers
+    fn shift(&self, m: &str) -> Option<i64> {
+        let (sign, body) = match m.as_bytes().first()? {
+            b'-' => (-1, &m[1..]),
+            b'+' => (1, &m[1..]),
+            _ => (1, m),
+        };
+
+        if body.contains(':') {
+            let ((h, min, s), 0) = parse_hms(body)? else {
+                return None;
+            };
+            return Some(self.jd + sign * (h * 3_600_000 + min * 60_000 + (s * 1000.0).round() as i64));
+        }
+
+        let (n, unit) = body.split_once(char::is_whitespace)?;
+        let n = sign as f64 * n.parse::<f64>().ok()?;
+        let unit = unit.trim();
+        let unit = unit.strip_suffix('s').unwrap_or(unit);
+
+        match unit {
+            "day" => Some(self.jd + (n * DAY as f64).round() as i64),
+            "hour" => Some(self.jd + (n * 3_600_000.0).round() as i64),
+            "minute" => Some(self.jd + (n * 60_000.0).round() as i64),
+            "second" => Some(self.jd + (n * 1000.0).round() as i64),
+            // Whole months and years move the calendar date, the fraction is
+            // added as 30 or 365 days
+            "month" => {
+                let (y, m, d) = self.ymd();
+                let m = m + n.trunc() as i64;
+                let carry = if m > 0 { (m - 1) / 12 } else { (m - 12) / 12 };
+                let jd = DateTime::jd_from_parts((y + carry, m - carry * 12, d), self.hms());
+                Some(jd + (n.fract() * 30.0 * DAY as f64).round() as i64)
+            }
+            "year" => {
+                let (y, m, d) = self.ymd();
+                let jd = DateTime::jd_from_parts((y + n.trunc() as i64, m, d), self.hms());
+                Some(jd + (n.fract() * 365.0 * DAY as f64).round() as i64)
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse the time value and apply every modifier in order.
+    fn from_args(args: &[SerialValue]) -> Option<Self> {
+        let mut t = match args.first() {
+            Some(value) => DateTime::parse(value)?,
+            None => DateTime::from_str("now")?,
+        };
+
+        for modifier in args.iter().skip(1) {
+            match modifier {
+                SerialValue::String(m) => t = t.modify(m)?,
+                _ => return None,
+            }
+        }
+
+        t.valid().then_some(t)
+    }
+
+    fn format_date(&self) -> String {
+        let (y, m, d) = self.ymd();
+        format!("{:04}-{:02}-{:02}", y, m, d)
+    }
+
+    fn format_time(&self) -> String {
+        let (h, m, s) = self.hms();
+        if self.subsec {
+            format!("{:02}:{:02}:{:06.3}", h, m, s)
+        } else {
+            format!("{:02}:{:02}:{:02}", h, m, s as i64)
+        }
+    }
+}
+
+/// `date(time-value, modifier, ...)` returns `YYYY-MM-DD`
+pub fn date(args: &[SerialValue]) -> SerialValue {
+    match DateTime::from_args(args) {
+        Some(t) => SerialValue::String(t.format_date()),
+        None => SerialValue::Null,
+    }
+}
+
+/// `time(time-value, modifier, ...)` returns `HH:MM:SS`
+pub fn time(args: &[SerialValue]) -> SerialValue {
+    match DateTime::from_args(args) {
+        Some(t) => SerialValue::String(t.format_time()),
+        None => SerialValue::Null,
+    }
+}
+
+/// `datetime(time-value, modifier, ...)` returns `YYYY-MM-DD HH:MM:SS`
+pub fn datetime(args: &[SerialValue]) -> SerialValue {
+    match DateTime::from_args(args) {
+        Some(t) => SerialValue::String(format!("{} {}", t.format_date(), t.format_time())),
+        None => SerialValue::Null,
+    }
+}
+
+/// `julianday(time-value, modifier, ...)` returns the fractional Julian day
+pub fn julianday(args: &[SerialValue]) -> SerialValue {
+    match DateTime::from_args(args) {
+        Some(t) => SerialValue::Float(t.jd as f64 / DAY as f64),
+        None => SerialValue::Null,
+    }
+}
+
+/// `unixepoch(time-value, modifier, ...)` returns seconds since 1970-01-01
+pub fn unixepoch(args: &[SerialValue]) -> SerialValue {
+    match DateTime::from_args(args) {
+        Some(t) if t.subsec => SerialValue::Float((t.jd - UNIX_EPOCH_JD) as f64 / 1000.0),
+        Some(t) => SerialValue::Number(t.unixepoch()),
+        None => SerialValue::Null,
+    }
+}
+
+/// `strftime(format, time-value, modifier, ...)`
+///
+/// Supports the same substitutions as SQLite, unknown ones return NULL.
+pub fn strftime(args: &[SerialValue]) -> SerialValue {
+    let Some(SerialValue::String(format)) = args.first() else {
+        return SerialValue::Null;
+    };
+    let Some(t) = DateTime::from_args(&args[1..]) else {
+        return SerialValue::Null;
+    };
+
+    let (y, m, d) = t.ymd();
+    let (h, min, s) = t.hms();
+    let mut out = String::with_capacity(format.len());
+    let mut chars = format.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let field = match chars.next() {
+            Some('d') => format!("{:02}", d),
+            Some('e') => format!("{:2}", d),
+            Some('f') => format!("{:06.3}", s.min(59.999)),
+            Some('F') => t.format_date(),
+            Some('H') => format!("{:02}", h),
+            Some('k') => format!("{:2}", h),
+            Some('I') => format!("{:02}", (h + 11) % 12 + 1),
+            Some('l') => format!("{:2}", (h + 11) % 12 + 1),
+            Some('j') => format!("{:03}", t.yearday() + 1),
+            Some('J') => format!("{}", t.jd as f64 / DAY as f64),
+            Some('m') => format!("{:02}", m),
+            Some('M') => format!("{:02}", min),
+            Some('p') => (if h < 12 { "AM" } else { "PM" }).to_string(),
+            Some('P') => (if h < 12 { "am" } else { "pm" }).to_string(),
+            Some('R') => format!("{:02}:{:02}", h, min),
+            Some('s') => format!("{}", t.unixepoch()),
+            Some('S') => format!("{:02}", s as i64),
+            Some('T') => format!("{:02}:{:02}:{:02}", h, min, s as i64),
+            Some('u') => format!("{}", (t.weekday() + 6) % 7 + 1),
+            Some('w') => format!("{}", t.weekday()),
+            Some('U') => format!("{:02}", (t.yearday() + 7 - t.weekday()) / 7),
+            Some('W') => format!("{:02}", (t.yearday() + 7 - (t.weekday() + 6) % 7) / 7),
+            Some(c @ ('V' | 'G' | 'g')) => {
+                // The ISO week belongs to the year holding its Thursday
+                let thursday = DateTime::new(t.jd + (3 - (t.weekday() + 6) % 7) * DAY);
+                let iso_year = thursday.ymd().0;
+                match c {
+                    'V' => format!("{:02}", thursday.yearday() / 7 + 1),
+                    'G' => format!("{:04}", iso_year),
+                    _ => format!("{:02}", iso_year % 100),
+                }
+            }
+            Some('Y') => format!("{:04}", y),
+            Some('%') => "%".to_string(),
+            _ => return SerialValue::Null,
+        };
+        out.push_str(&field);
+    }
+
+    SerialValue::String(out)
+}
+
+// * Helper functions * //
+
+/// Parse `[+-]YYYY-MM-DD` from the start of the string
+fn parse_ymd(s: &str) -> Option<((i64, i64, i64), &str)> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+
+    let b = s.as_bytes();
+    if b.len() < 10 || b[4] != b'-' || b[7] != b'-' {
+        return None;
+    }
+
+    // Slicing by bytes, which may fall inside a character that isn't a digit
+    let y = digits(s.get(0..4)?)?;
+    let (m, d) = (digits(s.get(5..7)?)?, digits(s.get(8..10)?)?);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+
+    Some(((if neg { -y } else { y }, m, d), s.get(10..)?))
+}
+
+/// Parse `HH:MM[:SS[.SSS]]` followed by an optional timezone `[+-]HH:MM` or
+/// `Z`. Returns the time and timezone offset in minutes.
+fn parse_hms(s: &str) -> Option<((i64, i64, f64), i64)> {
+    let b = s.as_bytes();
+    if b.len() < 5 || b[2] != b':' {
+        return None;
+    }
+
+    let (h, m) = (digits(s.get(0..2)?)?, digits(s.get(3..5)?)?);
+    let mut rest = s.get(5..)?;
+    let mut sec = 0.0;
+
+    if let Some(r) = rest.strip_prefix(':') {
+        let end = r.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(r.len());
+        if end < 2 || !r[..2].bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        sec = r[..end].parse().ok()?;
+        rest = &r[end..];
+    }
+
+    if h > 24 || m > 59 || sec >= 60.0 {
+        return None;
+    }
+
+    let rest = rest.trim();
+    let tz = match rest.as_bytes().first() {
+        None => 0,
+        Some(b'Z' | b'z') if rest.len() == 1 => 0,
+        Some(&c @ (b'+' | b'-')) if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let mins = digits(rest.get(1..3)?)? * 60 + digits(rest.get(4..6)?)?;
+            if c == b'-' {
+                -mins
+            } else {
+                mins
+            }
+        }
+        _ => return None,
+    };
+
+    Some(((h, m, sec), tz))
+}
+
+fn digits(s: &str) -> Option<i64> {
+    s.bytes().all(|c| c.is_ascii_digit()).then(|| s.parse().ok())?
+}
+
+/// Offset of the local timezone from UTC in seconds at the given unix time
+fn local_offset(secs: i64) -> i64 {
+    #[cfg(unix)]
+    unsafe {
+        let time = secs as libc::time_t;
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return 0;
+        }
+        tm.tm_gmtoff as i64
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = secs;
+        0
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn call(f: fn(&[SerialValue]) -> SerialValue, args: &[&str]) -> SerialValue {
+        f(&args.iter().map(|&a| a.into()).collect::<Vec<_>>())
+    }
+
+    // Expected values from `sqlite3 :memory: "select ..."`
+    #[test]
+    fn modifiers() {
+        let t = vec![
+            (
+                date as fn(&[SerialValue]) -> SerialValue,
+                vec!["2024-01-31", "+1 month"],
+                "2024-03-02",
+            ),
+            (date, vec!["2024-02-29", "+1 year"], "2025-03-01"),
+            (date, vec!["2024-10-18", "weekday 0"], "2024-10-20"),
+            (time, vec!["12:30"], "12:30:00"),
+            (datetime, vec!["2020-01-01 12:00+02:00"], "2020-01-01 10:00:00"),
+            (datetime, vec!["2020-01-01T12:00:00Z"], "2020-01-01 12:00:00"),
+            (datetime, vec!["2024-01-01", "+1.5 months"], "2024-02-16 00:00:00"),
+            (datetime, vec!["2024-01-01", "+01:30"], "2024-01-01 01:30:00"),
+            (datetime, vec!["2024-01-01 00:00:00.999"], "2024-01-01 00:00:00"),
+            (
+                datetime,
+                vec!["2024-03-15 10:00:00", "start of month", "+1 day", "-3 hours"],
+                "2024-03-01 21:00:00",
+            ),
+            (
+                datetime,
+                vec!["2024-03-15 10:20:30", "start of year"],
+                "2024-01-01 00:00:00",
+            ),
+            (
+                datetime,
+                vec!["2024-03-15 10:20:30", "start of day"],
+                "2024-03-15 00:00:00",
+            ),
+            (
+                strftime,
+                vec![
+                    "%W %U %V %G %j %u %w %e %k %l %I %p %P %f %s",
+                    "2024-12-30 13:05:07.123",
+                ],
+                "53 52 01 2025 365 1 1 30 13  1 01 PM pm 07.123 1735563907",
+            ),
+            (strftime, vec!["%F %T", "2024-01-01 01:02:03"], "2024-01-01 01:02:03"),
+        ];
+
+        for (f, args, exp) in t.into_iter() {
+            assert_eq!(call(f, &args), exp.into(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        use SerialValue as V;
+
+        let epoch = vec![V::Number(1700000000), "unixepoch".into()];
+        assert_eq!(datetime(&epoch), "2023-11-14 22:13:20".into());
+        assert_eq!(date(&[V::Number(1700000000), "auto".into()]), "2023-11-14".into());
+        assert_eq!(datetime(&[V::Float(2460000.5)]), "2023-02-25 00:00:00".into());
+        assert_eq!(call(julianday, &["2000-01-01"]), V::Float(2451544.5));
+        assert_eq!(call(unixepoch, &["1969-12-31 23:59:59.5"]), V::Number(-1));
+        assert_eq!(
+            call(unixepoch, &["2024-01-01 00:00:00.750", "subsec"]),
+            V::Float(1704067200.75)
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(call(date, &["garbage"]), SerialValue::Null);
+        assert_eq!(call(date, &["2024-13-01"]), SerialValue::Null);
+        assert_eq!(call(date, &["2024-01-01", "+1 fortnight"]), SerialValue::Null);
+        assert_eq!(call(date, &["2024-01-01", "unixepoch"]), SerialValue::Null);
+        assert_eq!(call(strftime, &["%Q", "2024-01-01"]), SerialValue::Null);
+        assert_eq!(date(&[SerialValue::Null]), SerialValue::Null);
+
+        // Characters that take more than one byte where digits should be
+        for arg in ["2024-01-0é", "202é-01-01", "2024-01-01 12:3é", "2024-01-01 12:30+0é:00"] {
+            assert_eq!(call(date, &[arg]), SerialValue::Null, "{}", arg);
+        }
+        assert_eq!(call(time, &["12:3é"]), SerialValue::Null);
+        assert_eq!(call(time, &["1é:30"]), SerialValue::Null);
+    }
+}
@@ -0,0 +1,1136 @@
+//! # Changing the schema
+//!
+//! `CREATE TABLE`, `CREATE INDEX`, `DROP TABLE` and `DROP INDEX` run against
+//! the pages of a [Database] in memory, to migrate a schema and then save it
+//! with [Database::save]. So do `VACUUM` and `PRAGMA incremental_vacuum`, see
+//! [crate::vacuum], and `VACUUM INTO`, which writes a new file, see
+//! [crate::backup].
+//!
+//! - [CREATE TABLE docs](https://www.sqlite.org/lang_createtable.html)
+//! - [CREATE INDEX docs](https://www.sqlite.org/lang_createindex.html)
+//!
+//! Every table and index is a b-tree with its own root page, listed in the
+//! `sqlite_schema` table on page 1 along with the SQL that created it. Like in
+//! SQLite, `UNIQUE` and `PRIMARY KEY` constraints other than an `INTEGER
+//! PRIMARY KEY` get an automatic index, a new index is filled with the rows
+//! already in its table, and dropping a table drops its indexes too and puts
+//! all their pages on the freelist. Every change bumps the schema cookie so
+//! other connections read the schema again.
+//!
+//! Rows changed afterwards with [Database::insert], [Database::delete] and
+//! [Database::update] change the keys of the indexes on their table too, and
+//! rows breaking a `UNIQUE` constraint are refused. Views, triggers, `WITHOUT
+//! ROWID` tables, partial indexes and indexes on expressions are not supported.
+//!
+//! ```
+//! use rsqlite::{catalog::Catalog, schema::{CreateOptions, Database}};
+//!
+//! let mut db = Database::new(CreateOptions::default()).unwrap();
+//! db.execute("CREATE TABLE planets (id INTEGER PRIMARY KEY, name TEXT, moons INT)").unwrap();
+//! db.insert(2, 1, vec![().into(), "Mercury".into(), 0.into()]).unwrap();
+//! db.insert(2, 2, vec![().into(), "Earth".into(), 1.into()]).unwrap();
+//! db.execute("CREATE INDEX by_moons ON planets (moons DESC)").unwrap();
+//!
+//! let catalog = Catalog::read(&db);
+//! let index = catalog.index("by_moons").unwrap();
+//! assert_eq!(index.columns, vec!["moons"]);
+//! assert_eq!(db.index_keys(index.root)[0].payload, vec![1.into(), 2.into()]);
+//!
+//! db.insert(2, 3, vec![().into(), "Mars".into(), 2.into()]).unwrap();
+//! assert_eq!(db.index_keys(index.root)[0].payload, vec![2.into(), 3.into()]);
+//! ```
+
+use crate::{
+    btree::Key,
+    catalog::{self, Catalog, Table},
+    collation::{Collations, UnknownCollation},
+    schema::{Database, IndexLeafCell, SerialValue, TableLeafCell},
+    sql::{self, Token},
+};
+use std::{cmp::Ordering, fmt, ops::Range};
+
+#[derive(Debug)]
+pub enum Error {
+    Token(sql::Error),
+    /// Unexpected token, or `None` at the end of input
+    Syntax(Option<String>),
+    /// Kind and name of a missing object, like `("table", "planets")`
+    NoSuch(&'static str, String),
+    /// Kind and name of the object that already has the name
+    Exists(String, String),
+    NoSuchColumn(String),
+    DuplicateColumn(String),
+    /// Names starting with `sqlite_` are for SQLite's own tables and indexes
+    Reserved(String),
+    /// Columns of a `UNIQUE` index with duplicate values, as `table.column`
+    Unique(Vec<String>),
+    /// Objects SQLite refuses to drop or index, with the reason
+    Denied(String),
+    Unsupported(&'static str),
+    Collation(UnknownCollation),
+    /// Failed to change the pages
+    Storage(binrw::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Token(err) => write!(f, "{}", err),
+            Error::Syntax(Some(token)) => write!(f, "near \"{}\": syntax error", token),
+            Error::Syntax(None) => write!(f, "incomplete input"),
+            Error::NoSuch(kind, name) => write!(f, "no such {}: {}", kind, name),
+            Error::Exists(kind, name) => write!(f, "{} {} already exists", kind, name),
+            Error::NoSuchColumn(name) => write!(f, "no such column: {}", name),
+            Error::DuplicateColumn(name) => write!(f, "duplicate column name: {}", name),
+            Error::Reserved(name) => write!(f, "object name reserved for internal use: {}", name),
+            Error::Unique(columns) => write!(f, "UNIQUE constraint failed: {}", columns.join(", ")),
+            Error::Denied(reason) => write!(f, "{}", reason),
+            Error::Unsupported(what) => write!(f, "{} are not supported", what),
+            Error::Collation(err) => write!(f, "{}", err),
+            Error::Storage(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sql::Error> for Error {
+    fn from(err: sql::Error) -> Self {
+        Error::Token(err)
+    }
+}
+
+impl From<binrw::Error> for Error {
+    fn from(err: binrw::Error) -> Self {
+        Error::Storage(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A column of an index as written, `name [COLLATE collation] [ASC | DESC]`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct IndexedColumn {
+    /// Column name, or the tokens of an expression
+    pub(crate) name: String,
+    pub(crate) expression: bool,
+    pub(crate) collation: Option<String>,
+    pub(crate) desc: bool,
+}
+
+/// A column of an index key, by its position in the table row
+#[derive(Debug, Clone, PartialEq)]
+struct KeyColumn {
+    position: usize,
+    collation: String,
+    desc: bool,
+}
+
+/**
+ * An index to keep up to date as the rows of its table change, see
+ * [Database::insert]. Keys are compared in the built-in collations.
+ */
+pub(crate) struct TableIndex {
+    pub(crate) name: String,
+    pub(crate) root: u32,
+    unique: bool,
+    columns: Vec<KeyColumn>,
+    /// Indexed columns as `table.column`, for constraint errors
+    names: Vec<String>,
+    rowid_alias: Option<usize>,
+    collations: Collations,
+}
+
+impl TableIndex {
+    /// Key of a row, the values of the indexed columns followed by the rowid
+    pub(crate) fn key(&self, row_id: i64, payload: &[SerialValue]) -> Vec<SerialValue> {
+        let mut key: Vec<SerialValue> = self
+            .columns
+            .iter()
+            .map(|c| match Some(c.position) == self.rowid_alias {
+                true => SerialValue::Number(row_id),
+                false => payload.get(c.position).cloned().unwrap_or(SerialValue::Null),
+            })
+            .collect();
+        key.push(SerialValue::Number(row_id));
+        key
+    }
+
+    /// Order of keys, where a shorter key is equal to the keys it starts
+    pub(crate) fn order(&self, a: &Key, b: &Key) -> Ordering {
+        match (a, b) {
+            (Key::Record(a), Key::Record(b)) => compare_keys(&self.columns, &self.collations, a, b),
+            _ => unreachable!("Index b-trees are keyed by records"),
+        }
+    }
+
+    /// Indexed values of a key no other row may have, or None if the index
+    /// is not unique or one of them is NULL, since NULLs are distinct
+    pub(crate) fn unique_values<'k>(&self, key: &'k [SerialValue]) -> Option<&'k [SerialValue]> {
+        let values = &key[..self.columns.len()];
+        (self.unique && !values.contains(&SerialValue::Null)).then_some(values)
+    }
+
+    /// Error for a row with the same values as another in a unique index
+    pub(crate) fn conflict(&self) -> Error {
+        Error::Unique(self.names.clone())
+    }
+}
+
+impl Database {
+    /**
+     * Indexes on the table rooted at page `root`, with their key columns from
+     * the `CREATE INDEX` statement or, for automatic indexes, the constraint
+     * of the `CREATE TABLE` statement they were made for.
+     *
+     * Fails for the indexes [Database::execute] can't create either, and for
+     * collations that are not built in.
+     */
+    pub(crate) fn table_indexes(&self, root: u32) -> Result<Vec<TableIndex>> {
+        // sqlite_schema has no indexes, and changes with every statement
+        if root == 1 {
+            return Ok(vec![]);
+        }
+        let catalog = Catalog::read(self);
+        let Some(table) = catalog.tables.iter().find(|t| t.root == root) else {
+            return Ok(vec![]);
+        };
+
+        let mut constraints = vec![];
+        if catalog.indexes_on(&table.name).any(|index| index.sql.is_empty()) {
+            let body = table_definition(&table.sql)?;
+            constraints = autoindexes(table, &split(&body))?;
+        }
+        let collations = Collations::default();
+        let mut indexes = vec![];
+        for index in catalog.indexes_on(&table.name) {
+            let (columns, unique) = match index.sql.is_empty() {
+                true => {
+                    let i = index.name.rsplit('_').next().and_then(|i| i.parse::<usize>().ok());
+                    let columns = i
+                        .and_then(|i| constraints.get(i.wrapping_sub(1)))
+                        .ok_or_else(|| Error::NoSuch("constraint for index", index.name.clone()))?;
+                    (columns.clone(), true)
+                }
+                false => {
+                    let (columns, partial) = index_definition(&index.sql)?;
+                    if partial {
+                        return Err(Error::Unsupported("partial indexes"));
+                    }
+                    (key_columns(table, &columns)?, index.unique)
+                }
+            };
+            for column in &columns {
+                if !collations.contains(&column.collation) {
+                    return Err(Error::Collation(UnknownCollation(column.collation.clone())));
+                }
+            }
+            indexes.push(TableIndex {
+                name: index.name.clone(),
+                root: index.root,
+                unique,
+                names: columns
+                    .iter()
+                    .map(|c| format!("{}.{}", table.name, table.columns[c.position].name))
+                    .collect(),
+                columns,
+                rowid_alias: table.rowid_alias(),
+                collations: Collations::default(),
+            });
+        }
+        Ok(indexes)
+    }
+
+    /// Run a `CREATE TABLE`, `CREATE INDEX`, `DROP TABLE`, `DROP INDEX`,
+    /// `VACUUM` or `PRAGMA incremental_vacuum` statement, see the [module
+    /// docs](self).
+    pub fn execute(&mut self, sql: &str) -> Result<()> {
+        self.execute_with(sql, &Collations::default())
+    }
+
+    /// Like [Database::execute], with custom collations to sort new indexes
+    pub fn execute_with(&mut self, sql: &str, collations: &Collations) -> Result<()> {
+        let mut parser = Parser::new(sql)?;
+
+        let changed = if parser.eat("create") {
+            let unique = parser.eat("unique");
+            match parser.next() {
+                Some(token) if token.is("table") && !unique => self.create_table(&mut parser)?,
+                Some(token) if token.is("index") => self.create_index(&mut parser, unique, collations)?,
+                Some(token) if ["temp", "temporary"].iter().any(|w| token.is(w)) => {
+                    return Err(Error::Unsupported("temporary tables"))
+                }
+                Some(token) if ["view", "trigger", "virtual"].iter().any(|w| token.is(w)) => {
+                    return Err(Error::Unsupported("views, triggers and virtual tables"))
+                }
+                token => return Err(Error::Syntax(token.map(|t| t.to_string()))),
+            }
+        } else if parser.eat("drop") {
+            match parser.next() {
+                Some(token) if token.is("table") => self.drop_table(&mut parser)?,
+                Some(token) if token.is("index") => self.drop_index(&mut parser)?,
+                Some(token) if ["view", "trigger"].iter().any(|w| token.is(w)) => {
+                    return Err(Error::Unsupported("views and triggers"))
+                }
+                token => return Err(Error::Syntax(token.map(|t| t.to_string()))),
+            }
+        } else if parser.eat("vacuum") {
+            // The cookie is bumped by the vacuum itself
+            parser.schema()?;
+            let into = match parser.eat("into") {
+                true => match parser.next() {
+                    Some(Token::String(path)) => Some(path),
+                    token => return Err(Error::Syntax(token.map(|t| t.to_string()))),
+                },
+                false => None,
+            };
+            parser.finish()?;
+            match into {
+                Some(path) => self.vacuum_into(path)?,
+                None => self.vacuum(None)?,
+            }
+            false
+        } else if parser.eat("pragma") {
+            self.pragma(&mut parser)?;
+            false
+        } else {
+            return Err(parser.unexpected());
+        };
+
+        if changed {
+            self.db_header.schema_cookie = self.db_header.schema_cookie.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    /// `CREATE TABLE`, returning false if it exists and `IF NOT EXISTS` is given
+    fn create_table(&mut self, parser: &mut Parser) -> Result<bool> {
+        let if_not_exists = parser.if_exists(true)?;
+        let (name, start) = parser.qualified_name()?;
+        if parser.peek().is_some_and(|t| t.is("as")) {
+            return Err(Error::Unsupported("CREATE TABLE ... AS SELECT statements"));
+        }
+        let body = parser.group()?;
+        while let Some(token) = parser.peek() {
+            if token.is("without") {
+                return Err(Error::Unsupported("WITHOUT ROWID tables"));
+            } else if token.is("strict") || *token == Token::Symbol(",") {
+                parser.next();
+            } else {
+                break;
+            }
+        }
+        parser.finish()?;
+
+        reserved(&name)?;
+        if let Some(kind) = self.schema_object(&name) {
+            return match if_not_exists && kind == "table" {
+                true => Ok(false),
+                false => Err(Error::Exists(kind, name)),
+            };
+        }
+
+        // SQLite keeps the statement as written from the name on
+        let sql = format!("CREATE TABLE {}", parser.text(start));
+        let columns = catalog::table_columns(&sql);
+        let table = Table {
+            name: name.clone(),
+            root: 0,
+            columns,
+            sql,
+        };
+        for (i, column) in table.columns.iter().enumerate() {
+            if table.columns[..i]
+                .iter()
+                .any(|c| c.name.eq_ignore_ascii_case(&column.name))
+            {
+                return Err(Error::DuplicateColumn(column.name.clone()));
+            }
+        }
+
+        let definitions = split(&body);
+        if definitions.iter().any(|def| def.is_empty()) {
+            return Err(Error::Syntax(Some(")".into())));
+        }
+        let constraints = autoindexes(&table, &definitions)?;
+        let autoincrement = body.iter().any(|t| t.is("autoincrement"));
+
+        let root = self.create_btree::<TableLeafCell>()?;
+        self.add_schema_row("table", &name, &name, root, table.sql.as_str().into())?;
+        for i in 1..=constraints.len() {
+            let root = self.create_btree::<IndexLeafCell>()?;
+            let index = format!("sqlite_autoindex_{}_{}", name, i);
+            self.add_schema_row("index", &index, &name, root, SerialValue::Null)?;
+        }
+        if autoincrement && self.schema_object("sqlite_sequence").is_none() {
+            let root = self.create_btree::<TableLeafCell>()?;
+            let sql = "CREATE TABLE sqlite_sequence(name,seq)";
+            self.add_schema_row("table", "sqlite_sequence", "sqlite_sequence", root, sql.into())?;
+        }
+        Ok(true)
+    }
+
+    /// `CREATE [UNIQUE] INDEX`, returning false if it exists and `IF NOT
+    /// EXISTS` is given
+    fn create_index(&mut self, parser: &mut Parser, unique: bool, collations: &Collations) -> Result<bool> {
+        let if_not_exists = parser.if_exists(true)?;
+        let (name, start) = parser.qualified_name()?;
+        parser.expect("on")?;
+        let table_name = parser.name()?;
+        let body = parser.group()?;
+        if parser.peek().is_some_and(|t| t.is("where")) {
+            return Err(Error::Unsupported("partial indexes"));
+        }
+        parser.finish()?;
+
+        let catalog = Catalog::read(self);
+        let table = catalog.table(&table_name).ok_or(Error::NoSuch("table", table_name))?;
+        if table.name.to_lowercase().starts_with("sqlite_") {
+            return Err(Error::Denied(format!("table {} may not be indexed", table.name)));
+        }
+        reserved(&name)?;
+        if let Some(kind) = self.schema_object(&name) {
+            return match if_not_exists && kind == "index" {
+                true => Ok(false),
+                false => Err(Error::Exists(kind, name)),
+            };
+        }
+
+        let columns = split(&body)
+            .into_iter()
+            .map(indexed_column)
+            .collect::<Result<Vec<_>>>()?;
+        let columns = key_columns(table, &columns)?;
+        for column in &columns {
+            if !collations.contains(&column.collation) {
+                return Err(Error::Collation(UnknownCollation(column.collation.clone())));
+            }
+        }
+
+        let compare = |a: &[SerialValue], b: &[SerialValue]| compare_keys(&columns, collations, a, b);
+        let mut keys: Vec<Vec<SerialValue>> = self
+            .table_rows(table.root)
+            .into_iter()
+            .map(|cell| {
+                let row = table.row(cell);
+                let mut key: Vec<SerialValue> = columns.iter().map(|c| row[c.position].clone()).collect();
+                key.push(SerialValue::Number(cell.row_id.value as i64));
+                key
+            })
+            .collect();
+        keys.sort_by(|a, b| compare(a, b));
+
+        // NULLs are distinct from each other in unique indexes
+        let n = columns.len();
+        let duplicate = |pair: &[Vec<SerialValue>]| {
+            !pair[0][..n].contains(&SerialValue::Null) && compare(&pair[0][..n], &pair[1][..n]) == Ordering::Equal
+        };
+        if unique && keys.windows(2).any(duplicate) {
+            let names = columns
+                .iter()
+                .map(|c| format!("{}.{}", table.name, table.columns[c.position].name))
+                .collect();
+            return Err(Error::Unique(names));
+        }
+
+        let order = |a: &Key, b: &Key| match (a, b) {
+            (Key::Record(a), Key::Record(b)) => compare(a, b),
+            _ => unreachable!("Index b-trees are keyed by records"),
+        };
+        let root = self.create_btree::<IndexLeafCell>()?;
+        for key in keys {
+            let cell = self.index_cell(key);
+            self.insert_cell(root, cell, &order)?;
+        }
+
+        let sql = format!(
+            "CREATE{} INDEX {}",
+            if unique { " UNIQUE" } else { "" },
+            parser.text(start)
+        );
+        let table_name = table.name.clone();
+        self.add_schema_row("index", &name, &table_name, root, sql.as_str().into())?;
+        Ok(true)
+    }
+
+    /// `DROP TABLE` with its indexes, returning false if it doesn't exist and
+    /// `IF EXISTS` is given
+    fn drop_table(&mut self, parser: &mut Parser) -> Result<bool> {
+        let if_exists = parser.if_exists(false)?;
+        let (name, _) = parser.qualified_name()?;
+        parser.finish()?;
+
+        if ["sqlite_master", "sqlite_schema"]
+            .iter()
+            .any(|n| n.eq_ignore_ascii_case(&name))
+        {
+            return Err(Error::Denied(format!("table {} may not be dropped", name)));
+        }
+        let catalog = Catalog::read(self);
+        let Some(table) = catalog.table(&name) else {
+            return match if_exists {
+                true => Ok(false),
+                false => Err(Error::NoSuch("table", name)),
+            };
+        };
+        // Only the statistics tables can go
+        let lower = table.name.to_lowercase();
+        if lower.starts_with("sqlite_") && !lower.starts_with("sqlite_stat") {
+            return Err(Error::Denied(format!("table {} may not be dropped", table.name)));
+        }
+
+        self.drop_schema_rows(|row| text(row, 2).eq_ignore_ascii_case(&table.name))?;
+        if let Some(sequence) = catalog.table("sqlite_sequence") {
+            let row_ids: Vec<i64> = self
+                .table_rows(sequence.root)
+                .into_iter()
+                .filter(|cell| text(&cell.record.payload, 0) == table.name)
+                .map(|cell| cell.row_id.value as i64)
+                .collect();
+            for row_id in row_ids {
+                self.delete(sequence.root, row_id)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// `DROP INDEX`, returning false if it doesn't exist and `IF EXISTS` is given
+    fn drop_index(&mut self, parser: &mut Parser) -> Result<bool> {
+        let if_exists = parser.if_exists(false)?;
+        let (name, _) = parser.qualified_name()?;
+        parser.finish()?;
+
+        let catalog = Catalog::read(self);
+        let Some(index) = catalog.index(&name) else {
+            return match if_exists {
+                true => Ok(false),
+                false => Err(Error::NoSuch("index", name)),
+            };
+        };
+        if index.name.to_lowercase().starts_with("sqlite_autoindex_") {
+            return Err(Error::Denied(
+                "index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped".into(),
+            ));
+        }
+
+        self.drop_schema_rows(|row| text(row, 0) == "index" && text(row, 1).eq_ignore_ascii_case(&index.name))?;
+        Ok(true)
+    }
+
+    /// `PRAGMA incremental_vacuum`, with the number of pages to free as an
+    /// argument or after `=`, all of them by default
+    fn pragma(&mut self, parser: &mut Parser) -> Result<()> {
+        let (name, _) = parser.qualified_name()?;
+        if !name.eq_ignore_ascii_case("incremental_vacuum") {
+            return Err(Error::Unsupported("PRAGMA statements other than incremental_vacuum"));
+        }
+        let argument = match parser.peek() {
+            Some(Token::Symbol("(")) => parser.group()?,
+            Some(Token::Symbol("=")) => {
+                parser.next();
+                parser.next().into_iter().collect()
+            }
+            _ => vec![],
+        };
+        parser.finish()?;
+
+        let pages = match &argument[..] {
+            [] => 0,
+            [Token::Integer(n)] => (*n).clamp(0, u32::MAX as i64) as u32,
+            [token, ..] => return Err(Error::Syntax(Some(token.to_string()))),
+        };
+        self.incremental_vacuum(pages)?;
+        Ok(())
+    }
+
+    /// Kind of the object called `name` in `sqlite_schema`, like `table`
+    fn schema_object(&self, name: &str) -> Option<String> {
+        self.table_rows(1)
+            .into_iter()
+            .find(|cell| text(&cell.record.payload, 1).eq_ignore_ascii_case(name))
+            .map(|cell| text(&cell.record.payload, 0).to_string())
+    }
+
+    /// Add a row to `sqlite_schema`, after the last one
+    fn add_schema_row(&mut self, kind: &str, name: &str, table: &str, root: u32, sql: SerialValue) -> Result<()> {
+        let row_id = self.table_rows(1).last().map_or(1, |cell| cell.row_id.value as i64 + 1);
+        let payload = vec![kind.into(), name.into(), table.into(), (root as i64).into(), sql];
+        Ok(self.insert(1, row_id, payload)?)
+    }
+
+    /// Delete the `sqlite_schema` rows matching `f`, and free the b-trees
+    /// they are the roots of. In auto-vacuum mode the last root page moves
+    /// into the place of each one, from the last one dropped to the first.
+    fn drop_schema_rows(&mut self, f: impl Fn(&[SerialValue]) -> bool) -> Result<()> {
+        let mut rows: Vec<(i64, u32)> = self
+            .table_rows(1)
+            .into_iter()
+            .filter(|cell| f(&cell.record.payload))
+            .map(|cell| {
+                let root = match cell.record.payload.get(3) {
+                    Some(SerialValue::Number(n)) => *n as u32,
+                    _ => 0,
+                };
+                (cell.row_id.value as i64, root)
+            })
+            .collect();
+        rows.sort_by_key(|&(_, root)| std::cmp::Reverse(root));
+
+        for (row_id, root) in rows {
+            // Triggers and views have no b-tree
+            if root > 1 {
+                self.drop_btree(root)?;
+                if let Some(moved) = self.move_last_root(root)? {
+                    self.set_schema_root(moved, root)?;
+                }
+            }
+            self.delete(1, row_id)?;
+        }
+        Ok(())
+    }
+
+    /// Point the `sqlite_schema` row with root page `from` at page `to`
+    fn set_schema_root(&mut self, from: u32, to: u32) -> Result<()> {
+        let row = self
+            .table_rows(1)
+            .into_iter()
+            .find_map(|cell| match cell.record.payload.get(3) {
+                Some(&SerialValue::Number(n)) if n == from as i64 => {
+                    Some((cell.row_id.value as i64, cell.record.payload.clone()))
+                }
+                _ => None,
+            });
+        if let Some((row_id, mut payload)) = row {
+            payload[3] = (to as i64).into();
+            self.update(1, row_id, payload)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tokens of a single statement with where they are in the SQL
+struct Parser<'a> {
+    sql: &'a str,
+    tokens: Vec<(Token, Range<usize>)>,
+    next: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(sql: &'a str) -> Result<Parser<'a>> {
+        let mut tokens = sql::tokenize_spans(sql)?;
+        while tokens.last().is_some_and(|(t, _)| *t == Token::Symbol(";")) {
+            tokens.pop();
+        }
+        Ok(Parser { sql, tokens, next: 0 })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(t, _)| t)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.next += usize::from(token.is_some());
+        token
+    }
+
+    /// Consume the next token if it is `keyword`
+    fn eat(&mut self, keyword: &str) -> bool {
+        let found = self.peek().is_some_and(|t| t.is(keyword));
+        self.next += usize::from(found);
+        found
+    }
+
+    fn unexpected(&mut self) -> Error {
+        Error::Syntax(self.next().map(|t| t.to_string()))
+    }
+
+    fn expect(&mut self, keyword: &str) -> Result<()> {
+        match self.eat(keyword) {
+            true => Ok(()),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    /// `IF NOT EXISTS` or `IF EXISTS`, depending on `not`
+    fn if_exists(&mut self, not: bool) -> Result<bool> {
+        if !self.eat("if") {
+            return Ok(false);
+        }
+        if not {
+            self.expect("not")?;
+        }
+        self.expect("exists")?;
+        Ok(true)
+    }
+
+    fn name(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Ident(name) | Token::Quoted(name)) => Ok(name),
+            token => Err(Error::Syntax(token.map(|t| t.to_string()))),
+        }
+    }
+
+    /// A name with an optional `main.` in front, and the offset of the name
+    fn qualified_name(&mut self) -> Result<(String, usize)> {
+        let start = |parser: &Parser| parser.tokens.get(parser.next).map_or(0, |(_, span)| span.start);
+        let (mut offset, mut name) = (start(self), self.name()?);
+        if self.peek() == Some(&Token::Symbol(".")) {
+            self.next();
+            if !name.eq_ignore_ascii_case("main") {
+                return Err(Error::NoSuch("database", name));
+            }
+            offset = start(self);
+            name = self.name()?;
+        }
+        Ok((name, offset))
+    }
+
+    /// An optional schema name, which can only be `main`
+    fn schema(&mut self) -> Result<()> {
+        match self.peek() {
+            Some(token) if token.is("into") => Ok(()),
+            Some(Token::Ident(_) | Token::Quoted(_)) => match self.name()? {
+                name if name.eq_ignore_ascii_case("main") => Ok(()),
+                name => Err(Error::NoSuch("database", name)),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Tokens between a `(` and its `)`
+    fn group(&mut self) -> Result<Vec<Token>> {
+        match self.next() {
+            Some(Token::Symbol("(")) => {}
+            token => return Err(Error::Syntax(token.map(|t| t.to_string()))),
+        }
+        let mut tokens = vec![];
+        let mut depth = 0;
+        loop {
+            match self.next() {
+                Some(Token::Symbol(")")) if depth == 0 => return Ok(tokens),
+                Some(token) => {
+                    match token {
+                        Token::Symbol("(") => depth += 1,
+                        Token::Symbol(")") => depth -= 1,
+                        _ => {}
+                    }
+                    tokens.push(token);
+                }
+                None => return Err(Error::Syntax(None)),
+            }
+        }
+    }
+
+    /// The SQL as written from byte `start` to the end of the last token
+    fn text(&self, start: usize) -> &'a str {
+        let end = self.tokens.last().map_or(start, |(_, span)| span.end);
+        &self.sql[start..end]
+    }
+}
+
+// * Helper functions * //
+
+/// Split tokens on the commas outside of parentheses
+fn split(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut parts = vec![];
+    let (mut depth, mut from) = (0, 0);
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Symbol("(") => depth += 1,
+            Token::Symbol(")") => depth -= 1,
+            Token::Symbol(",") if depth == 0 => {
+                parts.push(&tokens[from..i]);
+                from = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&tokens[from..]);
+    parts
+}
+
+/// `name | expr [COLLATE collation] [ASC | DESC]`
+fn indexed_column(tokens: &[Token]) -> Result<IndexedColumn> {
+    let (mut rest, mut desc) = (tokens, false);
+    if let [init @ .., last] = rest {
+        if last.is("asc") || last.is("desc") {
+            desc = last.is("desc");
+            rest = init;
+        }
+    }
+    let mut collation = None;
+    if let [init @ .., collate, Token::Ident(name) | Token::Quoted(name) | Token::String(name)] = rest {
+        if collate.is("collate") {
+            collation = Some(name.clone());
+            rest = init;
+        }
+    }
+
+    match rest {
+        [Token::Ident(name) | Token::Quoted(name)] => Ok(IndexedColumn {
+            name: name.clone(),
+            expression: false,
+            collation,
+            desc,
+        }),
+        [] => Err(Error::Syntax(tokens.first().map(|t| t.to_string()))),
+        _ if rest.iter().any(|t| matches!(t, Token::Symbol(_))) => Ok(IndexedColumn {
+            name: rest.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" "),
+            expression: true,
+            collation,
+            desc,
+        }),
+        [_, token, ..] => Err(Error::Syntax(Some(token.to_string()))),
+        [token] => Err(Error::Syntax(Some(token.to_string()))),
+    }
+}
+
+/**
+ * Indexed columns of a `CREATE INDEX` statement as stored in `sqlite_schema`,
+ * and whether it is a partial index with a `WHERE` clause.
+ */
+pub(crate) fn index_definition(sql: &str) -> Result<(Vec<IndexedColumn>, bool)> {
+    let mut parser = Parser::new(sql)?;
+    parser.expect("create")?;
+    parser.eat("unique");
+    parser.expect("index")?;
+    parser.if_exists(true)?;
+    parser.qualified_name()?;
+    parser.expect("on")?;
+    parser.name()?;
+    let body = parser.group()?;
+    let columns = split(&body)
+        .into_iter()
+        .map(indexed_column)
+        .collect::<Result<Vec<_>>>()?;
+    Ok((columns, parser.eat("where")))
+}
+
+/**
+ * Columns of the `UNIQUE` and `PRIMARY KEY` constraints in a column or table
+ * constraint definition of `CREATE TABLE`, which get an automatic index.
+ *
+ * A column definition has its own name as its only column.
+ */
+fn unique_constraint(def: &[Token]) -> Vec<Result<Vec<IndexedColumn>>> {
+    let mut def = def;
+    if def.first().is_some_and(|t| t.is("constraint")) {
+        def = def.get(2..).unwrap_or_default();
+    }
+
+    let Some(first) = def.first() else {
+        return vec![];
+    };
+    let table_constraint = ["primary", "unique", "check", "foreign"].iter().any(|w| first.is(w));
+    if table_constraint {
+        if !(first.is("primary") || first.is("unique")) {
+            return vec![];
+        }
+        let Some(open) = def.iter().position(|t| *t == Token::Symbol("(")) else {
+            return vec![Err(Error::Syntax(None))];
+        };
+        let end = def.iter().position(|t| *t == Token::Symbol(")")).unwrap_or(def.len());
+        return vec![def[open + 1..end]
+            .split(|t| *t == Token::Symbol(","))
+            .map(indexed_column)
+            .collect()];
+    }
+
+    let (Token::Ident(name) | Token::Quoted(name)) = first else {
+        return vec![];
+    };
+    let column = |desc| IndexedColumn {
+        name: name.clone(),
+        expression: false,
+        collation: None,
+        desc,
+    };
+    let mut constraints = vec![];
+    let mut depth = 0;
+    for (i, token) in def.iter().enumerate().skip(1) {
+        match token {
+            Token::Symbol("(") => depth += 1,
+            Token::Symbol(")") => depth -= 1,
+            _ if depth > 0 => {}
+            _ if token.is("unique") => constraints.push(Ok(vec![column(false)])),
+            _ if token.is("primary") => {
+                let desc = def.get(i + 2).is_some_and(|t| t.is("desc"));
+                constraints.push(Ok(vec![column(desc)]));
+            }
+            _ => {}
+        }
+    }
+    constraints
+}
+
+/**
+ * Key columns of the automatic indexes of a table with column and table
+ * constraint `definitions`, in the order they are numbered. An `INTEGER
+ * PRIMARY KEY` is the rowid and a repeated constraint uses the same index,
+ * so neither gets one.
+ */
+fn autoindexes(table: &Table, definitions: &[&[Token]]) -> Result<Vec<Vec<KeyColumn>>> {
+    let mut constraints: Vec<Vec<KeyColumn>> = vec![];
+    for def in definitions {
+        for columns in unique_constraint(def) {
+            let columns = key_columns(table, &columns?)?;
+            if let [column] = &columns[..] {
+                if table.columns[column.position].rowid_alias {
+                    continue;
+                }
+            }
+            let same = |other: &Vec<KeyColumn>| other.iter().map(|c| c.position).eq(columns.iter().map(|c| c.position));
+            if !constraints.iter().any(same) {
+                constraints.push(columns);
+            }
+        }
+    }
+    Ok(constraints)
+}
+
+/// Tokens of the column and table constraint definitions of a `CREATE TABLE`
+/// statement as stored in `sqlite_schema`
+fn table_definition(sql: &str) -> Result<Vec<Token>> {
+    let mut parser = Parser::new(sql)?;
+    parser.expect("create")?;
+    parser.expect("table")?;
+    parser.if_exists(true)?;
+    parser.qualified_name()?;
+    parser.group()
+}
+
+/// Positions and collations of indexed columns, which default to the
+/// collation of the table column.
+fn key_columns(table: &Table, columns: &[IndexedColumn]) -> Result<Vec<KeyColumn>> {
+    columns
+        .iter()
+        .map(|column| {
+            if column.expression {
+                return Err(Error::Unsupported("indexes on expressions"));
+            }
+            let position = table
+                .column(&column.name)
+                .ok_or_else(|| Error::NoSuchColumn(column.name.clone()))?;
+            let collation = column
+                .collation
+                .clone()
+                .or_else(|| table.columns[position].collation.clone())
+                .unwrap_or("BINARY".to_string());
+            Ok(KeyColumn {
+                position,
+                collation,
+                desc: column.desc,
+            })
+        })
+        .collect()
+}
+
+/// Order of index keys, with the rowid after the indexed columns
+fn compare_keys(columns: &[KeyColumn], collations: &Collations, a: &[SerialValue], b: &[SerialValue]) -> Ordering {
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        let ord = match columns.get(i) {
+            Some(column) => {
+                let ord = collations
+                    .compare(&column.collation, x, y)
+                    .expect("Collations are checked before sorting");
+                if column.desc {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            }
+            None => collations.compare("binary", x, y).expect("BINARY is built in"),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+fn reserved(name: &str) -> Result<()> {
+    match name.to_lowercase().starts_with("sqlite_") {
+        true => Err(Error::Reserved(name.to_string())),
+        false => Ok(()),
+    }
+}
+
+/// Text value `i` of a `sqlite_schema` row, empty if it is not text
+fn text(row: &[SerialValue], i: usize) -> &str {
+    match row.get(i) {
+        Some(SerialValue::String(s)) => s,
+        _ => "",
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        schema::{CreateOptions, Page},
+        testing::{planets, reread},
+    };
+    use pretty_assertions::assert_eq;
+
+    /// Type, name, table and root page of every `sqlite_schema` row
+    fn schema(db: &Database) -> Vec<(String, String, String, i64)> {
+        db.table_rows(1)
+            .into_iter()
+            .map(|cell| {
+                let row = &cell.record.payload;
+                let root = match row[3] {
+                    SerialValue::Number(n) => n,
+                    _ => 0,
+                };
+                (text(row, 0).into(), text(row, 1).into(), text(row, 2).into(), root)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn create_table() {
+        let mut db = Database::new(CreateOptions::default()).unwrap();
+        db.execute("CREATE TABLE t (id INTEGER PRIMARY KEY AUTOINCREMENT, a TEXT UNIQUE, b, UNIQUE (b, a));")
+            .unwrap();
+        db.execute("create table if not exists main.\"u v\" (a integer, b unique, unique(b), primary key(a desc))")
+            .unwrap();
+        db.execute("CREATE TABLE IF NOT EXISTS t (x)").unwrap();
+
+        let db = reread(&db);
+        let row = |kind: &str, name: &str, table: &str, root| (kind.into(), name.into(), table.into(), root);
+        assert_eq!(
+            schema(&db),
+            vec![
+                row("table", "t", "t", 2),
+                row("index", "sqlite_autoindex_t_1", "t", 3),
+                row("index", "sqlite_autoindex_t_2", "t", 4),
+                row("table", "sqlite_sequence", "sqlite_sequence", 5),
+                row("table", "u v", "u v", 6),
+                row("index", "sqlite_autoindex_u v_1", "u v", 7),
+            ]
+        );
+        assert_eq!(db.db_header.schema_cookie, 2);
+        assert_eq!(db.db_header.database_page_count, 7);
+
+        let catalog = Catalog::read(&db);
+        assert_eq!(
+            catalog.table("u v").unwrap().sql,
+            "CREATE TABLE \"u v\" (a integer, b unique, unique(b), primary key(a desc))"
+        );
+        assert!(matches!(db.page(7), Some(Page::IndexLeaf(_))));
+    }
+
+    #[test]
+    fn create_index() {
+        let mut db = planets();
+        for id in 9..=500 {
+            let name = format!("planet {}", id % 100);
+            db.insert(2, id, vec![().into(), name.as_str().into(), "Dwarf".into(), 0.into()])
+                .unwrap();
+        }
+        db.execute("CREATE INDEX by_name ON planets (name COLLATE NOCASE DESC, id)")
+            .unwrap();
+
+        let err = db
+            .execute("CREATE UNIQUE INDEX unique_name ON planets (name)")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "UNIQUE constraint failed: planets.name");
+
+        let mut db = reread(&db);
+        let catalog = Catalog::read(&db);
+        let index = catalog.index("by_name").unwrap();
+        assert_eq!(index.columns, vec!["name", "id"]);
+        assert!(matches!(db.page(index.root), Some(Page::IndexInterior(_))));
+
+        // Names in descending order ignoring case, then by rowid
+        let keys: Vec<(String, i64)> = db
+            .index_keys(index.root)
+            .into_iter()
+            .map(|key| match &key.payload[..] {
+                [SerialValue::String(name), SerialValue::Number(id), SerialValue::Number(row_id)] => {
+                    assert_eq!(id, row_id);
+                    (name.clone(), *id)
+                }
+                key => panic!("Unexpected key {:?}", key),
+            })
+            .collect();
+        let mut sorted = keys.clone();
+        sorted.sort_by(|a, b| b.0.to_lowercase().cmp(&a.0.to_lowercase()).then(a.1.cmp(&b.1)));
+        assert_eq!(keys.len(), 500);
+        assert_eq!(keys, sorted);
+        assert_eq!(keys[..2], [("Venus".into(), 2), ("Uranus".into(), 7)]);
+
+        // Dropping the table drops its index and frees every page but page 1
+        let pages = db.db_header.database_page_count;
+        db.execute("DROP TABLE planets").unwrap();
+        let db = reread(&db);
+        assert_eq!(schema(&db), vec![]);
+        assert_eq!(db.db_header.freelist_page_count, pages - 1);
+        assert_eq!(db.db_header.schema_cookie, 3);
+    }
+
+    #[test]
+    fn errors() {
+        let t = vec![
+            ("SELECT 1", "near \"SELECT\": syntax error"),
+            ("CREATE TABLE planets (x)", "table planets already exists"),
+            ("CREATE INDEX planets ON planets (name)", "table planets already exists"),
+            ("CREATE TABLE t (a, A)", "duplicate column name: A"),
+            ("CREATE TABLE t (a", "incomplete input"),
+            (
+                "CREATE TABLE sqlite_t (a)",
+                "object name reserved for internal use: sqlite_t",
+            ),
+            (
+                "CREATE TABLE t (a) WITHOUT ROWID",
+                "WITHOUT ROWID tables are not supported",
+            ),
+            ("CREATE TABLE t (a, UNIQUE (b))", "no such column: b"),
+            ("CREATE TABLE other.t (a)", "no such database: other"),
+            ("CREATE INDEX i ON moons (name)", "no such table: moons"),
+            ("CREATE INDEX i ON planets (size)", "no such column: size"),
+            (
+                "CREATE INDEX i ON planets (lower(name))",
+                "indexes on expressions are not supported",
+            ),
+            (
+                "CREATE INDEX i ON planets (name) WHERE moons > 0",
+                "partial indexes are not supported",
+            ),
+            (
+                "CREATE INDEX i ON planets (name COLLATE custom)",
+                "no such collation sequence: custom",
+            ),
+            ("CREATE INDEX i ON sqlite_schema (name)", "no such table: sqlite_schema"),
+            ("DROP TABLE moons", "no such table: moons"),
+            ("DROP TABLE sqlite_master", "table sqlite_master may not be dropped"),
+            ("DROP INDEX i", "no such index: i"),
+            ("DROP TABLE planets extra", "near \"extra\": syntax error"),
+        ];
+
+        for (sql, exp) in t.into_iter() {
+            let mut db = planets();
+            let err = db
+                .execute(sql)
+                .map(|_| "no error".to_string())
+                .unwrap_or_else(|e| e.to_string());
+            assert_eq!(err, exp, "{}", sql);
+            assert_eq!(db.db_header.schema_cookie, 1, "{}", sql);
+        }
+
+        let mut db = planets();
+        db.execute("DROP TABLE IF EXISTS moons").unwrap();
+        db.execute("DROP INDEX IF EXISTS i").unwrap();
+        assert_eq!(db.db_header.schema_cookie, 1);
+    }
+}
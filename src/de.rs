@@ -0,0 +1,502 @@
+//! # Deserialize rows
+//!
+//! A [serde] `Deserializer` over a row of [SerialValue]s and the names of its
+//! columns, so rows can be read into structs instead of matching on values.
+//!
+//! Struct fields are matched to columns by name, use `#[serde(rename = "..")]`
+//! when they differ. Columns without a field are skipped.
+//!
+//! ```
+//! use rsqlite::{catalog::Catalog, schema::Database};
+//! use binrw::BinRead;
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct Planet {
+//!     name: String,
+//!     #[serde(rename = "type")]
+//!     kind: String,
+//!     moons: u32,
+//! }
+//!
+//! let mut file = std::fs::File::open("data/planets.db").unwrap();
+//! let db = Database::read(&mut file).unwrap();
+//! let catalog = Catalog::read(&db);
+//!
+//! let planets: Vec<Planet> = catalog.table("planets").unwrap().rows(&db).collect::<Result<_, _>>().unwrap();
+//! assert_eq!(planets[2].name, "Earth");
+//! assert_eq!(planets[4].kind, "Gas Giant");
+//! assert_eq!(planets[5].moons, 83);
+//! ```
+//!
+//! Values convert the way the [SerialValue] is stored, an integer never
+//! becomes a string. The exceptions are integers into floats, `0` and `1`
+//! into `bool`, and text into bytes. Blobs read into `Vec<u8>`.
+
+use crate::{
+    catalog::Table,
+    schema::{Database, SerialValue},
+};
+use serde::{
+    de::{
+        self,
+        value::{SeqDeserializer, StrDeserializer},
+        DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor,
+    },
+    forward_to_deserialize_any,
+};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A value of the wrong type for the field
+    Mismatch {
+        column: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// NULL into a field that is not an `Option`
+    Null(String),
+    /// Any other error, like a missing field
+    Custom(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Mismatch { column, expected, found } => {
+                write!(f, "column {}: expected {}, found {}", column, expected, found)
+            }
+            Error::Null(column) => write!(
+                f,
+                "column {}: unexpected NULL for a field that is not an Option",
+                column
+            ),
+            Error::Custom(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::Custom(message.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Deserialize a row given the names of its columns
+pub fn from_row<T: DeserializeOwned, S: AsRef<str>>(columns: &[S], row: &[SerialValue]) -> Result<T> {
+    T::deserialize(RowDeserializer { columns, row })
+}
+
+impl Table {
+    /// Every row of the table deserialized into `T`, in rowid order
+    pub fn rows<'a, T: DeserializeOwned>(&'a self, db: &'a Database) -> impl Iterator<Item = Result<T>> + 'a {
+        let columns: Vec<&str> = self.columns.iter().map(|c| c.name.as_str()).collect();
+        db.table_rows(self.root)
+            .into_iter()
+            .map(move |cell| from_row(&columns, &self.row(cell)))
+    }
+}
+
+/// A row is a map from column names to values, or a sequence of values for
+/// tuples.
+struct RowDeserializer<'a, S> {
+    columns: &'a [S],
+    row: &'a [SerialValue],
+}
+
+impl<'de, S: AsRef<str>> de::Deserializer<'de> for RowDeserializer<'_, S> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(Columns {
+            columns: self.columns,
+            row: self.row,
+            i: 0,
+        })
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Columns {
+            columns: self.columns,
+            row: self.row,
+            i: 0,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _: &'static str, _: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct map struct enum
+        identifier ignored_any
+    }
+}
+
+struct Columns<'a, S> {
+    columns: &'a [S],
+    row: &'a [SerialValue],
+    i: usize,
+}
+
+impl<S: AsRef<str>> Columns<'_, S> {
+    fn column(&self) -> &str {
+        self.columns.get(self.i).map_or("?", |c| c.as_ref())
+    }
+}
+
+impl<'de, S: AsRef<str>> de::MapAccess<'de> for Columns<'_, S> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.i >= self.row.len() {
+            return Ok(None);
+        }
+        let key: StrDeserializer<Error> = self.column().into_deserializer();
+        seed.deserialize(key).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let value = ValueDeserializer {
+            value: &self.row[self.i],
+            column: self.column(),
+        };
+        let result = seed.deserialize(value);
+        self.i += 1;
+        result
+    }
+}
+
+impl<'de, S: AsRef<str>> de::SeqAccess<'de> for Columns<'_, S> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.i >= self.row.len() {
+            return Ok(None);
+        }
+        let value = ValueDeserializer {
+            value: &self.row[self.i],
+            column: self.column(),
+        };
+        let result = seed.deserialize(value);
+        self.i += 1;
+        result.map(Some)
+    }
+}
+
+/// A single value, `column` is only used in errors
+struct ValueDeserializer<'a> {
+    value: &'a SerialValue,
+    column: &'a str,
+}
+
+impl ValueDeserializer<'_> {
+    fn mismatch(&self, expected: &'static str) -> Error {
+        match self.value {
+            SerialValue::Null => Error::Null(self.column.to_string()),
+            value => Error::Mismatch {
+                column: self.column.to_string(),
+                expected,
+                found: type_name(value),
+            },
+        }
+    }
+
+    /// Prefix errors from visitors, like an integer out of range, with the column
+    fn context(&self, err: Error) -> Error {
+        match err {
+            Error::Custom(message) => Error::Custom(format!("column {}: {}", self.column, message)),
+            err => err,
+        }
+    }
+
+    fn integer<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SerialValue::Number(n) => visitor.visit_i64(*n).map_err(|err| self.context(err)),
+            _ => Err(self.mismatch("integer")),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SerialValue::Null | SerialValue::Reserved => visitor.visit_unit(),
+            SerialValue::Number(n) => visitor.visit_i64(*n),
+            SerialValue::Float(x) => visitor.visit_f64(*x),
+            SerialValue::String(s) => visitor.visit_str(s),
+            SerialValue::Blob(b) => visitor.visit_bytes(b),
+        }
+        .map_err(|err| self.context(err))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SerialValue::Number(n @ (0 | 1)) => visitor.visit_bool(*n == 1),
+            _ => Err(self.mismatch("0 or 1")),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.integer(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.integer(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.integer(visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.integer(visitor)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.integer(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.integer(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.integer(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.integer(visitor)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SerialValue::Float(x) => visitor.visit_f64(*x),
+            SerialValue::Number(n) => visitor.visit_f64(*n as f64),
+            _ => Err(self.mismatch("real")),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SerialValue::String(s) => visitor.visit_str(s).map_err(|err| self.context(err)),
+            _ => Err(self.mismatch("text")),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SerialValue::Blob(b) => visitor.visit_bytes(b),
+            SerialValue::String(s) => visitor.visit_bytes(s.as_bytes()),
+            _ => Err(self.mismatch("blob")),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    /// Blobs are also a sequence of bytes, for `Vec<u8>`
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SerialValue::Blob(b) => visitor.visit_seq(SeqDeserializer::new(b.iter().copied())),
+            _ => Err(self.mismatch("blob")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SerialValue::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SerialValue::Null => visitor.visit_unit(),
+            _ => Err(self.mismatch("NULL")),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Unit variants stored as text
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.value {
+            SerialValue::String(s) => {
+                let variant: StrDeserializer<Error> = s.as_str().into_deserializer();
+                visitor.visit_enum(variant).map_err(|err| self.context(err))
+            }
+            _ => Err(self.mismatch("text")),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 tuple tuple_struct map struct identifier
+    }
+}
+
+/// Name of the storage class, as returned by `typeof()`
+fn type_name(value: &SerialValue) -> &'static str {
+    match value {
+        SerialValue::Null | SerialValue::Reserved => "null",
+        SerialValue::Number(_) => "integer",
+        SerialValue::Float(_) => "real",
+        SerialValue::String(_) => "text",
+        SerialValue::Blob(_) => "blob",
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::catalog::Catalog;
+    use binrw::BinRead;
+    use pretty_assertions::assert_eq;
+    use serde::Deserialize;
+    use std::fs::File;
+    use SerialValue as V;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Planet {
+        id: i64,
+        name: String,
+        #[serde(rename = "type")]
+        kind: Kind,
+        diameter: u32,
+        distance: f64,
+        moons: u8,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    enum Kind {
+        Terrestrial,
+        #[serde(rename = "Gas Giant")]
+        GasGiant,
+        #[serde(rename = "Ice Giant")]
+        IceGiant,
+    }
+
+    #[test]
+    fn table_rows() {
+        let mut file = File::open("data/planets.db").expect("Failed to open database");
+        let db = Database::read(&mut file).expect("Failed to read database");
+        let catalog = Catalog::read(&db);
+
+        let planets: Vec<Planet> = catalog
+            .table("planets")
+            .unwrap()
+            .rows(&db)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(planets.len(), 8);
+        assert_eq!(
+            planets[6],
+            Planet {
+                id: 7,
+                name: "Uranus".into(),
+                kind: Kind::IceGiant,
+                diameter: 50724,
+                distance: 2871000000.0,
+                moons: 27,
+            }
+        );
+
+        // Tuples take the columns in order
+        let rows: Vec<(i64, String)> = catalog
+            .table("planets")
+            .unwrap()
+            .rows(&db)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(rows[0], (1, "Mercury".into()));
+    }
+
+    #[test]
+    fn values() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Row {
+            flag: bool,
+            note: Option<String>,
+            data: Vec<u8>,
+        }
+
+        let columns = ["flag", "note", "data", "extra"];
+        let row: Row = from_row(&columns, &[V::Number(1), V::Null, V::Blob(vec![1, 2]), V::Float(0.5)]).unwrap();
+        assert_eq!(
+            row,
+            Row {
+                flag: true,
+                note: None,
+                data: vec![1, 2]
+            }
+        );
+
+        let t = vec![
+            (
+                vec![V::Number(2), V::Null, V::Blob(vec![])],
+                "column flag: expected 0 or 1, found integer",
+            ),
+            (
+                vec![V::Number(0), V::Number(5), V::Blob(vec![])],
+                "column note: expected text, found integer",
+            ),
+            (
+                vec![V::Number(0), V::Null, V::Null],
+                "column data: unexpected NULL for a field that is not an Option",
+            ),
+            (vec![V::Number(0), V::Null], "missing field `data`"),
+        ];
+        for (values, exp) in t.into_iter() {
+            let err = from_row::<Row, _>(&columns, &values).unwrap_err();
+            assert_eq!(err.to_string(), exp);
+        }
+
+        let err = from_row::<(u8,), _>(&["moons"], &[V::Number(300)]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "column moons: invalid value: integer `300`, expected u8"
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! # SQL functions
+//!
+//! A [Functions] registry maps SQL function names to Rust implementations,
+//! similar to `sqlite3_create_function`. Scalar functions are plain closures
+//! over [SerialValue]s, aggregates implement [Aggregate] and get a fresh
+//! accumulator for every group. Table valued functions return rows with
+//! named columns, and are used in the `FROM` clause like a table.
+//!
+//! The default registry comes with the built in [date and time](crate::datetime)
+//! and [JSON](crate::json) functions.
+//!
+//! ```
+//! use rsqlite::{functions::*, schema::SerialValue};
+//!
+//! let mut functions = Functions::default();
+//! functions.scalar("double", 1, |args| match &args[0] {
+//!     SerialValue::Number(n) => Ok(SerialValue::Number(n * 2)),
+//!     _ => Err("double() expects an integer".into()),
+//! });
+//!
+//! assert_eq!(functions.call("DOUBLE", &[SerialValue::Number(21)]), Ok(SerialValue::Number(42)));
+//! ```
+
+use crate::{datetime, json, schema::SerialValue};
+use std::{collections::HashMap, fmt};
+
+/// An error raised by a function, like `sqlite3_result_error`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error(pub String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error(message.to_string())
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error(message)
+    }
+}
+
+impl From<json::Error> for Error {
+    fn from(err: json::Error) -> Self {
+        Error(err.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Number of arguments a function accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Arity {
+    Exact(usize),
+    /// Any number of arguments, `-1` in the C API
+    Variadic,
+}
+
+impl From<usize> for Arity {
+    fn from(n: usize) -> Self {
+        Arity::Exact(n)
+    }
+}
+
+/**
+ * An aggregate accumulator.
+ *
+ * [step](Aggregate::step) is called once per row in the group and
+ * [finalize](Aggregate::finalize) once at the end, even for empty groups.
+ */
+pub trait Aggregate {
+    fn step(&mut self, args: &[SerialValue]) -> Result<()>;
+    fn finalize(&mut self) -> Result<SerialValue>;
+}
+
+/// An [Aggregate] built from an initial state and two closures, see
+/// [Functions::fold].
+pub struct Fold<S, F, G> {
+    state: S,
+    step: F,
+    finalize: G,
+}
+
+impl<S, F, G> Aggregate for Fold<S, F, G>
+where
+    F: Fn(&mut S, &[SerialValue]) -> Result<()>,
+    G: Fn(&S) -> Result<SerialValue>,
+{
+    fn step(&mut self, args: &[SerialValue]) -> Result<()> {
+        (self.step)(&mut self.state, args)
+    }
+
+    fn finalize(&mut self) -> Result<SerialValue> {
+        (self.finalize)(&self.state)
+    }
+}
+
+type Scalar = Box<dyn Fn(&[SerialValue]) -> Result<SerialValue> + Send + Sync>;
+type Factory = Box<dyn Fn() -> Box<dyn Aggregate> + Send + Sync>;
+type TableValued = (
+    Vec<String>,
+    Box<dyn Fn(&[SerialValue]) -> Result<Vec<Vec<SerialValue>>> + Send + Sync>,
+);
+
+/**
+ * Registry of scalar and aggregate SQL functions.
+ *
+ * Names are case insensitive. The same name can be registered with different
+ * arities, and an exact arity match is preferred over a variadic one.
+ */
+pub struct Functions {
+    scalars: HashMap<(String, Arity), Scalar>,
+    aggregates: HashMap<(String, Arity), Factory>,
+    tables: HashMap<(String, Arity), TableValued>,
+}
+
+impl Functions {
+    /// An empty registry, without any of the built in functions
+    pub fn empty() -> Self {
+        Functions {
+            scalars: HashMap::new(),
+            aggregates: HashMap::new(),
+            tables: HashMap::new(),
+        }
+    }
+
+    /// Register a scalar function, replacing any previous one with the same
+    /// name and arity.
+    pub fn scalar<F>(&mut self, name: &str, arity: impl Into<Arity>, f: F) -> &mut Self
+    where
+        F: Fn(&[SerialValue]) -> Result<SerialValue> + Send + Sync + 'static,
+    {
+        self.scalars.insert((name.to_lowercase(), arity.into()), Box::new(f));
+        self
+    }
+
+    /// Register an aggregate function. `new` creates an accumulator for each
+    /// group.
+    pub fn aggregate<A, F>(&mut self, name: &str, arity: impl Into<Arity>, new: F) -> &mut Self
+    where
+        A: Aggregate + 'static,
+        F: Fn() -> A + Send + Sync + 'static,
+    {
+        let factory: Factory = Box::new(move || Box::new(new()));
+        self.aggregates.insert((name.to_lowercase(), arity.into()), factory);
+        self
+    }
+
+    /// Register an aggregate from an initial state, a step closure and a
+    /// finalize closure.
+    pub fn fold<S, F, G>(&mut self, name: &str, arity: impl Into<Arity>, init: S, step: F, finalize: G) -> &mut Self
+    where
+        S: Clone + Send + Sync + 'static,
+        F: Fn(&mut S, &[SerialValue]) -> Result<()> + Clone + Send + Sync + 'static,
+        G: Fn(&S) -> Result<SerialValue> + Clone + Send + Sync + 'static,
+    {
+        self.aggregate(name, arity, move || Fold {
+            state: init.clone(),
+            step: step.clone(),
+            finalize: finalize.clone(),
+        })
+    }
+
+    /// Register a table valued function returning rows of `columns`, like
+    /// the eponymous virtual tables of SQLite.
+    pub fn table<F>(&mut self, name: &str, arity: impl Into<Arity>, columns: &[&str], f: F) -> &mut Self
+    where
+        F: Fn(&[SerialValue]) -> Result<Vec<Vec<SerialValue>>> + Send + Sync + 'static,
+    {
+        let columns = columns.iter().map(|c| c.to_string()).collect();
+        self.tables
+            .insert((name.to_lowercase(), arity.into()), (columns, Box::new(f)));
+        self
+    }
+
+    /// Call a scalar function by name
+    pub fn call(&self, name: &str, args: &[SerialValue]) -> Result<SerialValue> {
+        let f = lookup(&self.scalars, name, args.len()).ok_or_else(|| Error(format!("no such function: {}", name)))?;
+        f(args)
+    }
+
+    /// Create a new accumulator for an aggregate function taking `n_args`
+    pub fn accumulator(&self, name: &str, n_args: usize) -> Result<Box<dyn Aggregate>> {
+        let new = lookup(&self.aggregates, name, n_args).ok_or_else(|| Error(format!("no such function: {}", name)))?;
+        Ok(new())
+    }
+
+    /// Call a table valued function by name, returning all of its rows
+    pub fn call_table(&self, name: &str, args: &[SerialValue]) -> Result<Vec<Vec<SerialValue>>> {
+        let (_, f) = lookup(&self.tables, name, args.len()).ok_or_else(|| Error(format!("no such table: {}", name)))?;
+        f(args)
+    }
+
+    /// Columns of the table valued function `name` taking `n_args`
+    pub fn table_columns(&self, name: &str, n_args: usize) -> Option<&[String]> {
+        lookup(&self.tables, name, n_args).map(|(columns, _)| &columns[..])
+    }
+
+    /// Is there a scalar function `name` taking `n_args`?
+    pub fn is_scalar(&self, name: &str, n_args: usize) -> bool {
+        lookup(&self.scalars, name, n_args).is_some()
+    }
+
+    /// Is `name` an aggregate function? Decides how a query is executed.
+    pub fn is_aggregate(&self, name: &str, n_args: usize) -> bool {
+        lookup(&self.aggregates, name, n_args).is_some()
+    }
+}
+
+/// Built in functions
+impl Default for Functions {
+    fn default() -> Self {
+        let mut functions = Functions::empty();
+
+        // Date and time functions never fail, invalid input is NULL
+        functions
+            .scalar("date", Arity::Variadic, |args| Ok(datetime::date(args)))
+            .scalar("time", Arity::Variadic, |args| Ok(datetime::time(args)))
+            .scalar("datetime", Arity::Variadic, |args| Ok(datetime::datetime(args)))
+            .scalar("julianday", Arity::Variadic, |args| Ok(datetime::julianday(args)))
+            .scalar("unixepoch", Arity::Variadic, |args| Ok(datetime::unixepoch(args)))
+            .scalar("strftime", Arity::Variadic, |args| Ok(datetime::strftime(args)));
+
+        functions
+            .scalar("json", 1, |args| Ok(json::json(args)?))
+            .scalar("json_extract", Arity::Variadic, |args| Ok(json::json_extract(args)?))
+            .scalar("->", 2, |args| Ok(json::arrow(args)?))
+            .scalar("->>", 2, |args| Ok(json::arrow_value(args)?))
+            .scalar("json_type", Arity::Variadic, |args| Ok(json::json_type(args)?))
+            .scalar("json_array_length", Arity::Variadic, |args| {
+                Ok(json::json_array_length(args)?)
+            })
+            .table("json_each", Arity::Variadic, &json::JSON_EACH_COLUMNS, |args| {
+                Ok(json::json_each(args)?)
+            })
+            .table("json_tree", Arity::Variadic, &json::JSON_EACH_COLUMNS, |args| {
+                Ok(json::json_tree(args)?)
+            });
+
+        functions
+    }
+}
+
+fn lookup<'a, T>(map: &'a HashMap<(String, Arity), T>, name: &str, n_args: usize) -> Option<&'a T> {
+    let name = name.to_lowercase();
+    map.get(&(name.clone(), Arity::Exact(n_args)))
+        .or_else(|| map.get(&(name, Arity::Variadic)))
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use SerialValue as V;
+
+    #[derive(Default)]
+    struct Median(Vec<f64>);
+
+    impl Aggregate for Median {
+        fn step(&mut self, args: &[SerialValue]) -> Result<()> {
+            match args[0] {
+                V::Number(n) => self.0.push(n as f64),
+                V::Float(x) => self.0.push(x),
+                V::Null => {}
+                _ => return Err("median() of a non number".into()),
+            }
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> Result<SerialValue> {
+            self.0.sort_by(f64::total_cmp);
+            Ok(match self.0.len() {
+                0 => V::Null,
+                n if n % 2 == 1 => V::Float(self.0[n / 2]),
+                n => V::Float((self.0[n / 2 - 1] + self.0[n / 2]) / 2.0),
+            })
+        }
+    }
+
+    fn run(functions: &Functions, name: &str, rows: &[SerialValue]) -> Result<SerialValue> {
+        let mut acc = functions.accumulator(name, 1)?;
+        for row in rows {
+            acc.step(std::slice::from_ref(row))?;
+        }
+        acc.finalize()
+    }
+
+    #[test]
+    fn scalar() {
+        let mut functions = Functions::default();
+        functions
+            .scalar("add", 2, |args| match args {
+                [V::Number(a), V::Number(b)] => Ok(V::Number(a + b)),
+                _ => Err("add() expects integers".into()),
+            })
+            .scalar("add", Arity::Variadic, |args| Ok(V::Number(args.len() as i64)));
+
+        assert_eq!(functions.call("add", &[V::Number(1), V::Number(2)]), Ok(V::Number(3)));
+        assert_eq!(functions.call("ADD", &[V::Null, V::Null, V::Null]), Ok(V::Number(3)));
+        assert_eq!(
+            functions.call("add", &[V::Null, V::Null]),
+            Err("add() expects integers".into())
+        );
+        assert_eq!(functions.call("nope", &[]), Err("no such function: nope".into()));
+    }
+
+    #[test]
+    fn builtins() {
+        let functions = Functions::default();
+
+        assert_eq!(
+            functions.call("date", &["2024-01-31".into(), "+1 day".into()]),
+            Ok("2024-02-01".into())
+        );
+        assert_eq!(
+            functions.call("->>", &[r#"{"a":2}"#.into(), "a".into()]),
+            Ok(V::Number(2))
+        );
+        assert_eq!(functions.call("json", &["[".into()]), Err("malformed JSON".into()));
+        assert_eq!(
+            functions.call_table("json_each", &["[5]".into()]),
+            Ok(vec![vec![
+                V::Number(0),
+                V::Number(5),
+                "integer".into(),
+                V::Number(5),
+                V::Number(1),
+                V::Null,
+                "$[0]".into(),
+                "$".into()
+            ]])
+        );
+        assert_eq!(functions.table_columns("JSON_TREE", 2).map(|c| c.len()), Some(8));
+        assert_eq!(functions.call_table("json", &[]), Err("no such table: json".into()));
+        assert!(Functions::empty().call("date", &[]).is_err());
+    }
+
+    #[test]
+    fn aggregate() {
+        let mut functions = Functions::default();
+        functions.aggregate("median", 1, Median::default).fold(
+            "total_len",
+            1,
+            0,
+            |len, args| {
+                *len += args[0].to_string().len();
+                Ok(())
+            },
+            |len| Ok(V::Number(*len as i64)),
+        );
+
+        let rows = [V::Number(3), V::Null, V::Float(1.5), V::Number(10)];
+        assert_eq!(run(&functions, "median", &rows), Ok(V::Float(3.0)));
+        assert_eq!(run(&functions, "median", &[]), Ok(V::Null));
+        assert_eq!(
+            run(&functions, "median", &["x".into()]),
+            Err("median() of a non number".into())
+        );
+
+        // Every group starts from a fresh state
+        assert_eq!(
+            run(&functions, "total_len", &["ab".into(), "c".into()]),
+            Ok(V::Number(3))
+        );
+        assert_eq!(run(&functions, "total_len", &["ab".into()]), Ok(V::Number(2)));
+
+        assert!(functions.is_aggregate("MEDIAN", 1));
+        assert!(!functions.is_aggregate("median", 2));
+    }
+}
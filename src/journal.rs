@@ -0,0 +1,883 @@
+//! # Rollback journal
+//!
+//! Changes reach the database file atomically by first copying the original
+//! content of every page about to be overwritten to a `-journal` file next to
+//! it. If the process or the machine dies while the database file is written,
+//! the journal is left behind "hot", and writing its pages back restores the
+//! database as it was before the transaction, which SQLite and
+//! [Database::open] do before reading the file.
+//!
+//! - [Journal format docs](https://www.sqlite.org/fileformat2.html#the_rollback_journal)
+//! - [Atomic commit docs](https://www.sqlite.org/atomiccommit.html)
+//!
+//! A commit goes through these steps, each synced to disk before the next
+//!
+//! 1. Write the original pages to the journal, under a header without the
+//!    magic number or a page count so it is not hot yet, holding a RESERVED
+//!    [lock](crate::lock) so no other connection writes a journal too, once
+//!    the file is checked to be as the transaction found it
+//! 2. Write the magic number and page count, which makes the journal hot
+//! 3. Wait for readers to finish with an EXCLUSIVE lock, then write the
+//!    changed pages to the database file and truncate it
+//! 4. Delete, truncate or zero the header of the journal depending on the
+//!    [JournalMode], which commits the transaction
+//!
+//! A [Transaction] holds a SHARED lock from the start, so no other connection
+//! commits before it ends. In WAL mode other connections still append to the
+//! WAL, and a commit after theirs fails with a "database changed since the
+//! transaction began" error, since its changes are made to an older database.
+//!
+//! ```
+//! use rsqlite::schema::{CreateOptions, Database};
+//!
+//! let path = std::env::temp_dir().join(format!("rsqlite-journal-doc-{}.db", std::process::id()));
+//! let mut db = Database::create(&path, CreateOptions::default()).unwrap();
+//!
+//! let mut tx = db.transaction(&path).unwrap();
+//! tx.execute("CREATE TABLE planets (name TEXT)").unwrap();
+//! tx.rollback().unwrap();
+//! assert_eq!(db.table_rows(1).len(), 0);
+//!
+//! let mut tx = db.transaction(&path).unwrap();
+//! tx.execute("CREATE TABLE planets (name TEXT)").unwrap();
+//! tx.commit().unwrap();
+//! assert_eq!(db.table_rows(1).len(), 1);
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+
+use crate::{
+    lock::{busy_snapshot, FileLock, LockLevel},
+    schema::{lock_byte_page, Database},
+    wal::{self, CheckpointMode},
+};
+use binrw::{binrw, BinRead, BinResult, BinWrite};
+use std::{
+    fs::{File, OpenOptions},
+    hash::{BuildHasher, RandomState},
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Start of a valid journal header
+pub const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+
+/// Size the journal header is padded to, pages records start after it
+const SECTOR_SIZE: u32 = 512;
+
+/// How the journal is ended when a transaction commits, like `PRAGMA journal_mode`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum JournalMode {
+    /// Delete the journal file
+    #[default]
+    Delete,
+    /// Truncate the journal file to 0 bytes
+    Truncate,
+    /// Overwrite the journal header with zeros, keeping the file
+    Persist,
+    /// Append changed pages to the `-wal` file instead, see [crate::wal]
+    Wal,
+}
+
+/**
+ * Header at the start of each segment of a journal, padded to the sector size.
+ *
+ * | Offset | Size | Description                                            |
+ * |--------|------|--------------------------------------------------------|
+ * | 0      | 8    | Magic number `0xd9d505f920a163d7`                      |
+ * | 8      | 4    | Number of page records, or -1 to fill the file         |
+ * | 12     | 4    | Nonce for the checksums                                |
+ * | 16     | 4    | Size of the database in pages before the transaction   |
+ * | 20     | 4    | Sector size of the disk                                |
+ * | 24     | 4    | Page size                                              |
+ */
+#[binrw]
+#[brw(big)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JournalHeader {
+    /// [JOURNAL_MAGIC] once the journal is complete, zeros before
+    pub magic: [u8; 8],
+    pub page_count: u32,
+    pub nonce: u32,
+    pub initial_size: u32,
+    pub sector_size: u32,
+    pub page_size: u32,
+}
+
+/// The original content of a page, with a checksum of some of its bytes
+#[binrw]
+#[brw(big, import(page_size: u32))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageRecord {
+    pub number: u32,
+    #[br(count = page_size)]
+    pub data: Vec<u8>,
+    pub checksum: u32,
+}
+
+/// The header and the valid page records of a journal file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Journal {
+    pub header: JournalHeader,
+    pub records: Vec<PageRecord>,
+}
+
+impl JournalHeader {
+    fn is_valid(&self) -> bool {
+        let power = |n: u32, range: std::ops::RangeInclusive<u32>| n.is_power_of_two() && range.contains(&n);
+        self.magic == JOURNAL_MAGIC && power(self.page_size, 512..=65536) && power(self.sector_size, 32..=65536)
+    }
+}
+
+impl PageRecord {
+    fn new(number: u32, data: &[u8], nonce: u32) -> PageRecord {
+        PageRecord {
+            number,
+            data: data.to_vec(),
+            checksum: checksum(nonce, data),
+        }
+    }
+}
+
+impl Journal {
+    /**
+     * Read the page records of a journal, stopping at the first one with a
+     * wrong checksum like SQLite. Returns `None` if the journal has no valid
+     * header, in which case the database was not changed yet.
+     *
+     * Journals written by SQLite can have several segments, each starting
+     * with a header at a sector boundary.
+     */
+    pub fn read<R: Read + Seek>(reader: &mut R) -> BinResult<Option<Journal>> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        let mut journal: Option<Journal> = None;
+        let mut offset = 0;
+
+        while offset + 28 <= len {
+            reader.seek(SeekFrom::Start(offset))?;
+            let header = JournalHeader::read(reader)?;
+            if !header.is_valid() {
+                break;
+            }
+            let journal = journal.get_or_insert(Journal { header, records: vec![] });
+
+            let (page_size, sector_size) = (header.page_size as u64, header.sector_size as u64);
+            let start = offset + sector_size;
+            let count = match header.page_count {
+                u32::MAX => len.saturating_sub(start) / (page_size + 8),
+                n => n as u64,
+            };
+
+            reader.seek(SeekFrom::Start(start))?;
+            for _ in 0..count {
+                let Ok(record) = PageRecord::read_args(reader, (header.page_size,)) else {
+                    return Ok(Some(journal.clone()));
+                };
+                if record.number == 0 || record.checksum != checksum(header.nonce, &record.data) {
+                    return Ok(Some(journal.clone()));
+                }
+                journal.records.push(record);
+            }
+            offset = (start + count * (page_size + 8)).next_multiple_of(sector_size);
+        }
+
+        Ok(journal)
+    }
+}
+
+impl Database {
+    /// Start a transaction on the database file at `path`, which this
+    /// database was read from, using [JournalMode::Wal] if the database is
+    /// in WAL mode and [JournalMode::Delete] if not.
+    pub fn transaction<P: AsRef<Path>>(&mut self, path: P) -> BinResult<Transaction<'_>> {
+        let mode = self.journal_mode();
+        self.transaction_with(path, mode)
+    }
+
+    /// Like [Database::transaction], ending the journal with `mode`
+    pub fn transaction_with<P: AsRef<Path>>(&mut self, path: P, mode: JournalMode) -> BinResult<Transaction<'_>> {
+        let path = path.as_ref().to_path_buf();
+        let mut lock = lock_shared(&path)?;
+        let snapshot = Snapshot::read(&mut lock, &path)?;
+        Ok(Transaction {
+            db: self,
+            path,
+            mode,
+            lock,
+            snapshot,
+            done: false,
+        })
+    }
+
+    /// Write the database to a new or existing file atomically, counting it
+    /// as a change so other readers know to drop cached pages. In WAL mode
+    /// the pages are appended to the WAL instead, which readers check.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> BinResult<()> {
+        let mode = self.journal_mode();
+        self.save_with(path, mode)
+    }
+
+    /**
+     * Like [Database::save], ending the journal with `mode`.
+     *
+     * Databases in [AutoVacuum::Full](crate::schema::AutoVacuum::Full) mode
+     * are truncated first, see [crate::vacuum].
+     *
+     * Switching to [JournalMode::Wal] commits through a rollback journal once,
+     * which marks the database file as in WAL mode. Switching away from it
+     * checkpoints the WAL and truncates it first, then deletes it.
+     */
+    pub fn save_with<P: AsRef<Path>>(&mut self, path: P, mode: JournalMode) -> BinResult<()> {
+        let path = path.as_ref();
+        let mut lock = lock_shared(path)?;
+        let snapshot = Snapshot::read(&mut lock, path)?;
+        self.save_locked(&mut lock, path, mode, snapshot)
+    }
+
+    /// [JournalMode::Wal] if the header says the database is in WAL mode
+    fn journal_mode(&self) -> JournalMode {
+        match self.db_header.write_format {
+            2 => JournalMode::Wal,
+            _ => JournalMode::default(),
+        }
+    }
+
+    /// [Database::save_with] through `lock`, which holds SHARED since the
+    /// file was as in `snapshot`
+    fn save_locked(
+        &mut self,
+        lock: &mut FileLock,
+        path: &Path,
+        mode: JournalMode,
+        snapshot: Snapshot,
+    ) -> BinResult<()> {
+        // Like SQLite does at the end of every transaction in full auto-vacuum
+        if self.db_header.is_auto_vacuum() && self.db_header.incremental_vacuum == 0 {
+            self.incremental_vacuum(0)?;
+        }
+        let in_wal = wal::is_wal_mode_locked(lock)?;
+        let format = if mode == JournalMode::Wal { 2 } else { 1 };
+        self.db_header.write_format = format;
+        self.db_header.read_format = format;
+
+        let mut new = Cursor::new(vec![]);
+        if mode == JournalMode::Wal && in_wal {
+            self.write_be(&mut new)?;
+            return wal::commit(
+                lock,
+                path,
+                &new.into_inner(),
+                self.db_header.page_size_bytes(),
+                &snapshot,
+            );
+        }
+        let mut snapshot = snapshot;
+        if in_wal {
+            // The checkpoint changes the WAL, so its frames are checked first
+            lock.lock(LockLevel::Reserved)?;
+            snapshot.check(lock, path)?;
+            wal::checkpoint_locked(lock, path, CheckpointMode::Truncate)?;
+            snapshot = Snapshot::read(lock, path)?;
+        }
+
+        self.db_header.file_change_counter = self.db_header.file_change_counter.wrapping_add(1);
+        self.db_header.version_valid_for = self.db_header.file_change_counter;
+        self.write_be(&mut new)?;
+        commit(
+            lock,
+            path,
+            &new.into_inner(),
+            self.db_header.page_size_bytes(),
+            mode,
+            &snapshot,
+        )?;
+        if in_wal {
+            std::fs::remove_file(wal::wal_path(path))?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Changes to a [Database] that are written to its file together or not at
+ * all, dereferencing to the database to make them.
+ *
+ * The file is locked SHARED until the transaction ends, and committing it
+ * fails if the file changed since it began. Dropping a transaction without
+ * committing it rolls it back.
+ */
+pub struct Transaction<'db> {
+    db: &'db mut Database,
+    path: PathBuf,
+    mode: JournalMode,
+    lock: FileLock,
+    snapshot: Snapshot,
+    done: bool,
+}
+
+/// What a transaction found in the database file when it began, which has to
+/// still be there when it commits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Snapshot {
+    /// [Header::file_change_counter](crate::schema::Header::file_change_counter)
+    /// in the file, 0 if it is empty
+    change_counter: u32,
+    /// Number of committed frames in the WAL and its salts, if it has one
+    wal: Option<(u32, [u32; 2])>,
+}
+
+impl Transaction<'_> {
+    /// Write the changes to the file through the journal
+    pub fn commit(mut self) -> BinResult<()> {
+        self.done = true;
+        self.db
+            .save_locked(&mut self.lock, &self.path, self.mode, self.snapshot)
+    }
+
+    /// Undo the changes by reading the database from its file again, which
+    /// is only written on commit. Unsaved changes made before the transaction
+    /// are lost too.
+    pub fn rollback(mut self) -> BinResult<()> {
+        self.done = true;
+        self.reload()
+    }
+
+    fn reload(&mut self) -> BinResult<()> {
+        // Rolling back a hot journal needs an EXCLUSIVE lock
+        self.lock.unlock(LockLevel::None)?;
+        *self.db = Database::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    /// Read the snapshot of the database file at `path`, which `lock` holds
+    /// at least SHARED
+    pub(crate) fn read(lock: &mut FileLock, path: &Path) -> BinResult<Snapshot> {
+        let file = lock.file();
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = vec![];
+        file.take(28).read_to_end(&mut header)?;
+        let change_counter = match header.get(24..28) {
+            Some(&[a, b, c, d]) => u32::from_be_bytes([a, b, c, d]),
+            _ => 0,
+        };
+        let wal = wal::read_wal(path)?.map(|wal| (wal.committed().len() as u32, wal.header.salt));
+        Ok(Snapshot { change_counter, wal })
+    }
+
+    /// Fail with [busy_snapshot] if another connection committed since
+    pub(crate) fn check(&self, lock: &mut FileLock, path: &Path) -> BinResult<()> {
+        match Snapshot::read(lock, path)? == *self {
+            true => Ok(()),
+            false => Err(busy_snapshot().into()),
+        }
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        self.db
+    }
+}
+
+impl DerefMut for Transaction<'_> {
+    fn deref_mut(&mut self) -> &mut Database {
+        self.db
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            // Errors can't be returned here, call rollback to see them
+            let _ = self.reload();
+        }
+    }
+}
+
+/// Path of the rollback journal of the database at `path`
+pub fn journal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push("-journal");
+    PathBuf::from(name)
+}
+
+/**
+ * Restore the database at `path` from its journal if it is hot, like SQLite
+ * does before reading a database. Returns `Ok(true)` if anything was rolled
+ * back.
+ *
+ * A journal is hot when it has a valid header, the database file is not
+ * empty and no other connection holds a RESERVED lock, which it would while
+ * writing the journal. Its original pages are written back under an
+ * EXCLUSIVE lock, the file is truncated to its original size and synced, and
+ * only then the journal is deleted. A journal next to an empty database is
+ * deleted, and other journals are left alone, since they belong to a commit
+ * that either finished or never started writing the database file.
+ */
+pub fn rollback(path: &Path) -> BinResult<bool> {
+    let journal_path = journal_path(path);
+    if read_journal(&journal_path)?.is_none() {
+        return Ok(false);
+    }
+    let mut lock = match FileLock::open(path) {
+        Ok(lock) => lock,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            std::fs::remove_file(&journal_path)?;
+            return Ok(false);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    lock.lock(LockLevel::Shared)?;
+    if lock.is_reserved()? {
+        return Ok(false);
+    }
+
+    // Another connection may have rolled it back before this one got the lock
+    lock.lock(LockLevel::Exclusive)?;
+    let Some(journal) = read_journal(&journal_path)? else {
+        return Ok(false);
+    };
+
+    let file = lock.file();
+    let size = file.metadata()?.len();
+    if size > 0 {
+        let page_size = journal.header.page_size as u64;
+        for record in &journal.records {
+            file.seek(SeekFrom::Start((record.number as u64 - 1) * page_size))?;
+            file.write_all(&record.data)?;
+        }
+        file.set_len(journal.header.initial_size as u64 * page_size)?;
+        file.sync_all()?;
+    }
+
+    std::fs::remove_file(&journal_path)?;
+    Ok(size > 0)
+}
+
+// * Helper functions * //
+
+/// The journal at `journal_path` if it exists and has a valid header
+fn read_journal(journal_path: &Path) -> BinResult<Option<Journal>> {
+    match File::open(journal_path) {
+        Ok(mut file) => Journal::read(&mut file),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Commit the serialized database `new` to the file at `path`, see the
+/// [module docs](self) for the steps. The journal is written under a
+/// RESERVED lock taken through `lock`, once the file is checked against
+/// `snapshot`, and the database file under an EXCLUSIVE one.
+fn commit(
+    lock: &mut FileLock,
+    path: &Path,
+    new: &[u8],
+    page_size: u32,
+    mode: JournalMode,
+    snapshot: &Snapshot,
+) -> BinResult<()> {
+    lock.lock(LockLevel::Reserved)?;
+    snapshot.check(lock, path)?;
+    let mut old = vec![];
+    lock.file().seek(SeekFrom::Start(0))?;
+    lock.file().read_to_end(&mut old)?;
+
+    // The original pages keep the original page size, which a VACUUM can
+    // change, and are restored with it
+    let old_page_size = match old.get(16..18) {
+        Some([0, 1]) => 65536,
+        Some(&[a, b]) if old.len() >= 512 => u16::from_be_bytes([a, b]) as u32,
+        _ => page_size,
+    };
+
+    write_journal(path, &old, new, old_page_size)?;
+    lock.lock(LockLevel::Exclusive)?;
+    write_pages(lock.file(), &old, new, page_size)?;
+    end_journal(path, mode)
+}
+
+/// Steps 1 and 2, write the pages of `old` that differ in `new` to the journal,
+/// in the page size of `old`
+fn write_journal(path: &Path, old: &[u8], new: &[u8], page_size: u32) -> BinResult<()> {
+    let nonce = RandomState::new().hash_one(SystemTime::now()) as u32;
+    let lock_byte = lock_byte_page(page_size) as usize;
+    let records: Vec<PageRecord> = old
+        .chunks(page_size as usize)
+        .enumerate()
+        .filter(|(i, page)| i + 1 != lock_byte && new.chunks(page_size as usize).nth(*i) != Some(*page))
+        .map(|(i, page)| PageRecord::new(i as u32 + 1, page, nonce))
+        .collect();
+    let mut header = JournalHeader {
+        magic: [0; 8],
+        page_count: 0,
+        nonce,
+        initial_size: (old.len() / page_size as usize) as u32,
+        sector_size: SECTOR_SIZE,
+        page_size,
+    };
+
+    // A persisted journal may be longer, and what is left of it after the new
+    // records could be read as more segments
+    let journal_path = journal_path(path);
+    let mut journal = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&journal_path)?;
+    header.write(&mut journal)?;
+    journal.write_all(&[0; SECTOR_SIZE as usize - 28])?;
+    for record in &records {
+        record.write_args(&mut journal, (page_size,))?;
+    }
+    journal.sync_all()?;
+    sync_directory(&journal_path)?;
+
+    header.magic = JOURNAL_MAGIC;
+    header.page_count = records.len() as u32;
+    journal.seek(SeekFrom::Start(0))?;
+    header.write(&mut journal)?;
+    journal.sync_all()?;
+    Ok(())
+}
+
+/// Step 3, write the pages of `new` that differ from `old` and truncate. The
+/// lock-byte page is never written, it stays a hole when the file grows.
+fn write_pages(file: &mut File, old: &[u8], new: &[u8], page_size: u32) -> BinResult<()> {
+    let lock_byte = lock_byte_page(page_size) as usize;
+    for (i, page) in new.chunks(page_size as usize).enumerate() {
+        if i + 1 != lock_byte && old.chunks(page_size as usize).nth(i) != Some(page) {
+            file.seek(SeekFrom::Start(i as u64 * page_size as u64))?;
+            file.write_all(page)?;
+        }
+    }
+    file.set_len(new.len() as u64)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Step 4, the journal is no longer hot once this is on disk
+fn end_journal(path: &Path, mode: JournalMode) -> BinResult<()> {
+    let journal_path = journal_path(path);
+    match mode {
+        JournalMode::Delete | JournalMode::Wal => std::fs::remove_file(&journal_path)?,
+        JournalMode::Truncate => {
+            let journal = OpenOptions::new().write(true).open(&journal_path)?;
+            journal.set_len(0)?;
+            journal.sync_all()?;
+        }
+        JournalMode::Persist => {
+            let mut journal = OpenOptions::new().write(true).open(&journal_path)?;
+            journal.write_all(&[0; 28])?;
+            journal.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Open the database file at `path` to write it, creating it if it doesn't
+/// exist, and lock it SHARED
+fn lock_shared(path: &Path) -> BinResult<FileLock> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let mut lock = FileLock::new(file);
+    lock.lock(LockLevel::Shared)?;
+    Ok(lock)
+}
+
+/// Sync the directory of a new file, so the file is found after a crash
+pub(crate) fn sync_directory(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match cfg!(unix) {
+        true => File::open(dir)?.sync_all(),
+        false => Ok(()),
+    }
+}
+
+/// Checksum of a page record, the nonce plus every 200th byte from the end
+fn checksum(nonce: u32, data: &[u8]) -> u32 {
+    (1..)
+        .map(|k| data.len() as isize - 200 * k)
+        .take_while(|i| *i > 0)
+        .fold(nonce, |sum, i| sum.wrapping_add(data[i as usize] as u32))
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_copy;
+    use pretty_assertions::assert_eq;
+
+    fn open(path: &Path) -> Database {
+        Database::read(&mut File::open(path).expect("Failed to open database")).expect("Failed to read database")
+    }
+
+    #[test]
+    fn commit_and_rollback() {
+        let path = temp_copy("data/planets.db", "journal-commit");
+        let mut db = open(&path);
+        let counter = db.db_header.file_change_counter;
+
+        let mut tx = db.transaction(&path).unwrap();
+        tx.delete(2, 3).unwrap();
+        drop(tx);
+        assert_eq!(db.table_rows(2).len(), 8);
+
+        let mut tx = db.transaction(&path).unwrap();
+        tx.delete(2, 3).unwrap();
+        tx.execute("CREATE TABLE moons (name TEXT)").unwrap();
+        tx.commit().unwrap();
+        assert!(!journal_path(&path).exists());
+
+        let db = open(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(db.table_rows(2).len(), 7);
+        assert_eq!(db.table_rows(1).len(), 2);
+        assert_eq!(db.db_header.file_change_counter, counter + 1);
+    }
+
+    #[test]
+    fn stale_snapshot() {
+        let path = temp_copy("data/planets.db", "journal-snapshot");
+        let mut db = open(&path);
+        let counter = db.db_header.file_change_counter;
+
+        // No other connection writes the file while the transaction is open
+        let mut tx = db.transaction(&path).unwrap();
+        tx.execute("CREATE TABLE moons (name TEXT)").unwrap();
+        let mut other = FileLock::open(&path).unwrap();
+        assert_eq!(
+            other.lock(LockLevel::Exclusive).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+        other.unlock(LockLevel::None).unwrap();
+
+        // Unless it ignores the locks, which the commit notices
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(24)).unwrap();
+        file.write_all(&(counter + 5).to_be_bytes()).unwrap();
+        let err = match tx.commit().unwrap_err() {
+            binrw::Error::Io(err) => err.to_string(),
+            err => panic!("Unexpected error {:?}", err),
+        };
+        assert_eq!(err, "database changed since the transaction began");
+        assert!(!journal_path(&path).exists());
+
+        // The lock is released once the transaction ends
+        other.lock(LockLevel::Exclusive).unwrap();
+        drop(other);
+        let mut db = Database::open(&path).unwrap();
+        assert_eq!(db.table_rows(1).len(), 1);
+        let mut tx = db.transaction(&path).unwrap();
+        tx.execute("CREATE TABLE moons (name TEXT)").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(open(&path).db_header.file_change_counter, counter + 6);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journal_modes() {
+        let t = vec![
+            (JournalMode::Delete, None),
+            (JournalMode::Truncate, Some(0)),
+            (JournalMode::Persist, Some(512 + 4096 + 8)),
+        ];
+
+        for (mode, exp) in t.into_iter() {
+            let path = temp_copy("data/planets.db", &format!("journal-{:?}", mode));
+            let mut db = open(&path);
+            let mut tx = db.transaction_with(&path, mode).unwrap();
+            tx.execute("CREATE TABLE moons (name TEXT)").unwrap();
+            tx.commit().unwrap();
+
+            // Only page 1 was journaled, the table is on a new page
+            let journal = std::fs::read(journal_path(&path)).ok();
+            assert_eq!(journal.as_ref().map(|j| j.len()), exp, "{:?}", mode);
+            assert!(journal.is_none_or(|j| j.iter().take(28).all(|b| *b == 0)), "{:?}", mode);
+            assert!(!rollback(&path).unwrap(), "{:?}", mode);
+            assert_eq!(open(&path).table_rows(1).len(), 2, "{:?}", mode);
+            let _ = std::fs::remove_file(journal_path(&path));
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn hot_journal() {
+        let path = temp_copy("data/planets.db", "journal-hot");
+        let original = std::fs::read(&path).unwrap();
+        let mut db = open(&path);
+        for id in 9..=200 {
+            db.insert(2, id, vec![().into(), "Moon".repeat(20).as_str().into()])
+                .unwrap();
+        }
+        let mut new = Cursor::new(vec![]);
+        db.write_be(&mut new).unwrap();
+        let new = new.into_inner();
+
+        // A crash while writing the database file, after the journal is synced
+        write_journal(&path, &original, &new, 4096).unwrap();
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&new[..6000]).unwrap();
+
+        let journal = Journal::read(&mut File::open(journal_path(&path)).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!((journal.header.page_count, journal.header.initial_size), (2, 2));
+        assert_eq!(journal.records.iter().map(|r| r.number).collect::<Vec<_>>(), vec![1, 2]);
+
+        // Opening the database rolls it back before reading
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.table_rows(2).len(), 8);
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        assert!(!journal_path(&path).exists());
+
+        // Before the page count is written the journal is not hot
+        write_journal(&path, &original, &new, 4096).unwrap();
+        let mut journal = OpenOptions::new().write(true).open(journal_path(&path)).unwrap();
+        journal.write_all(&[0; 12]).unwrap();
+        assert!(!rollback(&path).unwrap());
+        assert!(journal_path(&path).exists());
+
+        // Nor while another connection holds the RESERVED lock to write it
+        write_journal(&path, &original, &new, 4096).unwrap();
+        let mut writer = FileLock::open(&path).unwrap();
+        writer.lock(LockLevel::Reserved).unwrap();
+        assert!(!rollback(&path).unwrap());
+        drop(writer);
+        assert!(rollback(&path).unwrap());
+
+        // A hot journal next to an empty database is deleted
+        write_journal(&path, &original, &new, 4096).unwrap();
+        File::create(&path).unwrap();
+        assert!(!rollback(&path).unwrap());
+        assert!(!journal_path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_journal() {
+        let path = temp_copy("data/planets.db", "journal-stale");
+        let original = std::fs::read(&path).unwrap();
+        let mut db = open(&path);
+        db.insert(2, 9, vec![().into(), "Pluto".into()]).unwrap();
+        let mut new = Cursor::new(vec![]);
+        db.write_be(&mut new).unwrap();
+        let new = new.into_inner();
+        write_journal(&path, &original, &new, 4096).unwrap();
+        let numbers = |journal: &Journal| journal.records.iter().map(|r| r.number).collect::<Vec<_>>();
+        let exp = numbers(&read_journal(&journal_path(&path)).unwrap().unwrap());
+
+        // An older, longer journal with another segment where the new one ends
+        let end = std::fs::metadata(journal_path(&path))
+            .unwrap()
+            .len()
+            .next_multiple_of(512);
+        let header = JournalHeader {
+            magic: JOURNAL_MAGIC,
+            page_count: 1,
+            nonce: 7,
+            initial_size: 2,
+            sector_size: 512,
+            page_size: 4096,
+        };
+        let mut journal = OpenOptions::new().write(true).open(journal_path(&path)).unwrap();
+        journal.seek(SeekFrom::Start(end)).unwrap();
+        header.write(&mut journal).unwrap();
+        journal.write_all(&[0; 512 - 28]).unwrap();
+        PageRecord::new(2, &[0xab; 4096], 7)
+            .write_args(&mut journal, (4096,))
+            .unwrap();
+        drop(journal);
+
+        // Is cut off when the new journal is written over it
+        write_journal(&path, &original, &new, 4096).unwrap();
+        let journal = read_journal(&journal_path(&path)).unwrap().unwrap();
+        assert_eq!(numbers(&journal), exp);
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&new).unwrap();
+        assert!(rollback(&path).unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn page_size_change() {
+        let path = temp_copy("data/planets.db", "journal-page-size");
+        let original = std::fs::read(&path).unwrap();
+        let mut db = open(&path);
+        db.vacuum(Some(1024)).unwrap();
+        let mut new = Cursor::new(vec![]);
+        db.write_be(&mut new).unwrap();
+        let new = new.into_inner();
+
+        // The journal keeps the old pages in the old page size
+        write_journal(&path, &original, &new, 4096).unwrap();
+        let journal = Journal::read(&mut File::open(journal_path(&path)).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!((journal.header.page_size, journal.header.initial_size), (4096, 2));
+        assert_eq!(journal.records.iter().map(|r| r.number).collect::<Vec<_>>(), vec![1, 2]);
+
+        // So a crash halfway through brings back the old page size
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&new[..1500]).unwrap();
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.db_header.page_size_bytes(), 4096);
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+
+        // And a finished commit leaves the new one
+        let mut db = open(&path);
+        let mut tx = db.transaction(&path).unwrap();
+        tx.vacuum(Some(1024)).unwrap();
+        tx.commit().unwrap();
+        let db = open(&path);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * 1024);
+        assert_eq!(db.db_header.page_size_bytes(), 1024);
+        assert_eq!(db.table_rows(2).len(), 8);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checksums() {
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        // Bytes 3896, 3696, ..., 96
+        let exp = (1..=20).map(|k| (4096 - 200 * k) as u8 as u32).sum::<u32>();
+        assert_eq!(checksum(7, &data), 7 + exp);
+
+        // Records after a bad checksum are ignored
+        let header = JournalHeader {
+            magic: JOURNAL_MAGIC,
+            page_count: 3,
+            nonce: 7,
+            initial_size: 3,
+            sector_size: 512,
+            page_size: 4096,
+        };
+        let mut bad = PageRecord::new(2, &data, 7);
+        bad.checksum += 1;
+        let records = [PageRecord::new(1, &data, 7), bad, PageRecord::new(3, &data, 7)];
+
+        let mut buffer = Cursor::new(vec![]);
+        header.write(&mut buffer).unwrap();
+        buffer.write_all(&[0; 512 - 28]).unwrap();
+        for record in &records {
+            record.write_args(&mut buffer, (4096,)).unwrap();
+        }
+        let journal = Journal::read(&mut buffer).unwrap().unwrap();
+        assert_eq!(journal.records, records[..1]);
+    }
+}
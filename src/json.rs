@@ -0,0 +1,1222 @@
+//! # JSON functions
+//!
+//! Implements the [JSON functions](https://www.sqlite.org/json1.html) over
+//! values stored as JSON text in `TEXT` columns or as the binary
+//! [JSONB](https://www.sqlite.org/jsonb.html) format in `BLOB` columns.
+//!
+//! Both encodings are decoded into a [Json] tree. Numbers keep the text they
+//! were written with, just like JSONB does, so `json('1.50')` round trips
+//! unchanged.
+//!
+//! ```
+//! use rsqlite::{json, schema::SerialValue};
+//!
+//! let args = [r#"{"a": [1, {"b": true}]}"#.into(), "$.a[1].b".into()];
+//! assert_eq!(json::json_extract(&args), Ok(SerialValue::Number(1)));
+//! ```
+
+use crate::schema::SerialValue;
+use std::fmt;
+
+/// JSON nested deeper than this is considered malformed, same as SQLite.
+const MAX_DEPTH: usize = 1000;
+
+/**
+ * A decoded JSON value.
+ *
+ * Objects are a list of pairs to preserve order and duplicate labels.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    True,
+    False,
+    Integer(String),
+    Real(String),
+    Text(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The input is neither valid JSON text nor a valid JSONB blob
+    Malformed,
+    /// The path argument is not a valid JSON path
+    BadPath(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Malformed => write!(f, "malformed JSON"),
+            Error::BadPath(path) => write!(f, "bad JSON path: '{}'", path),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/**
+ * JSONB element types, stored in the low 4 bits of the first header byte.
+ *
+ * [Docs](https://www.sqlite.org/jsonb.html#payload_size)
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+enum ElementType {
+    Null = 0,
+    True = 1,
+    False = 2,
+    Int = 3,
+    Int5 = 4,
+    Float = 5,
+    Float5 = 6,
+    Text = 7,
+    TextJ = 8,
+    Text5 = 9,
+    TextRaw = 10,
+    Array = 11,
+    Object = 12,
+}
+
+impl Json {
+    /// Parse JSON text
+    pub fn parse(text: &str) -> Result<Json> {
+        let mut parser = Parser { s: text.as_bytes(), pos: 0 };
+        let json = parser.value(0)?;
+        parser.ws();
+        if parser.pos != text.len() {
+            return Err(Error::Malformed);
+        }
+        Ok(json)
+    }
+
+    /// Decode a JSONB blob
+    pub fn from_jsonb(blob: &[u8]) -> Result<Json> {
+        let (json, len) = decode(blob, 0)?;
+        if len != blob.len() {
+            return Err(Error::Malformed);
+        }
+        Ok(json)
+    }
+
+    /// Encode as JSONB, using the smallest header for each element
+    pub fn to_jsonb(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+    /// Interpret a column value as JSON, NULL stays NULL.
+    ///
+    /// Text is parsed as JSON text and blobs as JSONB.
+    pub fn from_value(value: &SerialValue) -> Result<Option<Json>> {
+        match value {
+            SerialValue::Null => Ok(None),
+            SerialValue::Number(n) => Ok(Some(Json::Integer(n.to_string()))),
+            SerialValue::Float(x) => Ok(Some(Json::Real(format_real(*x)))),
+            SerialValue::String(s) => Json::parse(s).map(Some),
+            SerialValue::Blob(b) => Json::from_jsonb(b).map(Some),
+            SerialValue::Reserved => Err(Error::Malformed),
+        }
+    }
+
+    /// The SQL value of this element. Containers are returned as JSON text.
+    pub fn to_value(&self) -> SerialValue {
+        match self {
+            Json::Null => SerialValue::Null,
+            Json::True => SerialValue::Number(1),
+            Json::False => SerialValue::Number(0),
+            Json::Integer(n) => match n.parse() {
+                Ok(n) => SerialValue::Number(n),
+                // Too large for 64 bits
+                Err(_) => SerialValue::Float(n.parse().unwrap_or_default()),
+            },
+            Json::Real(x) => SerialValue::Float(x.parse().unwrap_or_default()),
+            Json::Text(s) => SerialValue::String(s.clone()),
+            Json::Array(_) | Json::Object(_) => SerialValue::String(self.to_string()),
+        }
+    }
+
+    /// Type name as returned by `json_type()`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::True => "true",
+            Json::False => "false",
+            Json::Integer(_) => "integer",
+            Json::Real(_) => "real",
+            Json::Text(_) => "text",
+            Json::Array(_) => "array",
+            Json::Object(_) => "object",
+        }
+    }
+
+    /// Find the element at `path`, `None` if it doesn't exist.
+    pub fn lookup(&self, path: &str) -> Result<Option<&Json>> {
+        let mut node = self;
+        for step in parse_path(path)? {
+            let next = match (node, step) {
+                (Json::Object(members), Step::Key(key)) => members.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+                (Json::Array(items), step) => step.index(items.len()).map(|i| &items[i]),
+                _ => None,
+            };
+            match next {
+                Some(next) => node = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(node))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let text = |out: &mut Vec<u8>, t: ElementType, s: &str| {
+            header(out, t, s.len());
+            out.extend_from_slice(s.as_bytes());
+        };
+
+        match self {
+            Json::Null => header(out, ElementType::Null, 0),
+            Json::True => header(out, ElementType::True, 0),
+            Json::False => header(out, ElementType::False, 0),
+            Json::Integer(n) => text(out, ElementType::Int, n),
+            Json::Real(x) => text(out, ElementType::Float, x),
+            Json::Text(s) => {
+                let escaped = escape(s);
+                if escaped.len() == s.len() {
+                    text(out, ElementType::Text, s)
+                } else {
+                    text(out, ElementType::TextJ, &escaped)
+                }
+            }
+            Json::Array(items) => {
+                let mut payload = Vec::new();
+                items.iter().for_each(|item| item.encode(&mut payload));
+                header(out, ElementType::Array, payload.len());
+                out.extend(payload);
+            }
+            Json::Object(members) => {
+                let mut payload = Vec::new();
+                for (label, value) in members {
+                    Json::Text(label.clone()).encode(&mut payload);
+                    value.encode(&mut payload);
+                }
+                header(out, ElementType::Object, payload.len());
+                out.extend(payload);
+            }
+        }
+    }
+
+    /// Size of the JSONB encoding, used as row ids by `json_each` and `json_tree`
+    fn encoded_len(&self) -> usize {
+        header_len(self.payload_len()) + self.payload_len()
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Json::Null | Json::True | Json::False => 0,
+            Json::Integer(s) | Json::Real(s) => s.len(),
+            Json::Text(s) => escape(s).len(),
+            Json::Array(items) => items.iter().map(Json::encoded_len).sum(),
+            Json::Object(members) => members
+                .iter()
+                .map(|(k, v)| Json::Text(k.clone()).encoded_len() + v.encoded_len())
+                .sum(),
+        }
+    }
+}
+
+/// Minified JSON text, the same format as `json()`
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::True => write!(f, "true"),
+            Json::False => write!(f, "false"),
+            Json::Integer(n) => write!(f, "{}", n),
+            Json::Real(x) => write!(f, "{}", x),
+            Json::Text(s) => write!(f, "\"{}\"", escape(s)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { "," } else { "" }, item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in members.iter().enumerate() {
+                    write!(f, "{}\"{}\":{}", if i > 0 { "," } else { "" }, escape(k), v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// * SQL functions * //
+
+/// `json(X)` returns X as minified JSON text
+pub fn json(args: &[SerialValue]) -> Result<SerialValue> {
+    match Json::from_value(arg(args, 0))? {
+        Some(json) => Ok(SerialValue::String(json.to_string())),
+        None => Ok(SerialValue::Null),
+    }
+}
+
+/// `json_extract(X, P1, P2, ...)`
+///
+/// With a single path the SQL value at that path is returned, otherwise a JSON
+/// array with the value of every path.
+pub fn json_extract(args: &[SerialValue]) -> Result<SerialValue> {
+    let Some(json) = Json::from_value(arg(args, 0))? else {
+        return Ok(SerialValue::Null);
+    };
+
+    match &args[args.len().min(1)..] {
+        [] => Ok(SerialValue::Null),
+        [path] => Ok(json.lookup(&path_arg(path)?)?.map_or(SerialValue::Null, Json::to_value)),
+        paths => {
+            let mut items = Vec::with_capacity(paths.len());
+            for path in paths {
+                items.push(json.lookup(&path_arg(path)?)?.cloned().unwrap_or(Json::Null));
+            }
+            Ok(SerialValue::String(Json::Array(items).to_string()))
+        }
+    }
+}
+
+/// `X -> P` returns the JSON text of the element at P.
+///
+/// P can be a full path, an object label or an array index.
+pub fn arrow(args: &[SerialValue]) -> Result<SerialValue> {
+    match arrow_lookup(args)? {
+        Some(json) => Ok(SerialValue::String(json.to_string())),
+        None => Ok(SerialValue::Null),
+    }
+}
+
+/// `X ->> P` returns the SQL value of the element at P.
+pub fn arrow_value(args: &[SerialValue]) -> Result<SerialValue> {
+    Ok(arrow_lookup(args)?.map_or(SerialValue::Null, |json| json.to_value()))
+}
+
+/// `json_type(X[, P])`
+pub fn json_type(args: &[SerialValue]) -> Result<SerialValue> {
+    Ok(optional_lookup(args)?.map_or(SerialValue::Null, |json| SerialValue::String(json.type_name().into())))
+}
+
+/// `json_array_length(X[, P])` returns 0 for anything other than an array
+pub fn json_array_length(args: &[SerialValue]) -> Result<SerialValue> {
+    Ok(match optional_lookup(args)? {
+        Some(Json::Array(items)) => SerialValue::Number(items.len() as i64),
+        Some(_) => SerialValue::Number(0),
+        None => SerialValue::Null,
+    })
+}
+
+/// Columns of the rows returned by [json_each] and [json_tree]
+pub const JSON_EACH_COLUMNS: [&str; 8] = ["key", "value", "type", "atom", "id", "parent", "fullkey", "path"];
+
+/// `json_each(X[, P])` is a table valued function with one row for each
+/// immediate child of the element at P. See [JSON_EACH_COLUMNS].
+pub fn json_each(args: &[SerialValue]) -> Result<Vec<Vec<SerialValue>>> {
+    walk(args, false)
+}
+
+/// `json_tree(X[, P])` walks the element at P recursively, including itself.
+pub fn json_tree(args: &[SerialValue]) -> Result<Vec<Vec<SerialValue>>> {
+    walk(args, true)
+}
+
+// * Helper functions * //
+
+fn arg(args: &[SerialValue], i: usize) -> &SerialValue {
+    args.get(i).unwrap_or(&SerialValue::Null)
+}
+
+fn path_arg(value: &SerialValue) -> Result<String> {
+    match value {
+        SerialValue::String(path) => Ok(path.clone()),
+        other => Err(Error::BadPath(other.to_string())),
+    }
+}
+
+/// Lookup X at the optional path P, `$` if omitted
+fn optional_lookup(args: &[SerialValue]) -> Result<Option<Json>> {
+    let Some(json) = Json::from_value(arg(args, 0))? else {
+        return Ok(None);
+    };
+    match args.get(1) {
+        Some(path) => Ok(json.lookup(&path_arg(path)?)?.cloned()),
+        None => Ok(Some(json)),
+    }
+}
+
+fn arrow_lookup(args: &[SerialValue]) -> Result<Option<Json>> {
+    let Some(json) = Json::from_value(arg(args, 0))? else {
+        return Ok(None);
+    };
+    let path = match arg(args, 1) {
+        SerialValue::Number(i) if *i < 0 => format!("$[#{}]", i),
+        SerialValue::Number(i) => format!("$[{}]", i),
+        SerialValue::String(s) if s.starts_with('$') => s.clone(),
+        SerialValue::String(s) if s.starts_with('[') => format!("${}", s),
+        SerialValue::String(s) => format!("$.{}", s),
+        _ => return Ok(None),
+    };
+    Ok(json.lookup(&path)?.cloned())
+}
+
+/// Rows of `json_each` and `json_tree`. Ids are byte offsets into the JSONB
+/// encoding of the value, which is what SQLite uses too.
+fn walk(args: &[SerialValue], recursive: bool) -> Result<Vec<Vec<SerialValue>>> {
+    let Some(json) = Json::from_value(arg(args, 0))? else {
+        return Ok(vec![]);
+    };
+    let path = match args.get(1) {
+        Some(path) => path_arg(path)?,
+        None => "$".to_string(),
+    };
+
+    // Find the starting element along with its position in the encoding
+    let mut node = &json;
+    let mut at = Position { id: 0, offset: 0 };
+    let mut fullkey = String::from("$");
+    let mut key = SerialValue::Null;
+    for step in parse_path(&path)? {
+        let found = match (node, step) {
+            (Json::Object(members), Step::Key(k)) => members
+                .iter()
+                .zip(at.members(node))
+                .find(|((label, _), _)| *label == k)
+                .map(|((label, v), at)| (v, at, SerialValue::String(label.clone()))),
+            (Json::Array(items), step) => step
+                .index(items.len())
+                .map(|i| (&items[i], at.members(node)[i], SerialValue::Number(i as i64))),
+            _ => None,
+        };
+        let Some((next, next_at, next_key)) = found else {
+            return Ok(vec![]);
+        };
+        push_key(&mut fullkey, &next_key);
+        (node, at, key) = (next, next_at, next_key);
+    }
+
+    // Children are reported relative to the path as written, like SQLite does
+    let parent_path = match fullkey.rfind(['.', '[']) {
+        Some(i) if at.id != 0 => fullkey[..i].to_string(),
+        _ => fullkey.clone(),
+    };
+    let fullkey = path;
+
+    let mut rows = Vec::new();
+    let mut walker = Walker { rows: &mut rows, recursive };
+    if recursive {
+        walker.visit(node, key, at, SerialValue::Null, fullkey, parent_path);
+    } else if matches!(node, Json::Array(_) | Json::Object(_)) {
+        walker.children(node, at, &fullkey, SerialValue::Null);
+    } else {
+        walker.row(
+            node,
+            SerialValue::Null,
+            at.id,
+            SerialValue::Null,
+            fullkey.clone(),
+            fullkey,
+        );
+    }
+
+    Ok(rows)
+}
+
+/// Where an element is in the JSONB encoding. The `id` of an object member is
+/// the offset of its label, for everything else it is the element's `offset`.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    id: usize,
+    offset: usize,
+}
+
+impl Position {
+    /// Positions of every child of the container at this position
+    fn members(&self, node: &Json) -> Vec<Position> {
+        let mut offset = self.offset + header_len(node.payload_len());
+        let mut positions = Vec::new();
+        match node {
+            Json::Array(items) => {
+                for item in items {
+                    positions.push(Position { id: offset, offset });
+                    offset += item.encoded_len();
+                }
+            }
+            Json::Object(members) => {
+                for (label, value) in members {
+                    let label_len = Json::Text(label.clone()).encoded_len();
+                    positions.push(Position {
+                        id: offset,
+                        offset: offset + label_len,
+                    });
+                    offset += label_len + value.encoded_len();
+                }
+            }
+            _ => {}
+        }
+        positions
+    }
+}
+
+struct Walker<'a> {
+    rows: &'a mut Vec<Vec<SerialValue>>,
+    recursive: bool,
+}
+
+impl Walker<'_> {
+    fn row(&mut self, node: &Json, key: SerialValue, id: usize, parent: SerialValue, fullkey: String, path: String) {
+        let atom = match node {
+            Json::Array(_) | Json::Object(_) => SerialValue::Null,
+            _ => node.to_value(),
+        };
+        self.rows.push(vec![
+            key,
+            node.to_value(),
+            SerialValue::String(node.type_name().into()),
+            atom,
+            SerialValue::Number(id as i64),
+            parent,
+            SerialValue::String(fullkey),
+            SerialValue::String(path),
+        ]);
+    }
+
+    fn visit(
+        &mut self,
+        node: &Json,
+        key: SerialValue,
+        at: Position,
+        parent: SerialValue,
+        fullkey: String,
+        path: String,
+    ) {
+        self.row(node, key, at.id, parent, fullkey.clone(), path);
+        self.children(node, at, &fullkey, SerialValue::Number(at.id as i64));
+    }
+
+    /// Visit every child of a container. `parent` is NULL for `json_each`.
+    fn children(&mut self, node: &Json, at: Position, fullkey: &str, parent: SerialValue) {
+        let keys: Vec<(SerialValue, &Json)> = match node {
+            Json::Array(items) => items
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (SerialValue::Number(i as i64), v))
+                .collect(),
+            Json::Object(members) => members
+                .iter()
+                .map(|(k, v)| (SerialValue::String(k.clone()), v))
+                .collect(),
+            _ => vec![],
+        };
+
+        for ((key, value), at) in keys.into_iter().zip(at.members(node)) {
+            let mut child_key = fullkey.to_string();
+            push_key(&mut child_key, &key);
+            if self.recursive {
+                self.visit(value, key, at, parent.clone(), child_key, fullkey.to_string());
+            } else {
+                self.row(value, key, at.id, parent.clone(), child_key, fullkey.to_string());
+            }
+        }
+    }
+}
+
+/// Append an array index or object label to a path
+fn push_key(path: &mut String, key: &SerialValue) {
+    match key {
+        SerialValue::Number(i) => path.push_str(&format!("[{}]", i)),
+        SerialValue::String(k) if !k.is_empty() && k.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+            path.push_str(&format!(".{}", k))
+        }
+        SerialValue::String(k) => path.push_str(&format!(".\"{}\"", k)),
+        _ => {}
+    }
+}
+
+/// Format a real like SQLite's `printf("%!.15g")`, 15 significant digits and
+/// always a decimal point, in exponential form outside of `1e-4..1e15`
+fn format_real(x: f64) -> String {
+    if x.is_infinite() {
+        return if x > 0.0 { "9e999" } else { "-9e999" }.to_string();
+    }
+    if x.is_nan() {
+        return x.to_string();
+    }
+
+    // The exponent after rounding to 15 digits decides the form
+    let scientific = format!("{:.14e}", x);
+    let (mantissa, exponent) = scientific.split_once('e').expect("exponent");
+    let exponent: i32 = exponent.parse().expect("exponent");
+    let point = |s: &str| match s.contains('.') {
+        true => s.trim_end_matches('0').trim_end_matches('.').to_string(),
+        false => s.to_string(),
+    };
+
+    if !(-4..15).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        let mantissa = point(mantissa);
+        let dot = if mantissa.contains('.') { "" } else { ".0" };
+        return format!("{}{}e{}{:02}", mantissa, dot, sign, exponent.abs());
+    }
+    let fixed = point(&format!("{:.*}", (14 - exponent) as usize, x));
+    match fixed.contains('.') {
+        true => fixed,
+        false => format!("{}.0", fixed),
+    }
+}
+
+/// Escape a string for JSON text, without the surrounding quotes
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// * JSON path * //
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+    /// `[#-N]`, N elements from the end
+    FromEnd(usize),
+}
+
+impl Step {
+    /// Position of an array step in an array of `len` items, if it exists
+    fn index(&self, len: usize) -> Option<usize> {
+        match self {
+            Step::Index(i) => Some(*i),
+            Step::FromEnd(n) => len.checked_sub(*n),
+            Step::Key(_) => None,
+        }
+        .filter(|&i| i < len)
+    }
+}
+
+/// Parse paths like `$.a."b c"[2][#-1]`
+fn parse_path(path: &str) -> Result<Vec<Step>> {
+    let bad = || Error::BadPath(path.to_string());
+    let mut rest = path.strip_prefix('$').ok_or_else(bad)?;
+    let mut steps = Vec::new();
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix(".\"") {
+            let end = r.find('"').ok_or_else(bad)?;
+            steps.push(Step::Key(r[..end].to_string()));
+            rest = &r[end + 1..];
+        } else if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            if end == 0 {
+                return Err(bad());
+            }
+            steps.push(Step::Key(r[..end].to_string()));
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']').ok_or_else(bad)?;
+            let index = &r[..end];
+            let step = if index == "#" {
+                Step::FromEnd(0)
+            } else if let Some(n) = index.strip_prefix("#-") {
+                Step::FromEnd(n.parse().map_err(|_| bad())?)
+            } else {
+                Step::Index(index.parse().map_err(|_| bad())?)
+            };
+            steps.push(step);
+            rest = &r[end + 1..];
+        } else {
+            return Err(bad());
+        }
+    }
+
+    Ok(steps)
+}
+
+// * JSON text parser * //
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn ws(&mut self) {
+        while self.pos < self.s.len() && matches!(self.s[self.pos], b' ' | b'\t' | b'\n' | b'\r') {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.ws();
+        let found = self.peek() == Some(c);
+        self.pos += found as usize;
+        found
+    }
+
+    fn keyword(&mut self, word: &str, json: Json) -> Result<Json> {
+        if self.s[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(json)
+        } else {
+            Err(Error::Malformed)
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json> {
+        self.ws();
+        match self.peek().ok_or(Error::Malformed)? {
+            b'[' | b'{' if depth >= MAX_DEPTH => Err(Error::Malformed),
+            b'n' => self.keyword("null", Json::Null),
+            b't' => self.keyword("true", Json::True),
+            b'f' => self.keyword("false", Json::False),
+            b'"' => self.string().map(Json::Text),
+            b'-' | b'0'..=b'9' => self.number(),
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.eat(b']') {
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if self.eat(b']') {
+                            break;
+                        }
+                        if !self.eat(b',') {
+                            return Err(Error::Malformed);
+                        }
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if !self.eat(b'}') {
+                    loop {
+                        self.ws();
+                        let key = self.string()?;
+                        if !self.eat(b':') {
+                            return Err(Error::Malformed);
+                        }
+                        members.push((key, self.value(depth + 1)?));
+                        if self.eat(b'}') {
+                            break;
+                        }
+                        if !self.eat(b',') {
+                            return Err(Error::Malformed);
+                        }
+                    }
+                }
+                Ok(Json::Object(members))
+            }
+            _ => Err(Error::Malformed),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        let digits = |p: &mut Self| {
+            let from = p.pos;
+            while p.peek().is_some_and(|c| c.is_ascii_digit()) {
+                p.pos += 1;
+            }
+            p.pos - from
+        };
+
+        self.pos += (self.peek() == Some(b'-')) as usize;
+        let int_start = self.pos;
+        if digits(self) == 0 || (self.s[int_start] == b'0' && self.pos - int_start > 1) {
+            return Err(Error::Malformed);
+        }
+
+        let mut real = false;
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            real = true;
+            if digits(self) == 0 {
+                return Err(Error::Malformed);
+            }
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            real = true;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if digits(self) == 0 {
+                return Err(Error::Malformed);
+            }
+        }
+
+        let text = String::from_utf8_lossy(&self.s[start..self.pos]).into_owned();
+        Ok(if real { Json::Real(text) } else { Json::Integer(text) })
+    }
+
+    fn string(&mut self) -> Result<String> {
+        if self.peek() != Some(b'"') {
+            return Err(Error::Malformed);
+        }
+        let start = self.pos + 1;
+        let mut end = start;
+        loop {
+            match self.s.get(end) {
+                None => return Err(Error::Malformed),
+                Some(b'"') => break,
+                Some(b'\\') => end += 2,
+                Some(_) => end += 1,
+            }
+        }
+        self.pos = end + 1;
+        let raw = std::str::from_utf8(&self.s[start..end]).map_err(|_| Error::Malformed)?;
+        unescape(raw, false)
+    }
+}
+
+/// Decode backslash escapes. JSON5 escapes are only allowed in JSONB `TEXT5`.
+fn unescape(raw: &str, json5: bool) -> Result<String> {
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+
+    let hex = |chars: &mut std::str::Chars, n: usize| -> Result<u32> {
+        let digits: String = chars.by_ref().take(n).collect();
+        if digits.len() != n {
+            return Err(Error::Malformed);
+        }
+        u32::from_str_radix(&digits, 16).map_err(|_| Error::Malformed)
+    };
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            if (c as u32) < 0x20 && !json5 {
+                return Err(Error::Malformed);
+            }
+            out.push(c);
+            continue;
+        }
+        match chars.next().ok_or(Error::Malformed)? {
+            '"' => out.push('"'),
+            '\\' => out.push('\\'),
+            '/' => out.push('/'),
+            'b' => out.push('\u{08}'),
+            'f' => out.push('\u{0c}'),
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            't' => out.push('\t'),
+            'u' => {
+                let mut code = hex(&mut chars, 4)?;
+                // Surrogate pair
+                if (0xd800..0xdc00).contains(&code) {
+                    if chars.next() != Some('\\') || chars.next() != Some('u') {
+                        return Err(Error::Malformed);
+                    }
+                    let low = hex(&mut chars, 4)?;
+                    code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                }
+                out.push(char::from_u32(code).ok_or(Error::Malformed)?);
+            }
+            '\'' if json5 => out.push('\''),
+            'v' if json5 => out.push('\u{0b}'),
+            '0' if json5 => out.push('\0'),
+            'x' if json5 => out.push(char::from_u32(hex(&mut chars, 2)?).ok_or(Error::Malformed)?),
+            '\n' if json5 => {}
+            _ => return Err(Error::Malformed),
+        }
+    }
+
+    Ok(out)
+}
+
+// * JSONB * //
+
+/// Number of bytes in a header for a payload of `size` bytes
+fn header_len(size: usize) -> usize {
+    match size {
+        0..=11 => 1,
+        12..=0xff => 2,
+        0x100..=0xffff => 3,
+        _ if size <= u32::MAX as usize => 5,
+        _ => 9,
+    }
+}
+
+fn header(out: &mut Vec<u8>, t: ElementType, size: usize) {
+    let t = t as u8;
+    match header_len(size) {
+        1 => out.push(((size as u8) << 4) | t),
+        2 => out.extend([0xc0 | t, size as u8]),
+        3 => {
+            out.push(0xd0 | t);
+            out.extend((size as u16).to_be_bytes());
+        }
+        5 => {
+            out.push(0xe0 | t);
+            out.extend((size as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xf0 | t);
+            out.extend((size as u64).to_be_bytes());
+        }
+    }
+}
+
+/**
+ * Decode the element starting at `pos`, returns it along with the position
+ * right after it. Containers nested deeper than [MAX_DEPTH] are malformed,
+ * like in JSON text.
+ *
+ * Blobs come from user data, so nesting is tracked on a stack of the
+ * containers still open rather than by recursion.
+ */
+fn decode(blob: &[u8], mut pos: usize) -> Result<(Json, usize)> {
+    // Type, end and elements so far of every container still open
+    let mut open: Vec<(u8, usize, Vec<Json>)> = vec![];
+
+    loop {
+        let limit = open.last().map_or(blob.len(), |(_, end, _)| *end);
+        let first = *blob[..limit].get(pos).ok_or(Error::Malformed)?;
+        let (size, header) = match first >> 4 {
+            n @ 0..=11 => (n as usize, 1),
+            n => {
+                let width = 1 << (n - 12);
+                let bytes = blob[..limit].get(pos + 1..pos + 1 + width).ok_or(Error::Malformed)?;
+                let size = bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+                (usize::try_from(size).map_err(|_| Error::Malformed)?, 1 + width)
+            }
+        };
+        let start = pos + header;
+        let end = start
+            .checked_add(size)
+            .filter(|&end| end <= limit)
+            .ok_or(Error::Malformed)?;
+
+        let t = first & 0x0f;
+        let mut done = if t == ElementType::Array as u8 || t == ElementType::Object as u8 {
+            if open.len() >= MAX_DEPTH {
+                return Err(Error::Malformed);
+            }
+            open.push((t, end, vec![]));
+            pos = start;
+            None
+        } else {
+            pos = end;
+            Some(scalar(t, &blob[start..end])?)
+        };
+
+        // Close every container that ends here, innermost first
+        loop {
+            if let Some(json) = done.take() {
+                match open.last_mut() {
+                    Some((_, _, items)) => items.push(json),
+                    None => return Ok((json, pos)),
+                }
+            }
+            match open.last() {
+                Some((_, end, _)) if *end == pos => {
+                    let (t, _, items) = open.pop().expect("open container");
+                    done = Some(container(t, items)?);
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+/// An array, or an object from its labels and values in turn
+fn container(t: u8, items: Vec<Json>) -> Result<Json> {
+    if t == ElementType::Array as u8 {
+        return Ok(Json::Array(items));
+    }
+    if !items.len().is_multiple_of(2) {
+        return Err(Error::Malformed);
+    }
+    let mut members = Vec::with_capacity(items.len() / 2);
+    let mut items = items.into_iter();
+    while let (Some(label), Some(value)) = (items.next(), items.next()) {
+        match label {
+            Json::Text(label) => members.push((label, value)),
+            _ => return Err(Error::Malformed),
+        }
+    }
+    Ok(Json::Object(members))
+}
+
+/// Decode the payload of an element of type `t` that is not a container
+fn scalar(t: u8, payload: &[u8]) -> Result<Json> {
+    let text = || std::str::from_utf8(payload).map_err(|_| Error::Malformed);
+    Ok(match t {
+        t if t == ElementType::Null as u8 => Json::Null,
+        t if t == ElementType::True as u8 => Json::True,
+        t if t == ElementType::False as u8 => Json::False,
+        t if t == ElementType::Int as u8 => Json::Integer(text()?.to_string()),
+        t if t == ElementType::Int5 as u8 => Json::Integer(int5(text()?)?),
+        t if t == ElementType::Float as u8 => Json::Real(text()?.to_string()),
+        t if t == ElementType::Float5 as u8 => Json::Real(float5(text()?)?),
+        t if t == ElementType::Text as u8 || t == ElementType::TextRaw as u8 => Json::Text(text()?.to_string()),
+        t if t == ElementType::TextJ as u8 => Json::Text(unescape(text()?, false)?),
+        t if t == ElementType::Text5 as u8 => Json::Text(unescape(text()?, true)?),
+        // 13, 14 and 15 are reserved
+        _ => return Err(Error::Malformed),
+    })
+}
+
+/// JSON5 integers, hex literals like `0x1F` or `-0x1f`
+fn int5(s: &str) -> Result<String> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"));
+    let n = match digits {
+        Some(hex) => i128::from_str_radix(hex, 16).map_err(|_| Error::Malformed)?,
+        None => s.parse().map_err(|_| Error::Malformed)?,
+    };
+    Ok(format!("{}", if neg { -n } else { n }))
+}
+
+/// JSON5 floats like `.5`, `5.`, `+1.0` or `Infinity`
+fn float5(s: &str) -> Result<String> {
+    let x: f64 = match s.trim_start_matches(['+', '-']) {
+        "Infinity" | "inf" => f64::INFINITY,
+        "NaN" => return Ok("null".to_string()),
+        t => t.parse().map_err(|_| Error::Malformed)?,
+    };
+    Ok(format_real(if s.starts_with('-') { -x } else { x }))
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use SerialValue as V;
+
+    fn args(args: &[&str]) -> Vec<SerialValue> {
+        args.iter().map(|&a| a.into()).collect()
+    }
+
+    // Expected values from `sqlite3 :memory: "select ..."`
+    #[test]
+    fn functions() {
+        let doc = r#"{"a":[1,{"b":true}]}"#;
+
+        let t = vec![
+            (
+                json(&args(&[r#" { "a" : [1, 2.50, "x"], "b":null } "#])),
+                r#"{"a":[1,2.50,"x"],"b":null}"#.into(),
+            ),
+            (json_extract(&args(&[doc, "$.a[1]"])), r#"{"b":true}"#.into()),
+            (json_extract(&args(&[doc, "$.a[1].b", "$.a[#-2]"])), "[true,1]".into()),
+            (json_extract(&args(&[doc, "$.a[1].b"])), V::Number(1)),
+            (json_extract(&args(&[doc, "$.missing"])), V::Null),
+            (arrow(&args(&[r#"{"a":"x"}"#, "$.a"])), r#""x""#.into()),
+            (arrow_value(&args(&[r#"{"a":"x"}"#, "a"])), "x".into()),
+            (arrow(&["[5,6]".into(), V::Number(1)]), "6".into()),
+            (arrow(&["[5,6]".into(), V::Number(-1)]), "6".into()),
+            (json_type(&args(&[r#"{"a":1.0}"#, "$.a"])), "real".into()),
+            (json_type(&args(&["[1]", "$[5]"])), V::Null),
+            (json_array_length(&args(&["[1,2,3]"])), V::Number(3)),
+            (json_array_length(&args(&["{}"])), V::Number(0)),
+        ];
+
+        for (got, exp) in t.into_iter() {
+            assert_eq!(got, Ok(exp));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(json(&args(&["[1"])), Err(Error::Malformed));
+        assert_eq!(json(&args(&["[01]"])), Err(Error::Malformed));
+        assert_eq!(json(&[V::Blob(vec![0x0d])]), Err(Error::Malformed));
+        assert_eq!(json_extract(&args(&["[1]", "x"])), Err(Error::BadPath("x".into())));
+        assert_eq!(json(&[V::Null]), Ok(V::Null));
+
+        // Too deep to decode, an array holding an array and so on down to an
+        // empty one, each with a 4 byte size
+        let depth = |n: usize| {
+            let mut blob = vec![];
+            for level in 1..n {
+                blob.push(0xeb);
+                blob.extend((5 * (n - 1 - level) as u32 + 1).to_be_bytes());
+            }
+            blob.push(0x0b);
+            V::Blob(blob)
+        };
+        assert_eq!(json(&[depth(1000)]).map(|v| v.to_string().len()), Ok(2000));
+        assert_eq!(json(&[depth(1001)]), Err(Error::Malformed));
+        assert_eq!(json(&[depth(200_000)]), Err(Error::Malformed));
+        let text = |n: usize| V::String(format!("{}1{}", "[".repeat(n), "]".repeat(n)));
+        assert_eq!(json(&[text(1000)]).map(|v| v.to_string().len()), Ok(2001));
+        assert_eq!(json(&[text(1001)]), Err(Error::Malformed));
+    }
+
+    // $ sqlite3 :memory: "select json(1e20), json(1.0/3), ..."
+    #[test]
+    fn reals() {
+        let t = vec![
+            (1e20, "1.0e+20"),
+            (1.5, "1.5"),
+            (3.0, "3.0"),
+            (1e-7, "1.0e-07"),
+            (2e-5, "2.0e-05"),
+            (1.25e-4, "0.000125"),
+            (1.0 / 3.0, "0.333333333333333"),
+            (1e14, "100000000000000.0"),
+            (1e15, "1.0e+15"),
+            (123456789012345678.0, "1.23456789012346e+17"),
+            (1e23, "1.0e+23"),
+            (-2.5e-10, "-2.5e-10"),
+            (1e300, "1.0e+300"),
+            (0.0, "0.0"),
+        ];
+        for (x, exp) in t.into_iter() {
+            assert_eq!(json(&[V::Float(x)]), Ok(exp.into()), "{}", x);
+        }
+    }
+
+    #[test]
+    fn jsonb() {
+        // $ sqlite3 :memory: "select hex(jsonb('{\"a\":[1,2.5,\"x\"],\"b\":null,\"c\":true}'))"
+        let blob = vec![
+            0xCC, 0x11, 0x17, 0x61, 0x8B, 0x13, 0x31, 0x35, 0x32, 0x2E, 0x35, 0x17, 0x78, 0x17, 0x62, 0x00, 0x17, 0x63,
+            0x01,
+        ];
+        let text = r#"{"a":[1,2.5,"x"],"b":null,"c":true}"#;
+
+        assert_eq!(json(&[V::Blob(blob.clone())]), Ok(text.into()));
+        assert_eq!(Json::parse(text).unwrap().to_jsonb(), blob);
+        assert_eq!(json_extract(&[V::Blob(blob), "$.a[1]".into()]), Ok(V::Float(2.5)));
+
+        // JSON5 flavours: 0x1F, 'it''s', .5
+        let json5 = vec![
+            0xCB, 0x0E, 0x44, 0x30, 0x78, 0x31, 0x46, 0x59, 0x69, 0x74, 0x5C, 0x27, 0x73, 0x26, 0x2E, 0x35,
+        ];
+        assert_eq!(json(&[V::Blob(json5)]), Ok(r#"[31,"it's",0.5]"#.into()));
+    }
+
+    #[test]
+    fn table_valued() {
+        let row = |key: V, value: V, t: &str, atom: V, id: i64, parent: V, fullkey: &str, path: &str| {
+            vec![
+                key,
+                value,
+                t.into(),
+                atom,
+                V::Number(id),
+                parent,
+                fullkey.into(),
+                path.into(),
+            ]
+        };
+
+        assert_eq!(
+            json_tree(&args(&[r#"{"a":[1,{"b c":true}]}"#])),
+            Ok(vec![
+                row(
+                    V::Null,
+                    r#"{"a":[1,{"b c":true}]}"#.into(),
+                    "object",
+                    V::Null,
+                    0,
+                    V::Null,
+                    "$",
+                    "$"
+                ),
+                row(
+                    "a".into(),
+                    r#"[1,{"b c":true}]"#.into(),
+                    "array",
+                    V::Null,
+                    1,
+                    V::Number(0),
+                    "$.a",
+                    "$"
+                ),
+                row(
+                    V::Number(0),
+                    V::Number(1),
+                    "integer",
+                    V::Number(1),
+                    4,
+                    V::Number(1),
+                    "$.a[0]",
+                    "$.a"
+                ),
+                row(
+                    V::Number(1),
+                    r#"{"b c":true}"#.into(),
+                    "object",
+                    V::Null,
+                    6,
+                    V::Number(1),
+                    "$.a[1]",
+                    "$.a"
+                ),
+                row(
+                    "b c".into(),
+                    V::Number(1),
+                    "true",
+                    V::Number(1),
+                    7,
+                    V::Number(6),
+                    r#"$.a[1]."b c""#,
+                    "$.a[1]"
+                ),
+            ])
+        );
+
+        assert_eq!(
+            json_each(&args(&[r#"[1,"two",null]"#])),
+            Ok(vec![
+                row(
+                    V::Number(0),
+                    V::Number(1),
+                    "integer",
+                    V::Number(1),
+                    1,
+                    V::Null,
+                    "$[0]",
+                    "$"
+                ),
+                row(
+                    V::Number(1),
+                    "two".into(),
+                    "text",
+                    "two".into(),
+                    3,
+                    V::Null,
+                    "$[1]",
+                    "$"
+                ),
+                row(V::Number(2), V::Null, "null", V::Null, 7, V::Null, "$[2]", "$"),
+            ])
+        );
+    }
+}
@@ -1,5 +1,31 @@
 //! # A very naive SQLite database reader.
 
+// Lets `rsqlite-derive` output paths work inside this crate too
+extern crate self as rsqlite;
+
+pub mod backup;
+pub mod btree;
+pub mod catalog;
+pub mod collation;
+pub mod cursor;
+pub mod datetime;
+pub mod ddl;
+pub mod de;
+pub mod functions;
+pub mod journal;
+pub mod json;
+pub mod lock;
+pub mod plan;
+pub mod planner;
 pub mod pretty;
+pub mod ptrmap;
+pub mod row;
 pub mod schema;
+pub mod shm;
+pub mod sql;
+pub mod statement;
+#[cfg(test)]
+mod testing;
+pub mod vacuum;
 pub mod varint;
+pub mod wal;
@@ -0,0 +1,517 @@
+//! # File locking
+//!
+//! SQLite connections in different processes take turns with the database
+//! file through advisory locks on bytes past 1 GiB, which no page ever uses,
+//! and in WAL mode on bytes of the WAL-index. Taking the same locks in the
+//! same order keeps this crate from reading a file halfway through a commit
+//! of `sqlite3`, and the other way around.
+//!
+//! [Docs](https://www.sqlite.org/lockingv3.html)
+//!
+//! | Lock      | Bytes held                                                 |
+//! |-----------|------------------------------------------------------------|
+//! | SHARED    | Read lock on the 510 shared bytes, to read the file        |
+//! | RESERVED  | Write lock on the reserved byte, to write a journal        |
+//! | PENDING   | Write lock on the pending byte, no new SHARED locks        |
+//! | EXCLUSIVE | Write lock on the shared bytes, to write the file          |
+//!
+//! Taking a SHARED lock briefly takes a read lock on the pending byte too,
+//! which fails while a writer waits for readers to finish.
+//!
+//! A lock that is held by another connection is retried until the
+//! [busy_timeout] runs out, then fails with an [std::io::ErrorKind::WouldBlock]
+//! error, "database is locked".
+//!
+//! ```
+//! use rsqlite::lock::{FileLock, LockLevel};
+//! use std::time::Duration;
+//!
+//! let mut reader = FileLock::open("data/planets.db").unwrap();
+//! let mut writer = FileLock::open("data/planets.db").unwrap();
+//! reader.lock(LockLevel::Shared).unwrap();
+//! writer.lock(LockLevel::Reserved).unwrap();
+//!
+//! // The reader has to finish before the writer can write the file
+//! let err = writer.lock(LockLevel::Exclusive).unwrap_err();
+//! assert_eq!(err.to_string(), "database is locked");
+//! reader.unlock(LockLevel::None).unwrap();
+//! writer.lock(LockLevel::Exclusive).unwrap();
+//! ```
+//!
+//! On Linux the locks belong to the open file, so they also keep threads of
+//! one process apart. Elsewhere they are POSIX locks of the whole process,
+//! which closing any handle to the file releases.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    ops::Range,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// First byte past 1 GiB, locked by a writer waiting for readers to finish
+pub const PENDING_BYTE: u64 = 0x40000000;
+
+/// Locked by the one connection that may write a journal
+pub const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+
+/// Start of the bytes readers lock, up to the end of the lock-byte page
+pub const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+
+pub const SHARED_SIZE: u64 = 510;
+
+/// Offset of the lock slots in the WAL-index
+pub const WAL_LOCK_OFFSET: u64 = 120;
+
+/// WAL-index slot locked by the one connection that may append to the WAL
+pub const WAL_WRITE_LOCK: u64 = 0;
+
+/// WAL-index slot locked by a checkpoint
+pub const WAL_CKPT_LOCK: u64 = 1;
+
+/// WAL-index slot locked while the WAL-index is rebuilt from the WAL
+pub const WAL_RECOVER_LOCK: u64 = 2;
+
+/// Number of reader slots, see [wal_read_lock]
+pub const WAL_READERS: u64 = 5;
+
+/// WAL-index slot every connection holds a read lock on while it has the
+/// WAL-index open, so the first one to open it can tell it is first
+pub const WAL_DMS_LOCK: u64 = 8;
+
+/// Busy timeout in milliseconds, see [set_busy_timeout]
+static BUSY_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// Delays between attempts to take a lock in milliseconds, like SQLite's
+/// busy handler, the last one repeats
+const DELAYS: [u64; 12] = [1, 2, 5, 10, 15, 20, 25, 25, 25, 50, 50, 100];
+
+/// Locks of the database file, from weakest to strongest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    #[default]
+    None,
+    Shared,
+    Reserved,
+    Pending,
+    Exclusive,
+}
+
+/// A handle to a database file and the lock it holds on it, released when
+/// dropped
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    level: LockLevel,
+    timeout: Duration,
+}
+
+/// A handle to the WAL-index of a database for its lock slots, released
+/// when dropped
+#[derive(Debug)]
+pub struct ShmLock {
+    file: File,
+    timeout: Duration,
+}
+
+/// How long to retry a lock held by another connection before failing, 0
+/// by default like SQLite. Used by every lock taken after it is set.
+pub fn set_busy_timeout(timeout: Duration) {
+    BUSY_TIMEOUT.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub fn busy_timeout() -> Duration {
+    Duration::from_millis(BUSY_TIMEOUT.load(Ordering::Relaxed))
+}
+
+/// WAL-index slot of reader `i`, locked while it reads up to the frame of
+/// its read mark
+pub const fn wal_read_lock(i: u64) -> u64 {
+    3 + i
+}
+
+impl FileLock {
+    pub fn new(file: File) -> FileLock {
+        FileLock {
+            file,
+            level: LockLevel::None,
+            timeout: busy_timeout(),
+        }
+    }
+
+    /// Open the file at `path` to lock it, for writing too if allowed
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileLock> {
+        let path = path.as_ref();
+        let file = match OpenOptions::new().read(true).write(true).open(path) {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => File::open(path)?,
+            file => file?,
+        };
+        Ok(FileLock::new(file))
+    }
+
+    /// Use `timeout` instead of the [busy_timeout] for this lock
+    pub fn with_timeout(mut self, timeout: Duration) -> FileLock {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    pub fn level(&self) -> LockLevel {
+        self.level
+    }
+
+    /**
+     * Take `level` or keep a stronger lock, waiting for other connections up
+     * to the timeout. SHARED comes first, and a connection waiting for
+     * EXCLUSIVE keeps PENDING so no new readers get in its way. Writers take
+     * RESERVED before EXCLUSIVE, rolling back a hot journal doesn't.
+     */
+    pub fn lock(&mut self, level: LockLevel) -> io::Result<()> {
+        let timeout = self.timeout;
+        if level > LockLevel::None {
+            wait(timeout, || self.try_lock(LockLevel::Shared))?;
+        }
+        if level > LockLevel::Shared {
+            wait(timeout, || self.try_lock(level))?;
+        }
+        Ok(())
+    }
+
+    /// Go back to SHARED or no lock at all
+    pub fn unlock(&mut self, level: LockLevel) -> io::Result<()> {
+        assert!(level <= LockLevel::Shared, "Can only unlock to SHARED or NONE");
+        if level >= self.level {
+            return Ok(());
+        }
+
+        match level {
+            LockLevel::None => set_lock(&self.file, Kind::Unlock, 0..0)?,
+            _ => {
+                if self.level == LockLevel::Exclusive {
+                    set_lock(&self.file, Kind::Read, shared())?;
+                }
+                set_lock(&self.file, Kind::Unlock, PENDING_BYTE..RESERVED_BYTE + 1)?
+            }
+        };
+        self.level = level;
+        Ok(())
+    }
+
+    /// Does another connection hold RESERVED or a stronger lock, so it may
+    /// be writing a journal?
+    pub fn is_reserved(&self) -> io::Result<bool> {
+        is_locked(&self.file, RESERVED_BYTE..RESERVED_BYTE + 1)
+    }
+
+    /// One attempt to take `level`, `false` if another connection is in the way
+    fn try_lock(&mut self, level: LockLevel) -> io::Result<bool> {
+        if self.level >= level {
+            return Ok(true);
+        }
+
+        match level {
+            LockLevel::None => {}
+            LockLevel::Shared => {
+                if !set_lock(&self.file, Kind::Read, PENDING_BYTE..PENDING_BYTE + 1)? {
+                    return Ok(false);
+                }
+                let locked = set_lock(&self.file, Kind::Read, shared())?;
+                set_lock(&self.file, Kind::Unlock, PENDING_BYTE..PENDING_BYTE + 1)?;
+                if !locked {
+                    return Ok(false);
+                }
+            }
+            LockLevel::Reserved => {
+                if !set_lock(&self.file, Kind::Write, RESERVED_BYTE..RESERVED_BYTE + 1)? {
+                    return Ok(false);
+                }
+            }
+            LockLevel::Pending | LockLevel::Exclusive => {
+                if self.level < LockLevel::Pending {
+                    if !set_lock(&self.file, Kind::Write, PENDING_BYTE..PENDING_BYTE + 1)? {
+                        return Ok(false);
+                    }
+                    self.level = LockLevel::Pending;
+                }
+                if level == LockLevel::Exclusive && !set_lock(&self.file, Kind::Write, shared())? {
+                    return Ok(false);
+                }
+            }
+        }
+        self.level = level;
+        Ok(true)
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Closing the file releases the locks anyway
+        let _ = self.unlock(LockLevel::None);
+    }
+}
+
+impl ShmLock {
+    /// Open the WAL-index of the database at `path` to lock its slots,
+    /// creating it empty if it doesn't exist like SQLite does
+    pub fn open(path: &Path) -> io::Result<ShmLock> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(crate::shm::shm_path(path))?;
+        Ok(ShmLock { file, timeout: busy_timeout() })
+    }
+
+    /// Use `timeout` instead of the [busy_timeout] for this lock
+    pub fn with_timeout(mut self, timeout: Duration) -> ShmLock {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Lock `slots`, for a shared read or exclusively, waiting for other
+    /// connections up to the timeout
+    pub fn lock(&self, slots: Range<u64>, exclusive: bool) -> io::Result<()> {
+        let kind = if exclusive { Kind::Write } else { Kind::Read };
+        let bytes = WAL_LOCK_OFFSET + slots.start..WAL_LOCK_OFFSET + slots.end;
+        wait(self.timeout, || set_lock(&self.file, kind, bytes.clone()))
+    }
+
+    /// Like [ShmLock::lock] without waiting, false if another connection
+    /// holds one of `slots`
+    pub fn try_lock(&self, slots: Range<u64>, exclusive: bool) -> io::Result<bool> {
+        let kind = if exclusive { Kind::Write } else { Kind::Read };
+        set_lock(
+            &self.file,
+            kind,
+            WAL_LOCK_OFFSET + slots.start..WAL_LOCK_OFFSET + slots.end,
+        )
+    }
+
+    pub fn unlock(&self, slots: Range<u64>) -> io::Result<()> {
+        set_lock(
+            &self.file,
+            Kind::Unlock,
+            WAL_LOCK_OFFSET + slots.start..WAL_LOCK_OFFSET + slots.end,
+        )?;
+        Ok(())
+    }
+
+    /// The WAL-index file, to read and write it
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+// * Helper functions * //
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Read,
+    Write,
+    Unlock,
+}
+
+/// The bytes SHARED and EXCLUSIVE lock
+fn shared() -> Range<u64> {
+    SHARED_FIRST..SHARED_FIRST + SHARED_SIZE
+}
+
+/// Error of a lock that is still held by another connection after the timeout
+pub(crate) fn busy() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "database is locked")
+}
+
+/// Error of a commit of a transaction that began before another connection
+/// committed, like `SQLITE_BUSY_SNAPSHOT`, which retrying doesn't help
+pub(crate) fn busy_snapshot() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::WouldBlock,
+        "database changed since the transaction began",
+    )
+}
+
+/// Retry `attempt` until it succeeds or `timeout` runs out
+fn wait(timeout: Duration, mut attempt: impl FnMut() -> io::Result<bool>) -> io::Result<()> {
+    let start = Instant::now();
+    for delay in DELAYS.iter().chain(std::iter::repeat(&DELAYS[DELAYS.len() - 1])) {
+        if attempt()? {
+            return Ok(());
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            break;
+        }
+        thread::sleep(Duration::from_millis(*delay).min(timeout - elapsed));
+    }
+    Err(busy())
+}
+
+#[cfg(target_os = "linux")]
+const SET_LOCK: libc::c_int = libc::F_OFD_SETLK;
+#[cfg(target_os = "linux")]
+const GET_LOCK: libc::c_int = libc::F_OFD_GETLK;
+#[cfg(all(unix, not(target_os = "linux")))]
+const SET_LOCK: libc::c_int = libc::F_SETLK;
+#[cfg(all(unix, not(target_os = "linux")))]
+const GET_LOCK: libc::c_int = libc::F_GETLK;
+
+/// Lock `bytes` of `file` without waiting, `false` if another connection
+/// holds a conflicting lock. An empty range is the whole file.
+fn set_lock(file: &File, kind: Kind, bytes: Range<u64>) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        let mut lock = flock(kind, bytes);
+        match fcntl(file, SET_LOCK, &mut lock) {
+            Ok(()) => Ok(true),
+            Err(err) if matches!(err.raw_os_error(), Some(libc::EAGAIN | libc::EACCES)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (file, kind, bytes);
+        Ok(true)
+    }
+}
+
+/// Does another connection hold a lock on any of `bytes` of `file`?
+fn is_locked(file: &File, bytes: Range<u64>) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        let mut lock = flock(Kind::Write, bytes);
+        fcntl(file, GET_LOCK, &mut lock)?;
+        Ok(lock.l_type != libc::F_UNLCK as libc::c_short)
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = (file, bytes);
+        Ok(false)
+    }
+}
+
+#[cfg(unix)]
+fn flock(kind: Kind, bytes: Range<u64>) -> libc::flock {
+    // Zeroed for the fields some platforms add, and `l_pid` must be 0 for
+    // locks of the open file
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = match kind {
+        Kind::Read => libc::F_RDLCK,
+        Kind::Write => libc::F_WRLCK,
+        Kind::Unlock => libc::F_UNLCK,
+    } as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = bytes.start as libc::off_t;
+    lock.l_len = (bytes.end - bytes.start) as libc::off_t;
+    lock
+}
+
+#[cfg(unix)]
+fn fcntl(file: &File, command: libc::c_int, lock: &mut libc::flock) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    match unsafe { libc::fcntl(file.as_raw_fd(), command, lock as *mut libc::flock) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::temp_copy;
+    use pretty_assertions::assert_eq;
+
+    fn open(path: &Path) -> FileLock {
+        FileLock::open(path).unwrap().with_timeout(Duration::ZERO)
+    }
+
+    #[test]
+    fn levels() {
+        use LockLevel::*;
+
+        // Lock `a` holds first, lock `b` asks for, can `b` have it?
+        let t = [
+            (Shared, Shared, true),
+            (Shared, Reserved, true),
+            (Shared, Exclusive, false),
+            (Reserved, Shared, true),
+            (Reserved, Reserved, false),
+            (Reserved, Exclusive, false),
+            (Pending, Shared, false),
+            (Exclusive, Shared, false),
+        ];
+
+        let path = temp_copy("data/planets.db", "lock-levels");
+        for (a_level, b_level, exp) in t.into_iter() {
+            let mut a = open(&path);
+            let mut b = open(&path);
+            a.lock(a_level).unwrap();
+            let result = b.lock(b_level).map_err(|err| err.kind());
+            assert_eq!(
+                result,
+                if exp { Ok(()) } else { Err(io::ErrorKind::WouldBlock) },
+                "{:?} {:?}",
+                a_level,
+                b_level
+            );
+            assert_eq!(a.level(), a_level);
+            assert_eq!(b.is_reserved().unwrap(), a_level == Reserved);
+        }
+
+        // A writer waiting for a reader holds PENDING, then gets EXCLUSIVE
+        let mut reader = open(&path);
+        let mut writer = open(&path);
+        reader.lock(Shared).unwrap();
+        assert!(writer.lock(Exclusive).is_err());
+        assert_eq!(writer.level(), Pending);
+        assert!(open(&path).lock(Shared).is_err());
+        reader.unlock(None).unwrap();
+        writer.lock(Exclusive).unwrap();
+
+        // Back to SHARED lets readers in, but not writers
+        writer.unlock(Shared).unwrap();
+        open(&path).lock(Shared).unwrap();
+        assert!(open(&path).lock(Exclusive).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn busy_timeout() {
+        let path = temp_copy("data/planets.db", "lock-busy");
+        let mut writer = open(&path);
+        writer.lock(LockLevel::Exclusive).unwrap();
+
+        let waiting = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let mut reader = FileLock::open(&path).unwrap().with_timeout(Duration::from_secs(10));
+                reader.lock(LockLevel::Shared).map(|_| reader.level())
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(writer);
+        assert_eq!(waiting.join().unwrap().unwrap(), LockLevel::Shared);
+
+        // The slots of the WAL-index lock the same way
+        let a = ShmLock::open(&path).unwrap().with_timeout(Duration::ZERO);
+        let b = ShmLock::open(&path).unwrap().with_timeout(Duration::ZERO);
+        a.lock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1, false).unwrap();
+        b.lock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1, false).unwrap();
+        assert!(b.lock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1, true).is_err());
+        a.unlock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1).unwrap();
+        b.lock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1, true).unwrap();
+        std::fs::remove_file(crate::shm::shm_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
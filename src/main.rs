@@ -1,21 +1,45 @@
 use binrw::BinRead;
-use rsqlite::{pretty::HeaderDisplay, schema::*};
-use std::{env, fs::File, io::BufReader, process};
+use rsqlite::{
+    journal,
+    pretty::HeaderDisplay,
+    schema::*,
+    shm,
+    wal::{self, AsOf, Wal},
+};
+use std::{env, fs::File, io::Cursor, path::Path, process};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
-        eprintln!("Usage: {} <file> <command>", args[0]);
+    if args.len() != 3 && args.len() != 4 {
+        eprintln!("Usage: {} <file> <command> [commit frame]", args[0]);
         process::exit(1);
     }
 
     let (file_path, command) = (&args[1], &args[2]);
-    let file = File::open(file_path).unwrap_or_else(|err| {
-        eprintln!("Failed to open file {}: {}", file_path, err);
+    // Read the database as of a commit of its WAL
+    let as_of = args.get(3).map(|frame| match frame.parse() {
+        Ok(frame) => AsOf::Frame(frame),
+        Err(_) => {
+            eprintln!("Invalid frame number: {}", frame);
+            process::exit(1);
+        }
+    });
+    // A crash during a commit leaves a hot journal, without which the file
+    // can have half the changes
+    if let Err(err) = journal::rollback(Path::new(file_path)) {
+        eprintln!("Failed to roll back the journal of {}: {}", file_path, err);
+        process::exit(1);
+    }
+    let bytes = match as_of {
+        Some(as_of) => wal::read_as_of(Path::new(file_path), as_of),
+        None => wal::read_with_wal(Path::new(file_path)),
+    };
+    let bytes = bytes.unwrap_or_else(|err| {
+        eprintln!("Failed to read file {}: {}", file_path, err);
         process::exit(1);
     });
-    let mut reader = BufReader::new(file);
+    let mut reader = Cursor::new(bytes);
 
     match command.as_str() {
         ".dbinfo" => match Header::read_be(&mut reader) {
@@ -32,6 +56,37 @@ fn main() {
                 process::exit(1);
             }
         },
+        ".walinfo" => {
+            let wal = File::open(wal::wal_path(Path::new(file_path)))
+                .map_err(binrw::Error::from)
+                .and_then(|mut file| Wal::read(&mut file));
+            match wal {
+                Ok(Some(wal)) => wal.commits().iter().for_each(|commit| println!("{}", commit)),
+                Ok(None) => println!("No valid WAL"),
+                Err(err) => {
+                    eprintln!("Failed to read WAL: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+        ".shmcheck" => match shm::check(Path::new(file_path)) {
+            Ok(problems) if problems.is_empty() => println!("ok"),
+            Ok(problems) => problems.iter().for_each(|problem| println!("{}", problem)),
+            Err(err) => {
+                eprintln!("Failed to read WAL-index: {}", err);
+                process::exit(1);
+            }
+        },
+        ".ptrmapcheck" => match Database::read_be(&mut reader) {
+            Ok(db) => match db.check_pointer_map() {
+                problems if problems.is_empty() => println!("ok"),
+                problems => problems.iter().for_each(|problem| println!("{}", problem)),
+            },
+            Err(err) => {
+                eprintln!("Failed to read database: {}", err);
+                process::exit(1);
+            }
+        },
         _ => {
             eprintln!("Unknown command: {}", command);
             process::exit(1);
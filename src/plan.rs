@@ -0,0 +1,269 @@
+//! # Query plans
+//!
+//! A [Plan] describes how a query reads its tables, and displays itself in the
+//! same format as `EXPLAIN QUERY PLAN` in the `sqlite3` shell. A
+//! [Statement](crate::statement::Statement) starting with `EXPLAIN QUERY PLAN`
+//! returns it as [rows](Plan::rows).
+//!
+//! [Docs](https://www.sqlite.org/eqp.html)
+//!
+//! ```text
+//! QUERY PLAN
+//! |--SCAN t
+//! `--SEARCH u USING INDEX uy (y=?)
+//! ```
+
+use std::fmt;
+
+/// A query plan is a tree of steps, executed top to bottom.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan(pub Vec<Node>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub step: Step,
+    pub children: Vec<Node>,
+}
+
+/**
+ * A single line of the plan.
+ *
+ * A `SCAN` visits every row of the table or index, while a `SEARCH` only
+ * visits the subset of rows matching the [Constraint]s.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Scan {
+        table: String,
+        index: Option<Index>,
+    },
+    Search {
+        table: String,
+        index: Index,
+        constraints: Vec<Constraint>,
+    },
+    /// Rows are sorted or de-duplicated in a temporary b-tree
+    TempBTree(TempBTree),
+}
+
+/// How rows are looked up
+#[derive(Debug, Clone, PartialEq)]
+pub enum Index {
+    /// The table b-tree itself, keyed by rowid
+    Rowid,
+    /// An index b-tree. A covering index has every column the query needs, so
+    /// the table is never read.
+    Named { name: String, covering: bool },
+}
+
+/// A constraint on an indexed column, `column op ?`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constraint {
+    pub column: String,
+    pub op: Op,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempBTree {
+    OrderBy,
+    GroupBy,
+    Distinct,
+}
+
+impl Plan {
+    /**
+     * The plan as the rows `EXPLAIN QUERY PLAN` returns: an id, the id of the
+     * parent or 0 at the top, and the step.
+     *
+     * Ids count the steps from 1 in the order they are printed, where SQLite
+     * uses the addresses of its bytecode instructions, so only how they relate
+     * matches SQLite.
+     */
+    pub fn rows(&self) -> Vec<(i64, i64, String)> {
+        fn walk(nodes: &[Node], parent: i64, rows: &mut Vec<(i64, i64, String)>) {
+            for node in nodes {
+                let id = rows.len() as i64 + 1;
+                rows.push((id, parent, node.step.to_string()));
+                walk(&node.children, id, rows);
+            }
+        }
+
+        let mut rows = vec![];
+        walk(&self.0, 0, &mut rows);
+        rows
+    }
+}
+
+impl Node {
+    pub fn new(step: Step) -> Self {
+        Node { step, children: vec![] }
+    }
+
+    /// Print this node and its children, `prefix` is the indentation so far.
+    fn render(&self, f: &mut fmt::Formatter<'_>, prefix: &str, last: bool) -> fmt::Result {
+        writeln!(f, "{}{}{}", prefix, if last { "`--" } else { "|--" }, self.step)?;
+        let prefix = format!("{}{}", prefix, if last { "   " } else { "|  " });
+        for (i, child) in self.children.iter().enumerate() {
+            child.render(f, &prefix, i + 1 == self.children.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "QUERY PLAN")?;
+        for (i, node) in self.0.iter().enumerate() {
+            node.render(f, "", i + 1 == self.0.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Scan {
+                table,
+                index: None | Some(Index::Rowid),
+            } => write!(f, "SCAN {}", table),
+            Step::Scan { table, index: Some(index) } => write!(f, "SCAN {} {}", table, index),
+            Step::Search { table, index, constraints } => {
+                let constraints: Vec<String> = constraints.iter().map(|c| c.to_string()).collect();
+                write!(f, "SEARCH {} {} ({})", table, index, constraints.join(" AND "))
+            }
+            Step::TempBTree(purpose) => write!(f, "USE TEMP B-TREE FOR {}", purpose),
+        }
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Index::Rowid => write!(f, "USING INTEGER PRIMARY KEY"),
+            Index::Named { name, covering: true } => write!(f, "USING COVERING INDEX {}", name),
+            Index::Named { name, covering: false } => write!(f, "USING INDEX {}", name),
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            Op::Eq => "=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        };
+        write!(f, "{}{}?", self.column, op)
+    }
+}
+
+impl fmt::Display for TempBTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TempBTree::OrderBy => write!(f, "ORDER BY"),
+            TempBTree::GroupBy => write!(f, "GROUP BY"),
+            TempBTree::Distinct => write!(f, "DISTINCT"),
+        }
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn index(name: &str, covering: bool) -> Index {
+        Index::Named { name: name.into(), covering }
+    }
+
+    fn constraint(column: &str, op: Op) -> Constraint {
+        Constraint { column: column.into(), op }
+    }
+
+    // Expected output from `sqlite3 eqp.db "explain query plan ..."`
+    #[test]
+    fn display() {
+        let plan = Plan(vec![
+            Node::new(Step::Scan { table: "t".into(), index: None }),
+            Node::new(Step::Search {
+                table: "u".into(),
+                index: index("uy", true),
+                constraints: vec![constraint("y", Op::Eq)],
+            }),
+            Node::new(Step::TempBTree(TempBTree::OrderBy)),
+        ]);
+        let expected = "QUERY PLAN
+|--SCAN t
+|--SEARCH u USING COVERING INDEX uy (y=?)
+`--USE TEMP B-TREE FOR ORDER BY
+";
+        assert_eq!(plan.to_string(), expected);
+
+        let plan = Plan(vec![Node::new(Step::Search {
+            table: "t".into(),
+            index: index("tab", false),
+            constraints: vec![
+                constraint("a", Op::Eq),
+                constraint("b", Op::Gt),
+                constraint("b", Op::Lt),
+            ],
+        })]);
+        assert_eq!(
+            plan.to_string(),
+            "QUERY PLAN\n`--SEARCH t USING INDEX tab (a=? AND b>? AND b<?)\n"
+        );
+
+        let plan = Plan(vec![Node::new(Step::Search {
+            table: "u".into(),
+            index: Index::Rowid,
+            constraints: vec![constraint("rowid", Op::Eq)],
+        })]);
+        assert_eq!(
+            plan.to_string(),
+            "QUERY PLAN\n`--SEARCH u USING INTEGER PRIMARY KEY (rowid=?)\n"
+        );
+    }
+
+    #[test]
+    fn nested() {
+        let mut outer = Node::new(Step::Scan {
+            table: "v".into(),
+            index: Some(index("vi", false)),
+        });
+        outer.children = vec![
+            Node::new(Step::Scan { table: "a".into(), index: None }),
+            Node::new(Step::TempBTree(TempBTree::Distinct)),
+        ];
+        let plan = Plan(vec![outer, Node::new(Step::TempBTree(TempBTree::GroupBy))]);
+
+        let expected = "QUERY PLAN
+|--SCAN v USING INDEX vi
+|  |--SCAN a
+|  `--USE TEMP B-TREE FOR DISTINCT
+`--USE TEMP B-TREE FOR GROUP BY
+";
+        assert_eq!(plan.to_string(), expected);
+
+        // Parents point to the id of the step above
+        let rows = plan.rows();
+        assert_eq!(
+            rows.iter().map(|(id, parent, _)| (*id, *parent)).collect::<Vec<_>>(),
+            vec![(1, 0), (2, 1), (3, 1), (4, 0)]
+        );
+        assert_eq!(rows[2].2, "USE TEMP B-TREE FOR DISTINCT");
+    }
+}
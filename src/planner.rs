@@ -0,0 +1,888 @@
+//! # Query planner
+//!
+//! Chooses how to read every table of a [Query], and in which order to join
+//! them, by estimating the cost of each option.
+//!
+//! For every table the options are a full scan, a `rowid` lookup, or a search
+//! or scan of one of its indexes. Row estimates come from `sqlite_stat1` and
+//! `sqlite_stat4` via the [Catalog] when the database was analyzed, and fall
+//! back to the same defaults SQLite uses otherwise.
+//!
+//! The cost model is a much simplified version of SQLite's `where.c`
+//! [docs](https://www.sqlite.org/queryplanner-ng.html)
+
+use crate::{
+    catalog::{Catalog, Index, Table},
+    collation::Collations,
+    plan::{self, Constraint, Node, Op, Plan, Step, TempBTree},
+    schema::SerialValue,
+};
+use std::{cmp::Ordering, fmt};
+
+/// Rows in a table that was never analyzed
+const DEFAULT_ROWS: f64 = 1_048_576.0;
+
+/// Rows matching an equality on the first N columns of an index that was
+/// never analyzed, see `sqlite3DefaultRowEst()`
+const DEFAULT_EQ: [f64; 5] = [10.0, 9.0, 8.0, 7.0, 6.0];
+
+/// Cost of visiting a row in a full scan, including testing the WHERE clause
+const SCAN_ROW: f64 = 3.0;
+
+/// Cost of looking up a table row from an index entry
+const LOOKUP: f64 = 3.0;
+
+/// A term not used by the index is assumed to keep 1 row in 4
+const SELECTIVITY: f64 = 0.25;
+
+/// Try every join order up to this many tables, use the FROM order beyond.
+const MAX_PERMUTE: usize = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnRef {
+    pub table: String,
+    pub column: String,
+}
+
+/// A term of the WHERE clause, all terms are ANDed together
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    /// `table.column op value`, the value is `None` for bound parameters.
+    /// Only an index sorted with the same collation can find the rows, which
+    /// is the collation of the column when `None`.
+    Compare {
+        column: ColumnRef,
+        op: Op,
+        value: Option<SerialValue>,
+        collation: Option<String>,
+    },
+    /// `a.x = b.y`
+    Join(ColumnRef, ColumnRef),
+}
+
+/**
+ * The parts of a SELECT statement that matter for planning.
+ *
+ * `SELECT DISTINCT columns FROM tables WHERE terms GROUP BY .. ORDER BY ..`
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    pub tables: Vec<String>,
+    /// Columns read by the query, `None` for every column like `SELECT *`
+    pub columns: Option<Vec<ColumnRef>>,
+    pub terms: Vec<Term>,
+    pub group_by: Vec<ColumnRef>,
+    pub order_by: Vec<ColumnRef>,
+    pub distinct: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A query must read at least one table
+    NoTables,
+    NoSuchTable(String),
+    NoSuchColumn(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoTables => write!(f, "no tables specified"),
+            Error::NoSuchTable(name) => write!(f, "no such table: {}", name),
+            Error::NoSuchColumn(name) => write!(f, "no such column: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A constraint usable for a table at some loop level
+#[derive(Debug, Clone)]
+struct Usable {
+    column: String,
+    op: Op,
+    value: Option<SerialValue>,
+    collation: String,
+}
+
+/// One way to read a table
+#[derive(Debug, Clone)]
+struct Access {
+    step: Step,
+    /// Estimated rows for each lookup, before other terms filter them
+    rows: f64,
+    /// Estimated cost for each lookup
+    cost: f64,
+    /// Number of usable terms this access handles
+    used: usize,
+    /// Columns the rows come out sorted by, or `None` when there is at most
+    /// one row which is always sorted.
+    sorted_by: Option<Vec<String>>,
+}
+
+/// A join order with a way to read each table
+#[derive(Debug, Clone)]
+struct Path {
+    loops: Vec<Access>,
+    cost: f64,
+    rows: f64,
+}
+
+pub struct Planner<'a> {
+    catalog: &'a Catalog,
+    collations: &'a Collations,
+}
+
+impl<'a> Planner<'a> {
+    /// A planner comparing the `sqlite_stat4` samples of indexes in their
+    /// collations from `collations`
+    pub fn new(catalog: &'a Catalog, collations: &'a Collations) -> Self {
+        Planner { catalog, collations }
+    }
+
+    /// Choose the cheapest plan for the query
+    pub fn plan(&self, query: &Query) -> Result<Plan, Error> {
+        let tables = self.resolve(query)?;
+
+        let orders = if tables.len() <= MAX_PERMUTE {
+            permutations(tables.len())
+        } else {
+            vec![(0..tables.len()).collect()]
+        };
+
+        let mut best: Option<Path> = None;
+        for order in orders {
+            for path in self.paths(query, &tables, &order) {
+                if best.as_ref().is_none_or(|b| path.cost < b.cost) {
+                    best = Some(path);
+                }
+            }
+        }
+
+        let path = best.expect("at least one join order");
+        let mut nodes: Vec<Node> = path.loops.iter().map(|access| Node::new(access.step.clone())).collect();
+
+        let outer = path.loops.first();
+        if !query.group_by.is_empty() && !sorted(outer, &query.group_by) {
+            nodes.push(Node::new(Step::TempBTree(TempBTree::GroupBy)));
+        }
+        let distinct = query.columns.as_deref().is_some_and(|columns| sorted(outer, columns));
+        if query.distinct && !distinct {
+            nodes.push(Node::new(Step::TempBTree(TempBTree::Distinct)));
+        }
+        if !query.order_by.is_empty() && !sorted(outer, &query.order_by) {
+            nodes.push(Node::new(Step::TempBTree(TempBTree::OrderBy)));
+        }
+
+        Ok(Plan(nodes))
+    }
+
+    /// Find every table and check every column exists
+    fn resolve(&self, query: &Query) -> Result<Vec<&'a Table>, Error> {
+        if query.tables.is_empty() {
+            return Err(Error::NoTables);
+        }
+        let tables = query
+            .tables
+            .iter()
+            .map(|name| self.catalog.table(name).ok_or_else(|| Error::NoSuchTable(name.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut refs: Vec<&ColumnRef> = query.columns.iter().flatten().collect();
+        refs.extend(query.group_by.iter().chain(&query.order_by));
+        for term in &query.terms {
+            match term {
+                Term::Compare { column, .. } => refs.push(column),
+                Term::Join(a, b) => refs.extend([a, b]),
+            }
+        }
+
+        for r in refs {
+            let table = query
+                .tables
+                .iter()
+                .position(|t| t.eq_ignore_ascii_case(&r.table))
+                .ok_or_else(|| Error::NoSuchTable(r.table.clone()))?;
+            if tables[table].column(&r.column).is_none() && !tables[table].is_rowid(&r.column) {
+                return Err(Error::NoSuchColumn(format!("{}.{}", r.table, r.column)));
+            }
+        }
+
+        Ok(tables)
+    }
+
+    /// Candidate paths for a join order. Every access for the outer loop is
+    /// tried since one that avoids sorting may win, inner loops take the
+    /// cheapest access.
+    fn paths(&self, query: &Query, tables: &[&Table], order: &[usize]) -> Vec<Path> {
+        let name = |i: usize| query.tables[i].as_str();
+        let usable = |i: usize, outer: &[usize]| -> Vec<Usable> {
+            let mut usable = vec![];
+            for term in &query.terms {
+                match term {
+                    Term::Compare { column, op, value, collation } if column.table.eq_ignore_ascii_case(name(i)) => {
+                        usable.push(Usable {
+                            column: column.column.clone(),
+                            op: *op,
+                            value: value.clone(),
+                            collation: collation
+                                .clone()
+                                .unwrap_or_else(|| column_collation(tables[i], &column.column)),
+                        })
+                    }
+                    Term::Join(a, b) => {
+                        for (this, other) in [(a, b), (b, a)] {
+                            let joined = outer.iter().any(|&o| other.table.eq_ignore_ascii_case(name(o)));
+                            if this.table.eq_ignore_ascii_case(name(i)) && joined {
+                                usable.push(Usable {
+                                    column: this.column.clone(),
+                                    op: Op::Eq,
+                                    value: None,
+                                    collation: column_collation(tables[i], &this.column),
+                                });
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            usable
+        };
+
+        let first = order[0];
+        let mut paths = vec![];
+        for access in self.access_paths(
+            tables[first],
+            name(first),
+            &usable(first, &[]),
+            self.needed(query, first),
+        ) {
+            let terms = usable(first, &[]).len();
+            let rows = access.rows * SELECTIVITY.powi((terms - access.used) as i32);
+            let mut path = Path {
+                cost: access.cost,
+                rows: rows.max(1.0),
+                loops: vec![access],
+            };
+
+            for (level, &i) in order.iter().enumerate().skip(1) {
+                let terms = usable(i, &order[..level]);
+                let inner = self
+                    .access_paths(tables[i], name(i), &terms, self.needed(query, i))
+                    .into_iter()
+                    .min_by(|a, b| a.cost.total_cmp(&b.cost))
+                    .expect("a full scan is always possible");
+                path.cost += path.rows * inner.cost;
+                path.rows = (path.rows * inner.rows * SELECTIVITY.powi((terms.len() - inner.used) as i32)).max(1.0);
+                path.loops.push(inner);
+            }
+
+            if !query.order_by.is_empty() && !sorted(path.loops.first(), &query.order_by) {
+                path.cost += path.rows * path.rows.log2().max(1.0);
+            }
+            paths.push(path);
+        }
+        paths
+    }
+
+    /// Columns of table `i` the query reads, `None` for all of them
+    fn needed(&self, query: &Query, i: usize) -> Option<Vec<String>> {
+        let name = &query.tables[i];
+        let mut columns: Vec<&ColumnRef> = query.columns.as_ref()?.iter().collect();
+        columns.extend(query.group_by.iter().chain(&query.order_by));
+        for term in &query.terms {
+            match term {
+                Term::Compare { column, .. } => columns.push(column),
+                Term::Join(a, b) => columns.extend([a, b]),
+            }
+        }
+        Some(
+            columns
+                .into_iter()
+                .filter(|c| c.table.eq_ignore_ascii_case(name))
+                .map(|c| c.column.clone())
+                .collect(),
+        )
+    }
+
+    /// Every way to read a table with the usable terms
+    fn access_paths(&self, table: &Table, name: &str, terms: &[Usable], needed: Option<Vec<String>>) -> Vec<Access> {
+        let n = self
+            .catalog
+            .stats
+            .table(&table.name)
+            .map_or(DEFAULT_ROWS, |s| s.rows as f64)
+            .max(1.0);
+        let seek = n.log2().max(1.0);
+        let rowid: Vec<String> = table
+            .rowid_alias()
+            .map(|i| table.columns[i].name.clone())
+            .into_iter()
+            .collect();
+
+        // Full table scan, in rowid order
+        let mut paths = vec![Access {
+            step: Step::Scan {
+                table: name.to_string(),
+                index: None,
+            },
+            rows: n,
+            cost: n * SCAN_ROW,
+            used: 0,
+            sorted_by: Some(rowid.clone()),
+        }];
+
+        // Rowid lookups and ranges
+        let on_rowid: Vec<&Usable> = terms.iter().filter(|t| table.is_rowid(&t.column)).collect();
+        if let Some(eq) = on_rowid.iter().find(|t| t.op == Op::Eq) {
+            paths.push(Access {
+                step: search(name, plan::Index::Rowid, vec![constraint("rowid", eq.op)]),
+                rows: 1.0,
+                cost: seek,
+                used: 1,
+                sorted_by: None,
+            });
+        } else if !on_rowid.is_empty() {
+            let bounds = range_bounds(&on_rowid);
+            let rows = n * SELECTIVITY.powi(bounds.len() as i32);
+            paths.push(Access {
+                step: search(
+                    name,
+                    plan::Index::Rowid,
+                    bounds.iter().map(|t| constraint("rowid", t.op)).collect(),
+                ),
+                rows,
+                cost: seek + rows,
+                used: bounds.len(),
+                sorted_by: Some(rowid),
+            });
+        }
+
+        // A partial index would miss rows unless its WHERE clause is implied
+        for index in self
+            .catalog
+            .indexes_on(&table.name)
+            .filter(|i| !i.columns.is_empty() && !i.partial)
+        {
+            paths.extend(self.index_access(table, index, name, terms, needed.as_deref(), n));
+        }
+
+        paths
+    }
+
+    /// Search or scan an index
+    fn index_access(
+        &self,
+        table: &Table,
+        index: &Index,
+        name: &str,
+        terms: &[Usable],
+        needed: Option<&[String]>,
+        n: f64,
+    ) -> Option<Access> {
+        let stat = self.catalog.stats.index(&table.name, &index.name);
+        let covering = needed.is_some_and(|needed| {
+            needed
+                .iter()
+                .all(|c| table.is_rowid(c) || index.columns.iter().any(|i| i.eq_ignore_ascii_case(c)))
+        });
+        let lookup = if covering { 0.0 } else { LOOKUP };
+        let ordered = !stat.is_some_and(|s| s.unordered);
+        let plan_index = plan::Index::Named {
+            name: index.name.clone(),
+            covering,
+        };
+
+        // Terms on index column `k` compared with the collation it is sorted by
+        let on = |k: usize| {
+            terms.iter().filter(move |t| {
+                t.column.eq_ignore_ascii_case(&index.columns[k])
+                    && t.collation.eq_ignore_ascii_case(&index.collations[k])
+            })
+        };
+
+        // Equality on a prefix of the index columns ...
+        let mut eq: Vec<&Usable> = vec![];
+        for k in 0..index.columns.len() {
+            match on(k).find(|t| t.op == Op::Eq) {
+                Some(term) => eq.push(term),
+                None => break,
+            }
+        }
+
+        // ... followed by an optional range on the next column
+        let range = match eq.len() < index.columns.len() {
+            true => range_bounds(&on(eq.len()).collect::<Vec<_>>()),
+            false => vec![],
+        };
+
+        // Rows come out sorted by the columns after the equalities, as long as
+        // they are ascending and sorted like the table column
+        let sorted_by = ordered.then(|| {
+            (eq.len()..index.columns.len())
+                .take_while(|&k| {
+                    let column = &index.columns[k];
+                    !index.desc[k] && index.collations[k].eq_ignore_ascii_case(&column_collation(table, column))
+                })
+                .map(|k| index.columns[k].clone())
+                .collect()
+        });
+
+        if eq.is_empty() && range.is_empty() {
+            // Only worth scanning the whole index when it's narrower than the
+            // table or returns rows sorted
+            let width = (index.columns.len() + 1) as f64 / (table.columns.len() + 1) as f64;
+            return Some(Access {
+                step: Step::Scan {
+                    table: name.to_string(),
+                    index: Some(plan_index),
+                },
+                rows: n,
+                cost: n * SCAN_ROW * width.min(1.0) + n * lookup,
+                used: 0,
+                sorted_by,
+            });
+        }
+
+        let mut rows = if eq.is_empty() {
+            n
+        } else {
+            self.eq_rows(table, index, &eq)
+        };
+        if !range.is_empty() {
+            rows = match (eq.is_empty(), self.range_rows(index, &range, n)) {
+                (true, Some(estimate)) => estimate,
+                _ => rows * SELECTIVITY.powi(range.len() as i32),
+            };
+        }
+        let rows = rows.max(1.0);
+
+        let mut constraints: Vec<Constraint> = eq.iter().map(|t| constraint(&t.column, Op::Eq)).collect();
+        constraints.extend(range.iter().map(|t| constraint(&t.column, t.op)));
+
+        Some(Access {
+            step: search(name, plan_index, constraints),
+            rows,
+            cost: n.log2().max(1.0) + rows * (1.0 + lookup),
+            used: eq.len() + range.len(),
+            sorted_by: if index.unique && eq.len() == index.columns.len() {
+                None
+            } else {
+                sorted_by
+            },
+        })
+    }
+
+    /// Rows matching equality on a prefix of the index columns
+    fn eq_rows(&self, table: &Table, index: &Index, eq: &[&Usable]) -> f64 {
+        let k = eq.len();
+
+        // A stat4 sample with the exact same key prefix
+        let values: Option<Vec<&SerialValue>> = eq.iter().map(|t| t.value.as_ref()).collect();
+        if let Some(values) = values {
+            let samples = self.catalog.stats.samples(&index.name);
+            let values: Vec<SerialValue> = values.into_iter().cloned().collect();
+            let found = samples.iter().find(|s| {
+                s.key.len() >= k
+                    && self.collations.compare_keys(&index.collations, &s.key[..k], &values) == Ok(Ordering::Equal)
+            });
+            if let Some(neq) = found.and_then(|s| s.neq.get(k - 1)) {
+                return *neq as f64;
+            }
+        }
+
+        match self
+            .catalog
+            .stats
+            .index(&table.name, &index.name)
+            .and_then(|s| s.avg_eq.get(k - 1))
+        {
+            Some(avg) => *avg as f64,
+            None if index.unique && k == index.columns.len() => 1.0,
+            None => DEFAULT_EQ[(k - 1).min(DEFAULT_EQ.len() - 1)],
+        }
+    }
+
+    /// Rows in a range of the first index column from stat4 samples, when
+    /// every bound has a known value and the column is in ascending order.
+    fn range_rows(&self, index: &Index, bounds: &[&Usable], n: f64) -> Option<f64> {
+        let samples = self.catalog.stats.samples(&index.name);
+        if samples.is_empty() || index.desc.first() != Some(&false) {
+            return None;
+        }
+        let collation = &index.collations[0];
+
+        // Rows less than the value, and rows equal to it if it was sampled
+        let below = |value: &SerialValue| -> Option<(f64, f64)> {
+            for sample in samples {
+                let first = sample.key.first()?;
+                let ord = self.collations.compare(collation, first, value).ok()?;
+                if ord != Ordering::Less {
+                    let nlt = *sample.nlt.first()? as f64;
+                    let neq = if ord == Ordering::Equal {
+                        *sample.neq.first()? as f64
+                    } else {
+                        0.0
+                    };
+                    return Some((nlt, neq));
+                }
+            }
+            Some((n, 0.0))
+        };
+        let (mut low, mut high) = (0.0, n);
+        for bound in bounds {
+            let (lt, eq) = below(bound.value.as_ref()?)?;
+            match bound.op {
+                Op::Gt => low = f64::max(low, lt + eq),
+                Op::Ge => low = f64::max(low, lt),
+                Op::Lt => high = f64::min(high, lt),
+                Op::Le => high = f64::min(high, lt + eq),
+                Op::Eq => {}
+            }
+        }
+        Some((high - low).max(1.0))
+    }
+}
+
+// * Helper functions * //
+
+fn search(table: &str, index: plan::Index, constraints: Vec<Constraint>) -> Step {
+    Step::Search {
+        table: table.to_string(),
+        index,
+        constraints,
+    }
+}
+
+fn constraint(column: &str, op: Op) -> Constraint {
+    Constraint { column: column.to_string(), op }
+}
+
+/// Collation a column of a table is compared with, unless another is given
+fn column_collation(table: &Table, column: &str) -> String {
+    table
+        .column(column)
+        .and_then(|i| table.columns[i].collation.clone())
+        .unwrap_or("BINARY".to_string())
+}
+
+/// At most one lower and one upper bound from range terms
+fn range_bounds<'a>(terms: &[&'a Usable]) -> Vec<&'a Usable> {
+    let lower = terms.iter().find(|t| matches!(t.op, Op::Gt | Op::Ge));
+    let upper = terms.iter().find(|t| matches!(t.op, Op::Lt | Op::Le));
+    lower.into_iter().chain(upper).copied().collect()
+}
+
+/// Does the outer loop produce rows sorted by these columns?
+fn sorted(outer: Option<&Access>, by: &[ColumnRef]) -> bool {
+    let Some(access) = outer else {
+        return false;
+    };
+    let table = match &access.step {
+        Step::Scan { table, .. } | Step::Search { table, .. } => table,
+        Step::TempBTree(_) => return false,
+    };
+    if !by.iter().all(|c| c.table.eq_ignore_ascii_case(table)) {
+        return false;
+    }
+
+    match &access.sorted_by {
+        None => true,
+        Some(columns) => {
+            by.len() <= columns.len() && by.iter().zip(columns).all(|(c, s)| c.column.eq_ignore_ascii_case(s))
+        }
+    }
+}
+
+/// Every ordering of `0..n`
+fn permutations(n: usize) -> Vec<Vec<usize>> {
+    if n == 0 {
+        return vec![vec![]];
+    }
+    let mut all = vec![];
+    for rest in permutations(n - 1) {
+        for i in 0..=rest.len() {
+            let mut order = rest.clone();
+            order.insert(i, n - 1);
+            all.push(order);
+        }
+    }
+    all.sort();
+    all
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{catalog::Sample, schema::Database};
+    use binrw::BinRead;
+    use pretty_assertions::assert_eq;
+    use std::fs::File;
+
+    fn catalog(path: &str) -> Catalog {
+        let mut file = File::open(path).expect("Failed to open database");
+        Catalog::read(&Database::read(&mut file).expect("Failed to read database"))
+    }
+
+    fn col(table: &str, column: &str) -> ColumnRef {
+        ColumnRef {
+            table: table.into(),
+            column: column.into(),
+        }
+    }
+
+    fn eq(table: &str, column: &str, value: Option<SerialValue>) -> Term {
+        Term::Compare {
+            column: col(table, column),
+            op: Op::Eq,
+            value,
+            collation: None,
+        }
+    }
+
+    fn explain(catalog: &Catalog, query: Query) -> String {
+        let plan = Planner::new(catalog, &Collations::default())
+            .plan(&query)
+            .expect("Failed to plan");
+        plan.to_string().trim_start_matches("QUERY PLAN\n").to_string()
+    }
+
+    // Expected plans from `sqlite3 data/analyze.db "explain query plan ..."`
+    #[test]
+    fn analyzed() {
+        let catalog = catalog("data/analyze.db");
+        let planets = || Query {
+            tables: vec!["planets".into()],
+            ..Default::default()
+        };
+
+        // select * from planets where type='gas'
+        let query = Query {
+            terms: vec![eq("planets", "type", Some("gas".into()))],
+            ..planets()
+        };
+        assert_eq!(
+            explain(&catalog, query),
+            "`--SEARCH planets USING INDEX by_type (type=?)\n"
+        );
+
+        // select * from planets where name='p5' and type='gas'
+        let terms = vec![eq("planets", "type", None), eq("planets", "name", None)];
+        assert_eq!(
+            explain(&catalog, Query { terms, ..planets() }),
+            "`--SEARCH planets USING INDEX by_name (name=?)\n"
+        );
+
+        // select * from planets where moons=3 and type='gas'
+        let terms = vec![eq("planets", "type", None), eq("planets", "moons", None)];
+        assert_eq!(
+            explain(&catalog, Query { terms, ..planets() }),
+            "`--SEARCH planets USING INDEX by_moons (moons=? AND type=?)\n"
+        );
+
+        // select * from planets order by name
+        let query = Query {
+            order_by: vec![col("planets", "name")],
+            ..planets()
+        };
+        assert_eq!(explain(&catalog, query), "`--SCAN planets USING INDEX by_name\n");
+
+        // select * from planets, moons where moons.planet_id = planets.id and planets.type='gas'
+        let query = Query {
+            tables: vec!["planets".into(), "moons".into()],
+            terms: vec![
+                Term::Join(col("moons", "planet_id"), col("planets", "id")),
+                eq("planets", "type", None),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            explain(&catalog, query),
+            "|--SCAN moons\n`--SEARCH planets USING INTEGER PRIMARY KEY (rowid=?)\n"
+        );
+    }
+
+    // Expected plans from sqlite3 on the same schema without ANALYZE
+    #[test]
+    fn heuristics() {
+        let mut catalog = catalog("data/analyze.db");
+        catalog.stats = Default::default();
+
+        // select name from planets where moons > 3
+        let query = Query {
+            tables: vec!["planets".into()],
+            columns: Some(vec![col("planets", "name")]),
+            terms: vec![Term::Compare {
+                column: col("planets", "moons"),
+                op: Op::Gt,
+                value: None,
+                collation: None,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            explain(&catalog, query),
+            "`--SEARCH planets USING INDEX by_moons (moons>?)\n"
+        );
+
+        // select type from planets where id > 10 and id < 20 order by moons
+        let query = Query {
+            tables: vec!["planets".into()],
+            columns: Some(vec![col("planets", "type")]),
+            terms: vec![
+                Term::Compare {
+                    column: col("planets", "id"),
+                    op: Op::Gt,
+                    value: None,
+                    collation: None,
+                },
+                Term::Compare {
+                    column: col("planets", "id"),
+                    op: Op::Lt,
+                    value: None,
+                    collation: None,
+                },
+            ],
+            order_by: vec![col("planets", "moons")],
+            ..Default::default()
+        };
+        assert_eq!(
+            explain(&catalog, query),
+            "|--SEARCH planets USING INTEGER PRIMARY KEY (rowid>? AND rowid<?)\n`--USE TEMP B-TREE FOR ORDER BY\n"
+        );
+
+        // select * from planets where type = 'gas' collate nocase
+        let query = Query {
+            tables: vec!["planets".into()],
+            terms: vec![Term::Compare {
+                column: col("planets", "type"),
+                op: Op::Eq,
+                value: None,
+                collation: Some("nocase".into()),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(explain(&catalog, query), "`--SCAN planets\n");
+
+        // select distinct type from planets
+        let query = Query {
+            tables: vec!["planets".into()],
+            columns: Some(vec![col("planets", "type")]),
+            distinct: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            explain(&catalog, query),
+            "`--SCAN planets USING COVERING INDEX by_type\n"
+        );
+    }
+
+    #[test]
+    fn stat4_samples() {
+        let collations = Collations::default();
+        let mut catalog = catalog("data/analyze.db");
+        let sample = |moons: i64, neq: u64, nlt: u64| Sample {
+            neq: vec![neq, 1],
+            nlt: vec![nlt, nlt],
+            ndlt: vec![moons as u64, moons as u64],
+            key: vec![moons.into(), "rock".into(), 1.into()],
+        };
+        // Pretend almost every planet has exactly 3 moons
+        catalog.stats.stat4.insert(
+            "by_moons".into(),
+            vec![sample(0, 5, 0), sample(3, 1990, 5), sample(6, 5, 1995)],
+        );
+
+        let planner = Planner::new(&catalog, &collations);
+        let index = catalog.index("by_moons").unwrap();
+        let table = catalog.table("planets").unwrap();
+        let usable = |op, value: i64| Usable {
+            column: "moons".into(),
+            op,
+            value: Some(value.into()),
+            collation: "BINARY".into(),
+        };
+
+        assert_eq!(planner.eq_rows(table, index, &[&usable(Op::Eq, 3)]), 1990.0);
+        // Not a sample, so the stat1 average
+        assert_eq!(planner.eq_rows(table, index, &[&usable(Op::Eq, 4)]), 286.0);
+        assert_eq!(planner.range_rows(index, &[&usable(Op::Gt, 3)], 2000.0), Some(5.0));
+        assert_eq!(
+            planner.range_rows(index, &[&usable(Op::Ge, 3), &usable(Op::Lt, 6)], 2000.0),
+            Some(1990.0)
+        );
+
+        // A frequent value makes the index worse than a full scan
+        let query = |moons| Query {
+            tables: vec!["planets".into()],
+            terms: vec![eq("planets", "moons", Some(SerialValue::Number(moons)))],
+            ..Default::default()
+        };
+        assert_eq!(explain(&catalog, query(3)), "`--SCAN planets\n");
+        assert_eq!(
+            explain(&catalog, query(6)),
+            "`--SEARCH planets USING INDEX by_moons (moons=?)\n"
+        );
+    }
+
+    #[test]
+    fn custom_collation() {
+        let mut catalog = catalog("data/analyze.db");
+        let index = catalog.indexes.iter_mut().find(|i| i.name == "by_moons").unwrap();
+        index.collations[0] = "parity".into();
+        let sample = Sample {
+            neq: vec![1990, 1],
+            nlt: vec![5, 5],
+            ndlt: vec![1, 1],
+            key: vec!["3".into(), "rock".into(), 1.into()],
+        };
+        catalog.stats.stat4.insert("by_moons".into(), vec![sample]);
+
+        // The sample of 3 moons counts for 5 moons too, in the index's own collation
+        let mut collations = Collations::default();
+        collations.register("parity", |a, b| {
+            let parity = |s: &str| s.parse::<i64>().map_or(0, |n| n % 2);
+            parity(a).cmp(&parity(b))
+        });
+        let usable = Usable {
+            column: "moons".into(),
+            op: Op::Eq,
+            value: Some("5".into()),
+            collation: "parity".into(),
+        };
+        let index = catalog.index("by_moons").unwrap();
+        let table = catalog.table("planets").unwrap();
+        assert_eq!(
+            Planner::new(&catalog, &collations).eq_rows(table, index, &[&usable]),
+            1990.0
+        );
+        assert_eq!(
+            Planner::new(&catalog, &Collations::default()).eq_rows(table, index, &[&usable]),
+            286.0
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let collations = Collations::default();
+        let catalog = catalog("data/planets.db");
+        let planner = Planner::new(&catalog, &collations);
+
+        assert_eq!(planner.plan(&Query::default()), Err(Error::NoTables));
+
+        let query = Query {
+            tables: vec!["stars".into()],
+            ..Default::default()
+        };
+        assert_eq!(planner.plan(&query), Err(Error::NoSuchTable("stars".into())));
+
+        let query = Query {
+            tables: vec!["planets".into()],
+            order_by: vec![col("planets", "mass")],
+            ..Default::default()
+        };
+        assert_eq!(planner.plan(&query), Err(Error::NoSuchColumn("planets.mass".into())));
+    }
+}
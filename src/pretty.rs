@@ -1,4 +1,4 @@
-use crate::schema::*;
+use crate::{schema::*, wal::Commit};
 use std::fmt;
 
 struct Indent(usize);
@@ -31,6 +31,39 @@ impl fmt::Display for Page {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Page::TableLeaf(leaf) => write!(f, "{}", leaf)?,
+            Page::TableInterior(node) => write!(f, "{}", node)?,
+            Page::IndexLeaf(leaf) => write!(f, "{}", leaf)?,
+            Page::IndexInterior(node) => write!(f, "{}", node)?,
+            Page::FreelistTrunk(trunk) => {
+                writeln!(f, "{}Freelist Trunk:", Indent::new(1))?;
+                writeln!(f, "{}Next trunk:              {}", Indent::new(2), trunk.next)?;
+                writeln!(f, "{}Leaves:                  {:?}\n", Indent::new(2), trunk.leaves)?;
+            }
+            Page::FreelistLeaf => writeln!(f, "{}Freelist Leaf\n", Indent::new(1))?,
+            Page::Overflow(page) => {
+                writeln!(f, "{}Overflow:", Indent::new(1))?;
+                writeln!(f, "{}Next page:               {}", Indent::new(2), page.next)?;
+                writeln!(
+                    f,
+                    "{}Content:                 {} bytes\n",
+                    Indent::new(2),
+                    page.content.len()
+                )?;
+            }
+            Page::PointerMap(map) => {
+                // Pages past the end of the file have unused entries
+                let used = map
+                    .entries
+                    .iter()
+                    .rposition(|entry| entry.page_type != PointerType::Unused)
+                    .map_or(0, |i| i + 1);
+                writeln!(f, "{}Pointer Map:", Indent::new(1))?;
+                for entry in &map.entries[..used] {
+                    writeln!(f, "{}{:?} {}", Indent::new(2), entry.page_type, entry.parent)?;
+                }
+                writeln!(f)?;
+            }
+            Page::LockByte => writeln!(f, "{}Lock-byte page\n", Indent::new(1))?,
         }
         Ok(())
     }
@@ -57,17 +90,18 @@ impl fmt::Display for TableLeaf {
         // Cells as table
         writeln!(f, "\n{}Cells\n", Indent::new(1))?;
         let indent = Indent::new(2);
+        let width = self.cells.first().map_or(0, |cell| cell.record.columns.len());
 
         // Header
         write!(f, "{}│ {:6} │ {:6} │", indent, "Size", "Row ID")?;
-        for i in 0..self.cells[0].record.columns.len() {
+        for i in 0..width {
             write!(f, " {:10} │", format!("Col {}", i))?;
         }
         writeln!(f)?;
 
         // Separator
         write!(f, "{}├─{:─<6}─┼{:─<8}┼", indent, "", "")?;
-        for _ in 0..self.cells[0].record.columns.len() {
+        for _ in 0..width {
             write!(f, "{:─<12}┼", "")?;
         }
         writeln!(f)?;
@@ -80,12 +114,85 @@ impl fmt::Display for TableLeaf {
             }
             writeln!(f)?;
         }
-        writeln!(f, "{}", "")?;
+        writeln!(f)?;
 
         Ok(())
     }
 }
 
+impl fmt::Display for TableInterior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.page_header)?;
+        writeln!(
+            f,
+            "{}Cell Pointers:             {:?}",
+            Indent::new(1),
+            self.cell_pointers
+        )?;
+
+        writeln!(f, "\n{}Cells\n", Indent::new(1))?;
+        let indent = Indent::new(2);
+        writeln!(f, "{}│ {:10} │ {:6} │", indent, "Left child", "Row ID")?;
+        writeln!(f, "{}├─{:─<10}─┼{:─<8}┼", indent, "", "")?;
+        for cell in &self.cells {
+            writeln!(f, "{}│ {:10} │ {:6} │", indent, cell.left_child, cell.row_id.value)?;
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for IndexLeaf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.page_header)?;
+        writeln!(
+            f,
+            "{}Cell Pointers:             {:?}",
+            Indent::new(1),
+            self.cell_pointers
+        )?;
+
+        writeln!(f, "\n{}Keys\n", Indent::new(1))?;
+        for cell in &self.cells {
+            writeln!(f, "{}{}", Indent::new(2), KeyDisplay(&cell.record))?;
+        }
+        writeln!(f)
+    }
+}
+
+impl fmt::Display for IndexInterior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.page_header)?;
+        writeln!(
+            f,
+            "{}Cell Pointers:             {:?}",
+            Indent::new(1),
+            self.cell_pointers
+        )?;
+
+        writeln!(f, "\n{}Keys\n", Indent::new(1))?;
+        for cell in &self.cells {
+            writeln!(
+                f,
+                "{}{:6} ◀ {}",
+                Indent::new(2),
+                cell.left_child,
+                KeyDisplay(&cell.record)
+            )?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Index keys as a list of values, `(a, b, rowid)`
+struct KeyDisplay<'a>(&'a Record);
+
+impl fmt::Display for KeyDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values: Vec<String> = self.0.payload.iter().map(|value| value.to_string()).collect();
+        write!(f, "({})", values.join(", "))
+    }
+}
+
 impl fmt::Display for BTreePageHeader {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{}Page Header:", Indent::new(1))?;
@@ -121,6 +228,16 @@ impl fmt::Display for SerialValue {
     }
 }
 
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frame {:<6} salt {:08x} {:08x}  size {:<6} pages {:?}",
+            self.frame, self.salt[0], self.salt[1], self.size, self.pages
+        )
+    }
+}
+
 pub struct HeaderDisplay(pub Header, pub usize);
 
 impl fmt::Display for HeaderDisplay {
@@ -0,0 +1,525 @@
+//! # Pointer maps
+//!
+//! A database in auto-vacuum mode gives back the space of deleted rows by
+//! moving pages from the end of the file into the holes the freelist has, and
+//! truncating it. Moving a page means changing the page pointing to it, so a
+//! [PointerMap] page records the [PointerType] and parent of every page after
+//! it, up to the next pointer map page.
+//!
+//! [Docs](https://www.sqlite.org/fileformat2.html#pointer_map_or_ptrmap_pages)
+//!
+//! Page 2 is the first pointer map page, followed by as many pages as it has
+//! entries for, then the next one. Root pages come first, right after page 1,
+//! and [Header::autovacuum_top_root] is the last of them. A new table takes
+//! the page after it, moving whatever page is there out of its way, and when
+//! a table is dropped the last root page takes its place.
+//!
+//! Pointer maps are kept up to date as b-tree pages are balanced, allocated
+//! and freed. The first overflow page of a cell has the b-tree page of the
+//! cell as its parent, and every other one the overflow page before it.
+//!
+//! ```
+//! use rsqlite::schema::{AutoVacuum, CreateOptions, Database, Page};
+//!
+//! let options = CreateOptions { page_size: 512, auto_vacuum: AutoVacuum::Full, ..Default::default() };
+//! let mut db = Database::new(options).unwrap();
+//! db.execute("CREATE TABLE planets (name TEXT)").unwrap();
+//! for row_id in 1..=100 {
+//!     db.insert(3, row_id, vec!["Saturn".into()]).unwrap();
+//! }
+//! assert!(matches!(db.page(2), Some(Page::PointerMap(_))));
+//! assert_eq!(db.db_header.pointer_map_page(100), 2);
+//! assert!(db.check_pointer_map().is_empty());
+//! ```
+
+use crate::{
+    btree::{children, replace_child, replace_overflow},
+    schema::{error, lock_byte_page, Database, Header, Page, PointerMap, PointerMapEntry, PointerType, SerialValue},
+};
+use binrw::BinResult;
+use std::{collections::BTreeMap, fmt};
+
+impl Header {
+    /// Number of the pointer map page with the entry of page `number`, 0 for
+    /// page 1 which has none. A pointer map page is its own.
+    pub fn pointer_map_page(&self, number: u32) -> u32 {
+        if number < 2 {
+            return 0;
+        }
+        let pages = self.usable_size() / 5 + 1;
+        let map = (number - 2) / pages * pages + 2;
+        // The lock-byte page is never used, not even as a pointer map
+        match map == lock_byte_page(self.page_size_bytes()) {
+            true => map + 1,
+            false => map,
+        }
+    }
+
+    /// Whether page `number` is a pointer map page of an auto-vacuum database
+    pub fn is_pointer_map_page(&self, number: u32) -> bool {
+        self.is_auto_vacuum() && self.pointer_map_page(number) == number
+    }
+}
+
+impl PointerMap {
+    /// A pointer map page with all of its entries unused
+    pub fn new(usable_size: u32) -> PointerMap {
+        let unused = PointerMapEntry {
+            page_type: PointerType::Unused,
+            parent: 0,
+        };
+        PointerMap {
+            entries: vec![unused; usable_size as usize / 5],
+        }
+    }
+}
+
+/// A pointer map entry that doesn't match the b-trees and the freelist
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// A page that should be a pointer map page is not
+    NotPointerMap(u32),
+    Entry {
+        page: u32,
+        expected: PointerMapEntry,
+        found: PointerMapEntry,
+    },
+    /// A root page after [Header::autovacuum_top_root], which SQLite would
+    /// move out of the way of a new root page
+    TopRoot { root: u32, top: u32 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::NotPointerMap(page) => write!(f, "page {} is not a pointer map page", page),
+            Problem::Entry { page, expected, found } => write!(
+                f,
+                "page {} is {:?} with parent {}, the pointer map has {:?} with parent {}",
+                page, expected.page_type, expected.parent, found.page_type, found.parent
+            ),
+            Problem::TopRoot { root, top } => write!(f, "root page {} is after the largest root page {}", root, top),
+        }
+    }
+}
+
+impl Database {
+    /// Pointer map entry of page `number`, if the database has one for it
+    pub fn pointer_map_entry(&self, number: u32) -> Option<PointerMapEntry> {
+        let map = self.db_header.pointer_map_page(number);
+        match self.page(map) {
+            Some(Page::PointerMap(page)) if map < number => page.entries.get((number - map - 1) as usize).copied(),
+            _ => None,
+        }
+    }
+
+    /**
+     * Compare the pointer maps to what every page is, found by following the
+     * b-trees from their roots in `sqlite_schema` and the freelist from the
+     * header, like `PRAGMA integrity_check` does. Pages in neither are left
+     * alone. Databases not in auto-vacuum mode have nothing to check.
+     */
+    pub fn check_pointer_map(&self) -> Vec<Problem> {
+        let header = &self.db_header;
+        if !header.is_auto_vacuum() {
+            return vec![];
+        }
+
+        let mut problems = vec![];
+        let expected = self.expected_pointers();
+        for number in 2..=self.pages.len() as u32 {
+            if header.is_pointer_map_page(number) && !matches!(self.page(number), Some(Page::PointerMap(_))) {
+                problems.push(Problem::NotPointerMap(number));
+            }
+            let (Some(&expected), Some(found)) = (expected.get(&number), self.pointer_map_entry(number)) else {
+                continue;
+            };
+            if expected != found {
+                problems.push(Problem::Entry { page: number, expected, found });
+            }
+        }
+
+        let top = header.autovacuum_top_root;
+        problems.extend(
+            self.root_pages()
+                .into_iter()
+                .filter(|&root| root > top)
+                .map(|root| Problem::TopRoot { root, top }),
+        );
+        problems
+    }
+
+    /// Set the pointer map entry of page `number`, in auto-vacuum mode
+    pub(crate) fn set_pointer(&mut self, number: u32, page_type: PointerType, parent: u32) {
+        let map = self.db_header.pointer_map_page(number);
+        if !self.db_header.is_auto_vacuum() || map >= number {
+            return;
+        }
+        if let Some(Page::PointerMap(page)) = self.pages.get_mut(map as usize - 1) {
+            if let Some(entry) = page.entries.get_mut((number - map - 1) as usize) {
+                *entry = PointerMapEntry { page_type, parent };
+            }
+        }
+    }
+
+    /// Point the pointer map entries of the children of page `number`, and of
+    /// the first overflow pages of its cells, at it
+    pub(crate) fn set_parent_of_children(&mut self, number: u32) {
+        if !self.db_header.is_auto_vacuum() {
+            return;
+        }
+        for child in self.page(number).map(children).unwrap_or_default() {
+            self.set_pointer(child, PointerType::BTree, number);
+        }
+        for (first, _) in self.page(number).map(Page::overflows).unwrap_or_default() {
+            self.set_pointer(first, PointerType::Overflow1, number);
+        }
+    }
+
+    /**
+     * Page for the root of a new b-tree in auto-vacuum mode, the one after
+     * [Header::autovacuum_top_root] like `btreeCreateTable()` in SQLite. It is
+     * taken off the freelist, or its b-tree page is moved to another page.
+     */
+    pub(crate) fn allocate_root(&mut self) -> BinResult<u32> {
+        let lock_byte = lock_byte_page(self.db_header.page_size_bytes());
+        let mut root = self.db_header.autovacuum_top_root + 1;
+        while root == lock_byte || self.db_header.is_pointer_map_page(root) {
+            root += 1;
+        }
+
+        if root as usize > self.pages.len() {
+            // Pages that are skipped for roots are skipped as the file grows
+            self.grow();
+        } else if !self.take_free(root) {
+            let number = self.allocate();
+            self.relocate(root, number)?;
+        }
+        self.db_header.autovacuum_top_root = root;
+        self.set_pointer(root, PointerType::RootPage, 0);
+        Ok(root)
+    }
+
+    /**
+     * After the b-tree rooted at `root` is dropped, move the last root page
+     * into its place like `btreeDropTable()` in SQLite, so the root pages stay
+     * at the start of the file. Returns the page the root moved from, which
+     * `sqlite_schema` has to be changed to point away from.
+     *
+     * The roots of b-trees dropped together have to go from the last one.
+     */
+    pub(crate) fn move_last_root(&mut self, root: u32) -> BinResult<Option<u32>> {
+        let top = self.db_header.autovacuum_top_root;
+        if !self.db_header.is_auto_vacuum() || root > top {
+            return Ok(None);
+        }
+
+        let moved = match root < top {
+            true => {
+                self.take_free(root);
+                self.relocate(top, root)?;
+                self.free(top);
+                Some(top)
+            }
+            false => None,
+        };
+
+        let lock_byte = lock_byte_page(self.db_header.page_size_bytes());
+        let mut top = top - 1;
+        while top > 1 && (top == lock_byte || self.db_header.is_pointer_map_page(top)) {
+            top -= 1;
+        }
+        self.db_header.autovacuum_top_root = top;
+        Ok(moved)
+    }
+
+    /**
+     * Move b-tree or overflow page `from` to page `to`, which is free but not
+     * on the freelist, and point its parent and children at it. The root page
+     * of a b-tree has no parent, so `sqlite_schema` has to be changed instead.
+     * Page `from` is left as a [Page::FreelistLeaf], which is not on the
+     * freelist either.
+     */
+    pub(crate) fn relocate(&mut self, from: u32, to: u32) -> BinResult<()> {
+        let entry = self
+            .pointer_map_entry(from)
+            .ok_or_else(|| error(format!("Page {} has no pointer map entry", from)))?;
+        match entry.page_type {
+            PointerType::RootPage => {}
+            PointerType::BTree => {
+                let parent = self
+                    .pages
+                    .get_mut(entry.parent as usize - 1)
+                    .ok_or_else(|| error(format!("Parent page {} is out of range", entry.parent)))?;
+                if !replace_child(parent, from, to) {
+                    return Err(error(format!("Page {} is not a child of page {}", from, entry.parent)));
+                }
+            }
+            PointerType::Overflow1 | PointerType::Overflow2 => {
+                let parent = self
+                    .pages
+                    .get_mut(entry.parent as usize - 1)
+                    .ok_or_else(|| error(format!("Parent page {} is out of range", entry.parent)))?;
+                if !replace_overflow(parent, from, to) {
+                    return Err(error(format!(
+                        "Page {} is not an overflow page of page {}",
+                        from, entry.parent
+                    )));
+                }
+            }
+            page_type => {
+                return Err(error(format!(
+                    "Page {} is a {:?} page, which can't move",
+                    from, page_type
+                )))
+            }
+        }
+        if !matches!(self.page(to), Some(Page::FreelistLeaf)) {
+            return Err(error(format!("Page {} is in use", to)));
+        }
+
+        self.pages.swap(from as usize - 1, to as usize - 1);
+        self.set_pointer(to, entry.page_type, entry.parent);
+        self.set_parent_of_children(to);
+        if let Some(Page::Overflow(page)) = self.page(to) {
+            if page.next != 0 {
+                self.set_pointer(page.next, PointerType::Overflow2, to);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take page `number` off the freelist, returning false if it isn't on it.
+    /// A trunk page is replaced by its last leaf.
+    pub(crate) fn take_free(&mut self, number: u32) -> bool {
+        let mut previous = None;
+        let mut trunk = self.db_header.freelist_trunk_page;
+        // A corrupt list could loop, but it can't be longer than the file
+        for _ in 0..self.pages.len() {
+            let Some(Page::FreelistTrunk(page)) = self.pages.get_mut((trunk as usize).wrapping_sub(1)) else {
+                return false;
+            };
+
+            if let Some(i) = page.leaves.iter().position(|&leaf| leaf == number) {
+                page.leaves.remove(i);
+                page.leaf_count -= 1;
+            } else if trunk == number {
+                let next = match page.leaves.pop() {
+                    Some(leaf) => {
+                        page.leaf_count -= 1;
+                        self.pages.swap(trunk as usize - 1, leaf as usize - 1);
+                        leaf
+                    }
+                    None => page.next,
+                };
+                match previous {
+                    Some(previous) => match &mut self.pages[previous as usize - 1] {
+                        Page::FreelistTrunk(page) => page.next = next,
+                        _ => unreachable!("The previous page is a trunk"),
+                    },
+                    None => self.db_header.freelist_trunk_page = next,
+                }
+            } else {
+                (previous, trunk) = (Some(trunk), page.next);
+                continue;
+            }
+
+            self.pages[number as usize - 1] = Page::FreelistLeaf;
+            self.db_header.freelist_page_count -= 1;
+            return true;
+        }
+        false
+    }
+
+    /// Root pages of the b-trees in `sqlite_schema`, besides page 1
+    fn root_pages(&self) -> Vec<u32> {
+        self.table_rows(1)
+            .into_iter()
+            .filter_map(|cell| match cell.record.payload.get(3) {
+                Some(SerialValue::Number(n)) if *n > 1 => Some(*n as u32),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Pointer map entries of the pages of every b-tree and the freelist
+    fn expected_pointers(&self) -> BTreeMap<u32, PointerMapEntry> {
+        let entry = |page_type, parent| PointerMapEntry { page_type, parent };
+        let mut pointers = BTreeMap::new();
+
+        let mut stack = vec![1];
+        for root in self.root_pages() {
+            pointers.insert(root, entry(PointerType::RootPage, 0));
+            stack.push(root);
+        }
+        while let Some(number) = stack.pop() {
+            for child in self.page(number).map(children).unwrap_or_default() {
+                // A corrupt b-tree could loop
+                if pointers.insert(child, entry(PointerType::BTree, number)).is_none() {
+                    stack.push(child);
+                }
+            }
+            for (first, _) in self.page(number).map(Page::overflows).unwrap_or_default() {
+                let mut parent = entry(PointerType::Overflow1, number);
+                for page in self.overflow_pages(first) {
+                    pointers.insert(page, parent);
+                    parent = entry(PointerType::Overflow2, page);
+                }
+            }
+        }
+
+        let mut trunk = self.db_header.freelist_trunk_page;
+        while let Some(Page::FreelistTrunk(page)) = self.page(trunk) {
+            if pointers.insert(trunk, entry(PointerType::FreePage, 0)).is_some() {
+                break;
+            }
+            for &leaf in &page.leaves {
+                pointers.insert(leaf, entry(PointerType::FreePage, 0));
+            }
+            trunk = page.next;
+        }
+        pointers
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        catalog::Catalog,
+        schema::{AutoVacuum, CreateOptions},
+    };
+    use binrw::BinReaderExt;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn read(path: &str) -> Database {
+        let bytes = std::fs::read(path).expect("Failed to read database");
+        Cursor::new(bytes).read_be().expect("Failed to parse database")
+    }
+
+    fn entry(page_type: PointerType, parent: u32) -> PointerMapEntry {
+        PointerMapEntry { page_type, parent }
+    }
+
+    fn roots(db: &Database) -> Vec<(String, u32)> {
+        let catalog = Catalog::read(db);
+        let tables = catalog.tables.iter().map(|t| (t.name.clone(), t.root));
+        tables
+            .chain(catalog.indexes.iter().map(|i| (i.name.clone(), i.root)))
+            .collect()
+    }
+
+    #[test]
+    fn pointer_map_pages() {
+        let header = |page_size, auto_vacuum| {
+            let options = CreateOptions {
+                page_size,
+                auto_vacuum,
+                ..Default::default()
+            };
+            Database::new(options).unwrap().db_header
+        };
+
+        // 102 entries per page of 512 bytes, 819 for 4096
+        let t = vec![
+            (512, 1, 0),
+            (512, 2, 2),
+            (512, 3, 2),
+            (512, 104, 2),
+            (512, 105, 105),
+            (512, 106, 105),
+            (4096, 821, 2),
+            (4096, 822, 822),
+            // The lock-byte page would be one, so the next page is instead
+            (1024, 1048576, 1048372),
+            (1024, 1048577, 1048578),
+            (1024, 1048578, 1048578),
+            (1024, 1048579, 1048578),
+        ];
+        for (page_size, number, map) in t.into_iter() {
+            let header = header(page_size, AutoVacuum::Full);
+            assert_eq!(header.pointer_map_page(number), map, "{} {}", page_size, number);
+            assert_eq!(
+                header.is_pointer_map_page(number),
+                number == map,
+                "{} {}",
+                page_size,
+                number
+            );
+        }
+        assert!(!header(512, AutoVacuum::None).is_pointer_map_page(2));
+    }
+
+    #[test]
+    fn check() {
+        let db = read("data/autovacuum.db");
+        assert!(matches!(db.page(105), Some(Page::PointerMap(_))));
+        assert_eq!(db.pointer_map_entry(3), Some(entry(PointerType::RootPage, 0)));
+        assert_eq!(db.pointer_map_entry(6), Some(entry(PointerType::BTree, 5)));
+        assert_eq!(db.pointer_map_entry(105), None);
+        assert_eq!(db.check_pointer_map(), vec![]);
+
+        let mut db = db;
+        db.set_pointer(6, PointerType::FreePage, 0);
+        db.pages[104] = Page::FreelistLeaf;
+        db.db_header.autovacuum_top_root = 4;
+        assert_eq!(
+            db.check_pointer_map(),
+            vec![
+                Problem::Entry {
+                    page: 6,
+                    expected: entry(PointerType::BTree, 5),
+                    found: entry(PointerType::FreePage, 0),
+                },
+                Problem::NotPointerMap(105),
+                Problem::TopRoot { root: 5, top: 4 },
+            ]
+        );
+    }
+
+    #[test]
+    fn write() {
+        // A new database has its first pointer map page with the first table
+        let options = CreateOptions {
+            page_size: 512,
+            auto_vacuum: AutoVacuum::Full,
+            ..Default::default()
+        };
+        let mut db = Database::new(options).unwrap();
+        db.execute("CREATE TABLE planets (name TEXT)").unwrap();
+        assert_eq!(db.db_header.autovacuum_top_root, 3);
+        assert_eq!(db.pointer_map_entry(3), Some(entry(PointerType::RootPage, 0)));
+
+        let mut db = read("data/autovacuum.db");
+        for row_id in 201..=400 {
+            db.insert(
+                5,
+                row_id,
+                vec![format!("{:04} {}", row_id, "*".repeat(100)).as_str().into()],
+            )
+            .unwrap();
+        }
+        assert_eq!(db.check_pointer_map(), vec![]);
+
+        // Page 6 of `log` moves out of the way of the new root page
+        db.execute("CREATE TABLE rings (planet TEXT)").unwrap();
+        assert_eq!(db.pointer_map_entry(6), Some(entry(PointerType::RootPage, 0)));
+        assert_eq!(db.db_header.autovacuum_top_root, 6);
+        assert_eq!(db.check_pointer_map(), vec![]);
+
+        // The last root page takes the place of each one dropped
+        db.execute("DROP TABLE moons").unwrap();
+        assert_eq!(roots(&db), vec![("log".into(), 3), ("rings".into(), 4)]);
+        assert_eq!(db.db_header.autovacuum_top_root, 4);
+        assert_eq!(db.pointer_map_entry(5), Some(entry(PointerType::FreePage, 0)));
+        assert_eq!(db.check_pointer_map(), vec![]);
+
+        for row_id in 1..=400 {
+            db.delete(4, row_id).unwrap();
+        }
+        assert_eq!(db.check_pointer_map(), vec![]);
+    }
+}
@@ -0,0 +1,272 @@
+//! # Map rows to structs
+//!
+//! [FromRow] builds a value from a row and the names of its columns, and can
+//! be derived for structs with named fields. Each field reads the column with
+//! the same name through [FromValue].
+//!
+//! ```
+//! use rsqlite::{catalog::Catalog, row::FromRow, schema::Database};
+//! use binrw::BinRead;
+//!
+//! #[derive(FromRow)]
+//! struct Planet {
+//!     name: String,
+//!     #[row(rename = "type")]
+//!     kind: String,
+//!     moons: Option<u32>,
+//! }
+//!
+//! let mut file = std::fs::File::open("data/planets.db").unwrap();
+//! let db = Database::read(&mut file).unwrap();
+//! let catalog = Catalog::read(&db);
+//!
+//! let planets: Vec<Planet> = catalog.table("planets").unwrap().rows_as(&db).collect::<Result<_, _>>().unwrap();
+//! assert_eq!(planets[3].name, "Mars");
+//! assert_eq!(planets[3].kind, "Terrestrial");
+//! assert_eq!(planets[3].moons, Some(2));
+//! ```
+//!
+//! A field marked `#[row(timestamp)]` reads dates in any format the
+//! [date and time functions](crate::datetime) accept as unix seconds.
+
+use crate::{
+    catalog::Table,
+    datetime,
+    de::{Error, Result},
+    schema::{Database, SerialValue},
+};
+
+pub use rsqlite_derive::FromRow;
+
+/// Build a value from a row and the names of its columns
+pub trait FromRow: Sized {
+    fn from_row<S: AsRef<str>>(columns: &[S], row: &[SerialValue]) -> Result<Self>;
+}
+
+/// Convert a single column, `column` is only used in errors
+pub trait FromValue: Sized {
+    fn from_value(value: &SerialValue, column: &str) -> Result<Self>;
+}
+
+impl Table {
+    /// Every row of the table as `T`, in rowid order
+    pub fn rows_as<'a, T: FromRow>(&'a self, db: &'a Database) -> impl Iterator<Item = Result<T>> + 'a {
+        let columns: Vec<&str> = self.columns.iter().map(|c| c.name.as_str()).collect();
+        db.table_rows(self.root)
+            .into_iter()
+            .map(move |cell| T::from_row(&columns, &self.row(cell)))
+    }
+}
+
+/// Read the column named `name`, used by the derived [FromRow]
+pub fn column<T: FromValue, S: AsRef<str>>(columns: &[S], row: &[SerialValue], name: &str) -> Result<T> {
+    let i = columns
+        .iter()
+        .position(|c| c.as_ref().eq_ignore_ascii_case(name))
+        .ok_or_else(|| Error::Custom(format!("missing column `{}`", name)))?;
+    T::from_value(row.get(i).unwrap_or(&SerialValue::Null), name)
+}
+
+/// Read the column named `name` as unix seconds, from text like
+/// `"2024-01-31 12:00:00"` or a number of seconds.
+pub fn timestamp<T: FromValue, S: AsRef<str>>(columns: &[S], row: &[SerialValue], name: &str) -> Result<T> {
+    let value: Option<SerialValue> = column(columns, row, name)?;
+    let seconds = match value {
+        None => SerialValue::Null,
+        Some(SerialValue::Number(n)) => SerialValue::Number(n),
+        Some(value) => match datetime::unixepoch(std::slice::from_ref(&value)) {
+            SerialValue::Null => return Err(mismatch(&value, name, "timestamp")),
+            seconds => seconds,
+        },
+    };
+    T::from_value(&seconds, name)
+}
+
+fn mismatch(value: &SerialValue, column: &str, expected: &'static str) -> Error {
+    let found = match value {
+        SerialValue::Null | SerialValue::Reserved => return Error::Null(column.to_string()),
+        SerialValue::Number(_) => "integer",
+        SerialValue::Float(_) => "real",
+        SerialValue::String(_) => "text",
+        SerialValue::Blob(_) => "blob",
+    };
+    Error::Mismatch {
+        column: column.to_string(),
+        expected,
+        found,
+    }
+}
+
+macro_rules! integer {
+    ($($t:ty),*) => {$(
+        impl FromValue for $t {
+            fn from_value(value: &SerialValue, column: &str) -> Result<Self> {
+                match value {
+                    SerialValue::Number(n) => <$t>::try_from(*n).map_err(|_| {
+                        Error::Custom(format!("column {}: {} out of range for {}", column, n, stringify!($t)))
+                    }),
+                    value => Err(mismatch(value, column, "integer")),
+                }
+            }
+        }
+    )*};
+}
+
+integer!(i8, i16, i32, i64, u8, u16, u32, u64, usize);
+
+impl FromValue for f64 {
+    fn from_value(value: &SerialValue, column: &str) -> Result<Self> {
+        match value {
+            SerialValue::Float(x) => Ok(*x),
+            SerialValue::Number(n) => Ok(*n as f64),
+            value => Err(mismatch(value, column, "real")),
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: &SerialValue, column: &str) -> Result<Self> {
+        f64::from_value(value, column).map(|x| x as f32)
+    }
+}
+
+/// SQLite has no booleans, `TRUE` and `FALSE` are stored as 1 and 0
+impl FromValue for bool {
+    fn from_value(value: &SerialValue, column: &str) -> Result<Self> {
+        match value {
+            SerialValue::Number(n @ (0 | 1)) => Ok(*n == 1),
+            value => Err(mismatch(value, column, "0 or 1")),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &SerialValue, column: &str) -> Result<Self> {
+        match value {
+            SerialValue::String(s) => Ok(s.clone()),
+            value => Err(mismatch(value, column, "text")),
+        }
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn from_value(value: &SerialValue, column: &str) -> Result<Self> {
+        match value {
+            SerialValue::Blob(b) => Ok(b.clone()),
+            SerialValue::String(s) => Ok(s.as_bytes().to_vec()),
+            value => Err(mismatch(value, column, "blob")),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &SerialValue, column: &str) -> Result<Self> {
+        match value {
+            SerialValue::Null => Ok(None),
+            value => T::from_value(value, column).map(Some),
+        }
+    }
+}
+
+/// The value as stored, for columns without a fixed type
+impl FromValue for SerialValue {
+    fn from_value(value: &SerialValue, _: &str) -> Result<Self> {
+        Ok(value.clone())
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use SerialValue as V;
+
+    #[derive(Clone, Debug, FromRow, PartialEq)]
+    struct Event {
+        id: i64,
+        #[row(rename = "title")]
+        name: String,
+        done: bool,
+        note: Option<String>,
+        data: Vec<u8>,
+        #[row(timestamp)]
+        created: i64,
+        #[row(timestamp)]
+        updated: Option<i64>,
+    }
+
+    const COLUMNS: [&str; 7] = ["id", "title", "done", "note", "data", "created", "updated"];
+
+    fn row(done: V, created: V, updated: V) -> Vec<SerialValue> {
+        vec![
+            V::Number(1),
+            "launch".into(),
+            done,
+            V::Null,
+            V::Blob(vec![0xca, 0xfe]),
+            created,
+            updated,
+        ]
+    }
+
+    #[test]
+    fn derive() {
+        let exp = Event {
+            id: 1,
+            name: "launch".into(),
+            done: true,
+            note: None,
+            data: vec![0xca, 0xfe],
+            created: 1706702400,
+            updated: None,
+        };
+
+        let t = vec![
+            row(V::Number(1), "2024-01-31 12:00:00".into(), V::Null),
+            row(V::Number(1), V::Number(1706702400), V::Null),
+            row(V::Number(1), V::Float(2460341.0), V::Null),
+        ];
+        for values in t.into_iter() {
+            assert_eq!(Event::from_row(&COLUMNS, &values), Ok(exp.clone()), "{:?}", values);
+        }
+
+        let values = row(V::Number(0), V::Number(0), "1970-01-02".into());
+        let event = Event::from_row(&COLUMNS, &values).unwrap();
+        assert_eq!((event.done, event.created, event.updated), (false, 0, Some(86400)));
+    }
+
+    #[test]
+    fn errors() {
+        let t = vec![
+            (
+                row(V::Number(2), V::Number(0), V::Null),
+                "column done: expected 0 or 1, found integer",
+            ),
+            (
+                row(V::Null, V::Number(0), V::Null),
+                "column done: unexpected NULL for a field that is not an Option",
+            ),
+            (
+                row(V::Number(1), "yesterday".into(), V::Null),
+                "column created: expected timestamp, found text",
+            ),
+            (
+                row(V::Number(1), V::Null, V::Null),
+                "column created: unexpected NULL for a field that is not an Option",
+            ),
+        ];
+        for (values, exp) in t.into_iter() {
+            assert_eq!(Event::from_row(&COLUMNS, &values).unwrap_err().to_string(), exp);
+        }
+
+        let err = Event::from_row(&COLUMNS[..6], &row(V::Number(1), V::Number(0), V::Null)[..6]).unwrap_err();
+        assert_eq!(err.to_string(), "missing column `updated`");
+
+        assert_eq!(
+            u8::from_value(&V::Number(300), "moons").unwrap_err().to_string(),
+            "column moons: 300 out of range for u8"
+        );
+    }
+}
@@ -3,18 +3,27 @@
 //! A SQLite [Database] is a sequence of [Page]s. The first 100 bytes of the
 //! first [Page] contains a [Header] with global metadata.
 //!
-//! Each [Page] can be one of the 5 types, but only [TableLeaf] is implemented
-//! for now to handle the simplest databases. [TableLeaf] page starts with a
-//! [BTreePageHeader], followed by [TableLeaf::cell_pointers] pointing to
-//! [TableLeaf::cells] with actual data.
+//! Each [Page] is one of the kinds listed on [Page]. The b-tree pages, like a
+//! [TableLeaf], start with a [BTreePageHeader], followed by
+//! [cell pointers](TableLeaf::cell_pointers) pointing to the
+//! [cells](TableLeaf::cells) with actual data.
 //!
 //! [TableLeafCell] holds metadata like `row_id` and `size` for a database row,
 //! along with a [Record] containing ([SerialType], [SerialValue]) pairs holding
-//! data itself.
+//! data itself. A record too large for its page continues on a list of
+//! [OverflowPage]s, which is followed as the cell is read so the [Record] is
+//! always whole.
+//!
+//! Everything can be written back with [BinWrite] too. Pages are written whole,
+//! with cells at their cell pointers and zeros in the unallocated space.
 
+use crate::lock::PENDING_BYTE;
 use crate::varint::VarInt;
-use binrw::{file_ptr::parse_from_iter, helpers::args_iter_with, io::SeekFrom, *};
-use std::io::{Cursor, Read, Seek};
+use binrw::{helpers::args_iter_with, io::SeekFrom, *};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Cursor, Read, Seek, Write},
+};
 
 /** A SQLite Database */
 #[derive(BinRead, Debug, PartialEq)]
@@ -24,55 +33,76 @@ pub struct Database {
     pub db_header: Header,
 
     /// ... followed by a number of pages.
-    // The header is part of first page, so start from the beginning again.
-    #[br(seek_before = SeekFrom::Start(0),
-         align_before = db_header.page_size,
-         count = db_header.database_page_count)]
+    // The header is part of first page, so every page is read from its own
+    // offset starting from the beginning again.
+    #[br(parse_with = read_pages, args(db_header))]
     pub pages: Vec<Page>,
 }
 
 /**
  * A page can be of 5 types as described
- * [here](https://www.sqlite.org/fileformat2.html#pages), but only table leaf
- * pages are implemented for now.
+ * [here](https://www.sqlite.org/fileformat2.html#pages).
  *
  * 1. B tree page
- *      1. Table interior
+ *      1. Table interior [TableInterior] ⭐
  *      2. Table leaf [TableLeaf] ⭐
- *      3. Index interior
- *      4. Index leaf
+ *      3. Index interior [IndexInterior] ⭐
+ *      4. Index leaf [IndexLeaf] ⭐
  * 2. Freelist page
- *      1. Trunk Page
- *      2. Leaf Page
- * 3. Payload overflow page
- * 4. A pointer map page
- * 5. The lock-byte page
+ *      1. Trunk Page [FreelistTrunk] ⭐
+ *      2. Leaf Page ⭐
+ * 3. Payload overflow page [OverflowPage] ⭐
+ * 4. A pointer map page [PointerMap] ⭐
+ * 5. The lock-byte page ⭐
  */
 #[derive(BinRead, Debug, PartialEq)]
-#[br(big)]
+#[br(big, import(header: Header))]
 pub enum Page {
-    TableLeaf(TableLeaf),
+    TableLeaf(#[br(args(header))] TableLeaf),
+    TableInterior(#[br(args(header))] TableInterior),
+    IndexLeaf(#[br(args(header))] IndexLeaf),
+    IndexInterior(#[br(args(header))] IndexInterior),
+    // Freelist pages can't be told apart by their content, only by following
+    // the list from the header, so only `read_pages` reads them.
+    #[br(pre_assert(false))]
+    FreelistTrunk(FreelistTrunk),
+    /// An unused page, with content that doesn't matter and is written as zeros
+    #[br(pre_assert(false))]
+    FreelistLeaf,
+    /// Overflow pages are only found by following the cells that spill onto
+    /// them, which `read_pages` does too
+    #[br(pre_assert(false))]
+    Overflow(OverflowPage),
+    /// Only in auto-vacuum databases, at places that follow from the page size
+    #[br(pre_assert(false))]
+    PointerMap(PointerMap),
+    /// The page holding byte [PENDING_BYTE], which is never used so that its
+    /// bytes can be locked. See [lock_byte_page].
+    #[br(pre_assert(false))]
+    LockByte,
 }
 
 /**
- * A B tree table leaf page is divided into regions in the following order
+ * A B tree page is divided into regions in the following order
  *
  * 1. The 100-byte database file [Header] (found on page 1 only)
  * 2. The 8 or 12 byte [b-tree page header][BTreePageHeader]
  * 3. The cell pointer array
  * 4. Unallocated space
- * 5. The cell content area
+ * 5. The cell content area, with [Freeblock]s where cells were removed
  * 6. The reserved region
  *
+ * The 4 kinds of b-tree pages only differ in the [Cell]s they hold.
+ *
  * See more [docs](https://www.sqlite.org/fileformat2.html#b_tree_pages)
  *
  * TODO: `#[binread]` instead of `#[derive(BinRead)]` breaks markdown docs on
  * the type. Fix this upstream.
  */
 #[binread]
-#[br(big, stream = s)]
+#[br(big, stream = s, import(header: Header))]
 #[derive(Debug, PartialEq)]
-pub struct TableLeaf {
+pub struct BTreePage<C: Cell> {
     // Page start offset for internal offset calculations.
     //
     // `cell_pointers` are offsets from the start of the page, so store starting
@@ -85,6 +115,7 @@ pub struct TableLeaf {
     pub db_header: Option<Header>,
 
     /// Page header
+    #[br(assert(page_header.page_type == C::PAGE_TYPE))]
     pub page_header: BTreePageHeader,
 
     // 🎉 It's really cool that previous values can be referred for count. binrw is awesome!
@@ -96,9 +127,144 @@ pub struct TableLeaf {
     /// [ Unallocated space ]
 
     /// Cells with metadata + (type, value) pairs in a record
-    #[br(parse_with = parse_from_iter(cell_pointers.iter().copied()),
-          seek_before(SeekFrom::Start(_page_start)))]
-    pub cells: Vec<TableLeafCell>,
+    #[br(parse_with = read_cells, args(_page_start, &cell_pointers, header))]
+    pub cells: Vec<C>,
+
+    /// Free space in the cell content area, in order of offset
+    #[br(parse_with = read_freeblocks, args(_page_start, page_header.first_freeblock))]
+    pub freeblocks: Vec<Freeblock>,
+}
+
+/**
+ * Space of at least 4 bytes in the cell content area that is not used by a
+ * cell. Freeblocks form a list in order of offset, starting from
+ * [BTreePageHeader::first_freeblock], and each starts with the 2 byte offset
+ * of the next freeblock and its own 2 byte size.
+ *
+ * Smaller gaps can't hold that, and are counted in
+ * [BTreePageHeader::fragmented_free_bytes] instead.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Freeblock {
+    /// Offset from the start of the page
+    pub offset: u16,
+    /// Size in bytes, including the 4 byte header
+    pub size: u16,
+}
+
+pub type TableLeaf = BTreePage<TableLeafCell>;
+pub type TableInterior = BTreePage<TableInteriorCell>;
+pub type IndexLeaf = BTreePage<IndexLeafCell>;
+pub type IndexInterior = BTreePage<IndexInteriorCell>;
+
+/// A cell of a b-tree page, which decides the type of the page. Reading a
+/// cell needs the [Header] to find the overflow pages of its payload.
+pub trait Cell: for<'a> BinRead<Args<'a> = (Header,)> + for<'a> BinWrite<Args<'a> = ()> {
+    const PAGE_TYPE: PageType;
+
+    /// First overflow page of a cell with a payload too large for the page,
+    /// and how many bytes of the payload are on overflow pages
+    fn overflow(&self) -> Option<(u32, u64)> {
+        None
+    }
+}
+
+/**
+ * Where the payload of a cell continues when it doesn't fit on the page. The
+ * first [local](Self::local) bytes are in the cell, followed by the page
+ * number of the first [OverflowPage], see [PageType::local_size].
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Overflow {
+    /// Bytes of the payload in the cell
+    pub local: u64,
+    /// Page number of the first overflow page
+    pub page: u32,
+}
+
+/**
+ * A page with the part of a payload that doesn't fit in its cell. The
+ * overflow pages of a cell form a list starting from [Overflow::page].
+ *
+ * | Offset | Size | Description                                                |
+ * |--------|------|------------------------------------------------------------|
+ * | 0      | 4    | Page number of the next overflow page, 0 for the last      |
+ * | 4      | U-4  | Content, where U is the [usable size](Header::usable_size) |
+ *
+ * [Docs](https://www.sqlite.org/fileformat2.html#cell_payload_overflow_pages)
+ */
+#[derive(BinRead, BinWrite, Debug, PartialEq)]
+#[brw(big)]
+#[br(import(count: usize))]
+pub struct OverflowPage {
+    pub next: u32,
+    /// Only the bytes of the payload, which fill every page but the last
+    #[br(count = count)]
+    pub content: Vec<u8>,
+}
+
+/**
+ * A freelist trunk page, the head of the list of unused pages is in
+ * [Header::freelist_trunk_page].
+ *
+ * Each trunk page holds the page numbers of a number of leaf pages, which are
+ * unused pages with no content, and the page number of the next trunk page.
+ *
+ * [Docs](https://www.sqlite.org/fileformat2.html#the_freelist)
+ */
+#[derive(BinRead, BinWrite, Debug, PartialEq)]
+#[brw(big)]
+pub struct FreelistTrunk {
+    /// Page number of the next trunk page, 0 for the last one
+    pub next: u32,
+    pub leaf_count: u32,
+    #[br(count = leaf_count)]
+    pub leaves: Vec<u32>,
+}
+
+/**
+ * A pointer map page, which a database in auto-vacuum mode has so it can move
+ * pages around, see [crate::ptrmap]. It has a 5 byte entry for each of the
+ * pages after it, up to the next pointer map page.
+ *
+ * | Offset | Size | Description                                    |
+ * |--------|------|------------------------------------------------|
+ * | 0      | 1    | [PointerType] of the page                      |
+ * | 1      | 4    | Page number of the parent page, 0 if it has none |
+ *
+ * [Docs](https://www.sqlite.org/fileformat2.html#pointer_map_or_ptrmap_pages)
+ */
+#[derive(BinRead, BinWrite, Debug, PartialEq)]
+#[brw(big)]
+#[br(import(count: usize))]
+pub struct PointerMap {
+    #[br(count = count)]
+    pub entries: Vec<PointerMapEntry>,
+}
+
+#[derive(BinRead, BinWrite, Clone, Copy, Debug, PartialEq)]
+#[brw(big)]
+pub struct PointerMapEntry {
+    pub page_type: PointerType,
+    pub parent: u32,
+}
+
+/// What a page is, as far as moving it goes
+#[derive(BinRead, BinWrite, Clone, Copy, Debug, PartialEq)]
+#[brw(repr(u8))]
+pub enum PointerType {
+    // Entries of pages past the end of the file are zeros
+    Unused = 0,
+    // The root of a b-tree, which has no parent
+    RootPage = 1,
+    // A page on the freelist, which has no parent either
+    FreePage = 2,
+    // The first overflow page of a cell, whose parent is the b-tree page
+    Overflow1 = 3,
+    // Any other overflow page, whose parent is the overflow page before it
+    Overflow2 = 4,
+    // A non-root b-tree page, whose parent is the interior page above it
+    BTree = 5,
 }
 
 /**
@@ -107,8 +273,8 @@ pub struct TableLeaf {
  * - [Docs](https://www.sqlite.org/fileformat.html#the_database_header)
  * - [SQLite Source](https://github.com/sqlite/sqlite/blob/e69b4d7/src/btreeInt.h#L45-L82)
  */
-#[derive(BinRead, Copy, Clone, Debug, PartialEq)]
-#[br(big, magic = b"SQLite format 3\0")]
+#[derive(BinRead, BinWrite, Copy, Clone, Debug, PartialEq)]
+#[brw(big, magic = b"SQLite format 3\0")]
 pub struct Header {
     pub page_size: u16,            // Page size in bytes.  (1 means 65536)
     pub write_format: u8,          // File format write version
@@ -154,8 +320,8 @@ pub struct Header {
  * | 7      | 1    | Number of fragmented free bytes in the cell content area.           |
  * | 8      | 4    | Right-most pointer (interior b-tree pages only, omitted otherwise). |
  */
-#[derive(BinRead, Debug, PartialEq)]
-#[br(big)]
+#[derive(BinRead, BinWrite, Debug, PartialEq)]
+#[brw(big)]
 pub struct BTreePageHeader {
     pub page_type: PageType,
     pub first_freeblock: u16,
@@ -170,8 +336,8 @@ pub struct BTreePageHeader {
 /**
  * A b-tree page is either an interior page or a leaf page.
  */
-#[derive(BinRead, Debug, PartialEq)]
-#[br(repr(u8))]
+#[derive(BinRead, BinWrite, Clone, Copy, Debug, PartialEq)]
+#[brw(repr(u8))]
 pub enum PageType {
     // An interior page contains K keys together with K+1 pointers to child
     // b-tree pages. A "pointer" in an interior b-tree page is just the 32-bit
@@ -196,14 +362,115 @@ pub enum PageType {
  * 4. A 4-byte big-endian integer page number for the first page of the overflow
  *    page list - omitted if all payload fits on the b-tree page.
  */
-#[derive(BinRead, Debug, PartialEq)]
-#[br(big)]
+#[derive(BinRead, BinWrite, Debug, PartialEq)]
+#[brw(big)]
+#[br(import(header: Header))]
 pub struct TableLeafCell {
     pub size: VarInt,
     pub row_id: VarInt,
+    // Read before the record it comes after, since the record continues there
+    #[br(restore_position, parse_with = read_overflow,
+        args(size.value, PageType::LeafTable.local_size(size.value, header.usable_size())))]
+    #[bw(ignore)]
+    pub overflow: Option<Overflow>,
+    #[br(parse_with = read_payload, args(size.value, overflow, header))]
+    #[bw(write_with = write_payload, args(*overflow))]
+    pub record: Record,
+}
+
+impl Cell for TableLeafCell {
+    const PAGE_TYPE: PageType = PageType::LeafTable;
+
+    fn overflow(&self) -> Option<(u32, u64)> {
+        self.overflow
+            .map(|overflow| (overflow.page, self.size.value - overflow.local))
+    }
+}
+
+/**
+ * Interior cell for a [PageType::InteriorTable]
+ *
+ * 1. A 4-byte big-endian page number which is the left child pointer.
+ * 2. A varint which is the integer key. Every row in the left child has a
+ *    `rowid` less than or equal to this key.
+ */
+#[derive(BinRead, BinWrite, Debug, PartialEq)]
+#[brw(big)]
+#[br(import(_header: Header))]
+pub struct TableInteriorCell {
+    pub left_child: u32,
+    pub row_id: VarInt,
+}
+
+impl Cell for TableInteriorCell {
+    const PAGE_TYPE: PageType = PageType::InteriorTable;
+}
+
+/**
+ * Leaf cell for a [PageType::LeafIndex]
+ *
+ * An index has no data apart from the key, which is a [Record] of the indexed
+ * columns followed by the `rowid` of the table row.
+ *
+ * 1. A varint for the total number of bytes of key payload, including any overflow
+ * 2. The initial portion of the payload that does not spill to overflow pages.
+ * 3. A 4-byte big-endian integer page number for the first page of the overflow
+ *    page list - omitted if all payload fits on the b-tree page.
+ */
+#[derive(BinRead, BinWrite, Debug, PartialEq)]
+#[brw(big)]
+#[br(import(header: Header))]
+pub struct IndexLeafCell {
+    pub size: VarInt,
+    #[br(restore_position, parse_with = read_overflow,
+        args(size.value, PageType::LeafIndex.local_size(size.value, header.usable_size())))]
+    #[bw(ignore)]
+    pub overflow: Option<Overflow>,
+    #[br(parse_with = read_payload, args(size.value, overflow, header))]
+    #[bw(write_with = write_payload, args(*overflow))]
     pub record: Record,
 }
 
+impl Cell for IndexLeafCell {
+    const PAGE_TYPE: PageType = PageType::LeafIndex;
+
+    fn overflow(&self) -> Option<(u32, u64)> {
+        self.overflow
+            .map(|overflow| (overflow.page, self.size.value - overflow.local))
+    }
+}
+
+/**
+ * Interior cell for a [PageType::InteriorIndex]
+ *
+ * Same as [IndexLeafCell], but starting with a 4-byte big-endian left child
+ * page number. Unlike table b-trees, keys in interior index pages are not
+ * repeated in the leaves.
+ */
+#[derive(BinRead, BinWrite, Debug, PartialEq)]
+#[brw(big)]
+#[br(import(header: Header))]
+pub struct IndexInteriorCell {
+    pub left_child: u32,
+    pub size: VarInt,
+    #[br(restore_position, parse_with = read_overflow,
+        args(size.value, PageType::InteriorIndex.local_size(size.value, header.usable_size())))]
+    #[bw(ignore)]
+    pub overflow: Option<Overflow>,
+    #[br(parse_with = read_payload, args(size.value, overflow, header))]
+    #[bw(write_with = write_payload, args(*overflow))]
+    pub record: Record,
+}
+
+impl Cell for IndexInteriorCell {
+    const PAGE_TYPE: PageType = PageType::InteriorIndex;
+
+    fn overflow(&self) -> Option<(u32, u64)> {
+        self.overflow
+            .map(|overflow| (overflow.page, self.size.value - overflow.local))
+    }
+}
+
 /**
  * A Record holds the contents of a row along with type info.
  *
@@ -270,7 +537,7 @@ pub enum SerialType {
 /**
  * Serial values holding table data.
  */
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SerialValue {
     Null,
     Number(i64),
@@ -280,6 +547,57 @@ pub enum SerialValue {
     Blob(Vec<u8>),
 }
 
+/**
+ * Settings for a new database, see [Database::create].
+ *
+ * The defaults match a new `sqlite3` database.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CreateOptions {
+    /// A power of two between 512 and 65536
+    pub page_size: u32,
+    /// Bytes at the end of every page for extensions, like checksums
+    pub reserved_bytes: u8,
+    /// Only [TextEncoding::Utf8], since text is always written as UTF-8
+    pub text_encoding: TextEncoding,
+    /// `PRAGMA user_version`
+    pub user_version: u32,
+    /// `PRAGMA application_id`
+    pub application_id: u32,
+    /// `PRAGMA auto_vacuum`
+    pub auto_vacuum: AutoVacuum,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        CreateOptions {
+            page_size: 4096,
+            reserved_bytes: 0,
+            text_encoding: TextEncoding::Utf8,
+            user_version: 0,
+            application_id: 0,
+            auto_vacuum: AutoVacuum::None,
+        }
+    }
+}
+
+/// Whether the database keeps [PointerMap]s to move pages to the end of the
+/// file and truncate it, every time or only when asked to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutoVacuum {
+    None = 0,
+    Full = 1,
+    Incremental = 2,
+}
+
+/// Encoding of all text in the database, [Header::text_encoding]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding {
+    Utf8 = 1,
+    Utf16le = 2,
+    Utf16be = 3,
+}
+
 impl BinRead for SerialType {
     type Args<'a> = ();
 
@@ -343,8 +661,618 @@ impl BinRead for SerialValue {
     }
 }
 
+impl BinWrite for SerialType {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(&self, w: &mut W, endian: Endian, _: Self::Args<'_>) -> BinResult<()> {
+        VarInt::new(self.code()).write_options(w, endian, ())
+    }
+}
+
+impl BinWrite for SerialValue {
+    type Args<'a> = SerialType;
+
+    fn write_options<W: Write + Seek>(&self, w: &mut W, _: Endian, serial_type: Self::Args<'_>) -> BinResult<()> {
+        use {SerialType as T, SerialValue as V};
+
+        let bytes = match (serial_type, self) {
+            (T::Null | T::Zero | T::One | T::Reserved, _) => vec![],
+            (T::Float, V::Float(x)) => x.to_be_bytes().to_vec(),
+            (T::String(_), V::String(s)) => s.as_bytes().to_vec(),
+            (T::Blob(_), V::Blob(b)) => b.clone(),
+            // Integers are the low order bytes of the big endian i64
+            (_, V::Number(n)) => n.to_be_bytes()[8 - serial_type.size()..].to_vec(),
+            (serial_type, value) => {
+                return Err(binrw::Error::AssertFail {
+                    pos: w.stream_position()?,
+                    message: format!("Can't write {:?} as {:?}", value, serial_type),
+                })
+            }
+        };
+        w.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+impl BinWrite for Record {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(&self, w: &mut W, endian: Endian, _: Self::Args<'_>) -> BinResult<()> {
+        self.header_size.write_options(w, endian, ())?;
+        self.columns.write_options(w, endian, ())?;
+        for (value, serial_type) in self.payload.iter().zip(&self.columns) {
+            value.write_options(w, endian, *serial_type)?;
+        }
+        Ok(())
+    }
+}
+
+/// Pages are written whole, `page_size` bytes including any unallocated space.
+/// Overflow pages are pages of their own, so cells only write their local part.
+impl<C: Cell> BinWrite for BTreePage<C> {
+    type Args<'a> = u32;
+
+    fn write_options<W: Write + Seek>(&self, w: &mut W, endian: Endian, page_size: Self::Args<'_>) -> BinResult<()> {
+        let mut page = Cursor::new(vec![0u8; page_size as usize]);
+        self.db_header.write_options(&mut page, endian, ())?;
+        self.page_header.write_options(&mut page, endian, ())?;
+        self.cell_pointers.write_options(&mut page, endian, ())?;
+
+        for (pointer, cell) in self.cell_pointers.iter().zip(&self.cells) {
+            page.set_position(*pointer as u64);
+            cell.write_options(&mut page, endian, ())?;
+        }
+        for (i, freeblock) in self.freeblocks.iter().enumerate() {
+            let next = self.freeblocks.get(i + 1).map_or(0, |next| next.offset);
+            page.set_position(freeblock.offset as u64);
+            (next, freeblock.size).write_options(&mut page, endian, ())?;
+        }
+
+        // Writing past the end grows the buffer instead of failing
+        if page.get_ref().len() > page_size as usize {
+            return Err(binrw::Error::AssertFail {
+                pos: w.stream_position()?,
+                message: format!("Cells don't fit in a page of {} bytes", page_size),
+            });
+        }
+        w.write_all(page.get_ref())?;
+        Ok(())
+    }
+}
+
+impl BinWrite for Page {
+    type Args<'a> = u32;
+
+    fn write_options<W: Write + Seek>(&self, w: &mut W, endian: Endian, page_size: Self::Args<'_>) -> BinResult<()> {
+        match self {
+            Page::TableLeaf(page) => page.write_options(w, endian, page_size),
+            Page::TableInterior(page) => page.write_options(w, endian, page_size),
+            Page::IndexLeaf(page) => page.write_options(w, endian, page_size),
+            Page::IndexInterior(page) => page.write_options(w, endian, page_size),
+            Page::FreelistTrunk(trunk) => write_padded(w, trunk, endian, page_size),
+            Page::Overflow(page) => write_padded(w, page, endian, page_size),
+            Page::PointerMap(map) => write_padded(w, map, endian, page_size),
+            Page::FreelistLeaf | Page::LockByte => Ok(w.write_all(&vec![0u8; page_size as usize])?),
+        }
+    }
+}
+
+/// The database is written page by page. The header is part of page 1, but
+/// [Database::db_header] is written over it, so changes only need to go there.
+impl BinWrite for Database {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(&self, w: &mut W, _: Endian, _: Self::Args<'_>) -> BinResult<()> {
+        let start = w.stream_position()?;
+        for page in &self.pages {
+            page.write_options(w, Endian::Big, self.db_header.page_size_bytes())?;
+        }
+        let end = w.stream_position()?;
+        w.seek(SeekFrom::Start(start))?;
+        self.db_header.write_options(w, Endian::Big, ())?;
+        w.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+impl PageType {
+    /**
+     * Bytes of a payload of `size` bytes kept in a cell on a page of this
+     * type, the rest goes on overflow pages. Like
+     * `btreeParseCellAdjustSizeForOverflow()` in SQLite, as much as possible
+     * spills so the last overflow page is full, unless that leaves less than
+     * the minimum on the page.
+     */
+    pub fn local_size(&self, size: u64, usable_size: u32) -> u64 {
+        let usable_size = usable_size as u64;
+        let max_local = match self {
+            PageType::LeafTable => usable_size - 35,
+            _ => (usable_size - 12) * 64 / 255 - 23,
+        };
+        let min_local = (usable_size - 12) * 32 / 255 - 23;
+        if size <= max_local {
+            return size;
+        }
+        match min_local + (size - min_local) % (usable_size - 4) {
+            local if local <= max_local => local,
+            _ => min_local,
+        }
+    }
+}
+
+impl Page {
+    /// First overflow page of every cell with one on a b-tree page, and how
+    /// many bytes of its payload are on overflow pages
+    pub fn overflows(&self) -> Vec<(u32, u64)> {
+        fn cells<C: Cell>(page: &BTreePage<C>) -> Vec<(u32, u64)> {
+            page.cells.iter().filter_map(Cell::overflow).collect()
+        }
+        match self {
+            Page::TableLeaf(page) => cells(page),
+            Page::IndexLeaf(page) => cells(page),
+            Page::IndexInterior(page) => cells(page),
+            _ => vec![],
+        }
+    }
+}
+
+impl SerialType {
+    /// The serial type number stored in the record header
+    pub fn code(&self) -> u64 {
+        match *self {
+            SerialType::Null => 0,
+            SerialType::I8 => 1,
+            SerialType::I16 => 2,
+            SerialType::I24 => 3,
+            SerialType::I32 => 4,
+            SerialType::I48 => 5,
+            SerialType::I64 => 6,
+            SerialType::Float => 7,
+            SerialType::Zero => 8,
+            SerialType::One => 9,
+            SerialType::Reserved => 10,
+            SerialType::Blob(n) => n as u64 * 2 + 12,
+            SerialType::String(n) => n as u64 * 2 + 13,
+        }
+    }
+
+    /// Size of the value in bytes
+    pub fn size(&self) -> usize {
+        match *self {
+            SerialType::Null | SerialType::Zero | SerialType::One | SerialType::Reserved => 0,
+            SerialType::I8 => 1,
+            SerialType::I16 => 2,
+            SerialType::I24 => 3,
+            SerialType::I32 => 4,
+            SerialType::I48 => 6,
+            SerialType::I64 | SerialType::Float => 8,
+            SerialType::Blob(n) | SerialType::String(n) => n,
+        }
+    }
+}
+
+impl SerialValue {
+    /// The smallest serial type that holds this value, like SQLite with
+    /// schema format 4 where 0 and 1 take no space.
+    pub fn serial_type(&self) -> SerialType {
+        match self {
+            SerialValue::Null => SerialType::Null,
+            SerialValue::Reserved => SerialType::Reserved,
+            SerialValue::Float(_) => SerialType::Float,
+            SerialValue::String(s) => SerialType::String(s.len()),
+            SerialValue::Blob(b) => SerialType::Blob(b.len()),
+            SerialValue::Number(0) => SerialType::Zero,
+            SerialValue::Number(1) => SerialType::One,
+            SerialValue::Number(n) => match n {
+                -0x80..=0x7f => SerialType::I8,
+                -0x8000..=0x7fff => SerialType::I16,
+                -0x80_0000..=0x7f_ffff => SerialType::I24,
+                -0x8000_0000..=0x7fff_ffff => SerialType::I32,
+                -0x8000_0000_0000..=0x7fff_ffff_ffff => SerialType::I48,
+                _ => SerialType::I64,
+            },
+        }
+    }
+}
+
+impl Record {
+    /// A record for the values, each with its smallest serial type
+    pub fn new(payload: Vec<SerialValue>) -> Record {
+        let columns: Vec<SerialType> = payload.iter().map(SerialValue::serial_type).collect();
+        let types: u64 = columns.iter().map(|t| VarInt::new(t.code()).width as u64).sum();
+
+        // The header size counts its own varint, which may get wider
+        let mut header_size = VarInt::new(types + 1);
+        while header_size.value != types + header_size.width as u64 {
+            header_size = VarInt::new(types + header_size.width as u64);
+        }
+
+        Record { header_size, columns, payload }
+    }
+
+    /// Size in bytes of the header and the values
+    pub fn size(&self) -> u64 {
+        self.header_size.value + self.columns.iter().map(|t| t.size() as u64).sum::<u64>()
+    }
+}
+
+impl TableLeafCell {
+    /// A cell with all of its payload on the page
+    pub fn new(row_id: i64, payload: Vec<SerialValue>) -> TableLeafCell {
+        let record = Record::new(payload);
+        TableLeafCell {
+            size: VarInt::new(record.size()),
+            row_id: VarInt::new(row_id as u64),
+            overflow: None,
+            record,
+        }
+    }
+}
+
+impl IndexLeafCell {
+    /// A cell with all of its payload on the page
+    pub fn new(key: Vec<SerialValue>) -> IndexLeafCell {
+        let record = Record::new(key);
+        IndexLeafCell {
+            size: VarInt::new(record.size()),
+            overflow: None,
+            record,
+        }
+    }
+}
+
+impl Header {
+    /// Page size in bytes, the header stores 65536 as 1 to fit in 16 bits.
+    pub fn page_size_bytes(&self) -> u32 {
+        match self.page_size {
+            1 => 65536,
+            n => n as u32,
+        }
+    }
+
+    /// Page size without the bytes reserved at the end of each page
+    pub fn usable_size(&self) -> u32 {
+        self.page_size_bytes() - self.reserved_bytes as u32
+    }
+
+    /// Whether the database is in auto-vacuum mode, which has [PointerMap]s.
+    /// A new database without tables has 1 as its largest root page.
+    pub fn is_auto_vacuum(&self) -> bool {
+        self.autovacuum_top_root != 0
+    }
+}
+
+/// Version number written to [Header::sqlite_version] of new databases
+const SQLITE_VERSION_NUMBER: u32 = 3047000;
+
+impl Database {
+    /// An empty database, with only the empty `sqlite_schema` table on page 1
+    pub fn new(options: CreateOptions) -> BinResult<Database> {
+        if !options.page_size.is_power_of_two() || !(512..=65536).contains(&options.page_size) {
+            return Err(error("Page size must be a power of two between 512 and 65536"));
+        }
+        // Cells need at least 480 usable bytes per page to fit 4 per page
+        let usable_size = options.page_size - options.reserved_bytes as u32;
+        if usable_size < 480 {
+            return Err(error("Too many reserved bytes for the page size"));
+        }
+        if options.text_encoding != TextEncoding::Utf8 {
+            return Err(error("Only UTF-8 databases can be created, text is written as UTF-8"));
+        }
+
+        let db_header = Header {
+            page_size: options.page_size as u16 | (options.page_size >> 16) as u16,
+            write_format: 1,
+            read_format: 1,
+            reserved_bytes: options.reserved_bytes,
+            max_payload_fraction: 64,
+            min_payload_fraction: 32,
+            leaf_payload_fraction: 32,
+            file_change_counter: 1,
+            database_page_count: 1,
+            freelist_trunk_page: 0,
+            freelist_page_count: 0,
+            schema_cookie: 0,
+            schema_format: 4,
+            default_page_cache: 0,
+            autovacuum_top_root: (options.auto_vacuum != AutoVacuum::None) as u32,
+            text_encoding: options.text_encoding as u32,
+            user_version: options.user_version,
+            incremental_vacuum: (options.auto_vacuum == AutoVacuum::Incremental) as u32,
+            application_id: options.application_id,
+            reserved: [0; 20],
+            version_valid_for: 1,
+            sqlite_version: SQLITE_VERSION_NUMBER,
+        };
+
+        let schema = TableLeaf {
+            db_header: Some(db_header),
+            page_header: BTreePageHeader {
+                page_type: PageType::LeafTable,
+                first_freeblock: 0,
+                num_cells: 0,
+                // 65536 wraps around to 0, which is what the format expects
+                cell_content_start: usable_size as u16,
+                fragmented_free_bytes: 0,
+                right_most_pointer: None,
+            },
+            cell_pointers: vec![],
+            cells: vec![],
+            freeblocks: vec![],
+        };
+
+        Ok(Database {
+            db_header,
+            pages: vec![Page::TableLeaf(schema)],
+        })
+    }
+
+    /// Create a new empty database file, failing if `path` already exists.
+    pub fn create<P: AsRef<std::path::Path>>(path: P, options: CreateOptions) -> BinResult<Database> {
+        let db = Database::new(options)?;
+        let mut file = std::fs::File::create_new(path)?;
+        db.write_be(&mut file)?;
+        file.sync_all()?;
+        Ok(db)
+    }
+
+    /// Open the database file at `path`, first rolling back a transaction a
+    /// crash left half written, see [crate::journal::rollback]. Pages
+    /// committed to the WAL of a database in WAL mode replace the ones in the
+    /// file, see [crate::wal].
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> BinResult<Database> {
+        crate::journal::rollback(path.as_ref())?;
+        let bytes = crate::wal::read_with_wal(path.as_ref())?;
+        Database::read(&mut Cursor::new(bytes))
+    }
+
+    /// Page by its number, which starts at 1
+    pub fn page(&self, number: u32) -> Option<&Page> {
+        self.pages.get(number.checked_sub(1)? as usize)
+    }
+
+    /// Every row of the table b-tree rooted at page `root`, in `rowid` order
+    pub fn table_rows(&self, root: u32) -> Vec<&TableLeafCell> {
+        match self.page(root) {
+            Some(Page::TableLeaf(leaf)) => leaf.cells.iter().collect(),
+            Some(Page::TableInterior(node)) => node
+                .cells
+                .iter()
+                .map(|cell| cell.left_child)
+                .chain(node.page_header.right_most_pointer)
+                .flat_map(|child| self.table_rows(child))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Every key of the index b-tree rooted at page `root`, in index order
+    pub fn index_keys(&self, root: u32) -> Vec<&Record> {
+        match self.page(root) {
+            Some(Page::IndexLeaf(leaf)) => leaf.cells.iter().map(|cell| &cell.record).collect(),
+            Some(Page::IndexInterior(node)) => {
+                let mut keys = vec![];
+                for cell in &node.cells {
+                    keys.extend(self.index_keys(cell.left_child));
+                    keys.push(&cell.record);
+                }
+                keys.extend(
+                    node.page_header
+                        .right_most_pointer
+                        .map_or(vec![], |child| self.index_keys(child)),
+                );
+                keys
+            }
+            _ => vec![],
+        }
+    }
+}
+
 // * Helper functions and Traits * //
 
+/// Number of the page holding byte [PENDING_BYTE] of the file. SQLite never
+/// reads or writes it and never allocates it, so databases over 1 GiB skip it.
+pub fn lock_byte_page(page_size: u32) -> u32 {
+    (PENDING_BYTE / page_size as u64) as u32 + 1
+}
+
+/// Write `value` at the start of a page of zeros
+fn write_padded<W, T>(w: &mut W, value: &T, endian: Endian, page_size: u32) -> BinResult<()>
+where
+    W: Write + Seek,
+    T: for<'a> BinWrite<Args<'a> = ()>,
+{
+    let mut page = Cursor::new(vec![0u8; page_size as usize]);
+    value.write_options(&mut page, endian, ())?;
+    w.write_all(page.get_ref())?;
+    Ok(())
+}
+
+/// Read the pages counted in the header, each starting at a multiple of the
+/// page size. Pages on the freelist are found first, since they can't be told
+/// apart from b-tree pages, pointer map pages by their page numbers, and
+/// overflow pages last by following the cells of the b-tree pages.
+#[binrw::parser(reader, endian)]
+fn read_pages(header: Header) -> BinResult<Vec<Page>> {
+    let page_size = header.page_size_bytes();
+    let seek = |reader: &mut _, number: u32| -> BinResult<()> {
+        Seek::seek(reader, SeekFrom::Start((number as u64 - 1) * page_size as u64))?;
+        Ok(())
+    };
+
+    let mut trunks = HashMap::new();
+    let mut leaves = HashSet::new();
+    let mut next = header.freelist_trunk_page;
+    // A corrupt list could loop, so stop at the first trunk seen twice
+    while next != 0 && !trunks.contains_key(&next) {
+        seek(reader, next)?;
+        let trunk = FreelistTrunk::read_options(reader, endian, ())?;
+        leaves.extend(trunk.leaves.iter().copied());
+        let number = std::mem::replace(&mut next, trunk.next);
+        trunks.insert(number, trunk);
+    }
+
+    let lock_byte = lock_byte_page(page_size);
+    let entries = header.usable_size() as usize / 5;
+    let pages = (1..=header.database_page_count)
+        .map(|number| match trunks.remove(&number) {
+            _ if number == lock_byte => Ok(Page::LockByte),
+            _ if header.is_pointer_map_page(number) => {
+                seek(reader, number)?;
+                Ok(Page::PointerMap(PointerMap::read_options(reader, endian, (entries,))?))
+            }
+            Some(trunk) => Ok(Page::FreelistTrunk(trunk)),
+            None if leaves.contains(&number) => Ok(Page::FreelistLeaf),
+            None => {
+                seek(reader, number)?;
+                Page::read_options(reader, endian, (header,))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Overflow pages fail to read as b-tree pages, so they replace the error
+    let capacity = header.usable_size() as u64 - 4;
+    let mut overflows = HashMap::new();
+    for (mut number, mut left) in pages.iter().flatten().flat_map(Page::overflows) {
+        while left > 0 && number != 0 && !overflows.contains_key(&number) {
+            seek(reader, number)?;
+            let page = OverflowPage::read_options(reader, endian, (left.min(capacity) as usize,))?;
+            left -= page.content.len() as u64;
+            let next = page.next;
+            overflows.insert(number, page);
+            number = next;
+        }
+    }
+
+    pages
+        .into_iter()
+        .zip(1..)
+        .map(|(page, number)| match overflows.remove(&number) {
+            Some(overflow) => Ok(Page::Overflow(overflow)),
+            None => page,
+        })
+        .collect()
+}
+
+/// The [Overflow] of a payload of `size` bytes with `local` of them in the
+/// cell, which are followed by the page number of the first overflow page
+#[binrw::parser(reader, endian)]
+fn read_overflow(size: u64, local: u64) -> BinResult<Option<Overflow>> {
+    if local == size {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Current(local as i64))?;
+    let page = u32::read_options(reader, endian, ())?;
+    Ok(Some(Overflow { local, page }))
+}
+
+/// A record of `size` bytes, put back together from the cell and its overflow
+/// pages when it has them
+#[binrw::parser(reader, endian)]
+fn read_payload(size: u64, overflow: Option<Overflow>, header: Header) -> BinResult<Record> {
+    let Some(overflow) = overflow else {
+        return Record::read_options(reader, endian, ());
+    };
+    let mut payload = vec![0; overflow.local as usize];
+    reader.read_exact(&mut payload)?;
+    let end = reader.stream_position()? + 4;
+
+    let capacity = header.usable_size() as u64 - 4;
+    let mut number = overflow.page;
+    // Every page adds to the payload, so a corrupt list can't loop forever
+    while (payload.len() as u64) < size {
+        if number == 0 {
+            return Err(binrw::Error::AssertFail {
+                pos: end,
+                message: format!("Overflow pages end {} bytes short", size - payload.len() as u64),
+            });
+        }
+        reader.seek(SeekFrom::Start((number as u64 - 1) * header.page_size_bytes() as u64))?;
+        let count = (size - payload.len() as u64).min(capacity) as usize;
+        let page = OverflowPage::read_options(reader, endian, (count,))?;
+        payload.extend(page.content);
+        number = page.next;
+    }
+
+    reader.seek(SeekFrom::Start(end))?;
+    Record::read_options(&mut Cursor::new(payload), endian, ())
+}
+
+/// The part of the record in the cell, followed by the page number of the
+/// first overflow page when the rest is on overflow pages
+#[binrw::writer(writer, endian)]
+fn write_payload(record: &Record, overflow: Option<Overflow>) -> BinResult<()> {
+    let Some(overflow) = overflow else {
+        return record.write_options(writer, endian, ());
+    };
+    let mut payload = Cursor::new(vec![]);
+    record.write_options(&mut payload, endian, ())?;
+    writer.write_all(&payload.get_ref()[..overflow.local as usize])?;
+    overflow.page.write_options(writer, endian, ())
+}
+
+/// Read the cells at `pointers`, which are offsets from `page_start`
+fn read_cells<R: Read + Seek, C: Cell>(
+    reader: &mut R,
+    endian: Endian,
+    (page_start, pointers, header): (u64, &Vec<u16>, Header),
+) -> BinResult<Vec<C>> {
+    pointers
+        .iter()
+        .map(|&pointer| {
+            reader.seek(SeekFrom::Start(page_start + pointer as u64))?;
+            C::read_options(reader, endian, (header,))
+        })
+        .collect()
+}
+
+/// Follow the list of freeblocks of the page starting at `page_start`
+#[binrw::parser(reader, endian)]
+fn read_freeblocks(page_start: u64, first: u16) -> BinResult<Vec<Freeblock>> {
+    let mut freeblocks: Vec<Freeblock> = vec![];
+    let mut offset = first;
+    // Offsets only grow, which also stops a corrupt list from looping
+    while offset != 0 && freeblocks.last().is_none_or(|last| last.offset < offset) {
+        reader.seek(SeekFrom::Start(page_start + offset as u64))?;
+        let (next, size) = <(u16, u16)>::read_options(reader, endian, ())?;
+        freeblocks.push(Freeblock { offset, size });
+        offset = next;
+    }
+    Ok(freeblocks)
+}
+
+/// Error for a database that can't be created or changed as asked
+pub(crate) fn error(message: impl Into<String>) -> binrw::Error {
+    binrw::Error::AssertFail {
+        pos: 0,
+        message: message.into(),
+    }
+}
+
+impl From<&str> for SerialValue {
+    fn from(value: &str) -> Self {
+        SerialValue::String(value.to_string())
+    }
+}
+
+impl From<i64> for SerialValue {
+    fn from(value: i64) -> Self {
+        SerialValue::Number(value)
+    }
+}
+
+impl From<Vec<u8>> for SerialValue {
+    fn from(value: Vec<u8>) -> Self {
+        SerialValue::Blob(value)
+    }
+}
+
+impl From<()> for SerialValue {
+    fn from(_: ()) -> Self {
+        SerialValue::Null
+    }
+}
+
 fn read_u24_be<R: Read>(r: &mut R) -> BinResult<u32> {
     let mut buf = [0u8; 3];
     r.read_exact(&mut buf)?;
@@ -364,33 +1292,10 @@ fn read_i48_be<R: Read>(r: &mut R) -> BinResult<i64> {
 #[cfg(test)]
 mod planets {
     use super::{SerialType as T, *};
+    use crate::testing::temp_path;
     use pretty_assertions::assert_eq;
     use std::fs::File;
 
-    impl From<&str> for SerialValue {
-        fn from(value: &str) -> Self {
-            SerialValue::String(value.to_string())
-        }
-    }
-
-    impl From<i64> for SerialValue {
-        fn from(value: i64) -> Self {
-            SerialValue::Number(value)
-        }
-    }
-
-    impl From<Vec<u8>> for SerialValue {
-        fn from(value: Vec<u8>) -> Self {
-            SerialValue::Blob(value)
-        }
-    }
-
-    impl From<()> for SerialValue {
-        fn from(_: ()) -> Self {
-            SerialValue::Null
-        }
-    }
-
     // $ sqlite3 data/planets.db .dbinfo
     const DB_HEADER: Header = Header {
         page_size: 4096,
@@ -469,12 +1374,14 @@ mod planets {
                 cells: vec![TableLeafCell {
                     size: VarInt { value: 216, width: 2 },
                     row_id: VarInt::new(1),
+                    overflow: None,
                     record: Record {
                         header_size: VarInt::new(7),
                         columns: vec![T::String(5), T::String(7), T::String(7), T::I8, T::String(189)],
                         payload: vec!["table".into(), "planets".into(), "planets".into(), 2.into(), query]
                     }
                 }],
+                freeblocks: vec![],
             })
         );
     }
@@ -499,6 +1406,7 @@ mod planets {
             TableLeafCell {
                 size: VarInt::new(31),
                 row_id: VarInt::new(1),
+                overflow: None,
                 record: Record {
                     header_size: VarInt::new(7),
                     // TODO: 🔥 This null byte at the start of column is a mystery
@@ -516,6 +1424,7 @@ mod planets {
             TableLeafCell {
                 size: VarInt::new(29),
                 row_id: VarInt::new(2),
+                overflow: None,
                 record: Record {
                     header_size: VarInt::new(7),
                     columns: vec![T::Null, T::String(5), T::String(11), T::I16, T::I32, T::Zero],
@@ -532,6 +1441,7 @@ mod planets {
             TableLeafCell {
                 size: VarInt::new(29),
                 row_id: VarInt::new(3),
+                overflow: None,
                 record: Record {
                     header_size: VarInt::new(7),
                     columns: vec![T::Null, T::String(5), T::String(11), T::I16, T::I32, T::One],
@@ -548,6 +1458,7 @@ mod planets {
             TableLeafCell {
                 size: VarInt::new(29),
                 row_id: VarInt::new(4),
+                overflow: None,
                 record: Record {
                     header_size: VarInt::new(7),
                     columns: vec![T::Null, T::String(4), T::String(11), T::I16, T::I32, T::I8],
@@ -564,6 +1475,7 @@ mod planets {
             TableLeafCell {
                 size: VarInt::new(31),
                 row_id: VarInt::new(5),
+                overflow: None,
                 record: Record {
                     header_size: VarInt::new(7),
                     columns: vec![T::Null, T::String(7), T::String(9), T::I24, T::I32, T::I8],
@@ -580,6 +1492,7 @@ mod planets {
             TableLeafCell {
                 size: VarInt::new(30),
                 row_id: VarInt::new(6),
+                overflow: None,
                 record: Record {
                     header_size: VarInt::new(7),
                     columns: vec![T::Null, T::String(6), T::String(9), T::I24, T::I32, T::I8],
@@ -596,6 +1509,7 @@ mod planets {
             TableLeafCell {
                 size: VarInt::new(32),
                 row_id: VarInt::new(7),
+                overflow: None,
                 record: Record {
                     header_size: VarInt::new(7),
                     columns: vec![T::Null, T::String(6), T::String(9), T::I24, T::I48, T::I8],
@@ -612,6 +1526,7 @@ mod planets {
             TableLeafCell {
                 size: VarInt::new(33),
                 row_id: VarInt::new(8),
+                overflow: None,
                 record: Record {
                     header_size: VarInt::new(7),
                     columns: vec![T::Null, T::String(7), T::String(9), T::I24, T::I48, T::I8],
@@ -633,8 +1548,189 @@ mod planets {
                 db_header: None,
                 page_header,
                 cell_pointers,
-                cells
+                cells,
+                freeblocks: vec![],
             })
         );
     }
+
+    #[test]
+    fn write_database() {
+        let bytes = std::fs::read("data/planets.db").expect("Failed to read planets.db");
+        let db: Database = Cursor::new(&bytes).read_be().expect("Failed to parse database");
+
+        let mut out = Cursor::new(vec![]);
+        db.write_be(&mut out).expect("Failed to write database");
+        assert!(out.get_ref() == &bytes, "Written database differs");
+
+        // Deleted cells are left behind in freeblocks of pages SQLite changed,
+        // so these only round trip to the same pages.
+        let mut file = File::open("data/analyze.db").expect("Failed to open analyze.db");
+        let db: Database = file.read_be().expect("Failed to parse database");
+
+        let mut out = Cursor::new(vec![]);
+        db.write_be(&mut out).expect("Failed to write database");
+        out.set_position(0);
+        assert_eq!(
+            Database::read_be(&mut out).expect("Failed to parse written database"),
+            db
+        );
+    }
+
+    #[test]
+    fn read_overflow() {
+        let bytes = std::fs::read("data/overflow.db").expect("Failed to read overflow.db");
+        let db: Database = Cursor::new(&bytes).read_be().expect("Failed to parse database");
+
+        // $ sqlite3 data/overflow.db "select rootpage from sqlite_schema"
+        let mut bodies: Vec<String> = (1..=40).map(|i| format!("{} {}", i, "x".repeat(i * 60))).collect();
+        let rows = db.table_rows(3);
+        assert_eq!(rows.len(), 40);
+        for (row, body) in rows.into_iter().zip(&bodies) {
+            assert_eq!(row.record.payload[2], body.as_str().into());
+            assert_eq!(row.overflow.is_some(), row.size.value > 512 - 35, "{}", body.len());
+        }
+        bodies.sort();
+        let keys: Vec<_> = db.index_keys(4).into_iter().map(|key| key.payload[0].clone()).collect();
+        assert_eq!(
+            keys,
+            bodies
+                .iter()
+                .map(|body| body.as_str().into())
+                .collect::<Vec<SerialValue>>()
+        );
+
+        let overflows = db.pages.iter().filter(|page| matches!(page, Page::Overflow(_))).count();
+        assert!(overflows > 100, "{}", overflows);
+        assert_eq!(db.check_pointer_map(), vec![]);
+
+        let mut out = Cursor::new(vec![]);
+        db.write_be(&mut out).expect("Failed to write database");
+        // Balancing left old cell pointers behind in the unallocated space
+        out.set_position(0);
+        assert_eq!(
+            Database::read_be(&mut out).expect("Failed to parse written database"),
+            db
+        );
+        let overflow = |bytes: &[u8]| {
+            bytes
+                .chunks(512)
+                .zip(&db.pages)
+                .filter(|(_, page)| matches!(page, Page::Overflow(_)))
+                .map(|(bytes, _)| bytes.to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert!(overflow(out.get_ref()) == overflow(&bytes), "Overflow pages differ");
+    }
+
+    #[test]
+    fn local_size() {
+        // Payloads on 512 byte pages, from the largest kept whole to one
+        // filling two overflow pages
+        let t = vec![
+            (PageType::LeafTable, 477, 477),
+            (PageType::LeafTable, 478, 39),
+            (PageType::LeafTable, 647, 139),
+            (PageType::LeafTable, 39 + 2 * 508, 39),
+            (PageType::LeafIndex, 102, 102),
+            (PageType::LeafIndex, 103, 39),
+            (PageType::InteriorIndex, 597, 89),
+        ];
+        for (page_type, size, exp) in t.into_iter() {
+            assert_eq!(page_type.local_size(size, 512), exp, "{:?} {}", page_type, size);
+        }
+    }
+
+    #[test]
+    fn write_record() {
+        let t = vec![
+            (vec![().into(), 0.into(), 1.into()], vec![4, 0, 8, 9]),
+            (vec![(-1).into(), 300.into()], vec![3, 1, 2, 0xff, 0x01, 0x2c]),
+            (
+                vec![SerialValue::Float(0.5), "ab".into()],
+                vec![3, 7, 17, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0, b'a', b'b'],
+            ),
+            (
+                vec![(1 << 40).into(), vec![0xca].into()],
+                vec![3, 5, 14, 0x01, 0, 0, 0, 0, 0, 0xca],
+            ),
+        ];
+
+        for (values, exp) in t.into_iter() {
+            let record = Record::new(values);
+            let mut out = Cursor::new(vec![]);
+            record.write_be(&mut out).expect("Failed to write record");
+            assert_eq!(out.get_ref(), &exp);
+            assert_eq!(record.size(), exp.len() as u64);
+            assert_eq!(Record::read_be(&mut Cursor::new(&exp)).unwrap(), record);
+        }
+
+        // A header longer than 127 bytes needs a 2 byte size varint
+        let record = Record::new(vec![().into(); 127]);
+        assert_eq!(record.header_size, VarInt::new(129));
+
+        // Same cell as SQLite wrote for Mercury
+        let mut file = File::open("data/planets.db").expect("Failed to open planets.db");
+        let db: Database = file.read_be().expect("Failed to parse database");
+        let Page::TableLeaf(page) = &db.pages[1] else {
+            panic!("Not a table leaf")
+        };
+        let cell = &page.cells[0];
+        assert_eq!(&TableLeafCell::new(1, cell.record.payload.clone()), cell);
+    }
+
+    #[test]
+    fn create_database() {
+        let path = temp_path("schema-create");
+        let _ = std::fs::remove_file(&path);
+
+        let options = CreateOptions {
+            page_size: 65536,
+            reserved_bytes: 8,
+            text_encoding: TextEncoding::Utf8,
+            user_version: 7,
+            application_id: 0x0f0f,
+            auto_vacuum: AutoVacuum::Incremental,
+        };
+        let db = Database::create(&path, options).expect("Failed to create database");
+        assert!(Database::create(&path, options).is_err(), "Overwrote an existing file");
+
+        let bytes = std::fs::read(&path).expect("Failed to read database");
+        std::fs::remove_file(&path).expect("Failed to remove database");
+        assert_eq!(bytes.len(), 65536);
+
+        let read: Database = Cursor::new(&bytes).read_be().expect("Failed to parse database");
+        assert_eq!(read, db);
+        let header = read.db_header;
+        assert_eq!(
+            (header.page_size_bytes(), header.reserved_bytes, header.text_encoding),
+            (65536, 8, 1)
+        );
+        assert_eq!((header.user_version, header.application_id), (7, 0x0f0f));
+        assert_eq!((header.autovacuum_top_root, header.incremental_vacuum), (1, 1));
+        assert_eq!(read.table_rows(1), Vec::<&TableLeafCell>::new());
+
+        let t = vec![
+            (1000, 0, TextEncoding::Utf8),
+            (512, 33, TextEncoding::Utf8),
+            (131072, 0, TextEncoding::Utf8),
+            (4096, 0, TextEncoding::Utf16le),
+            (4096, 0, TextEncoding::Utf16be),
+        ];
+        for (page_size, reserved_bytes, text_encoding) in t.into_iter() {
+            let options = CreateOptions {
+                page_size,
+                reserved_bytes,
+                text_encoding,
+                ..Default::default()
+            };
+            assert!(
+                Database::new(options).is_err(),
+                "{} {} {:?}",
+                page_size,
+                reserved_bytes,
+                text_encoding
+            );
+        }
+    }
 }
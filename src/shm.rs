@@ -0,0 +1,750 @@
+//! # WAL-index
+//!
+//! Connections to a database in WAL mode share a `-shm` file next to it,
+//! mapped into memory, which indexes the WAL so readers don't have to scan
+//! it to find the newest frame of a page. It is rebuilt from the WAL by the
+//! first connection to open the database, so it is never needed to read the
+//! database, but a crash or a bug can leave it out of step with the WAL.
+//!
+//! [Docs](https://www.sqlite.org/walformat.html#the_wal_index_file_format)
+//!
+//! Unlike every other file, it is in the byte order of the machine, except
+//! the salts which are copied from the WAL header. It is made of 32 KiB
+//! segments, each holding the page numbers of 4096 frames and a hash table
+//! from page numbers to them. The first segment starts with two copies of
+//! the [WalIndexHeader] and the [CheckpointInfo] instead of its first 34 page
+//! numbers.
+//!
+//! Commits append to the hash tables and then write the header, the second
+//! copy first, and a WAL-index that doesn't match its WAL is rebuilt with
+//! [WalIndex::recover] before that. Checkpoints only write the
+//! [CheckpointInfo], moving the read marks of free reader slots and counting
+//! the frames copied back.
+//!
+//! ```
+//! use rsqlite::{shm::WalIndex, wal::Wal};
+//! use std::fs::File;
+//!
+//! let index = WalIndex::read(&mut File::open("data/wal.db-shm").unwrap()).unwrap();
+//! let wal = Wal::read(&mut File::open("data/wal.db-wal").unwrap()).unwrap();
+//! assert_eq!(index.frame(2), Some(3));
+//! assert!(index.check(wal.as_ref()).is_empty());
+//! ```
+
+use crate::wal::{self, Frame, Wal, WalHeader};
+use binrw::{binrw, BinRead, BinResult, BinWrite, Endian};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Size of a segment of the WAL-index
+pub const SEGMENT_SIZE: usize = 32768;
+
+/// [CheckpointInfo::read_marks] of a reader slot no one uses
+pub const READ_MARK_NOT_USED: u32 = 0xffffffff;
+
+/// Frames indexed by a segment, the first one has the headers instead of some
+const SEGMENT_FRAMES: usize = 4096;
+
+/// Slots of the hash table of a segment, twice the frames to keep it sparse
+const HASH_SLOTS: usize = 2 * SEGMENT_FRAMES;
+
+/// Size of the two [WalIndexHeader]s and the [CheckpointInfo]
+const HEADERS_SIZE: usize = 136;
+
+/// Size of a [WalIndexHeader]
+const HEADER_SIZE: usize = 48;
+
+/**
+ * The 48 byte header of the WAL-index, written twice. Readers only trust it
+ * when both copies are the same, since a writer changes the second one first.
+ *
+ * | Offset | Size | Description                                            |
+ * |--------|------|--------------------------------------------------------|
+ * | 0      | 4    | WAL-index format version, 3007000                      |
+ * | 4      | 4    | Unused padding                                         |
+ * | 8      | 4    | Incremented by every transaction                       |
+ * | 12     | 1    | 1 once the header is initialized                       |
+ * | 13     | 1    | 1 if the WAL checksums are of big endian words         |
+ * | 14     | 2    | Page size, 1 for 65536                                 |
+ * | 16     | 4    | Number of valid and committed frames in the WAL        |
+ * | 20     | 4    | Size of the database in pages                          |
+ * | 24     | 8    | Checksum of the last frame in the WAL                  |
+ * | 32     | 8    | Salts of the WAL header, in the byte order of the WAL  |
+ * | 40     | 8    | Checksum of the first 40 bytes of this header          |
+ */
+#[binrw]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WalIndexHeader {
+    pub version: u32,
+    pub unused: u32,
+    pub change: u32,
+    pub is_init: u8,
+    pub big_endian_checksum: u8,
+    pub page_size: u16,
+    pub max_frame: u32,
+    pub page_count: u32,
+    pub frame_checksum: [u32; 2],
+    #[brw(big)]
+    pub salt: [u32; 2],
+    pub checksum: [u32; 2],
+}
+
+/**
+ * The 40 bytes after the headers, shared by checkpoints and readers.
+ *
+ * | Offset | Size | Description                                            |
+ * |--------|------|--------------------------------------------------------|
+ * | 96     | 4    | Frames already copied back to the database file        |
+ * | 100    | 20   | Read marks, the last frame each reader slot uses       |
+ * | 120    | 8    | Bytes locked by connections, with no content           |
+ * | 128    | 4    | Frames a checkpoint tried to copy back                 |
+ * | 132    | 4    | Unused                                                 |
+ */
+#[binrw]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CheckpointInfo {
+    pub backfill: u32,
+    pub read_marks: [u32; 5],
+    pub locks: [u8; 8],
+    pub backfill_attempted: u32,
+    pub unused: u32,
+}
+
+/// The page numbers of the frames of a segment, and a hash table to them
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashTable {
+    /// Frame number before the first one of the segment
+    pub first_frame: u32,
+    /// Page number of each frame, 0 for frames not written yet
+    pub pages: Vec<u32>,
+    /// Each slot is 0 or the index of a frame in [HashTable::pages] plus 1
+    pub slots: Vec<u16>,
+}
+
+/// A decoded `-shm` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalIndex {
+    pub headers: [WalIndexHeader; 2],
+    pub checkpoint: CheckpointInfo,
+    pub tables: Vec<HashTable>,
+}
+
+/// A way the WAL-index disagrees with itself or with the WAL
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The two copies of the header differ, a writer stopped halfway
+    Headers,
+    /// The header was never initialized or its checksum is wrong
+    Header,
+    /// There is no WAL with a valid header to index
+    NoWal,
+    Salt {
+        index: [u32; 2],
+        wal: [u32; 2],
+    },
+    PageSize {
+        index: u32,
+        wal: u32,
+    },
+    /// The header has more frames than the WAL has valid and committed ones
+    MaxFrame {
+        index: u32,
+        wal: u32,
+    },
+    /// Size of the database after the last frame the header counts
+    PageCount {
+        index: u32,
+        wal: u32,
+    },
+    FrameChecksum {
+        index: [u32; 2],
+        wal: [u32; 2],
+    },
+    /// More frames were copied back than the header counts
+    Backfill {
+        backfill: u32,
+        max_frame: u32,
+    },
+    /// A reader uses more frames than the header counts
+    ReadMark {
+        reader: usize,
+        mark: u32,
+    },
+    /// A frame indexed under the wrong page number
+    FramePage {
+        frame: u32,
+        index: u32,
+        wal: u32,
+    },
+    /// The newest frame of a page found through the hash tables is not the
+    /// one in the WAL, or there is none
+    Lookup {
+        page: u32,
+        index: Option<u32>,
+        wal: u32,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Headers => write!(f, "the two header copies differ"),
+            Problem::Header => write!(f, "the header is not initialized or its checksum is wrong"),
+            Problem::NoWal => write!(f, "there is no valid WAL"),
+            Problem::Salt { index, wal } => write!(f, "salts are {:08x?}, the WAL has {:08x?}", index, wal),
+            Problem::PageSize { index, wal } => write!(f, "page size is {}, the WAL has {}", index, wal),
+            Problem::MaxFrame { index, wal } => {
+                write!(f, "{} frames are indexed, the WAL has {} committed frames", index, wal)
+            }
+            Problem::PageCount { index, wal } => write!(f, "database size is {} pages, the WAL has {}", index, wal),
+            Problem::FrameChecksum { index, wal } => {
+                write!(f, "last frame checksum is {:08x?}, the WAL has {:08x?}", index, wal)
+            }
+            Problem::Backfill { backfill, max_frame } => {
+                write!(f, "{} frames are checkpointed out of {}", backfill, max_frame)
+            }
+            Problem::ReadMark { reader, mark } => write!(f, "reader {} reads up to frame {}", reader, mark),
+            Problem::FramePage { frame, index, wal } => {
+                write!(
+                    f,
+                    "frame {} is indexed as page {}, the WAL has page {}",
+                    frame, index, wal
+                )
+            }
+            Problem::Lookup { page, index, wal } => match index {
+                Some(index) => write!(
+                    f,
+                    "page {} is found in frame {}, the WAL has it in {}",
+                    page, index, wal
+                ),
+                None => write!(f, "page {} is not found, the WAL has it in frame {}", page, wal),
+            },
+        }
+    }
+}
+
+impl WalIndexHeader {
+    /// Is the header initialized, with the right checksum?
+    pub fn is_valid(&self) -> bool {
+        self.is_init == 1 && self.version == wal::WAL_VERSION && self.sum() == self.checksum
+    }
+
+    /// Checksum of the first 40 bytes, of native words
+    fn sum(&self) -> [u32; 2] {
+        let mut bytes = Cursor::new(vec![]);
+        self.write_options(&mut bytes, Endian::NATIVE, ())
+            .expect("Failed to write WAL-index header");
+        wal::checksum(cfg!(target_endian = "big"), [0, 0], &bytes.into_inner()[..40])
+    }
+
+    pub fn page_size_bytes(&self) -> u32 {
+        match self.page_size {
+            1 => 65536,
+            size => size as u32,
+        }
+    }
+}
+
+impl HashTable {
+    /// Segment `i` with no frames
+    fn new(i: usize) -> HashTable {
+        let skip = if i == 0 { HEADERS_SIZE / 4 } else { 0 };
+        HashTable {
+            first_frame: (i * SEGMENT_FRAMES).saturating_sub(HEADERS_SIZE / 4) as u32,
+            pages: vec![0; SEGMENT_FRAMES - skip],
+            slots: vec![0; HASH_SLOTS],
+        }
+    }
+
+    /// Index frame `frame` of this segment as a copy of `page`. Frames from
+    /// it on are forgotten first, left over from a transaction that never
+    /// committed or from before the WAL started over, like SQLite does.
+    fn insert(&mut self, frame: u32, page: u32) {
+        let index = (frame - self.first_frame) as usize;
+        if index == 1 || self.pages[index - 1] != 0 {
+            self.truncate(frame - 1);
+        }
+        self.pages[index - 1] = page;
+        let mut slot = hash(page);
+        while self.slots[slot] != 0 {
+            slot = (slot + 1) % HASH_SLOTS;
+        }
+        self.slots[slot] = index as u16;
+    }
+
+    /// Forget the frames after `max_frame`
+    fn truncate(&mut self, max_frame: u32) {
+        let keep = max_frame.saturating_sub(self.first_frame) as usize;
+        self.pages.iter_mut().skip(keep).for_each(|page| *page = 0);
+        self.slots
+            .iter_mut()
+            .filter(|slot| **slot as usize > keep)
+            .for_each(|slot| *slot = 0);
+    }
+
+    /// Newest frame of `page` in this segment up to `max_frame`
+    fn frame(&self, page: u32, max_frame: u32) -> Option<u32> {
+        let mut newest = None;
+        let mut slot = hash(page);
+        for _ in 0..HASH_SLOTS {
+            let index = *self.slots.get(slot)? as usize;
+            if index == 0 {
+                break;
+            }
+            let frame = self.first_frame + index as u32;
+            if self.pages.get(index - 1) == Some(&page) && frame <= max_frame {
+                newest = newest.max(Some(frame));
+            }
+            slot = (slot + 1) % HASH_SLOTS;
+        }
+        newest
+    }
+}
+
+impl WalIndex {
+    /// A WAL-index of no frames of a WAL that starts with `header`, like
+    /// after the WAL starts over
+    pub fn new(header: &WalHeader) -> WalIndex {
+        let page_size = header.page_size;
+        let mut index_header = WalIndexHeader {
+            version: wal::WAL_VERSION,
+            unused: 0,
+            change: 0,
+            is_init: 1,
+            big_endian_checksum: header.is_big_endian() as u8,
+            page_size: ((page_size & 0xff00) | (page_size >> 16)) as u16,
+            max_frame: 0,
+            page_count: 0,
+            frame_checksum: header.checksum,
+            salt: header.salt,
+            checksum: [0, 0],
+        };
+        index_header.checksum = index_header.sum();
+        let mut read_marks = [READ_MARK_NOT_USED; 5];
+        read_marks[..2].fill(0);
+
+        WalIndex {
+            headers: [index_header; 2],
+            checkpoint: CheckpointInfo {
+                backfill: 0,
+                read_marks,
+                locks: [0; 8],
+                backfill_attempted: 0,
+                unused: 0,
+            },
+            tables: vec![],
+        }
+    }
+
+    /// Rebuild the WAL-index of `wal` from its committed frames, like the
+    /// first connection to open the database does
+    pub fn recover(wal: &Wal) -> WalIndex {
+        let mut index = WalIndex::new(&wal.header);
+        index.append(wal.committed());
+        index
+    }
+
+    /// Read a WAL-index written by a machine with the same byte order
+    pub fn read<R: Read + Seek>(reader: &mut R) -> BinResult<WalIndex> {
+        let headers = [WalIndexHeader::read_ne(reader)?, WalIndexHeader::read_ne(reader)?];
+        let checkpoint = CheckpointInfo::read_ne(reader)?;
+        // Segments are read from the start of the file
+        let mut bytes = vec![0; HEADERS_SIZE];
+        reader.read_to_end(&mut bytes)?;
+
+        let tables = bytes
+            .chunks_exact(SEGMENT_SIZE)
+            .enumerate()
+            .map(|(i, segment)| {
+                let (pages, slots) = segment.split_at(SEGMENT_SIZE / 2);
+                let skip = if i == 0 { HEADERS_SIZE / 4 } else { 0 };
+                HashTable {
+                    first_frame: (i * SEGMENT_FRAMES).saturating_sub(HEADERS_SIZE / 4) as u32,
+                    pages: pages
+                        .chunks_exact(4)
+                        .skip(skip)
+                        .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect(),
+                    slots: slots
+                        .chunks_exact(2)
+                        .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Ok(WalIndex { headers, checkpoint, tables })
+    }
+
+    /**
+     * Index `frames`, appended to the WAL after the frames the header counts,
+     * and count them in the header up to the last commit frame among them.
+     */
+    pub fn append(&mut self, frames: &[Frame]) {
+        let mut header = self.headers[0];
+        for (number, frame) in (header.max_frame + 1..).zip(frames) {
+            let segment = segment(number);
+            while self.tables.len() <= segment {
+                self.tables.push(HashTable::new(self.tables.len()));
+            }
+            self.tables[segment].insert(number, frame.header.page_number);
+            if frame.header.is_commit() {
+                header.max_frame = number;
+                header.page_count = frame.header.commit_size;
+                header.frame_checksum = frame.header.checksum;
+            }
+        }
+        header.change = header.change.wrapping_add(1);
+        header.checksum = header.sum();
+        self.headers = [header; 2];
+    }
+
+    /// Write the whole WAL-index in the byte order of this machine, the
+    /// second copy of the header first
+    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> BinResult<()> {
+        self.write_from(writer, HEADER_SIZE)
+    }
+
+    /**
+     * Write the headers, and the segments with frames from `first` on,
+     * leaving the [CheckpointInfo] alone, which checkpoints and readers
+     * change too. Enough after [WalIndex::append] to a WAL-index read from
+     * `writer`.
+     */
+    pub fn write_frames<W: Write + Seek>(&self, writer: &mut W, first: u32) -> BinResult<()> {
+        writer.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+        self.headers[1].write_options(writer, Endian::NATIVE, ())?;
+        self.write_from(writer, (segment(first) * SEGMENT_SIZE).max(HEADERS_SIZE))
+    }
+
+    /// Write [CheckpointInfo::backfill] and [CheckpointInfo::backfill_attempted]
+    /// alone, which only a checkpoint changes, holding
+    /// [WAL_CKPT_LOCK](crate::lock::WAL_CKPT_LOCK)
+    pub fn write_backfill<W: Write + Seek>(&self, writer: &mut W) -> BinResult<()> {
+        writer.seek(SeekFrom::Start(2 * HEADER_SIZE as u64))?;
+        self.checkpoint.backfill.write_options(writer, Endian::NATIVE, ())?;
+        writer.seek(SeekFrom::Start(2 * HEADER_SIZE as u64 + 32))?;
+        self.checkpoint
+            .backfill_attempted
+            .write_options(writer, Endian::NATIVE, ())
+    }
+
+    /// Write read mark `i` alone, which only the connection holding reader
+    /// slot `i` exclusively changes
+    pub fn write_read_mark<W: Write + Seek>(&self, writer: &mut W, i: usize) -> BinResult<()> {
+        writer.seek(SeekFrom::Start((2 * HEADER_SIZE + 4 + 4 * i) as u64))?;
+        self.checkpoint.read_marks[i].write_options(writer, Endian::NATIVE, ())
+    }
+
+    /// The newest frame of `page` a reader would use, up to the last frame
+    /// the first header counts
+    pub fn frame(&self, page: u32) -> Option<u32> {
+        let max_frame = self.headers[0].max_frame;
+        self.tables.iter().rev().find_map(|table| table.frame(page, max_frame))
+    }
+
+    /**
+     * Every way the index disagrees with itself or with `wal`, the WAL next
+     * to it if it has a valid header. An empty list means readers would find
+     * the same pages through the index as by reading the WAL.
+     */
+    pub fn check(&self, wal: Option<&Wal>) -> Vec<Problem> {
+        let mut problems = vec![];
+        let header = &self.headers[0];
+        if self.headers[0] != self.headers[1] {
+            problems.push(Problem::Headers);
+        }
+        if !header.is_valid() {
+            problems.push(Problem::Header);
+        }
+
+        let max_frame = header.max_frame;
+        if self.checkpoint.backfill > max_frame {
+            problems.push(Problem::Backfill {
+                backfill: self.checkpoint.backfill,
+                max_frame,
+            });
+        }
+        for (reader, &mark) in self.checkpoint.read_marks.iter().enumerate() {
+            if mark != READ_MARK_NOT_USED && mark > max_frame {
+                problems.push(Problem::ReadMark { reader, mark });
+            }
+        }
+
+        let Some(wal) = wal else {
+            problems.push(Problem::NoWal);
+            return problems;
+        };
+        if header.salt != wal.header.salt {
+            problems.push(Problem::Salt {
+                index: header.salt,
+                wal: wal.header.salt,
+            });
+        }
+        if header.page_size_bytes() != wal.header.page_size {
+            problems.push(Problem::PageSize {
+                index: header.page_size_bytes(),
+                wal: wal.header.page_size,
+            });
+        }
+
+        let frames = wal.committed();
+        let Some(last) = frames.get((max_frame as usize).wrapping_sub(1)) else {
+            if max_frame > 0 {
+                problems.push(Problem::MaxFrame {
+                    index: max_frame,
+                    wal: frames.len() as u32,
+                });
+            }
+            return problems;
+        };
+        if header.page_count != last.header.commit_size {
+            problems.push(Problem::PageCount {
+                index: header.page_count,
+                wal: last.header.commit_size,
+            });
+        }
+        if header.frame_checksum != last.header.checksum {
+            problems.push(Problem::FrameChecksum {
+                index: header.frame_checksum,
+                wal: last.header.checksum,
+            });
+        }
+
+        let mut newest = BTreeMap::new();
+        for (i, frame) in frames[..max_frame as usize].iter().enumerate() {
+            let number = i as u32 + 1;
+            let page = frame.header.page_number;
+            newest.insert(page, number);
+            let indexed = self.tables.iter().find_map(|table| {
+                let offset = number.checked_sub(table.first_frame + 1)?;
+                table.pages.get(offset as usize).copied()
+            });
+            if indexed != Some(page) {
+                problems.push(Problem::FramePage {
+                    frame: number,
+                    index: indexed.unwrap_or(0),
+                    wal: page,
+                });
+            }
+        }
+        for (page, frame) in newest {
+            let index = self.frame(page);
+            if index != Some(frame) {
+                problems.push(Problem::Lookup { page, index, wal: frame });
+            }
+        }
+
+        problems
+    }
+
+    /// Write from `start` on, then the first copy of the header
+    fn write_from<W: Write + Seek>(&self, writer: &mut W, start: usize) -> BinResult<()> {
+        let bytes = self.bytes()?;
+        writer.seek(SeekFrom::Start(start as u64))?;
+        writer.write_all(&bytes[start..])?;
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_all(&bytes[..HEADER_SIZE])?;
+        Ok(())
+    }
+
+    /// The WAL-index as SQLite maps it, a segment at least
+    fn bytes(&self) -> BinResult<Vec<u8>> {
+        let mut bytes = Cursor::new(vec![0; SEGMENT_SIZE * self.tables.len().max(1)]);
+        for header in &self.headers {
+            header.write_options(&mut bytes, Endian::NATIVE, ())?;
+        }
+        self.checkpoint.write_options(&mut bytes, Endian::NATIVE, ())?;
+        for (i, table) in self.tables.iter().enumerate() {
+            let start = (i + 1) * SEGMENT_SIZE - HASH_SLOTS * 2 - table.pages.len() * 4;
+            bytes.seek(SeekFrom::Start(start as u64))?;
+            for page in &table.pages {
+                bytes.write_all(&page.to_ne_bytes())?;
+            }
+            for slot in &table.slots {
+                bytes.write_all(&slot.to_ne_bytes())?;
+            }
+        }
+        Ok(bytes.into_inner())
+    }
+}
+
+/// Path of the WAL-index of the database at `path`
+pub fn shm_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push("-shm");
+    PathBuf::from(name)
+}
+
+/// Check the WAL-index of the database at `path` against its WAL, see
+/// [WalIndex::check]
+pub fn check(path: &Path) -> BinResult<Vec<Problem>> {
+    let index = WalIndex::read(&mut File::open(shm_path(path))?)?;
+    let wal = match File::open(wal::wal_path(path)) {
+        Ok(mut file) => Wal::read(&mut file)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    Ok(index.check(wal.as_ref()))
+}
+
+// * Helper functions * //
+
+/// Segment of the WAL-index with frame `frame`, counting from 1
+fn segment(frame: u32) -> usize {
+    (frame as usize - 1 + HEADERS_SIZE / 4) / SEGMENT_FRAMES
+}
+
+/// First slot of the hash table to look for `page` in
+fn hash(page: u32) -> usize {
+    (page as usize * 383) % HASH_SLOTS
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    type Change = Box<dyn Fn(&mut Vec<u8>)>;
+
+    fn read() -> (Vec<u8>, Wal) {
+        let shm = std::fs::read("data/wal.db-shm").expect("Failed to read wal.db-shm");
+        let mut file = File::open("data/wal.db-wal").expect("Failed to open wal.db-wal");
+        (shm, Wal::read(&mut file).unwrap().expect("Invalid WAL header"))
+    }
+
+    #[test]
+    fn read_index() {
+        let (shm, wal) = read();
+        let index = WalIndex::read(&mut Cursor::new(shm)).unwrap();
+        let header = index.headers[0];
+        assert!(header.is_valid());
+        assert_eq!(
+            (header.max_frame, header.page_count, header.page_size_bytes()),
+            (3, 2, 1024)
+        );
+        assert_eq!(header.salt, wal.header.salt);
+        assert_eq!(index.tables.len(), 1);
+        assert_eq!(index.tables[0].pages.len(), SEGMENT_FRAMES - HEADERS_SIZE / 4);
+        assert_eq!(index.tables[0].pages[..4], [2, 2, 2, 0]);
+        assert_eq!((index.frame(1), index.frame(2)), (None, Some(3)));
+    }
+
+    #[test]
+    fn recover() {
+        let (shm, wal) = read();
+        let written = WalIndex::read(&mut Cursor::new(shm)).unwrap();
+        let index = WalIndex::recover(&wal);
+        assert!(index.check(Some(&wal)).is_empty());
+        assert_eq!(index.tables, written.tables);
+        assert_eq!(index.headers[0].max_frame, written.headers[0].max_frame);
+
+        let mut bytes = Cursor::new(vec![]);
+        index.write(&mut bytes).unwrap();
+        assert_eq!(bytes.get_ref().len(), SEGMENT_SIZE);
+        bytes.set_position(0);
+        assert_eq!(WalIndex::read(&mut bytes).unwrap(), index);
+    }
+
+    #[test]
+    fn append() {
+        let header = WalHeader::new(512, 0, [1, 2]);
+        let frames = |pages: std::ops::Range<u32>, commit: bool| -> Vec<Frame> {
+            let last = pages.end - 1;
+            pages
+                .map(|page| Frame::new(&header, [0, 0], page, if commit && page == last { 9 } else { 0 }, &[]))
+                .collect()
+        };
+
+        // A transaction that never committed is overwritten by the next one
+        let mut index = WalIndex::new(&header);
+        index.append(&frames(1..4, true));
+        index.append(&frames(10..12, false));
+        assert_eq!((index.headers[0].max_frame, index.frame(10)), (3, None));
+        index.append(&frames(20..21, true));
+        assert_eq!(index.tables[0].pages[..5], [1, 2, 3, 20, 0]);
+        assert_eq!((index.frame(20), index.frame(11)), (Some(4), None));
+
+        // Frames past the first segment go to the next ones
+        let many = frames(100..5100, true);
+        index.append(&many);
+        assert_eq!(index.tables.len(), 2);
+        assert_eq!(index.tables[1].first_frame, 4062);
+        assert_eq!(
+            (index.frame(2), index.frame(5099), index.frame(4100)),
+            (Some(2), Some(5004), Some(4005))
+        );
+        let mut wal = Wal {
+            header,
+            frames: frames(1..4, true),
+        };
+        wal.frames.extend(frames(20..21, true));
+        wal.frames.extend(many);
+        assert!(index.check(Some(&wal)).is_empty());
+
+        // Only the headers and the segments with new frames are written
+        let mut bytes = Cursor::new(vec![0xff; 2 * SEGMENT_SIZE]);
+        index.write_frames(&mut bytes, 4063).unwrap();
+        let bytes = bytes.into_inner();
+        assert!(bytes[2 * HEADER_SIZE..SEGMENT_SIZE].iter().all(|b| *b == 0xff));
+        let read = WalIndex::read(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!((read.headers, &read.tables[1]), (index.headers, &index.tables[1]));
+    }
+
+    #[test]
+    fn check() {
+        // Offset of a header field in both copies
+        let both = |shm: &mut Vec<u8>, offset: usize, value: u32| {
+            for start in [offset, offset + 48] {
+                shm[start..start + 4].copy_from_slice(&value.to_ne_bytes());
+            }
+        };
+        let slot = SEGMENT_SIZE / 2 + hash(2) * 2;
+        let t: Vec<(&str, Change, Vec<Problem>)> = vec![
+            ("as written", Box::new(|_| {}), vec![]),
+            ("second copy", Box::new(|shm| shm[48 + 8] ^= 1), vec![Problem::Headers]),
+            (
+                "max frame",
+                Box::new(move |shm| both(shm, 16, 4)),
+                vec![Problem::Header, Problem::MaxFrame { index: 4, wal: 3 }],
+            ),
+            (
+                "backfill",
+                Box::new(|shm| shm[96..100].copy_from_slice(&9u32.to_ne_bytes())),
+                vec![Problem::Backfill { backfill: 9, max_frame: 3 }],
+            ),
+            (
+                "frame page",
+                Box::new(|shm| shm[HEADERS_SIZE + 4..HEADERS_SIZE + 8].copy_from_slice(&5u32.to_ne_bytes())),
+                vec![Problem::FramePage { frame: 2, index: 5, wal: 2 }],
+            ),
+            (
+                "hash slot",
+                Box::new(move |shm| shm[slot + 4..slot + 6].copy_from_slice(&[0, 0])),
+                vec![Problem::Lookup {
+                    page: 2,
+                    index: Some(2),
+                    wal: 3,
+                }],
+            ),
+        ];
+
+        for (name, change, exp) in t.into_iter() {
+            let (mut shm, wal) = read();
+            change(&mut shm);
+            let index = WalIndex::read(&mut Cursor::new(shm)).unwrap();
+            assert_eq!(index.check(Some(&wal)), exp, "{}", name);
+        }
+
+        let (shm, _) = read();
+        let index = WalIndex::read(&mut Cursor::new(shm)).unwrap();
+        assert_eq!(index.check(None), vec![Problem::NoWal]);
+    }
+}
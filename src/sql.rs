@@ -0,0 +1,320 @@
+//! # SQL tokenizer
+//!
+//! Splits SQL text into [Token]s, following the rules of SQLite's
+//! `tokenize.c` for the subset of SQL this crate understands.
+//!
+//! [Docs](https://www.sqlite.org/lang_keywords.html)
+//!
+//! ```
+//! use rsqlite::sql::{tokenize, Token};
+//!
+//! let tokens = tokenize("SELECT \"a b\" FROM t WHERE x >= :min").unwrap();
+//! assert_eq!(tokens[1], Token::Quoted("a b".into()));
+//! assert_eq!(tokens[6], Token::Symbol(">="));
+//! assert_eq!(tokens[7], Token::Param(":min".into()));
+//! ```
+
+use std::{fmt, ops::Range};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /// A keyword or an identifier, keywords are matched case insensitively
+    Ident(String),
+    /// An identifier in `"double quotes"`, `[brackets]` or `` `backticks` ``,
+    /// which is never a keyword
+    Quoted(String),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Blob(Vec<u8>),
+    /// A parameter as written, one of `?`, `?NNN`, `:name`, `@name` or `$name`
+    Param(String),
+    /// An operator or punctuation
+    Symbol(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    token: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unrecognized token: \"{}\"", self.token)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Longest symbols first so `<=` is not read as `<` then `=`
+const SYMBOLS: [&str; 22] = [
+    "->>", "==", "!=", "<>", "<=", ">=", "<<", ">>", "||", "->", "*", ",", ";", "(", ")", "=", "<", ">", "+", "-", "/",
+    ".",
+];
+
+impl Token {
+    /// Is this the keyword `word`?
+    pub fn is(&self, word: &str) -> bool {
+        matches!(self, Token::Ident(ident) if ident.eq_ignore_ascii_case(word))
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(s) | Token::Param(s) => write!(f, "{}", s),
+            Token::Quoted(s) => write!(f, "\"{}\"", s.replace('"', "\"\"")),
+            Token::Integer(n) => write!(f, "{}", n),
+            Token::Float(x) => write!(f, "{}", x),
+            Token::String(s) => write!(f, "'{}'", s.replace('\'', "''")),
+            Token::Blob(b) => write!(f, "X'{}'", b.iter().map(|b| format!("{:02X}", b)).collect::<String>()),
+            Token::Symbol(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Split SQL into tokens, skipping whitespace and comments
+pub fn tokenize(sql: &str) -> Result<Vec<Token>, Error> {
+    Ok(tokenize_spans(sql)?.into_iter().map(|(token, _)| token).collect())
+}
+
+/// Like [tokenize], with the byte range of every token in `sql` to copy
+/// parts of it as written.
+pub fn tokenize_spans(sql: &str) -> Result<Vec<(Token, Range<usize>)>, Error> {
+    let mut tokens = vec![];
+    let mut rest = sql;
+
+    while let Some(c) = rest.chars().next() {
+        let ident_len = |s: &str| {
+            s.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(s.len())
+        };
+
+        let (token, len) = match c {
+            _ if c.is_whitespace() => (None, c.len_utf8()),
+            '-' if rest.starts_with("--") => (None, rest.find('\n').unwrap_or(rest.len())),
+            '/' if rest.starts_with("/*") => (None, rest.find("*/").map_or(rest.len(), |i| i + 2)),
+            '\'' => {
+                let (s, len) = quoted(rest, '\'')?;
+                (Some(Token::String(s)), len)
+            }
+            '"' | '`' => {
+                let (s, len) = quoted(rest, c)?;
+                (Some(Token::Quoted(s)), len)
+            }
+            '[' => {
+                let end = rest.find(']').ok_or_else(|| unrecognized(rest.to_string()))?;
+                (Some(Token::Quoted(rest[1..end].to_string())), end + 1)
+            }
+            'x' | 'X' if rest[1..].starts_with('\'') => {
+                let (hex, len) = quoted(&rest[1..], '\'')?;
+                let blob = (hex.len() % 2 == 0)
+                    .then(|| {
+                        (0..hex.len())
+                            .step_by(2)
+                            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                            .collect::<Option<Vec<u8>>>()
+                    })
+                    .flatten()
+                    .ok_or_else(|| unrecognized(rest[..len + 1].to_string()))?;
+                (Some(Token::Blob(blob)), len + 1)
+            }
+            '?' => {
+                let len = 1 + rest[1..].find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len() - 1);
+                (Some(Token::Param(rest[..len].to_string())), len)
+            }
+            ':' | '@' | '$' => {
+                let len = 1 + ident_len(&rest[1..]);
+                if len == 1 {
+                    return Err(unrecognized(c.to_string()));
+                }
+                (Some(Token::Param(rest[..len].to_string())), len)
+            }
+            _ if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) => {
+                let (token, len) = number(rest)?;
+                (Some(token), len)
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let len = ident_len(rest);
+                (Some(Token::Ident(rest[..len].to_string())), len)
+            }
+            _ => match SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+                Some(symbol) => (Some(Token::Symbol(symbol)), symbol.len()),
+                None => return Err(unrecognized(c.to_string())),
+            },
+        };
+
+        let start = sql.len() - rest.len();
+        tokens.extend(token.map(|token| (token, start..start + len)));
+        rest = &rest[len..];
+    }
+
+    Ok(tokens)
+}
+
+fn unrecognized(token: impl Into<String>) -> Error {
+    Error { token: token.into() }
+}
+
+/// Contents of a quoted string and the length including quotes, a doubled
+/// quote is an escaped quote.
+fn quoted(s: &str, quote: char) -> Result<(String, usize), Error> {
+    let mut out = String::new();
+    let mut chars = s.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == quote {
+            match chars.peek() {
+                Some((_, next)) if *next == quote => {
+                    chars.next();
+                }
+                _ => return Ok((out, i + 1)),
+            }
+        }
+        out.push(c);
+    }
+    Err(unrecognized(s.to_string()))
+}
+
+/// Integer, float or hex integer literal
+fn number(s: &str) -> Result<(Token, usize), Error> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        let len = 2 + s[2..].find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(s.len() - 2);
+        let n = u64::from_str_radix(&s[2..len], 16).map_err(|_| unrecognized(s[..len].to_string()))?;
+        return Ok((Token::Integer(n as i64), len));
+    }
+
+    let mut len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let mut float = false;
+    if s[len..].starts_with('.') {
+        float = true;
+        len += 1;
+        len += s[len..].find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len() - len);
+    }
+    if s[len..].starts_with(['e', 'E']) {
+        let sign = usize::from(s[len + 1..].starts_with(['+', '-']));
+        let digits = s[len + 1 + sign..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(s.len() - len - 1 - sign);
+        if digits == 0 {
+            return Err(unrecognized(s[..len + 1 + sign].to_string()));
+        }
+        float = true;
+        len += 1 + sign + digits;
+    }
+    if s[len..].starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        let end = s[len..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map_or(s.len(), |i| len + i);
+        return Err(unrecognized(s[..end].to_string()));
+    }
+
+    let text = &s[..len];
+    // Integers too large for i64 are floats, like in SQLite
+    let token = match (float, text.parse::<i64>()) {
+        (false, Ok(n)) => Token::Integer(n),
+        _ => Token::Float(text.parse().map_err(|_| unrecognized(text.to_string()))?),
+    };
+    Ok((token, len))
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use Token as T;
+
+    #[test]
+    fn tokens() {
+        let t = vec![
+            ("select *", vec![T::Ident("select".into()), T::Symbol("*")]),
+            (
+                "'it''s' X'0aff'",
+                vec![T::String("it's".into()), T::Blob(vec![0x0a, 0xff])],
+            ),
+            (
+                "\"a\"\"b\" [c d] `e`",
+                vec![T::Quoted("a\"b".into()), T::Quoted("c d".into()), T::Quoted("e".into())],
+            ),
+            (
+                "1 2.5 .5 1e3 0x10",
+                vec![
+                    T::Integer(1),
+                    T::Float(2.5),
+                    T::Float(0.5),
+                    T::Float(1000.0),
+                    T::Integer(16),
+                ],
+            ),
+            ("9223372036854775808", vec![T::Float(9223372036854775808.0)]),
+            (
+                "? ?12 :a @b $c",
+                vec![
+                    T::Param("?".into()),
+                    T::Param("?12".into()),
+                    T::Param(":a".into()),
+                    T::Param("@b".into()),
+                    T::Param("$c".into()),
+                ],
+            ),
+            (
+                "a<=b<>c -- comment\n/* block */ d",
+                vec![
+                    T::Ident("a".into()),
+                    T::Symbol("<="),
+                    T::Ident("b".into()),
+                    T::Symbol("<>"),
+                    T::Ident("c".into()),
+                    T::Ident("d".into()),
+                ],
+            ),
+            (
+                "j->'a'->>-1",
+                vec![
+                    T::Ident("j".into()),
+                    T::Symbol("->"),
+                    T::String("a".into()),
+                    T::Symbol("->>"),
+                    T::Symbol("-"),
+                    T::Integer(1),
+                ],
+            ),
+        ];
+
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(tokenize(sql), Ok(exp), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let sql = "CREATE TABLE \"a b\" ( x ) -- done";
+        let spans: Vec<&str> = tokenize_spans(sql)
+            .unwrap()
+            .into_iter()
+            .map(|(_, span)| &sql[span])
+            .collect();
+        assert_eq!(spans, vec!["CREATE", "TABLE", "\"a b\"", "(", "x", ")"]);
+    }
+
+    #[test]
+    fn errors() {
+        let t = vec![
+            ("'abc", "unrecognized token: \"'abc\""),
+            ("X'abc'", "unrecognized token: \"X'abc'\""),
+            ("1e", "unrecognized token: \"1e\""),
+            ("12abc", "unrecognized token: \"12abc\""),
+            ("a # b", "unrecognized token: \"#\""),
+        ];
+
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(
+                tokenize(sql).map_err(|e| e.to_string()),
+                Err(exp.to_string()),
+                "{}",
+                sql
+            );
+        }
+    }
+}
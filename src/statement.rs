@@ -0,0 +1,1993 @@
+//! # Prepared statements
+//!
+//! A [Statement] is SQL parsed once, with parameters bound to values before
+//! every run, like `sqlite3_prepare_v2`, `sqlite3_bind_*` and `sqlite3_step`.
+//!
+//! [Docs](https://www.sqlite.org/lang_expr.html#parameters)
+//!
+//! Only a single table `SELECT` is understood for now
+//!
+//! ```sql
+//! [EXPLAIN QUERY PLAN]
+//! SELECT * | expr [AS name], ... FROM table | function(expr, ...)
+//!     [WHERE expr]
+//!     [ORDER BY expr [COLLATE name] [ASC | DESC], ...]
+//!     [LIMIT expr [OFFSET expr]]
+//! ```
+//!
+//! where `expr` is a literal, a parameter, a column, a call to one of the
+//! [functions], `expr -> expr` and `expr ->> expr` to read JSON, a comparison
+//! `expr op expr`, `expr AND expr` or any of these in parentheses. Rows come
+//! from a table, or from a table valued function like `json_each`. Parameters are numbered like in SQLite: `?`
+//! takes the next free number, `?NNN` takes number `NNN` and a named parameter
+//! `:name`, `@name` or `$name` takes the next free number the first time it
+//! appears, and the same number after.
+//!
+//! A query with aggregate functions in its result columns returns a single
+//! row for all the rows that match, like SQLite does without `GROUP BY`.
+//! `ORDER BY` terms can also be the number of a result column, or its name
+//! after `AS`, and compare text with the collation of the column unless they
+//! name another one.
+//!
+//! Rows are read as the [Plan] the [planner] chooses says, which searches the
+//! rowid or an index with the `WHERE` terms comparing a column to a value or a
+//! parameter rather than reading the whole table. With `EXPLAIN QUERY PLAN` in
+//! front the statement returns the steps of the plan instead, as rows of `id`,
+//! `parent`, `notused` and `detail` like SQLite.
+//!
+//! ```
+//! use rsqlite::{schema::Database, statement::Statement};
+//! use binrw::BinRead;
+//!
+//! let mut file = std::fs::File::open("data/planets.db").unwrap();
+//! let db = Database::read(&mut file).unwrap();
+//!
+//! let mut stmt = Statement::prepare(&db, "SELECT name FROM planets WHERE moons > :min").unwrap();
+//! stmt.bind_named(":min", 50).unwrap();
+//! let names: Vec<_> = stmt.rows().map(|row| row[0].to_string()).collect();
+//! assert_eq!(names, vec!["Jupiter", "Saturn"]);
+//!
+//! let stmt = Statement::prepare(&db, "SELECT name FROM planets WHERE id = 3").unwrap();
+//! assert_eq!(stmt.plan().to_string(), "QUERY PLAN\n`--SEARCH planets USING INTEGER PRIMARY KEY (rowid=?)\n");
+//!
+//! let stmt = Statement::prepare(&db, "EXPLAIN QUERY PLAN SELECT name FROM planets ORDER BY name").unwrap();
+//! let details: Vec<_> = stmt.rows().map(|row| row[3].to_string()).collect();
+//! assert_eq!(details, vec!["SCAN planets", "USE TEMP B-TREE FOR ORDER BY"]);
+//!
+//! let mut stmt = Statement::prepare(&db, "SELECT name, date(:day, '+1 month') FROM planets WHERE id = 3").unwrap();
+//! stmt.bind_named(":day", "2024-01-31").unwrap();
+//! assert_eq!(stmt.column_names(), vec!["name", "date(:day, '+1 month')"]);
+//! assert_eq!(stmt.rows().next().unwrap()[1].to_string(), "2024-03-02");
+//!
+//! let mut stmt = Statement::prepare(&db, "SELECT value ->> 'n' FROM json_each(?) WHERE key > 0").unwrap();
+//! stmt.bind(1, r#"[{"n": 1}, {"n": 2}]"#).unwrap();
+//! assert_eq!(stmt.rows().next().unwrap()[0].to_string(), "2");
+//! ```
+
+use crate::{
+    catalog::{self, Catalog, Column, Table},
+    collation::{Collations, UnknownCollation},
+    cursor::{IndexCursor, TableCursor},
+    functions::{self, Aggregate, Functions},
+    plan::{self, Constraint, Node, Op, Plan, Step, TempBTree},
+    planner::{self, ColumnRef, Planner, Query, Term},
+    schema::{Database, SerialValue, TableLeafCell},
+    sql::{self, Token},
+};
+use std::{cmp::Ordering, fmt, iter, ops::Range, vec};
+
+/// Largest parameter number, `SQLITE_MAX_VARIABLE_NUMBER`
+const MAX_PARAMETER: usize = 32766;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Token(sql::Error),
+    /// Unexpected token, or `None` at the end of input
+    Syntax(Option<String>),
+    NoSuchTable(String),
+    NoSuchColumn(String),
+    NoSuchFunction(String),
+    /// An aggregate function outside of the result columns, or inside another
+    Misuse(String),
+    Collation(UnknownCollation),
+    /// Parameter number out of range, or a parameter name not in the SQL
+    Range,
+    /// `LIMIT` or `OFFSET` is not an integer
+    Mismatch,
+    /// An `ORDER BY` term, counting from 1, with the number of a result
+    /// column that doesn't exist, and the number of result columns
+    OrderBy(usize, usize),
+    /// Raised by a function while running the statement
+    Function(functions::Error),
+    Plan(planner::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Token(err) => write!(f, "{}", err),
+            Error::Syntax(Some(token)) => write!(f, "near \"{}\": syntax error", token),
+            Error::Syntax(None) => write!(f, "incomplete input"),
+            Error::NoSuchTable(name) => write!(f, "no such table: {}", name),
+            Error::NoSuchColumn(name) => write!(f, "no such column: {}", name),
+            Error::NoSuchFunction(name) => write!(f, "no such function: {}", name),
+            Error::Misuse(name) => write!(f, "misuse of aggregate function {}()", name),
+            Error::Collation(err) => write!(f, "{}", err),
+            Error::Range => write!(f, "column index out of range"),
+            Error::Mismatch => write!(f, "datatype mismatch"),
+            Error::OrderBy(i, n) => write!(
+                f,
+                "{} ORDER BY term out of range - should be between 1 and {}",
+                ordinal(*i),
+                n
+            ),
+            Error::Function(err) => write!(f, "{}", err),
+            Error::Plan(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sql::Error> for Error {
+    fn from(err: sql::Error) -> Self {
+        Error::Token(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Where a column value comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    Rowid,
+    Column(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Value(SerialValue),
+    /// Parameter number, starting at 1
+    Parameter(usize),
+    Column(Source),
+    /// A scalar function and its arguments
+    Call(String, Vec<Expr>),
+    /// Result of aggregate `i` of the statement
+    Aggregate(usize),
+    /// True when comparing `left` to `right` gives one of the accepted
+    /// orderings, NULL when either is NULL
+    Compare {
+        left: Box<Expr>,
+        right: Box<Expr>,
+        accept: &'static [Ordering],
+        collation: String,
+    },
+    And(Box<Expr>, Box<Expr>),
+}
+
+/// A call to an aggregate function in the result columns
+#[derive(Debug, Clone, PartialEq)]
+struct AggregateCall {
+    name: String,
+    args: Vec<Expr>,
+}
+
+/// A term of `ORDER BY`
+#[derive(Debug, Clone, PartialEq)]
+struct OrderTerm {
+    expr: Expr,
+    collation: String,
+    desc: bool,
+}
+
+/// A `WHERE` term comparing a column with a value or a parameter, which the
+/// plan can use to find the rows without reading all of them
+#[derive(Debug, Clone, PartialEq)]
+struct Bound {
+    source: Source,
+    /// With the column on the left
+    op: Op,
+    value: Expr,
+    collation: String,
+}
+
+pub struct Statement<'db> {
+    db: &'db Database,
+    table: Table,
+    /// Table valued function the rows come from instead of the table, which
+    /// then only describes its columns
+    function: Option<(String, Vec<Expr>)>,
+    columns: Vec<Expr>,
+    names: Vec<String>,
+    /// `WHERE` terms, a row is returned when all of them are true
+    filters: Vec<Expr>,
+    /// Rows are sorted by these when the plan doesn't read them in order
+    order_by: Vec<OrderTerm>,
+    aggregates: Vec<AggregateCall>,
+    limit: Option<Expr>,
+    offset: Option<Expr>,
+    /// How the rows are read, chosen by the [planner]
+    plan: Plan,
+    /// `EXPLAIN QUERY PLAN`, which returns the plan instead of the rows
+    explain: bool,
+    /// The index the plan reads
+    index: Option<catalog::Index>,
+    bounds: Vec<Bound>,
+    /// Name of each parameter, `None` for a nameless `?`
+    parameters: Vec<Option<String>>,
+    bindings: Vec<SerialValue>,
+    collations: Collations,
+    functions: Functions,
+}
+
+impl<'db> Statement<'db> {
+    /// Parse `sql` against the schema of `db`
+    pub fn prepare(db: &'db Database, sql: &str) -> Result<Statement<'db>> {
+        Statement::prepare_with(db, sql, Collations::default(), Functions::default())
+    }
+
+    /// Like [Statement::prepare], with custom collations for the columns and
+    /// custom functions
+    pub fn prepare_with(
+        db: &'db Database,
+        sql: &str,
+        collations: Collations,
+        functions: Functions,
+    ) -> Result<Statement<'db>> {
+        let catalog = Catalog::read(db);
+        let mut parser = Parser::new(sql)?;
+
+        let explain = parser.eat("explain");
+        if explain {
+            parser.expect("query")?;
+            parser.expect("plan")?;
+        }
+
+        // The result columns can only be read knowing the table after them
+        parser.expect("select")?;
+        let select = parser.next;
+        parser.skip_to("from");
+        parser.expect("from")?;
+        let table_name = parser.name()?;
+        let arguments = parser.next;
+        let empty = function_table(&[]);
+        let table = match parser.peek() {
+            Some(Token::Symbol("(")) => {
+                // Only count the arguments for now, so that parameters are
+                // numbered in the order they are written
+                let mut scope = Scope {
+                    table: &empty,
+                    collations: &collations,
+                    functions: &functions,
+                    aggregates: None,
+                };
+                let parameters = parser.parameters.clone();
+                let n_args = parser.args(&mut scope)?.len();
+                parser.parameters = parameters;
+                let columns = functions
+                    .table_columns(&table_name, n_args)
+                    .ok_or(Error::NoSuchTable(table_name.clone()))?;
+                function_table(columns)
+            }
+            _ => catalog
+                .table(&table_name)
+                .ok_or(Error::NoSuchTable(table_name.clone()))?
+                .clone(),
+        };
+        let from = parser.next;
+
+        let mut scope = Scope {
+            table: &table,
+            collations: &collations,
+            functions: &functions,
+            aggregates: Some(vec![]),
+        };
+        parser.next = select;
+        let (mut columns, mut names, mut aliases) = (vec![], vec![], vec![]);
+        if parser.eat_symbol("*") {
+            for (i, column) in table.columns.iter().enumerate() {
+                columns.push(Expr::Column(source(&table, i)));
+                names.push(column.name.clone());
+            }
+        } else {
+            loop {
+                let start = parser.start();
+                let expr = parser.expr(&mut scope)?;
+                let name = match (parser.eat("as"), &expr) {
+                    (true, _) => {
+                        aliases.push(columns.len());
+                        parser.name()?
+                    }
+                    (false, Expr::Column(source)) => source_name(&table, *source).to_string(),
+                    (false, _) => parser.text(start),
+                };
+                columns.push(expr);
+                names.push(name);
+                if !parser.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        parser.expect("from")?;
+        let aggregates = scope.aggregates.take().unwrap_or_default();
+
+        parser.next = arguments;
+        let function = match parser.peek() {
+            Some(Token::Symbol("(")) => {
+                let mut scope = Scope {
+                    table: &empty,
+                    collations: &collations,
+                    functions: &functions,
+                    aggregates: None,
+                };
+                Some((table_name, parser.args(&mut scope)?))
+            }
+            _ => None,
+        };
+        debug_assert_eq!(parser.next, from);
+
+        let mut filters = vec![];
+        if parser.eat("where") {
+            let mut expr = parser.expr(&mut scope)?;
+            while let Expr::And(left, right) = expr {
+                filters.push(*right);
+                expr = *left;
+            }
+            filters.push(expr);
+            filters.reverse();
+        }
+
+        let mut order_by = vec![];
+        if parser.eat("order") {
+            parser.expect("by")?;
+            loop {
+                // A result column by number or by its alias, or an expression
+                let alias = match parser.peek() {
+                    Some(Token::Ident(name) | Token::Quoted(name)) if parser.ends_term(1) => {
+                        aliases.iter().copied().find(|&i| names[i].eq_ignore_ascii_case(name))
+                    }
+                    _ => None,
+                };
+                let expr = match (parser.peek(), alias) {
+                    (_, Some(i)) => {
+                        parser.next();
+                        columns[i].clone()
+                    }
+                    (Some(Token::Integer(n)), _) if parser.ends_term(1) => {
+                        let i = usize::try_from(*n).ok().filter(|i| (1..=columns.len()).contains(i));
+                        let i = i.ok_or(Error::OrderBy(order_by.len() + 1, columns.len()))?;
+                        parser.next();
+                        columns[i - 1].clone()
+                    }
+                    _ => parser.expr(&mut scope)?,
+                };
+                let collation = match parser.eat("collate") {
+                    true => {
+                        let name = parser.name()?;
+                        match collations.contains(&name) {
+                            true => name,
+                            false => return Err(Error::Collation(UnknownCollation(name))),
+                        }
+                    }
+                    false => scope.collation(&expr, &expr)?,
+                };
+                let desc = parser.eat("desc");
+                if !desc {
+                    parser.eat("asc");
+                }
+                order_by.push(OrderTerm { expr, collation, desc });
+                if !parser.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+
+        let (mut limit, mut offset) = (None, None);
+        if parser.eat("limit") {
+            limit = Some(parser.expr(&mut scope)?);
+            if parser.eat("offset") {
+                offset = Some(parser.expr(&mut scope)?);
+            }
+        }
+
+        parser.eat_symbol(";");
+        parser.finish()?;
+
+        let bounds: Vec<Bound> = filters.iter().filter_map(bound).collect();
+        // Only ascending columns in their own collation can come in order
+        // from the table or an index
+        let in_order = order_by
+            .iter()
+            .map(|term| match term.expr {
+                Expr::Column(source)
+                    if !term.desc && term.collation.eq_ignore_ascii_case(source_collation(&table, source)) =>
+                {
+                    Some(source)
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<Source>>>();
+        let (mut plan, index) = match &function {
+            Some((name, _)) => {
+                let step = Step::Scan {
+                    table: name.clone(),
+                    index: None,
+                };
+                (Plan(vec![Node::new(step)]), None)
+            }
+            None => {
+                let exprs = columns
+                    .iter()
+                    .chain(&filters)
+                    .chain(order_by.iter().map(|term| &term.expr))
+                    .chain(aggregates.iter().flat_map(|call| &call.args));
+                let mut query = query(&table, exprs, &bounds);
+                query.order_by = in_order
+                    .iter()
+                    .flatten()
+                    .map(|&source| column_ref(&table, source))
+                    .collect();
+                let plan = Planner::new(&catalog, &collations).plan(&query).map_err(Error::Plan)?;
+                let index = match &plan.0[0].step {
+                    Step::Scan {
+                        index: Some(plan::Index::Named { name, .. }),
+                        ..
+                    }
+                    | Step::Search {
+                        index: plan::Index::Named { name, .. },
+                        ..
+                    } => catalog.index(name).cloned(),
+                    _ => None,
+                };
+                if let Some(name) = index
+                    .iter()
+                    .flat_map(|i| &i.collations)
+                    .find(|c| !collations.contains(c))
+                {
+                    return Err(Error::Collation(UnknownCollation(name.clone())));
+                }
+                (plan, index)
+            }
+        };
+        let sorted = plan
+            .0
+            .iter()
+            .any(|node| node.step == Step::TempBTree(TempBTree::OrderBy));
+        if !order_by.is_empty() && (in_order.is_none() || function.is_some()) && !sorted {
+            plan.0.push(Node::new(Step::TempBTree(TempBTree::OrderBy)));
+        }
+
+        Ok(Statement {
+            db,
+            table,
+            function,
+            columns,
+            names,
+            filters,
+            order_by,
+            aggregates,
+            limit,
+            offset,
+            plan,
+            explain,
+            index,
+            bounds,
+            bindings: vec![SerialValue::Null; parser.parameters.len()],
+            parameters: parser.parameters,
+            collations,
+            functions,
+        })
+    }
+
+    /// Number of the largest parameter, `sqlite3_bind_parameter_count`
+    pub fn parameter_count(&self) -> usize {
+        self.parameters.len()
+    }
+
+    /// Name of parameter `i` as written including its prefix, `None` for
+    /// nameless parameters
+    pub fn parameter_name(&self, i: usize) -> Option<&str> {
+        self.parameters.get(i.checked_sub(1)?)?.as_deref()
+    }
+
+    /// Number of the parameter named `name`, which includes its prefix
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.parameters
+            .iter()
+            .position(|p| p.as_deref() == Some(name))
+            .map(|i| i + 1)
+    }
+
+    /// Bind a value to parameter `i`, starting at 1
+    pub fn bind(&mut self, i: usize, value: impl Into<SerialValue>) -> Result<&mut Self> {
+        let slot = i
+            .checked_sub(1)
+            .and_then(|i| self.bindings.get_mut(i))
+            .ok_or(Error::Range)?;
+        *slot = value.into();
+        Ok(self)
+    }
+
+    pub fn bind_named(&mut self, name: &str, value: impl Into<SerialValue>) -> Result<&mut Self> {
+        let i = self.parameter_index(name).ok_or(Error::Range)?;
+        self.bind(i, value)
+    }
+
+    /// Reset every parameter to NULL
+    pub fn clear_bindings(&mut self) {
+        self.bindings.fill(SerialValue::Null);
+    }
+
+    /// Names of the result columns, as written in the SQL for expressions
+    pub fn column_names(&self) -> Vec<&str> {
+        match self.explain {
+            true => vec!["id", "parent", "notused", "detail"],
+            false => self.names.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// How the rows are read, chosen when the statement was prepared
+    pub fn plan(&self) -> &Plan {
+        &self.plan
+    }
+
+    /// Run the statement with the current bindings. Unbound parameters are NULL.
+    pub fn rows(&self) -> Rows<'_, 'db> {
+        if self.explain {
+            let rows = self
+                .plan
+                .rows()
+                .into_iter()
+                .map(|(id, parent, detail)| vec![id.into(), parent.into(), 0.into(), detail.as_str().into()]);
+            return Rows {
+                buffered: Some(rows.collect::<Vec<_>>().into_iter()),
+                ..Rows::empty(self)
+            };
+        }
+
+        let integer = |expr: &Option<Expr>| match expr.as_ref().map(|e| self.eval(e, None, &[])) {
+            None => Ok(None),
+            Some(Ok(SerialValue::Number(n))) => Ok(Some(n)),
+            Some(Ok(_)) => Err(Error::Mismatch),
+            Some(Err(err)) => Err(err),
+        };
+
+        // A negative limit means no limit, like in SQLite
+        let (limit, offset) = match (integer(&self.limit), integer(&self.offset)) {
+            (Ok(limit), Ok(offset)) => (limit.filter(|n| *n >= 0), offset.unwrap_or(0).max(0)),
+            (Err(err), _) | (_, Err(err)) => {
+                return Rows {
+                    error: Some(err),
+                    ..Rows::empty(self)
+                }
+            }
+        };
+
+        let scan = match self.scan() {
+            Ok(scan) => scan,
+            Err(err) => {
+                return Rows {
+                    error: Some(err),
+                    ..Rows::empty(self)
+                }
+            }
+        };
+
+        Rows {
+            statement: self,
+            scan,
+            buffered: None,
+            skip: offset as usize,
+            remaining: limit.map(|n| n as usize),
+            error: None,
+        }
+    }
+
+    /**
+     * Rows of the table read as the plan says, or of the table valued function
+     * called with the current bindings.
+     *
+     * A search may return more rows than its constraints match, which the
+     * `WHERE` clause then drops, but never fewer.
+     */
+    fn scan<'s>(&'s self) -> Result<Scan<'s, 'db>> {
+        let Some((name, args)) = &self.function else {
+            return Ok(match (&self.plan.0[0].step, &self.index) {
+                (
+                    Step::Search {
+                        index: plan::Index::Rowid,
+                        constraints,
+                        ..
+                    },
+                    _,
+                ) => self.rowid_search(constraints),
+                (Step::Search { constraints, .. }, Some(index)) => self.index_search(index, constraints),
+                (Step::Scan { .. }, Some(index)) => self.index_search(index, &[]),
+                _ => Box::new(TableCursor::new(self.db, self.table.root).map(Row::Cell)),
+            });
+        };
+        let args = args
+            .iter()
+            .map(|arg| self.eval(arg, None, &[]))
+            .collect::<Result<Vec<_>>>()?;
+        let rows = self.functions.call_table(name, &args).map_err(Error::Function)?;
+        Ok(Box::new(
+            rows.into_iter()
+                .enumerate()
+                .map(|(i, values)| Row::Values(i as i64, values)),
+        ))
+    }
+
+    /// Rows in a range of rowids. Bounds that aren't numbers are left to the
+    /// `WHERE` clause.
+    fn rowid_search<'s>(&'s self, constraints: &[Constraint]) -> Scan<'s, 'db> {
+        let (mut start, mut end) = (None, None);
+        for constraint in constraints {
+            let (low, high) = match self.bound_value(constraint) {
+                Some((_, SerialValue::Null)) => return Box::new(iter::empty()),
+                Some((_, SerialValue::Number(n))) => (n, n),
+                Some((_, SerialValue::Float(x))) => (x.floor() as i64, x.ceil() as i64),
+                _ => continue,
+            };
+            match constraint.op {
+                Op::Eq => (start, end) = (Some(low), Some(high)),
+                Op::Gt | Op::Ge => start = Some(low),
+                Op::Lt | Op::Le => end = Some(high),
+            }
+        }
+
+        let mut cursor = TableCursor::new(self.db, self.table.root);
+        if let Some(start) = start {
+            cursor.seek(start);
+        }
+        Box::new(
+            cursor
+                .take_while(move |cell| end.is_none_or(|end| cell.row_id.value as i64 <= end))
+                .map(Row::Cell),
+        )
+    }
+
+    /**
+     * Rows found through an index, in index order, from the first key matching
+     * the constraints to the last one. Without constraints that is every row.
+     *
+     * The index is only searched when its collations are the ones the
+     * comparisons use, otherwise its order says nothing about which keys
+     * match and the whole table is read.
+     */
+    fn index_search<'s>(&'s self, index: &'s catalog::Index, constraints: &[Constraint]) -> Scan<'s, 'db> {
+        let (mut eq, mut lower, mut upper) = (vec![], None, None);
+        for constraint in constraints {
+            let position = index
+                .columns
+                .iter()
+                .position(|c| c.eq_ignore_ascii_case(&constraint.column));
+            let (bound, value) = match (self.bound_value(constraint), position) {
+                (Some((bound, value)), Some(i)) if bound.collation.eq_ignore_ascii_case(&index.collations[i]) => {
+                    (bound, value)
+                }
+                _ => return Box::new(TableCursor::new(self.db, self.table.root).map(Row::Cell)),
+            };
+            if value == SerialValue::Null {
+                return Box::new(iter::empty());
+            }
+            match bound.op {
+                Op::Eq => eq.push(value),
+                Op::Gt | Op::Ge => lower = Some(value),
+                Op::Lt | Op::Le => upper = Some(value),
+            }
+        }
+        // Keys run from the largest value down on a descending column
+        if index.desc.get(eq.len()) == Some(&true) {
+            (lower, upper) = (upper, lower);
+        }
+        let start: Vec<SerialValue> = eq.iter().cloned().chain(lower).collect();
+        let end: Vec<SerialValue> = eq.into_iter().chain(upper).collect();
+
+        let collations = &self.collations;
+        let mut keys = IndexCursor::new(self.db, index.root);
+        if !start.is_empty() {
+            keys.seek(|key| compare_key(collations, index, key, &start));
+        }
+        let mut rows = TableCursor::new(self.db, self.table.root);
+        Box::new(
+            keys.take_while(move |key| compare_key(collations, index, &key.payload, &end) != Ordering::Greater)
+                .filter_map(move |key| {
+                    let Some(SerialValue::Number(row_id)) = key.payload.last() else {
+                        return None;
+                    };
+                    rows.seek(*row_id);
+                    rows.next().filter(|cell| cell.row_id.value as i64 == *row_id)
+                })
+                .map(Row::Cell),
+        )
+    }
+
+    /// The `WHERE` term a plan constraint comes from, and the value its
+    /// column is compared with, converted to the column affinity
+    fn bound_value(&self, constraint: &Constraint) -> Option<(&Bound, SerialValue)> {
+        let source = match self.table.column(&constraint.column) {
+            Some(i) => source(&self.table, i),
+            None => Source::Rowid,
+        };
+        let bound = self
+            .bounds
+            .iter()
+            .find(|bound| bound.source == source && bound.op == constraint.op)?;
+        let value = self.eval(&bound.value, None, &[]).ok()?;
+        let column = self.column(&Expr::Column(source)).flatten();
+        Some((bound, affinity(column, value)))
+    }
+
+    /// Column an expression reads directly, `None` inside for the rowid
+    fn column(&self, expr: &Expr) -> Option<Option<&Column>> {
+        match expr {
+            Expr::Column(Source::Column(i)) => Some(Some(&self.table.columns[*i])),
+            Expr::Column(Source::Rowid) => Some(None),
+            _ => None,
+        }
+    }
+
+    /// Value of `expr` for a row, or with all columns NULL without one
+    fn eval(&self, expr: &Expr, row: Option<&Row>, aggregates: &[SerialValue]) -> Result<SerialValue> {
+        Ok(match expr {
+            Expr::Value(value) => value.clone(),
+            Expr::Parameter(i) => self.bindings[i - 1].clone(),
+            Expr::Column(source) => row.map_or(SerialValue::Null, |row| row.read(*source)),
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg, row, aggregates))
+                    .collect::<Result<Vec<_>>>()?;
+                self.functions.call(name, &args).map_err(Error::Function)?
+            }
+            Expr::Aggregate(i) => aggregates[*i].clone(),
+            Expr::Compare { left, right, accept, collation } => {
+                let (mut a, mut b) = (self.eval(left, row, aggregates)?, self.eval(right, row, aggregates)?);
+                match (self.column(left), self.column(right)) {
+                    (Some(column), None) => b = affinity(column, b),
+                    (None, Some(column)) => a = affinity(column, a),
+                    _ => {}
+                }
+                if a == SerialValue::Null || b == SerialValue::Null {
+                    return Ok(SerialValue::Null);
+                }
+                let ord = self
+                    .collations
+                    .compare(collation, &a, &b)
+                    .expect("collation checked in prepare");
+                SerialValue::Number(accept.contains(&ord) as i64)
+            }
+            Expr::And(left, right) => {
+                let (a, b) = (self.eval(left, row, aggregates)?, self.eval(right, row, aggregates)?);
+                match (truth(&a), truth(&b)) {
+                    (Some(false), _) | (_, Some(false)) => SerialValue::Number(0),
+                    (Some(true), Some(true)) => SerialValue::Number(1),
+                    _ => SerialValue::Null,
+                }
+            }
+        })
+    }
+
+    fn matches(&self, row: &Row) -> Result<bool> {
+        for filter in &self.filters {
+            if truth(&self.eval(filter, Some(row), &[])?) != Some(true) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn project(&self, row: Option<&Row>, aggregates: &[SerialValue]) -> Result<Vec<SerialValue>> {
+        self.columns
+            .iter()
+            .map(|column| self.eval(column, row, aggregates))
+            .collect()
+    }
+
+    /**
+     * The single row of an aggregate query. Columns outside of the aggregate
+     * functions are read from the last row that matched, like in SQLite.
+     */
+    fn aggregate(&self, scan: &mut Scan<'_, 'db>) -> Result<Vec<SerialValue>> {
+        let mut accumulators = self
+            .aggregates
+            .iter()
+            .map(|call| self.functions.accumulator(&call.name, call.args.len()))
+            .collect::<functions::Result<Vec<Box<dyn Aggregate>>>>()
+            .map_err(Error::Function)?;
+
+        let mut last = None;
+        for row in scan {
+            if !self.matches(&row)? {
+                continue;
+            }
+            for (call, accumulator) in self.aggregates.iter().zip(accumulators.iter_mut()) {
+                let args = call
+                    .args
+                    .iter()
+                    .map(|arg| self.eval(arg, Some(&row), &[]))
+                    .collect::<Result<Vec<_>>>()?;
+                accumulator.step(&args).map_err(Error::Function)?;
+            }
+            last = Some(row);
+        }
+
+        let values = accumulators
+            .iter_mut()
+            .map(|accumulator| accumulator.finalize())
+            .collect::<functions::Result<Vec<_>>>()
+            .map_err(Error::Function)?;
+        self.project(last.as_ref(), &values)
+    }
+
+    /// Every row that matches, sorted by the `ORDER BY` terms. Rows that
+    /// compare equal stay in the order they were read.
+    fn sort(&self, scan: &mut Scan<'_, 'db>) -> Result<Vec<Vec<SerialValue>>> {
+        let mut rows = vec![];
+        for row in scan {
+            if !self.matches(&row)? {
+                continue;
+            }
+            let keys = self
+                .order_by
+                .iter()
+                .map(|term| self.eval(&term.expr, Some(&row), &[]))
+                .collect::<Result<Vec<_>>>()?;
+            rows.push((keys, self.project(Some(&row), &[])?));
+        }
+
+        rows.sort_by(|(a, _), (b, _)| {
+            for (term, (x, y)) in self.order_by.iter().zip(a.iter().zip(b)) {
+                let ord = self
+                    .collations
+                    .compare(&term.collation, x, y)
+                    .expect("collation checked in prepare");
+                match (ord, term.desc) {
+                    (Ordering::Equal, _) => continue,
+                    (ord, true) => return ord.reverse(),
+                    (ord, false) => return ord,
+                }
+            }
+            Ordering::Equal
+        });
+        Ok(rows.into_iter().map(|(_, row)| row).collect())
+    }
+}
+
+/// A row being read, from the table b-tree or from a table valued function
+enum Row<'db> {
+    Cell(&'db TableLeafCell),
+    /// A row of a table valued function, with its rowid counted from 0
+    Values(i64, Vec<SerialValue>),
+}
+
+impl Row<'_> {
+    fn read(&self, source: Source) -> SerialValue {
+        match (self, source) {
+            (Row::Cell(cell), Source::Rowid) => SerialValue::Number(cell.row_id.value as i64),
+            // Columns added by ALTER TABLE are missing from older records
+            (Row::Cell(cell), Source::Column(i)) => cell.record.payload.get(i).cloned().unwrap_or(SerialValue::Null),
+            (Row::Values(rowid, _), Source::Rowid) => SerialValue::Number(*rowid),
+            (Row::Values(_, values), Source::Column(i)) => values.get(i).cloned().unwrap_or(SerialValue::Null),
+        }
+    }
+}
+
+/// Where the rows of a statement come from
+type Scan<'s, 'db> = Box<dyn Iterator<Item = Row<'db>> + 's>;
+
+/// Result rows of a [Statement], read lazily from the table b-tree with a
+/// [TableCursor], or through an index with an [IndexCursor]
+pub struct Rows<'s, 'db> {
+    statement: &'s Statement<'db>,
+    scan: Scan<'s, 'db>,
+    /// Rows found once all the rows have been read: the row of an aggregate
+    /// query, or every row when sorting
+    buffered: Option<vec::IntoIter<Vec<SerialValue>>>,
+    skip: usize,
+    remaining: Option<usize>,
+    error: Option<Error>,
+}
+
+impl<'s, 'db> Rows<'s, 'db> {
+    fn empty(statement: &'s Statement<'db>) -> Self {
+        Rows {
+            statement,
+            scan: Box::new(iter::empty()),
+            buffered: Some(vec![].into_iter()),
+            skip: 0,
+            remaining: None,
+            error: None,
+        }
+    }
+
+    /// Error that stopped the statement, like a function that failed. No
+    /// more rows are returned after it.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    /// Next result row before `LIMIT` and `OFFSET`
+    fn next_row(&mut self) -> Result<Option<Vec<SerialValue>>> {
+        let stmt = self.statement;
+        if self.buffered.is_none() {
+            if !stmt.aggregates.is_empty() {
+                self.buffered = Some(vec![stmt.aggregate(&mut self.scan)?].into_iter());
+            } else if stmt
+                .plan
+                .0
+                .iter()
+                .any(|node| node.step == Step::TempBTree(TempBTree::OrderBy))
+            {
+                self.buffered = Some(stmt.sort(&mut self.scan)?.into_iter());
+            }
+        }
+        if let Some(rows) = self.buffered.as_mut() {
+            return Ok(rows.next());
+        }
+
+        for row in self.scan.by_ref() {
+            if stmt.matches(&row)? {
+                return stmt.project(Some(&row), &[]).map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for Rows<'_, '_> {
+    type Item = Vec<SerialValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) || self.error.is_some() {
+            return None;
+        }
+
+        loop {
+            let row = match self.next_row() {
+                Ok(row) => row?,
+                Err(err) => {
+                    self.error = Some(err);
+                    return None;
+                }
+            };
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+            if let Some(n) = self.remaining.as_mut() {
+                *n -= 1;
+            }
+            return Some(row);
+        }
+    }
+}
+
+/// What names in an expression refer to
+struct Scope<'a> {
+    table: &'a Table,
+    collations: &'a Collations,
+    functions: &'a Functions,
+    /// Aggregate calls found so far, `None` where they aren't allowed
+    aggregates: Option<Vec<AggregateCall>>,
+}
+
+impl Scope<'_> {
+    fn source(&self, name: &str) -> Result<Source> {
+        match self.table.column(name) {
+            Some(i) => Ok(source(self.table, i)),
+            None if self.table.is_rowid(name) => Ok(Source::Rowid),
+            None => Err(Error::NoSuchColumn(name.to_string())),
+        }
+    }
+
+    /// Collation of a comparison, from the column on the left or else the
+    /// column on the right
+    fn collation(&self, left: &Expr, right: &Expr) -> Result<String> {
+        let column = |expr: &Expr| match expr {
+            Expr::Column(Source::Column(i)) => Some(collation(&self.table.columns[*i])),
+            _ => None,
+        };
+        let name = column(left).or(column(right)).unwrap_or("binary");
+        match self.collations.contains(name) {
+            true => Ok(name.to_string()),
+            false => Err(Error::Collation(UnknownCollation(name.to_string()))),
+        }
+    }
+}
+
+struct Parser<'a> {
+    sql: &'a str,
+    tokens: Vec<(Token, Range<usize>)>,
+    next: usize,
+    /// Name of each parameter found so far
+    parameters: Vec<Option<String>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(sql: &'a str) -> Result<Parser<'a>> {
+        Ok(Parser {
+            sql,
+            tokens: sql::tokenize_spans(sql)?,
+            next: 0,
+            parameters: vec![],
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(t, _)| t)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.next += usize::from(token.is_some());
+        token
+    }
+
+    /// Consume the next token if it is `keyword`
+    fn eat(&mut self, keyword: &str) -> bool {
+        let found = self.peek().is_some_and(|t| t.is(keyword));
+        self.next += usize::from(found);
+        found
+    }
+
+    /// Consume the next token if it is `symbol`
+    fn eat_symbol(&mut self, symbol: &'static str) -> bool {
+        let found = self.peek().is_some_and(|t| *t == Token::Symbol(symbol));
+        self.next += usize::from(found);
+        found
+    }
+
+    fn unexpected(&mut self) -> Error {
+        Error::Syntax(self.next().map(|t| t.to_string()))
+    }
+
+    fn expect(&mut self, keyword: &str) -> Result<()> {
+        match self.eat(keyword) {
+            true => Ok(()),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &'static str) -> Result<()> {
+        match self.eat_symbol(symbol) {
+            true => Ok(()),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    /// A table or column name
+    fn name(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Ident(name) | Token::Quoted(name)) => Ok(name),
+            token => Err(Error::Syntax(token.map(|t| t.to_string()))),
+        }
+    }
+
+    /// Move to the next `keyword`, or to the end
+    fn skip_to(&mut self, keyword: &str) {
+        while self.peek().is_some_and(|t| !t.is(keyword)) {
+            self.next += 1;
+        }
+    }
+
+    /// Whether the token `ahead` tokens on ends an `ORDER BY` term
+    fn ends_term(&self, ahead: usize) -> bool {
+        match self.tokens.get(self.next + ahead) {
+            None => true,
+            Some((Token::Symbol(symbol), _)) => [",", ";"].contains(symbol),
+            Some((token, _)) => ["collate", "asc", "desc", "limit"].iter().any(|w| token.is(w)),
+        }
+    }
+
+    /// Byte offset of the next token in the SQL
+    fn start(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.sql.len(), |(_, span)| span.start)
+    }
+
+    /// SQL text from `start` to the end of the last token read
+    fn text(&self, start: usize) -> String {
+        let end = self.tokens[..self.next].last().map_or(start, |(_, span)| span.end);
+        self.sql[start..end].to_string()
+    }
+
+    /// Comparisons joined by `AND`
+    fn expr(&mut self, scope: &mut Scope) -> Result<Expr> {
+        let mut expr = self.comparison(scope)?;
+        while self.eat("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison(scope)?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self, scope: &mut Scope) -> Result<Expr> {
+        let left = self.arrow(scope)?;
+        let accept = match self.peek() {
+            Some(Token::Symbol("=" | "==")) => &[Ordering::Equal][..],
+            Some(Token::Symbol("!=" | "<>")) => &[Ordering::Less, Ordering::Greater],
+            Some(Token::Symbol("<")) => &[Ordering::Less],
+            Some(Token::Symbol("<=")) => &[Ordering::Less, Ordering::Equal],
+            Some(Token::Symbol(">")) => &[Ordering::Greater],
+            Some(Token::Symbol(">=")) => &[Ordering::Greater, Ordering::Equal],
+            _ => return Ok(left),
+        };
+        self.next();
+        let right = self.arrow(scope)?;
+        Ok(Expr::Compare {
+            collation: scope.collation(&left, &right)?,
+            left: Box::new(left),
+            right: Box::new(right),
+            accept,
+        })
+    }
+
+    /// JSON lookups with `->` and `->>`, which call the functions registered
+    /// under those names
+    fn arrow(&mut self, scope: &mut Scope) -> Result<Expr> {
+        let mut expr = self.primary(scope)?;
+        while let Some(Token::Symbol(op @ ("->" | "->>"))) = self.peek() {
+            let op = op.to_string();
+            self.next();
+            let right = self.primary(scope)?;
+            if !scope.functions.is_scalar(&op, 2) {
+                return Err(Error::NoSuchFunction(op));
+            }
+            expr = Expr::Call(op, vec![expr, right]);
+        }
+        Ok(expr)
+    }
+
+    /// A literal, a parameter, a column, a function call or an expression in
+    /// parentheses
+    fn primary(&mut self, scope: &mut Scope) -> Result<Expr> {
+        let negative = self.eat_symbol("-");
+        let value = match self.next() {
+            Some(Token::Integer(n)) if negative => SerialValue::Number(n.wrapping_neg()),
+            Some(Token::Float(x)) if negative => SerialValue::Float(-x),
+            Some(Token::Integer(n)) => SerialValue::Number(n),
+            Some(Token::Float(x)) => SerialValue::Float(x),
+            Some(Token::String(s)) if !negative => SerialValue::String(s),
+            Some(Token::Blob(b)) if !negative => SerialValue::Blob(b),
+            Some(Token::Ident(word)) if !negative && word.eq_ignore_ascii_case("null") => SerialValue::Null,
+            Some(Token::Param(param)) if !negative => return self.parameter(param).map(Expr::Parameter),
+            Some(Token::Symbol("(")) if !negative => {
+                let expr = self.expr(scope)?;
+                self.expect_symbol(")")?;
+                return Ok(expr);
+            }
+            Some(Token::Ident(name)) if !negative && self.peek() == Some(&Token::Symbol("(")) => {
+                return self.call(name, scope);
+            }
+            Some(Token::Ident(name) | Token::Quoted(name)) if !negative => {
+                return scope.source(&name).map(Expr::Column)
+            }
+            token => return Err(Error::Syntax(token.map(|t| t.to_string()))),
+        };
+        Ok(Expr::Value(value))
+    }
+
+    /// A function call after its name
+    fn call(&mut self, name: String, scope: &mut Scope) -> Result<Expr> {
+        let before = scope.aggregates.as_ref().map(Vec::len);
+        let args = self.args(scope)?;
+
+        if scope.functions.is_aggregate(&name, args.len()) {
+            let aggregates = scope.aggregates.as_mut().ok_or(Error::Misuse(name.clone()))?;
+            // Aggregates can't be nested
+            if let Some(inner) = aggregates.get(before.unwrap_or_default()) {
+                return Err(Error::Misuse(inner.name.clone()));
+            }
+            aggregates.push(AggregateCall { name, args });
+            return Ok(Expr::Aggregate(aggregates.len() - 1));
+        }
+        match scope.functions.is_scalar(&name, args.len()) {
+            true => Ok(Expr::Call(name, args)),
+            false => Err(Error::NoSuchFunction(name)),
+        }
+    }
+
+    /// Arguments of a function in parentheses, `*` for none like in `count(*)`
+    fn args(&mut self, scope: &mut Scope) -> Result<Vec<Expr>> {
+        self.expect_symbol("(")?;
+        let mut args = vec![];
+        if !self.eat_symbol("*") && self.peek() != Some(&Token::Symbol(")")) {
+            loop {
+                args.push(self.expr(scope)?);
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        self.expect_symbol(")")?;
+        Ok(args)
+    }
+
+    /// Number of a parameter, registering it the first time it appears
+    fn parameter(&mut self, param: String) -> Result<usize> {
+        let parameters = &mut self.parameters;
+        let i = match param.strip_prefix('?') {
+            Some("") => {
+                parameters.push(None);
+                return Ok(parameters.len());
+            }
+            Some(digits) => match digits.parse::<usize>() {
+                Ok(n) if (1..=MAX_PARAMETER).contains(&n) => n,
+                _ => return Err(Error::Range),
+            },
+            None => match parameters.iter().position(|p| p.as_ref() == Some(&param)) {
+                Some(i) => return Ok(i + 1),
+                None => parameters.len() + 1,
+            },
+        };
+
+        if parameters.len() < i {
+            parameters.resize(i, None);
+        }
+        // `?NNN` is only named if nothing else took the number
+        parameters[i - 1].get_or_insert(param);
+        Ok(i)
+    }
+}
+
+// * Helper functions * //
+
+/// A table with the columns of a table valued function, which have no type
+fn function_table(columns: &[String]) -> Table {
+    let columns = columns
+        .iter()
+        .map(|name| Column {
+            name: name.clone(),
+            decl_type: String::new(),
+            collation: None,
+            rowid_alias: false,
+        })
+        .collect();
+    Table {
+        name: String::new(),
+        root: 0,
+        columns,
+        sql: String::new(),
+    }
+}
+
+/// A comparison of a column with a value or a parameter
+fn bound(filter: &Expr) -> Option<Bound> {
+    let Expr::Compare { left, right, accept, collation } = filter else {
+        return None;
+    };
+    let op = match accept {
+        [Ordering::Equal] => Op::Eq,
+        [Ordering::Less] => Op::Lt,
+        [Ordering::Less, Ordering::Equal] => Op::Le,
+        [Ordering::Greater] => Op::Gt,
+        [Ordering::Greater, Ordering::Equal] => Op::Ge,
+        _ => return None,
+    };
+    let reversed = match op {
+        Op::Eq => Op::Eq,
+        Op::Lt => Op::Gt,
+        Op::Le => Op::Ge,
+        Op::Gt => Op::Lt,
+        Op::Ge => Op::Le,
+    };
+    let (source, value, op) = match (left.as_ref(), right.as_ref()) {
+        (Expr::Column(source), value @ (Expr::Value(_) | Expr::Parameter(_))) => (*source, value, op),
+        (value @ (Expr::Value(_) | Expr::Parameter(_)), Expr::Column(source)) => (*source, value, reversed),
+        _ => return None,
+    };
+    Some(Bound {
+        source,
+        op,
+        value: value.clone(),
+        collation: collation.clone(),
+    })
+}
+
+/// What the planner needs to know about a statement reading `table`, the
+/// expressions in it and the terms usable to find rows
+fn query<'a>(table: &Table, exprs: impl Iterator<Item = &'a Expr>, bounds: &[Bound]) -> Query {
+    let mut found = vec![];
+    for expr in exprs {
+        sources(expr, &mut found);
+    }
+    let column = |source: Source| column_ref(table, source);
+    let terms = bounds
+        .iter()
+        .map(|bound| {
+            let value = match (&bound.value, bound.source) {
+                (Expr::Value(value), Source::Column(i)) => Some(affinity(Some(&table.columns[i]), value.clone())),
+                (Expr::Value(value), Source::Rowid) => Some(affinity(None, value.clone())),
+                _ => None,
+            };
+            Term::Compare {
+                column: column(bound.source),
+                op: bound.op,
+                value,
+                collation: Some(bound.collation.clone()),
+            }
+        })
+        .collect();
+    Query {
+        tables: vec![table.name.clone()],
+        columns: Some(found.into_iter().map(column).collect()),
+        terms,
+        ..Default::default()
+    }
+}
+
+fn column_ref(table: &Table, source: Source) -> ColumnRef {
+    ColumnRef {
+        table: table.name.clone(),
+        column: source_name(table, source).to_string(),
+    }
+}
+
+/// Every column an expression reads, added to `found` once
+fn sources(expr: &Expr, found: &mut Vec<Source>) {
+    match expr {
+        Expr::Column(source) if !found.contains(source) => found.push(*source),
+        Expr::Call(_, args) => args.iter().for_each(|arg| sources(arg, found)),
+        Expr::Compare { left, right, .. } | Expr::And(left, right) => {
+            sources(left, found);
+            sources(right, found);
+        }
+        _ => {}
+    }
+}
+
+/// Order of an index key and a prefix of one, with the collation and sort
+/// order of each index column
+fn compare_key(
+    collations: &Collations,
+    index: &catalog::Index,
+    key: &[SerialValue],
+    prefix: &[SerialValue],
+) -> Ordering {
+    for (i, (a, b)) in key.iter().zip(prefix).enumerate() {
+        let ord = collations
+            .compare(&index.collations[i], a, b)
+            .expect("collation checked in prepare");
+        let ord = if index.desc[i] { ord.reverse() } else { ord };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Where column `i` of a table is read from, the rowid for its alias
+fn source(table: &Table, i: usize) -> Source {
+    match table.columns[i].rowid_alias {
+        true => Source::Rowid,
+        false => Source::Column(i),
+    }
+}
+
+fn source_name(table: &Table, source: Source) -> &str {
+    match source {
+        Source::Column(i) => &table.columns[i].name,
+        Source::Rowid => table.rowid_alias().map_or("rowid", |i| &table.columns[i].name),
+    }
+}
+
+fn collation(column: &Column) -> &str {
+    column.collation.as_deref().unwrap_or("binary")
+}
+
+fn source_collation(table: &Table, source: Source) -> &str {
+    match source {
+        Source::Column(i) => collation(&table.columns[i]),
+        Source::Rowid => "binary",
+    }
+}
+
+/// `1st`, `2nd`, `3rd`, `4th` .. `11th` .. `21st`
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+/// Whether a value is true in a condition, `None` for NULL. Text and blobs
+/// are read as numbers first.
+fn truth(value: &SerialValue) -> Option<bool> {
+    match value {
+        SerialValue::Null | SerialValue::Reserved => None,
+        SerialValue::Number(n) => Some(*n != 0),
+        SerialValue::Float(x) => Some(*x != 0.0),
+        SerialValue::String(s) => Some(s.trim().parse::<f64>().is_ok_and(|x| x != 0.0)),
+        SerialValue::Blob(b) => {
+            Some(std::str::from_utf8(b).is_ok_and(|s| s.trim().parse::<f64>().is_ok_and(|x| x != 0.0)))
+        }
+    }
+}
+
+/**
+ * Apply the column affinity to a value it is compared with.
+ *
+ * Text compared to a numeric column is converted to a number if it looks like
+ * one, and numbers compared to a text column become text.
+ * [Docs](https://www.sqlite.org/datatype3.html#type_conversions_prior_to_comparison)
+ */
+fn affinity(column: Option<&Column>, value: SerialValue) -> SerialValue {
+    let decl = column.map_or("INTEGER".to_string(), |c| c.decl_type.to_uppercase());
+    let text = ["CHAR", "CLOB", "TEXT"].iter().any(|t| decl.contains(t));
+    let numeric = !text && !decl.is_empty() && !decl.contains("BLOB");
+
+    match value {
+        SerialValue::String(s) if numeric => match s.trim().parse::<i64>() {
+            Ok(n) => SerialValue::Number(n),
+            Err(_) => s
+                .trim()
+                .parse::<f64>()
+                .map_or(SerialValue::String(s), SerialValue::Float),
+        },
+        SerialValue::Number(n) if text => SerialValue::String(n.to_string()),
+        SerialValue::Float(x) if text => SerialValue::String(x.to_string()),
+        value => value,
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::planets;
+    use pretty_assertions::assert_eq;
+    use SerialValue as V;
+
+    #[test]
+    fn select() {
+        let db = planets();
+
+        let mut stmt = Statement::prepare(&db, "select id, name from planets where type = ? and moons >= ?").unwrap();
+        assert_eq!(stmt.column_names(), vec!["id", "name"]);
+
+        stmt.bind(1, "Terrestrial").unwrap().bind(2, 1).unwrap();
+        assert_eq!(
+            stmt.rows().collect::<Vec<_>>(),
+            vec![vec![V::Number(3), "Earth".into()], vec![V::Number(4), "Mars".into()]]
+        );
+
+        // Rebinding runs the same statement again
+        stmt.bind(1, "Ice Giant").unwrap();
+        let names: Vec<_> = stmt.rows().map(|row| row[1].clone()).collect();
+        assert_eq!(names, vec!["Uranus".into(), "Neptune".into()]);
+
+        // NULL never compares equal
+        stmt.clear_bindings();
+        assert_eq!(stmt.rows().count(), 0);
+
+        assert_eq!(stmt.bind(3, 1).err(), Some(Error::Range));
+        assert_eq!(stmt.bind(0, 1).err(), Some(Error::Range));
+    }
+
+    #[test]
+    fn limit_and_affinity() {
+        let db = planets();
+
+        let stmt = Statement::prepare(
+            &db,
+            "SELECT * FROM planets WHERE distance > '1000000000' LIMIT 2 OFFSET 1;",
+        )
+        .unwrap();
+        assert_eq!(
+            stmt.column_names(),
+            vec!["id", "name", "type", "diameter", "distance", "moons"]
+        );
+        let ids: Vec<_> = stmt.rows().map(|row| row[0].clone()).collect();
+        assert_eq!(ids, vec![V::Number(7), V::Number(8)]);
+
+        let mut stmt = Statement::prepare(&db, "SELECT rowid FROM planets WHERE rowid <> 1 LIMIT $n").unwrap();
+        stmt.bind_named("$n", -1).unwrap();
+        assert_eq!(stmt.rows().count(), 7);
+
+        stmt.bind_named("$n", "x").unwrap();
+        let rows = stmt.rows();
+        assert_eq!(rows.error(), Some(&Error::Mismatch));
+    }
+
+    #[test]
+    fn parameters() {
+        let db = planets();
+
+        let sql = "SELECT * FROM planets WHERE id > ? AND moons < :m AND name = ?5 AND type = :m AND diameter > @d AND distance > $x";
+        let stmt = Statement::prepare(&db, sql).unwrap();
+
+        assert_eq!(stmt.parameter_count(), 7);
+        let names: Vec<_> = (0..=8).map(|i| stmt.parameter_name(i)).collect();
+        assert_eq!(
+            names,
+            vec![
+                None,
+                None,
+                Some(":m"),
+                None,
+                None,
+                Some("?5"),
+                Some("@d"),
+                Some("$x"),
+                None
+            ]
+        );
+        assert_eq!(stmt.parameter_index(":m"), Some(2));
+        assert_eq!(stmt.parameter_index("$x"), Some(7));
+        assert_eq!(stmt.parameter_index(":x"), None);
+    }
+
+    /// The built in functions, and a few more that SQLite has
+    fn functions() -> Functions {
+        let mut functions = Functions::default();
+        functions
+            .scalar("upper", 1, |args| {
+                Ok(args[0].to_string().to_uppercase().as_str().into())
+            })
+            .fold(
+                "count",
+                0,
+                0,
+                |n, _| {
+                    *n += 1;
+                    Ok(())
+                },
+                |n| Ok(V::Number(*n)),
+            )
+            .fold(
+                "max",
+                1,
+                V::Null,
+                |max, args| {
+                    let greater = Collations::default().compare("binary", &args[0], max) == Ok(Ordering::Greater);
+                    if args[0] != V::Null && (*max == V::Null || greater) {
+                        *max = args[0].clone();
+                    }
+                    Ok(())
+                },
+                |max| Ok(max.clone()),
+            );
+        functions
+    }
+
+    #[test]
+    fn calls() {
+        let db = planets();
+        let prepare = |sql| Statement::prepare_with(&db, sql, Collations::default(), functions());
+
+        let stmt =
+            prepare("SELECT upper(name) AS n, json_type('[1]') FROM planets WHERE (moons > 10 AND id < 7) AND id >= 6")
+                .unwrap();
+        assert_eq!(stmt.column_names(), vec!["n", "json_type('[1]')"]);
+        assert_eq!(
+            stmt.rows().collect::<Vec<_>>(),
+            vec![vec!["SATURN".into(), "array".into()]]
+        );
+
+        let t = vec![
+            // Other columns are read from the last row
+            (
+                "SELECT name, max(moons), count(*) FROM planets WHERE type = 'Gas Giant'",
+                vec![vec!["Saturn".into(), V::Number(83), V::Number(2)]],
+            ),
+            // One row even without any rows to aggregate
+            (
+                "SELECT count(*), max(moons) FROM planets WHERE id > 100",
+                vec![vec![V::Number(0), V::Null]],
+            ),
+            ("SELECT count(*) FROM planets LIMIT 1 OFFSET 1", vec![]),
+            (
+                "SELECT json_type(max(moons)) FROM planets",
+                vec![vec!["integer".into()]],
+            ),
+            (
+                "SELECT id FROM planets WHERE json_array_length('[1, 2]') = 2 AND upper(type) = 'ICE GIANT'",
+                vec![vec![V::Number(7)], vec![V::Number(8)]],
+            ),
+        ];
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(prepare(sql).unwrap().rows().collect::<Vec<_>>(), exp, "{}", sql);
+        }
+
+        // Errors raised by functions stop the statement
+        let stmt = prepare("SELECT json(name) FROM planets").unwrap();
+        let mut rows = stmt.rows();
+        assert_eq!(rows.next(), None);
+        assert_eq!(rows.error().map(|e| e.to_string()), Some("malformed JSON".to_string()));
+
+        let t = vec![
+            ("SELECT nope(name) FROM planets", "no such function: nope"),
+            ("SELECT upper(name, 1) FROM planets", "no such function: upper"),
+            (
+                "SELECT name FROM planets WHERE count(*) > 1",
+                "misuse of aggregate function count()",
+            ),
+            (
+                "SELECT max(count(*)) FROM planets",
+                "misuse of aggregate function count()",
+            ),
+            ("SELECT count(* FROM planets", "near \"FROM\": syntax error"),
+        ];
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(
+                prepare(sql).err().map(|e| e.to_string()),
+                Some(exp.to_string()),
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn json() {
+        let db = planets();
+
+        let mut stmt = Statement::prepare(&db, "SELECT value ->> 'n' FROM json_each(?) WHERE key > 0").unwrap();
+        stmt.bind(1, r#"[{"n": 1}, {"n": 2}, {"n": 3}]"#).unwrap();
+        assert_eq!(stmt.column_names(), vec!["value ->> 'n'"]);
+        assert_eq!(
+            stmt.rows().collect::<Vec<_>>(),
+            vec![vec![V::Number(2)], vec![V::Number(3)]]
+        );
+
+        let t = vec![
+            (
+                r#"SELECT * FROM json_each('{"a": [1, 2]}')"#,
+                vec![vec![
+                    "a".into(),
+                    "[1,2]".into(),
+                    "array".into(),
+                    V::Null,
+                    V::Number(1),
+                    V::Null,
+                    "$.a".into(),
+                    "$".into(),
+                ]],
+            ),
+            (
+                r#"SELECT key, value, type FROM json_tree('{"a": [1, 2]}') WHERE type = 'integer'"#,
+                vec![
+                    vec![V::Number(0), V::Number(1), "integer".into()],
+                    vec![V::Number(1), V::Number(2), "integer".into()],
+                ],
+            ),
+            (
+                "SELECT rowid, value FROM json_each('[5, 6]')",
+                vec![vec![V::Number(0), V::Number(5)], vec![V::Number(1), V::Number(6)]],
+            ),
+            (
+                r#"SELECT name, '{"Earth": [1]}' -> name FROM planets WHERE '{"Earth": 1}' ->> name = 1"#,
+                vec![vec!["Earth".into(), "[1]".into()]],
+            ),
+        ];
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(
+                Statement::prepare(&db, sql).unwrap().rows().collect::<Vec<_>>(),
+                exp,
+                "{}",
+                sql
+            );
+        }
+
+        // Parameters are numbered in the order they are written
+        let mut stmt = Statement::prepare(&db, "SELECT ?, value FROM json_each(?)").unwrap();
+        assert_eq!(stmt.parameter_count(), 2);
+        stmt.bind(1, "x").unwrap().bind(2, "[1]").unwrap();
+        assert_eq!(stmt.rows().collect::<Vec<_>>(), vec![vec!["x".into(), V::Number(1)]]);
+
+        let stmt = Statement::prepare(&db, "SELECT * FROM json_each('[')").unwrap();
+        let mut rows = stmt.rows();
+        assert_eq!(rows.next(), None);
+        assert_eq!(rows.error().map(|e| e.to_string()), Some("malformed JSON".to_string()));
+
+        let t = vec![
+            ("SELECT * FROM nope(1)", "no such table: nope"),
+            ("SELECT * FROM json_each(key)", "no such column: key"),
+            ("SELECT * FROM json_each('[1]' ->)", "near \")\": syntax error"),
+        ];
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(
+                Statement::prepare(&db, sql).err().map(|e| e.to_string()),
+                Some(exp.to_string()),
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn plans() {
+        let db = Database::open("data/analyze.db").unwrap();
+        // The same tables read without any index
+        let mut scan = Database::open("data/analyze.db").unwrap();
+        for index in ["by_type", "by_name", "by_moons", "moons_planet"] {
+            scan.execute(&format!("DROP INDEX {}", index)).unwrap();
+        }
+
+        let t = vec![
+            (
+                "SELECT * FROM planets WHERE type = 'gas'",
+                "SEARCH planets USING INDEX by_type (type=?)",
+                200,
+            ),
+            (
+                "SELECT id FROM planets WHERE name >= 'p1990' AND name < 'p1999'",
+                "SEARCH planets USING COVERING INDEX by_name (name>=? AND name<?)",
+                9,
+            ),
+            (
+                "SELECT name FROM planets WHERE type = 'gas' AND moons = '3'",
+                "SEARCH planets USING INDEX by_moons (moons=? AND type=?)",
+                29,
+            ),
+            (
+                "SELECT name FROM planets WHERE moons > 5 AND type = 'gas'",
+                "SEARCH planets USING INDEX by_moons (moons>?)",
+                29,
+            ),
+            (
+                "SELECT * FROM planets WHERE 1995 < id",
+                "SEARCH planets USING INTEGER PRIMARY KEY (rowid>?)",
+                5,
+            ),
+            (
+                "SELECT * FROM planets WHERE id = 5.0",
+                "SEARCH planets USING INTEGER PRIMARY KEY (rowid=?)",
+                1,
+            ),
+            (
+                "SELECT * FROM planets WHERE id <= 2.5",
+                "SEARCH planets USING INTEGER PRIMARY KEY (rowid<=?)",
+                2,
+            ),
+            (
+                "SELECT * FROM planets WHERE moons = NULL",
+                "SEARCH planets USING INDEX by_moons (moons=?)",
+                0,
+            ),
+            (
+                "SELECT name FROM moons WHERE planet_id < 10",
+                "SEARCH moons USING INDEX moons_planet (planet_id<?)",
+                8,
+            ),
+            (
+                "SELECT type FROM planets",
+                "SCAN planets USING COVERING INDEX by_type",
+                2000,
+            ),
+        ];
+        for (sql, plan, n) in t.into_iter() {
+            let stmt = Statement::prepare(&db, sql).unwrap();
+            assert_eq!(stmt.plan().to_string(), format!("QUERY PLAN\n`--{}\n", plan), "{}", sql);
+            let mut rows: Vec<_> = stmt.rows().collect();
+            let mut expected: Vec<_> = Statement::prepare(&scan, sql).unwrap().rows().collect();
+            let order = |a: &Vec<V>, b: &Vec<V>| format!("{:?}", a).cmp(&format!("{:?}", b));
+            rows.sort_by(order);
+            expected.sort_by(order);
+            assert_eq!(rows.len(), n, "{}", sql);
+            assert_eq!(rows, expected, "{}", sql);
+        }
+
+        // Bound parameters are read when the statement runs
+        let mut stmt = Statement::prepare(&db, "SELECT id FROM planets WHERE id > ? AND id < ?").unwrap();
+        stmt.bind(1, 10).unwrap().bind(2, 13).unwrap();
+        assert_eq!(
+            stmt.rows().collect::<Vec<_>>(),
+            vec![vec![V::Number(11)], vec![V::Number(12)]]
+        );
+        stmt.bind(1, V::Null).unwrap();
+        assert_eq!(stmt.rows().count(), 0);
+
+        let mut db = planets();
+        db.execute("CREATE INDEX by_moons ON planets (moons DESC)").unwrap();
+        db.execute("CREATE INDEX by_name ON planets (name COLLATE NOCASE)")
+            .unwrap();
+
+        // Descending keys, from the upper bound down
+        let stmt = Statement::prepare(&db, "SELECT name FROM planets WHERE moons > 1 AND moons <= 83").unwrap();
+        assert_eq!(
+            stmt.plan().to_string(),
+            "QUERY PLAN\n`--SEARCH planets USING INDEX by_moons (moons>? AND moons<=?)\n"
+        );
+        let names: Vec<_> = stmt.rows().map(|row| row[0].to_string()).collect();
+        assert_eq!(names, vec!["Saturn", "Jupiter", "Uranus", "Neptune", "Mars"]);
+
+        // The index sorts with another collation than the comparison
+        let stmt = Statement::prepare(&db, "SELECT name FROM planets WHERE name = 'earth'").unwrap();
+        assert_eq!(stmt.rows().count(), 0);
+    }
+
+    #[test]
+    fn order_by() {
+        let db = planets();
+        let names = |sql: &str| -> Vec<String> {
+            let stmt = Statement::prepare_with(&db, sql, Collations::default(), functions()).unwrap();
+            stmt.rows().map(|row| row[0].to_string()).collect()
+        };
+
+        // Expected from `sqlite3 data/planets.db`
+        let t = vec![
+            (
+                "SELECT name FROM planets WHERE id < 5 ORDER BY name",
+                vec!["Earth", "Mars", "Mercury", "Venus"],
+            ),
+            (
+                "SELECT name, type FROM planets ORDER BY 2 DESC, moons LIMIT 3",
+                vec!["Mercury", "Venus", "Earth"],
+            ),
+            (
+                "SELECT name AS moons FROM planets WHERE id < 4 ORDER BY moons DESC",
+                vec!["Venus", "Mercury", "Earth"],
+            ),
+            (
+                "SELECT upper(name) FROM planets WHERE moons > 20 ORDER BY upper(name) COLLATE nocase",
+                vec!["JUPITER", "SATURN", "URANUS"],
+            ),
+            (
+                "SELECT name FROM planets ORDER BY id DESC LIMIT 2",
+                vec!["Neptune", "Uranus"],
+            ),
+            (
+                "SELECT value FROM json_each('[\"b\", \"A\", \"c\"]') ORDER BY value COLLATE nocase",
+                vec!["A", "b", "c"],
+            ),
+        ];
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(names(sql), exp, "{}", sql);
+        }
+
+        // Text is sorted with the collation of the column
+        let mut db = planets();
+        db.execute("CREATE TABLE t (a TEXT COLLATE nocase)").unwrap();
+        for (i, a) in ["b", "A", "c", "B"].into_iter().enumerate() {
+            db.insert(3, i as i64 + 1, vec![a.into()]).unwrap();
+        }
+        let stmt = Statement::prepare(&db, "SELECT a FROM t ORDER BY a").unwrap();
+        let rows: Vec<_> = stmt.rows().map(|row| row[0].to_string()).collect();
+        assert_eq!(rows, vec!["A", "b", "B", "c"]);
+        let stmt = Statement::prepare(&db, "SELECT a FROM t ORDER BY a COLLATE binary").unwrap();
+        let rows: Vec<_> = stmt.rows().map(|row| row[0].to_string()).collect();
+        assert_eq!(rows, vec!["A", "B", "b", "c"]);
+
+        // Rows already in order aren't sorted again
+        let mut db = Database::open("data/analyze.db").unwrap();
+        let plan = |db: &Database, sql: &str| Statement::prepare(db, sql).unwrap().plan().to_string();
+        assert_eq!(
+            plan(&db, "SELECT name FROM planets ORDER BY name"),
+            "QUERY PLAN\n`--SCAN planets USING COVERING INDEX by_name\n"
+        );
+        assert_eq!(
+            plan(&db, "SELECT name FROM planets ORDER BY name DESC"),
+            "QUERY PLAN\n|--SCAN planets USING COVERING INDEX by_name\n`--USE TEMP B-TREE FOR ORDER BY\n"
+        );
+        let stmt = Statement::prepare(&db, "SELECT name FROM planets WHERE name > 'p998' ORDER BY name").unwrap();
+        let rows: Vec<_> = stmt.rows().map(|row| row[0].to_string()).collect();
+        assert_eq!(rows, vec!["p999"]);
+
+        // Not for an index sorted with another collation
+        db.execute("DROP INDEX by_name").unwrap();
+        db.execute("CREATE INDEX by_name ON planets (name COLLATE nocase)")
+            .unwrap();
+        assert_eq!(
+            plan(&db, "SELECT name FROM planets WHERE name = 'P1' ORDER BY name"),
+            "QUERY PLAN\n|--SCAN planets USING COVERING INDEX by_name\n`--USE TEMP B-TREE FOR ORDER BY\n"
+        );
+        let stmt = Statement::prepare(&db, "SELECT name FROM planets WHERE name = 'P1'").unwrap();
+        assert_eq!(stmt.rows().count(), 0);
+
+        let t = vec![
+            (
+                "SELECT name FROM planets ORDER BY 2",
+                "1st ORDER BY term out of range - should be between 1 and 1",
+            ),
+            (
+                "SELECT name, id FROM planets ORDER BY id, 0",
+                "2nd ORDER BY term out of range - should be between 1 and 2",
+            ),
+            (
+                "SELECT name FROM planets ORDER BY name COLLATE foo",
+                "no such collation sequence: foo",
+            ),
+            ("SELECT name FROM planets ORDER BY", "incomplete input"),
+            ("SELECT name FROM planets ORDER name", "near \"name\": syntax error"),
+        ];
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(
+                Statement::prepare(&db, sql).err().map(|e| e.to_string()),
+                Some(exp.to_string()),
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn explain() {
+        let db = Database::open("data/analyze.db").unwrap();
+
+        // Expected from `sqlite3 data/analyze.db "explain query plan ..."`
+        let sql = "EXPLAIN QUERY PLAN SELECT name FROM planets WHERE type = ? ORDER BY name COLLATE nocase";
+        let stmt = Statement::prepare(&db, sql).unwrap();
+        assert_eq!(stmt.column_names(), vec!["id", "parent", "notused", "detail"]);
+        assert_eq!(stmt.parameter_count(), 1);
+        assert_eq!(
+            stmt.rows().collect::<Vec<_>>(),
+            vec![
+                vec![
+                    V::Number(1),
+                    V::Number(0),
+                    V::Number(0),
+                    "SEARCH planets USING INDEX by_type (type=?)".into()
+                ],
+                vec![
+                    V::Number(2),
+                    V::Number(0),
+                    V::Number(0),
+                    "USE TEMP B-TREE FOR ORDER BY".into()
+                ],
+            ]
+        );
+
+        // Reading by_moons in order costs less than sorting
+        let sql = "EXPLAIN QUERY PLAN SELECT name FROM planets WHERE type = ? ORDER BY moons";
+        let stmt = Statement::prepare(&db, sql).unwrap();
+        let details: Vec<_> = stmt.rows().map(|row| row[3].to_string()).collect();
+        assert_eq!(details, vec!["SCAN planets USING INDEX by_moons"]);
+
+        let stmt = Statement::prepare(&db, "explain query plan select * from json_each('[]')").unwrap();
+        let details: Vec<_> = stmt.rows().map(|row| row[3].to_string()).collect();
+        assert_eq!(details, vec!["SCAN json_each"]);
+
+        let t = vec![
+            ("EXPLAIN SELECT * FROM planets", "near \"SELECT\": syntax error"),
+            ("EXPLAIN QUERY SELECT * FROM planets", "near \"SELECT\": syntax error"),
+            ("EXPLAIN QUERY PLAN SELECT * FROM stars", "no such table: stars"),
+        ];
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(
+                Statement::prepare(&db, sql).err().map(|e| e.to_string()),
+                Some(exp.to_string()),
+                "{}",
+                sql
+            );
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let db = planets();
+
+        let t = vec![
+            ("SELECT * FROM stars", "no such table: stars"),
+            ("SELECT mass FROM planets", "no such column: mass"),
+            ("SELECT * FROM planets WHERE", "incomplete input"),
+            ("SELECT * FROM planets WHERE id ? 1", "near \"?\": syntax error"),
+            ("SELECT * FROM planets LIMIT 1 ORDER", "near \"ORDER\": syntax error"),
+            ("SELECT * FROM planets WHERE id = ?0", "column index out of range"),
+            ("SELECT * FROM 'planets", "unrecognized token: \"'planets\""),
+        ];
+
+        for (sql, exp) in t.into_iter() {
+            assert_eq!(
+                Statement::prepare(&db, sql).err().map(|e| e.to_string()),
+                Some(exp.to_string()),
+                "{}",
+                sql
+            );
+        }
+    }
+}
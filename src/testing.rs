@@ -0,0 +1,68 @@
+//! # Test support
+//!
+//! Helpers shared by the tests of several modules, to read the databases in
+//! `data/` and to copy them to files of their own, so tests running in
+//! parallel, or in several processes at once, never write the same file, and
+//! to check the databases they change.
+
+use crate::{btree::Key, catalog::Catalog, schema::Database};
+use binrw::{BinReaderExt, BinWrite};
+use std::{cmp::Ordering, fs::File, io::Cursor, path::PathBuf};
+
+/// A path in the temporary directory only used by the test named `name`
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rsqlite-{}-{}.db", name, std::process::id()))
+}
+
+/// A copy of the database file at `source` only used by the test named `name`
+pub(crate) fn temp_copy(source: &str, name: &str) -> PathBuf {
+    let path = temp_path(name);
+    std::fs::copy(source, &path).expect("Failed to copy database");
+    path
+}
+
+/// planets.db read into memory
+pub(crate) fn planets() -> Database {
+    let mut file = File::open("data/planets.db").expect("Failed to open planets.db");
+    file.read_be().expect("Failed to read planets.db")
+}
+
+/// Write a database out and read it back
+pub(crate) fn reread(db: &Database) -> Database {
+    let mut buffer = Cursor::new(vec![]);
+    db.write_be(&mut buffer).expect("Failed to write database");
+    buffer.set_position(0);
+    buffer.read_be().expect("Failed to read database back")
+}
+
+/**
+ * Problems with the indexes of a database, worded like `PRAGMA
+ * integrity_check` does: rows missing from an index, indexes with more or
+ * fewer keys than their table has rows, and keys out of order.
+ */
+pub(crate) fn integrity_check(db: &Database) -> Vec<String> {
+    let mut errors = vec![];
+    for table in Catalog::read(db).tables {
+        let rows = db.table_rows(table.root);
+        for index in db.table_indexes(table.root).expect("Failed to read indexes") {
+            let keys: Vec<_> = db
+                .index_keys(index.root)
+                .into_iter()
+                .map(|key| key.payload.clone())
+                .collect();
+            for (i, cell) in rows.iter().enumerate() {
+                if !keys.contains(&index.key(cell.row_id.value as i64, &cell.record.payload)) {
+                    errors.push(format!("row {} missing from index {}", i + 1, index.name));
+                }
+            }
+            if keys.len() != rows.len() {
+                errors.push(format!("wrong # of entries in index {}", index.name));
+            }
+            let order = |a: &Vec<_>, b: &Vec<_>| index.order(&Key::Record(a), &Key::Record(b));
+            if keys.windows(2).any(|pair| order(&pair[0], &pair[1]) != Ordering::Less) {
+                errors.push(format!("keys out of order in index {}", index.name));
+            }
+        }
+    }
+    errors
+}
@@ -0,0 +1,359 @@
+//! # Vacuum
+//!
+//! Deleted rows and dropped tables leave free pages behind, which new rows
+//! reuse, but the file never gets smaller on its own.
+//!
+//! - [VACUUM docs](https://www.sqlite.org/lang_vacuum.html)
+//! - [incremental_vacuum docs](https://www.sqlite.org/pragma.html#pragma_incremental_vacuum)
+//!
+//! [Database::vacuum] rebuilds the database by copying every row into a new
+//! one like `VACUUM` does, which leaves no free pages and packs the pages of
+//! each b-tree together, optionally with a new page size. Everything else in
+//! the header is kept, and the schema cookie is bumped as the root pages move.
+//!
+//! Databases in auto-vacuum mode can shrink without a rebuild.
+//! [Database::incremental_vacuum] moves the pages at the end of the file into
+//! free pages before them, pointing their parents at the new place with the
+//! help of the [pointer maps](crate::ptrmap), and truncates the file like
+//! `PRAGMA incremental_vacuum` does. Databases in [AutoVacuum::Full] mode have
+//! it done every time they are saved.
+//!
+//! ```
+//! use rsqlite::schema::{CreateOptions, Database};
+//!
+//! let mut db = Database::new(CreateOptions::default()).unwrap();
+//! db.execute("CREATE TABLE planets (name TEXT)").unwrap();
+//! for row_id in 1..=500 {
+//!     db.insert(2, row_id, vec!["Jupiter".into()]).unwrap();
+//! }
+//! for row_id in 1..=500 {
+//!     db.delete(2, row_id).unwrap();
+//! }
+//! assert!(db.db_header.freelist_page_count > 0);
+//!
+//! db.execute("VACUUM").unwrap();
+//! assert_eq!(db.db_header.freelist_page_count, 0);
+//! assert_eq!(db.db_header.database_page_count, 2);
+//! ```
+
+use crate::{
+    btree::Key,
+    schema::{
+        error, AutoVacuum, CreateOptions, Database, Header, IndexLeafCell, Page, PointerType, SerialValue,
+        TableLeafCell,
+    },
+};
+use binrw::BinResult;
+use std::cmp::Ordering;
+
+impl Database {
+    /**
+     * Rebuild the database with every row copied into new pages, like
+     * `VACUUM`. A `page_size` changes the page size, which databases in WAL
+     * mode can't do.
+     */
+    pub fn vacuum(&mut self, page_size: Option<u32>) -> BinResult<()> {
+        let header = &self.db_header;
+        if header.write_format == 2 && page_size.is_some_and(|size| size != header.page_size_bytes()) {
+            return Err(error("The page size of a database in WAL mode can't be changed"));
+        }
+        *self = self.vacuumed(page_size)?;
+        Ok(())
+    }
+
+    /// A copy of the database rebuilt like [Database::vacuum] does
+    pub fn vacuumed(&self, page_size: Option<u32>) -> BinResult<Database> {
+        let header = &self.db_header;
+        let auto_vacuum = match (header.is_auto_vacuum(), header.incremental_vacuum) {
+            (false, _) => AutoVacuum::None,
+            (true, 0) => AutoVacuum::Full,
+            (true, _) => AutoVacuum::Incremental,
+        };
+        let mut db = Database::new(CreateOptions {
+            page_size: page_size.unwrap_or(header.page_size_bytes()),
+            reserved_bytes: header.reserved_bytes,
+            auto_vacuum,
+            ..Default::default()
+        })?;
+        let new = db.db_header;
+        db.db_header = Header {
+            page_size: new.page_size,
+            database_page_count: new.database_page_count,
+            freelist_trunk_page: 0,
+            freelist_page_count: 0,
+            autovacuum_top_root: new.autovacuum_top_root,
+            schema_cookie: header.schema_cookie.wrapping_add(1),
+            ..*header
+        };
+
+        // Every root page first, so they are together at the start of the
+        // file in auto-vacuum mode
+        let mut copies = vec![];
+        for cell in self.table_rows(1) {
+            let mut payload = cell.record.payload.clone();
+            let root = match payload.get(3) {
+                Some(&SerialValue::Number(n)) if n > 0 => n as u32,
+                // Views and triggers have no b-tree
+                _ => 0,
+            };
+            if root > 0 {
+                let index = matches!(payload.first(), Some(SerialValue::String(kind)) if kind == "index");
+                let new_root = match index {
+                    true => db.create_btree::<IndexLeafCell>()?,
+                    false => db.create_btree::<TableLeafCell>()?,
+                };
+                payload[3] = (new_root as i64).into();
+                copies.push((index, root, new_root));
+            }
+            db.insert(1, cell.row_id.value as i64, payload)?;
+        }
+
+        // Keys come in order, so each one goes after the ones before it
+        let append = |_: &Key, _: &Key| Ordering::Greater;
+        for (index, root, new_root) in copies {
+            match index {
+                true => {
+                    for record in self.index_keys(root) {
+                        let cell = db.index_cell(record.payload.clone());
+                        db.insert_cell(new_root, cell, &append)?;
+                    }
+                }
+                false => {
+                    // Indexes are copied as they are, not filled again
+                    for cell in self.table_rows(root) {
+                        let cell = db.table_cell(cell.row_id.value as i64, cell.record.payload.clone());
+                        db.insert_cell(new_root, cell, &append)?;
+                    }
+                }
+            }
+        }
+        Ok(db)
+    }
+
+    /**
+     * Move up to `pages` pages from the end of the file into free pages and
+     * truncate it, or as many as there are free pages when `pages` is 0, like
+     * `PRAGMA incremental_vacuum(N)`. Returns how many pages the file lost,
+     * not counting pointer map pages no longer needed.
+     *
+     * Databases not in auto-vacuum mode are left alone, since they have no
+     * pointer maps to find the parents of pages with.
+     */
+    pub fn incremental_vacuum(&mut self, pages: u32) -> BinResult<u32> {
+        if !self.db_header.is_auto_vacuum() {
+            return Ok(0);
+        }
+        let free = self.db_header.freelist_page_count;
+        let count = if pages == 0 { free } else { pages.min(free) };
+
+        for _ in 0..count {
+            self.truncate_unused();
+            let last = self.pages.len() as u32;
+            if !self.take_free(last) {
+                // Root pages are all before the free pages, see `move_last_root`
+                if self.pointer_map_entry(last).map(|entry| entry.page_type) == Some(PointerType::RootPage) {
+                    return Err(error(format!("Root page {} is after the free pages", last)));
+                }
+                let number = self.allocate();
+                self.relocate(last, number)?;
+            }
+            self.pages.pop();
+        }
+        self.truncate_unused();
+        self.db_header.database_page_count = self.pages.len() as u32;
+        Ok(count)
+    }
+
+    /// Drop the pointer map and lock-byte pages at the end of the file, which
+    /// are only there for the pages after them
+    fn truncate_unused(&mut self) {
+        while self.pages.len() > 1 && matches!(self.pages.last(), Some(Page::PointerMap(_) | Page::LockByte)) {
+            self.pages.pop();
+        }
+    }
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{catalog::Catalog, ddl::Error, testing::temp_copy};
+    use binrw::BinReaderExt;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn read(path: &str) -> Database {
+        let bytes = std::fs::read(path).expect("Failed to read database");
+        Cursor::new(bytes).read_be().expect("Failed to parse database")
+    }
+
+    /// Every row of every table and every index key, by name
+    fn contents(db: &Database) -> Vec<(String, Vec<Vec<SerialValue>>)> {
+        let catalog = Catalog::read(db);
+        let tables = catalog.tables.iter().map(|table| {
+            let rows = db.table_rows(table.root).into_iter().map(|cell| {
+                let mut row = cell.record.payload.clone();
+                row.push(SerialValue::Number(cell.row_id.value as i64));
+                row
+            });
+            (table.name.clone(), rows.collect())
+        });
+        let indexes = catalog.indexes.iter().map(|index| {
+            let keys = db
+                .index_keys(index.root)
+                .into_iter()
+                .map(|record| record.payload.clone());
+            (index.name.clone(), keys.collect())
+        });
+        tables.chain(indexes).collect()
+    }
+
+    #[test]
+    fn vacuum() {
+        // Page counts after `VACUUM` in SQLite, which packs index pages tighter
+        let t = vec![
+            ("data/planets.db", None, 2),
+            ("data/analyze.db", None, 42),
+            ("data/analyze.db", Some(1024), 137),
+            ("data/autovacuum.db", None, 55),
+            ("data/overflow.db", None, 227),
+        ];
+        for (path, page_size, pages) in t.into_iter() {
+            let db = read(path);
+            let mut vacuumed = read(path);
+            vacuumed.vacuum(page_size).unwrap();
+
+            let header = vacuumed.db_header;
+            assert_eq!(
+                (header.database_page_count, header.freelist_page_count),
+                (pages, 0),
+                "{} {:?}",
+                path,
+                page_size
+            );
+            assert_eq!(header.schema_cookie, db.db_header.schema_cookie + 1, "{}", path);
+            assert_eq!(
+                header.autovacuum_top_root > 0,
+                db.db_header.is_auto_vacuum(),
+                "{}",
+                path
+            );
+            assert_eq!(contents(&vacuumed), contents(&db), "{} {:?}", path, page_size);
+            assert_eq!(vacuumed.check_pointer_map(), vec![], "{}", path);
+        }
+
+        // Rows that need overflow pages at the new size get them
+        let mut db = read("data/analyze.db");
+        let text = "x".repeat(600);
+        db.insert(2, 1 << 40, vec![().into(), text.as_str().into()]).unwrap();
+        let original = contents(&db);
+        db.vacuum(Some(512)).unwrap();
+        assert_eq!(db.db_header.page_size_bytes(), 512);
+        assert!(db.pages.iter().any(|page| matches!(page, Page::Overflow(_))));
+        assert_eq!(contents(&db), original);
+    }
+
+    #[test]
+    fn save_vacuumed() {
+        let path = temp_copy("data/analyze.db", "vacuum-save");
+
+        let mut db = Database::open(&path).unwrap();
+        db.vacuum(Some(1024)).unwrap();
+        db.save(&path).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        let saved = Database::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(len, 137 * 1024);
+        assert_eq!(contents(&saved), contents(&read("data/analyze.db")));
+
+        // Full auto-vacuum databases give back free pages when saved
+        let options = CreateOptions {
+            auto_vacuum: AutoVacuum::Full,
+            ..Default::default()
+        };
+        let mut db = Database::create(&path, options).unwrap();
+        db.execute("CREATE TABLE planets (name TEXT)").unwrap();
+        for row_id in 1..=500 {
+            db.insert(3, row_id, vec!["Jupiter".into()]).unwrap();
+        }
+        db.save(&path).unwrap();
+        for row_id in 1..=500 {
+            db.delete(3, row_id).unwrap();
+        }
+        db.save(&path).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            (db.db_header.database_page_count, db.db_header.freelist_page_count),
+            (3, 0)
+        );
+        assert_eq!(len, 3 * 4096);
+    }
+
+    #[test]
+    fn incremental_vacuum() {
+        let original = read("data/autovacuum.db");
+        let (pages, free) = (
+            original.db_header.database_page_count,
+            original.db_header.freelist_page_count,
+        );
+        assert_eq!((pages, free), (108, 52));
+
+        // Page counts after `PRAGMA incremental_vacuum(N)` in SQLite, pointer
+        // map page 105 goes with the pages after it
+        let t = vec![(1, 107, 51), (10, 97, 42), (0, 55, 0), (100, 55, 0)];
+        for (n, pages, free) in t.into_iter() {
+            let mut db = read("data/autovacuum.db");
+            assert_eq!(db.incremental_vacuum(n).unwrap(), 52 - free, "{}", n);
+            let header = db.db_header;
+            assert_eq!(
+                (header.database_page_count, header.freelist_page_count),
+                (pages, free),
+                "{}",
+                n
+            );
+            assert_eq!(db.pages.len(), pages as usize, "{}", n);
+            assert_eq!(db.check_pointer_map(), vec![], "{}", n);
+            assert_eq!(contents(&db), contents(&original), "{}", n);
+        }
+
+        // Overflow pages move too, with the cells or pages pointing at them
+        let mut db = read("data/overflow.db");
+        for row_id in 1..=20 {
+            db.delete(3, row_id).unwrap();
+        }
+        let (original, free) = (contents(&db), db.db_header.freelist_page_count);
+        assert_eq!(db.incremental_vacuum(0).unwrap(), free);
+        assert_eq!(db.db_header.freelist_page_count, 0);
+        assert_eq!(db.check_pointer_map(), vec![]);
+        assert_eq!(contents(&db), original);
+
+        // Without pointer maps nothing can move
+        let mut db = read("data/analyze.db");
+        assert_eq!(db.incremental_vacuum(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn statements() {
+        let t = vec![
+            ("VACUUM", Some(55)),
+            ("vacuum main;", Some(55)),
+            ("PRAGMA incremental_vacuum", Some(55)),
+            ("PRAGMA main.incremental_vacuum(10)", Some(97)),
+            ("PRAGMA incremental_vacuum = 1", Some(107)),
+            ("VACUUM other", None),
+            ("VACUUM INTO 1", None),
+            ("PRAGMA incremental_vacuum('all')", None),
+            ("PRAGMA page_size = 1024", None),
+        ];
+        for (sql, pages) in t.into_iter() {
+            let mut db = read("data/autovacuum.db");
+            match (db.execute(sql), pages) {
+                (Ok(()), Some(pages)) => assert_eq!(db.db_header.database_page_count, pages, "{}", sql),
+                (Err(Error::NoSuch(..) | Error::Syntax(_) | Error::Unsupported(_)), None) => {}
+                (result, _) => panic!("{}: {:?}", sql, result),
+            }
+        }
+    }
+}
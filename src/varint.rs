@@ -1,5 +1,5 @@
-use binrw::{BinRead, BinResult as Result, Endian};
-use std::io::{Read, Seek};
+use binrw::{BinRead, BinResult as Result, BinWrite, Endian};
+use std::io::{Read, Seek, Write};
 
 /// Variable length u64 integers
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -18,6 +18,18 @@ impl VarInt {
     }
 
     pub fn encode(value: u64) -> Vec<u8> {
+        // Values over 56 bits take 9 bytes, and the last byte holds 8 bits
+        if value >> 56 != 0 {
+            let mut result = vec![0u8; 9];
+            result[8] = value as u8;
+            let mut value = value >> 8;
+            for byte in result[..8].iter_mut().rev() {
+                *byte = ((value & 0x7f) as u8) | 0x80;
+                value >>= 7;
+            }
+            return result;
+        }
+
         let mut buf = [0u8; 10];
         let mut n = 0;
         let mut value = value;
@@ -46,6 +58,15 @@ impl VarInt {
     }
 }
 
+impl BinWrite for VarInt {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(&self, writer: &mut W, _: Endian, _: Self::Args<'_>) -> Result<()> {
+        writer.write_all(&VarInt::encode(self.value))?;
+        Ok(())
+    }
+}
+
 /// A custom parser for VarInt
 ///
 /// Note: Implicitly big endian, even though not specified anywhere
@@ -62,6 +83,12 @@ impl BinRead for VarInt {
                 buf[0]
             };
 
+            // If this is the 9th byte, include all 8 bits.
+            if i == 8 {
+                value = (value << 8) | (byte as u64);
+                return Ok(VarInt { value, width });
+            }
+
             // Shift 7 bits left ++ 7 low order bits of byte
             value = (value << 7) | ((byte & 0x7F) as u64);
 
@@ -69,12 +96,6 @@ impl BinRead for VarInt {
             if byte & 0x80 == 0 {
                 return Ok(VarInt { value, width });
             }
-
-            // If this is the 9th byte, include all 8 bits.
-            if i == 8 {
-                value = (value << 8) | (byte as u64);
-                return Ok(VarInt { value, width });
-            }
         }
 
         // If we exit the loop, this is an error (invalid varint format).
@@ -119,10 +140,13 @@ mod test {
             (127, vec![0x7f]),
             (128, vec![0x81, 0x00]),
             (216, vec![0x81, 0x58]),
+            (1 << 56, vec![0x80, 0xc0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00]),
+            (u64::MAX, vec![0xff; 9]),
         ];
 
         for (num, exp) in t.into_iter() {
-            assert_eq!(VarInt::encode(num), exp)
+            assert_eq!(VarInt::encode(num), exp);
+            assert_eq!(VarInt::read_be(&mut Cursor::new(&exp)).unwrap().value, num);
         }
     }
 }
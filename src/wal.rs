@@ -0,0 +1,993 @@
+//! # Write-ahead log
+//!
+//! In WAL mode, [Header::write_format] and [Header::read_format] are 2 and
+//! commits append the changed pages to a `-wal` file next to the database
+//! instead of writing them to the database file. Until a checkpoint copies
+//! them back, the newest committed copy of a page in the WAL replaces the one
+//! in the database file.
+//!
+//! [Docs](https://www.sqlite.org/fileformat2.html#the_write_ahead_log)
+//!
+//! Each frame of the WAL is a page with a header that repeats the salts of
+//! the WAL header, and a checksum that covers every frame before it. A
+//! checkpoint changes the salts, so frames left over from before it don't
+//! match, and neither does a frame torn by a crash. Frames are read up to the
+//! first one that doesn't, and used up to the last commit frame before that.
+//!
+//! Saving a database in [JournalMode::Wal](crate::journal::JournalMode::Wal) appends the pages that changed,
+//! the last frame marking the commit, and indexes them in the
+//! [WAL-index](crate::shm), and a [checkpoint] copies the frames back. The first commit after every frame is in the database file starts
+//! the WAL over with new salts.
+//!
+//! ```
+//! use rsqlite::{schema::Database, wal::{checkpoint, CheckpointMode}};
+//!
+//! // Three moons are only in data/wal.db-wal
+//! let db = Database::open("data/wal.db").unwrap();
+//! assert_eq!(db.table_rows(2).len(), 7);
+//!
+//! let path = std::env::temp_dir().join(format!("rsqlite-wal-doc-{}.db", std::process::id()));
+//! std::fs::copy("data/wal.db", &path).unwrap();
+//! std::fs::copy("data/wal.db-wal", rsqlite::wal::wal_path(&path)).unwrap();
+//! assert_eq!(checkpoint(&path, CheckpointMode::Truncate).unwrap(), (0, 0));
+//! assert_eq!(std::fs::metadata(rsqlite::wal::wal_path(&path)).unwrap().len(), 0);
+//! # std::fs::remove_file(rsqlite::wal::wal_path(&path)).unwrap();
+//!
+//! let db = Database::open(&path).unwrap();
+//! assert_eq!(db.table_rows(2).len(), 7);
+//! # std::fs::remove_file(rsqlite::shm::shm_path(&path)).unwrap();
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+
+use crate::{
+    journal::{sync_directory, Snapshot},
+    lock::{
+        busy, wal_read_lock, FileLock, LockLevel, ShmLock, WAL_CKPT_LOCK, WAL_DMS_LOCK, WAL_READERS, WAL_WRITE_LOCK,
+    },
+    schema::{error, lock_byte_page, Database, Header},
+    shm::{WalIndex, READ_MARK_NOT_USED},
+};
+use binrw::{binrw, BinRead, BinResult, BinWrite};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    hash::{BuildHasher, RandomState},
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// [WalHeader::magic] with checksums of little endian words, the lowest bit
+/// is set for big endian words.
+pub const WAL_MAGIC: u32 = 0x377f0682;
+
+/// [WalHeader::version] of every WAL
+pub const WAL_VERSION: u32 = 3007000;
+
+/// Which commit of a WAL to read the database as of, see [Wal::as_of]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AsOf {
+    /// The commit ending at this frame, counting from 1 like SQLite does
+    Frame(u32),
+    /// The last commit of the WAL with this salt-1, which is different every
+    /// time the WAL starts over
+    Salt(u32),
+}
+
+/// A transaction committed to the WAL
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    /// Frame number of the commit frame, counting from 1
+    pub frame: u32,
+    /// Salts of the WAL the frames were written with
+    pub salt: [u32; 2],
+    /// Size of the database in pages after the commit
+    pub size: u32,
+    /// Numbers of the pages written, in order
+    pub pages: Vec<u32>,
+}
+
+/// How much a [checkpoint] does, like `PRAGMA wal_checkpoint(mode)`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CheckpointMode {
+    /// Copy the committed frames to the database file, up to the oldest one
+    /// another connection still reads, without waiting for any lock
+    #[default]
+    Passive,
+    /// Wait for the writer and for readers of older frames to finish, then
+    /// copy every frame, busy if one of them still hasn't after the timeout
+    Full,
+    /// Then start the WAL over with new salts, which makes every frame stale
+    Restart,
+    /// Then truncate the WAL file to 0 bytes
+    Truncate,
+}
+
+/**
+ * The 32 byte header at the start of a WAL file.
+ *
+ * | Offset | Size | Description                                            |
+ * |--------|------|--------------------------------------------------------|
+ * | 0      | 4    | Magic number 0x377f0682 or 0x377f0683                  |
+ * | 4      | 4    | File format version, 3007000                           |
+ * | 8      | 4    | Database page size                                     |
+ * | 12     | 4    | Checkpoint sequence number                             |
+ * | 16     | 4    | Salt-1, incremented with every checkpoint              |
+ * | 20     | 4    | Salt-2, a different random number for each checkpoint  |
+ * | 24     | 4    | Checksum-1, of the first 24 bytes of the header        |
+ * | 28     | 4    | Checksum-2                                             |
+ */
+#[binrw]
+#[brw(big)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WalHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub page_size: u32,
+    pub checkpoint_sequence: u32,
+    pub salt: [u32; 2],
+    pub checksum: [u32; 2],
+}
+
+/**
+ * The 24 byte header of a frame, followed by the page.
+ *
+ * | Offset | Size | Description                                            |
+ * |--------|------|--------------------------------------------------------|
+ * | 0      | 4    | Page number                                            |
+ * | 4      | 4    | Size of the database in pages after a commit, else 0   |
+ * | 8      | 4    | Salt-1 copied from the WAL header                      |
+ * | 12     | 4    | Salt-2 copied from the WAL header                      |
+ * | 16     | 4    | Checksum-1, of the WAL up to the end of this page      |
+ * | 20     | 4    | Checksum-2                                             |
+ */
+#[binrw]
+#[brw(big)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    pub page_number: u32,
+    pub commit_size: u32,
+    pub salt: [u32; 2],
+    pub checksum: [u32; 2],
+}
+
+#[binrw]
+#[brw(big, import(page_size: u32))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub header: FrameHeader,
+    #[br(count = page_size)]
+    pub data: Vec<u8>,
+}
+
+/// The header and the valid frames of a WAL file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wal {
+    pub header: WalHeader,
+    pub frames: Vec<Frame>,
+}
+
+impl WalHeader {
+    /// A header of little endian checksums, with its own checksum
+    pub fn new(page_size: u32, checkpoint_sequence: u32, salt: [u32; 2]) -> WalHeader {
+        let mut header = WalHeader {
+            magic: WAL_MAGIC,
+            version: WAL_VERSION,
+            page_size,
+            checkpoint_sequence,
+            salt,
+            checksum: [0, 0],
+        };
+        header.checksum = checksum(false, [0, 0], &header.bytes()[..24]);
+        header
+    }
+
+    /// The header that starts the WAL over after a checkpoint
+    pub fn next(&self) -> WalHeader {
+        let salt = [self.salt[0].wrapping_add(1), random()];
+        WalHeader::new(self.page_size, self.checkpoint_sequence.wrapping_add(1), salt)
+    }
+
+    pub(crate) fn is_big_endian(&self) -> bool {
+        self.magic & 1 == 1
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Cursor::new(vec![]);
+        self.write(&mut bytes).expect("Failed to write WAL header");
+        bytes.into_inner()
+    }
+}
+
+impl FrameHeader {
+    /// Is this the last frame of a transaction?
+    pub fn is_commit(&self) -> bool {
+        self.commit_size != 0
+    }
+}
+
+impl Frame {
+    /// A frame of the WAL with `header`, following frames that add up to `sum`
+    pub fn new(header: &WalHeader, sum: [u32; 2], page_number: u32, commit_size: u32, data: &[u8]) -> Frame {
+        let mut frame = Frame {
+            header: FrameHeader {
+                page_number,
+                commit_size,
+                salt: header.salt,
+                checksum: [0, 0],
+            },
+            data: data.to_vec(),
+        };
+        frame.header.checksum = frame.checksum(header.is_big_endian(), sum);
+        frame
+    }
+
+    /// Checksum of the frame, of its first 8 bytes and the page, continuing from `sum`
+    fn checksum(&self, big_endian: bool, sum: [u32; 2]) -> [u32; 2] {
+        let mut bytes = self.header.page_number.to_be_bytes().to_vec();
+        bytes.extend(self.header.commit_size.to_be_bytes());
+        checksum(big_endian, checksum(big_endian, sum, &bytes), &self.data)
+    }
+}
+
+impl Wal {
+    /**
+     * Read a WAL up to its first frame with different salts or a wrong
+     * checksum. Returns `None` if the header is not valid, in which case the
+     * WAL has no frames to use.
+     */
+    pub fn read<R: Read + Seek>(reader: &mut R) -> BinResult<Option<Wal>> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        if len < 32 {
+            return Ok(None);
+        }
+
+        let header = WalHeader::read(reader)?;
+        let big_endian = header.is_big_endian();
+        let page_size = header.page_size;
+        let valid = header.magic | 1 == WAL_MAGIC | 1
+            && header.version == WAL_VERSION
+            && page_size.is_power_of_two()
+            && (512..=65536).contains(&page_size)
+            && checksum(big_endian, [0, 0], &header.bytes()[..24]) == header.checksum;
+        if !valid {
+            return Ok(None);
+        }
+
+        let mut frames = vec![];
+        let mut sum = header.checksum;
+        while reader.stream_position()? + 24 + page_size as u64 <= len {
+            let frame = Frame::read_args(reader, (page_size,))?;
+            sum = frame.checksum(big_endian, sum);
+            if frame.header.page_number == 0 || frame.header.salt != header.salt || frame.header.checksum != sum {
+                break;
+            }
+            frames.push(frame);
+        }
+
+        Ok(Some(Wal { header, frames }))
+    }
+
+    /// Frames of the transactions that were committed, in order
+    pub fn committed(&self) -> &[Frame] {
+        let end = self
+            .frames
+            .iter()
+            .rposition(|f| f.header.is_commit())
+            .map_or(0, |i| i + 1);
+        &self.frames[..end]
+    }
+
+    /// The committed transactions, oldest first
+    pub fn commits(&self) -> Vec<Commit> {
+        let mut commits = vec![];
+        let mut pages = vec![];
+        for (i, frame) in self.committed().iter().enumerate() {
+            pages.push(frame.header.page_number);
+            if frame.header.is_commit() {
+                pages.sort();
+                pages.dedup();
+                commits.push(Commit {
+                    frame: i as u32 + 1,
+                    salt: frame.header.salt,
+                    size: frame.header.commit_size,
+                    pages: std::mem::take(&mut pages),
+                });
+            }
+        }
+        commits
+    }
+
+    /**
+     * The WAL without the frames after the commit `as_of`, so applying it
+     * gives the database as it was right after that commit. Fails if `as_of`
+     * is not a commit of this WAL, since frames of an earlier salt are
+     * overwritten or stale.
+     */
+    pub fn as_of(&self, as_of: AsOf) -> BinResult<Wal> {
+        let frames = self.committed();
+        let end = match as_of {
+            AsOf::Frame(n)
+                if frames
+                    .get(n.wrapping_sub(1) as usize)
+                    .is_some_and(|f| f.header.is_commit()) =>
+            {
+                n
+            }
+            AsOf::Frame(n) => return Err(error(format!("Frame {} is not a commit of the WAL", n))),
+            AsOf::Salt(salt) if salt == self.header.salt[0] => frames.len() as u32,
+            AsOf::Salt(salt) => return Err(error(format!("The WAL was started over since salt {}", salt))),
+        };
+
+        Ok(Wal {
+            header: self.header,
+            frames: frames[..end as usize].to_vec(),
+        })
+    }
+
+    /**
+     * Replace the pages of the database file `bytes` with their newest
+     * committed frames, and resize it to the database size of the last
+     * commit.
+     */
+    pub fn apply(&self, bytes: &mut Vec<u8>) {
+        let page_size = self.header.page_size as usize;
+        let frames = self.committed();
+        let Some(last) = frames.last() else {
+            return;
+        };
+
+        bytes.resize(last.header.commit_size as usize * page_size, 0);
+        for frame in frames {
+            let start = (frame.header.page_number as usize - 1) * page_size;
+            if let Some(page) = bytes.get_mut(start..start + page_size) {
+                page.copy_from_slice(&frame.data);
+            }
+        }
+    }
+}
+
+/// Path of the WAL of the database at `path`
+pub fn wal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push("-wal");
+    PathBuf::from(name)
+}
+
+/**
+ * The bytes of the database file at `path`, with the pages committed to its
+ * WAL on top when it is in WAL mode.
+ */
+pub fn read_with_wal(path: &Path) -> BinResult<Vec<u8>> {
+    let (mut bytes, wal) = read_locked(path)?;
+    if let Some(wal) = wal {
+        wal.apply(&mut bytes);
+    }
+    Ok(bytes)
+}
+
+/**
+ * Like [read_with_wal], with only the frames up to the commit `as_of`. The
+ * files are not changed, so a copy of them can be looked at as it was at
+ * every commit still in the WAL.
+ */
+pub fn read_as_of(path: &Path, as_of: AsOf) -> BinResult<Vec<u8>> {
+    let (mut bytes, wal) = read_locked(path)?;
+    let Some(wal) = wal else {
+        return Err(error(format!("{} has no valid WAL", path.display())));
+    };
+    wal.as_of(as_of)?.apply(&mut bytes);
+    Ok(bytes)
+}
+
+/// Does the header of the database file at `path` say it is in WAL mode?
+pub fn is_wal_mode(path: &Path) -> BinResult<bool> {
+    match File::open(path) {
+        Ok(file) => {
+            let mut bytes = vec![];
+            file.take(100).read_to_end(&mut bytes)?;
+            Ok(wal_mode(&bytes))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// [is_wal_mode] for the database file `lock` is a handle to, so no other
+/// handle is opened on it
+pub(crate) fn is_wal_mode_locked(lock: &mut FileLock) -> BinResult<bool> {
+    let mut bytes = vec![];
+    lock.file().seek(SeekFrom::Start(0))?;
+    lock.file().take(100).read_to_end(&mut bytes)?;
+    Ok(wal_mode(&bytes))
+}
+
+/**
+ * Copy the pages committed to the WAL of the database at `path` back to the
+ * database file, up to the oldest frame another connection still reads the
+ * database as of. Returns the number of frames in the WAL and the number of
+ * them copied, like `PRAGMA wal_checkpoint` does, which are both 0 after
+ * truncating it.
+ *
+ * Like SQLite, it holds [WAL_CKPT_LOCK] and takes each reader slot it can to
+ * move its read mark past the frames it copies, and records how far it got
+ * in the [WAL-index](crate::shm), which stays in place for the connections
+ * that have it open. The database file is synced before the WAL is started
+ * over, so the frames can be copied again if the checkpoint is interrupted.
+ */
+pub fn checkpoint(path: &Path, mode: CheckpointMode) -> BinResult<(u32, u32)> {
+    let mut lock = FileLock::open(path)?;
+    lock.lock(LockLevel::Shared)?;
+    checkpoint_locked(&mut lock, path, mode)
+}
+
+/// [checkpoint] the database file at `path` through `lock`, which holds
+/// SHARED, so no other handle is opened on it
+pub(crate) fn checkpoint_locked(lock: &mut FileLock, path: &Path, mode: CheckpointMode) -> BinResult<(u32, u32)> {
+    if !is_wal_mode_locked(lock)? && lock.file().metadata()?.len() > 0 {
+        return Ok((0, 0));
+    }
+
+    let shm = ShmLock::open(path)?;
+    shm.lock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1, false)?;
+    shm.lock(WAL_CKPT_LOCK..WAL_CKPT_LOCK + 1, true)?;
+    let wait = mode != CheckpointMode::Passive;
+    if wait {
+        shm.lock(WAL_WRITE_LOCK..WAL_WRITE_LOCK + 1, true)?;
+    }
+    let Some(mut wal) = read_wal(path)? else {
+        return Ok((0, 0));
+    };
+
+    // A WAL-index that doesn't match the WAL is rebuilt like in commit, and
+    // frames after its last one are a commit that isn't indexed yet
+    let mut index = match WalIndex::read(&mut shm.file()) {
+        Ok(index) if index.check(Some(&wal)).is_empty() => index,
+        _ => {
+            shm.lock(WAL_WRITE_LOCK..WAL_DMS_LOCK, true)?;
+            wal = read_wal(path)?.ok_or_else(|| error(format!("{} has no valid WAL", path.display())))?;
+            let index = WalIndex::recover(&wal);
+            index.write(&mut shm.file())?;
+            index
+        }
+    };
+
+    // Readers in a slot this can't take read up to its mark, the others are
+    // moved to the last frame or freed
+    let max_frame = index.headers[0].max_frame;
+    let mut safe = max_frame;
+    if index.checkpoint.backfill < max_frame {
+        for i in 1..WAL_READERS as usize {
+            let mark = index.checkpoint.read_marks[i];
+            if mark >= safe {
+                continue;
+            }
+            let slot = wal_read_lock(i as u64);
+            if try_lock(&shm, slot..slot + 1, wait)? {
+                index.checkpoint.read_marks[i] = if i == 1 { max_frame } else { READ_MARK_NOT_USED };
+                index.write_read_mark(&mut shm.file(), i)?;
+                shm.unlock(slot..slot + 1)?;
+            } else {
+                safe = mark;
+            }
+        }
+    }
+
+    // Reader slot 0 keeps a commit from starting the WAL over meanwhile
+    let backfill = index.checkpoint.backfill;
+    let slot = wal_read_lock(0);
+    if backfill < safe && try_lock(&shm, slot..slot + 1, wait)? {
+        index.checkpoint.backfill_attempted = safe;
+        index.write_backfill(&mut shm.file())?;
+
+        let frames = &wal.committed()[..safe as usize];
+        let page_size = wal.header.page_size as u64;
+        let size = frames[safe as usize - 1].header.commit_size;
+        let newest: BTreeMap<u32, &Frame> = frames[backfill as usize..]
+            .iter()
+            .filter(|f| f.header.page_number <= size)
+            .map(|f| (f.header.page_number, f))
+            .collect();
+
+        let file = lock.file();
+        for (number, frame) in newest {
+            file.seek(SeekFrom::Start((number as u64 - 1) * page_size))?;
+            file.write_all(&frame.data)?;
+        }
+        if safe == max_frame {
+            file.set_len(size as u64 * page_size)?;
+        }
+        file.sync_all()?;
+        index.checkpoint.backfill = safe;
+        index.write_backfill(&mut shm.file())?;
+        shm.unlock(slot..slot + 1)?;
+    }
+
+    let backfill = index.checkpoint.backfill;
+    match mode {
+        CheckpointMode::Passive => return Ok((max_frame, backfill)),
+        _ if backfill < max_frame => return Err(busy().into()),
+        CheckpointMode::Full => return Ok((max_frame, backfill)),
+        _ => {}
+    }
+
+    // Starting the WAL over waits for every reader, since none of them can
+    // read the frames after it
+    if !try_lock(&shm, wal_read_lock(1)..wal_read_lock(WAL_READERS), true)? {
+        return Err(busy().into());
+    }
+    let next = wal.header.next();
+    let mut file = OpenOptions::new().write(true).open(wal_path(path))?;
+    let result = if mode == CheckpointMode::Restart {
+        file.write_all(&next.bytes())?;
+        (max_frame, max_frame)
+    } else {
+        file.set_len(0)?;
+        (0, 0)
+    };
+    file.sync_all()?;
+    WalIndex::new(&next).write(&mut shm.file())?;
+    Ok(result)
+}
+
+/**
+ * Commit the serialized database `new` to the WAL of the database file at
+ * `path`, which is in WAL mode, appending the pages that differ from the
+ * database as seen through the WAL and syncing them, then indexing them in
+ * the [WAL-index](crate::shm).
+ *
+ * Frames are appended after the last commit, overwriting any frames of a
+ * transaction that never committed, holding [WAL_WRITE_LOCK] only, so other
+ * connections keep reading meanwhile. If every committed frame is in the
+ * database file already and no reader holds a reader slot, the WAL starts
+ * over with a new header instead. Fails if another connection committed
+ * since the files were as in `snapshot`, reading them through `lock`, which
+ * holds SHARED.
+ */
+pub(crate) fn commit(
+    lock: &mut FileLock,
+    path: &Path,
+    new: &[u8],
+    page_size: u32,
+    snapshot: &Snapshot,
+) -> BinResult<()> {
+    let shm = ShmLock::open(path)?;
+    shm.lock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1, false)?;
+    shm.lock(WAL_WRITE_LOCK..WAL_WRITE_LOCK + 1, true)?;
+    snapshot.check(lock, path)?;
+    let mut main = vec![];
+    lock.file().seek(SeekFrom::Start(0))?;
+    lock.file().read_to_end(&mut main)?;
+    let wal = read_wal(path)?.filter(|wal| wal.header.page_size == page_size);
+    let mut old = main.clone();
+    if let Some(wal) = &wal {
+        wal.apply(&mut old);
+    }
+
+    let chunk = page_size as usize;
+    let lock_byte = lock_byte_page(page_size) as usize;
+    let mut pages: Vec<(u32, &[u8])> = new
+        .chunks(chunk)
+        .enumerate()
+        .filter(|(i, page)| i + 1 != lock_byte && old.chunks(chunk).nth(*i) != Some(*page))
+        .map(|(i, page)| (i as u32 + 1, page))
+        .collect();
+    if pages.is_empty() {
+        if old.len() == new.len() {
+            return Ok(());
+        }
+        // A commit needs a frame even if the database only got smaller
+        pages.push((1, &new[..chunk]));
+    }
+
+    let restart = match &wal {
+        Some(_) if old == main => try_lock(&shm, wal_read_lock(0)..wal_read_lock(WAL_READERS), true)?,
+        _ => false,
+    };
+    let (header, frames) = match &wal {
+        Some(wal) if !restart => (wal.header, wal.committed()),
+        Some(wal) => (wal.header.next(), &[][..]),
+        None => (WalHeader::new(page_size, 0, [random(), random()]), &[][..]),
+    };
+
+    // A WAL-index that doesn't match the WAL is rebuilt, holding every slot
+    // but the writer's like SQLite does, and so is the one of a new WAL
+    let index = WalIndex::read(&mut shm.file()).ok().filter(|index| {
+        let matches = wal.as_ref().is_some_and(|wal| index.check(Some(wal)).is_empty());
+        matches && index.headers[0].max_frame == frames.len() as u32
+    });
+    let (mut index, rebuilt) = match index {
+        Some(index) => (index, false),
+        None => {
+            shm.lock(WAL_CKPT_LOCK..WAL_DMS_LOCK, true)?;
+            match &wal {
+                Some(wal) if !restart => (WalIndex::recover(wal), true),
+                _ => (WalIndex::new(&header), true),
+            }
+        }
+    };
+
+    let wal_path = wal_path(path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&wal_path)?;
+    if frames.is_empty() {
+        header.write(&mut file)?;
+    }
+    file.seek(SeekFrom::Start(32 + frames.len() as u64 * (24 + page_size as u64)))?;
+
+    let mut sum = frames.last().map_or(header.checksum, |f| f.header.checksum);
+    let size = (new.len() / chunk) as u32;
+    let mut written = vec![];
+    for (i, (number, page)) in pages.iter().enumerate() {
+        let commit_size = if i + 1 == pages.len() { size } else { 0 };
+        let frame = Frame::new(&header, sum, *number, commit_size, page);
+        sum = frame.header.checksum;
+        frame.write_args(&mut file, (page_size,))?;
+        written.push(frame);
+    }
+    file.sync_all()?;
+    if frames.is_empty() {
+        sync_directory(&wal_path)?;
+    }
+
+    index.append(&written);
+    match rebuilt {
+        true => index.write(&mut shm.file()),
+        false => index.write_frames(&mut shm.file(), frames.len() as u32 + 1),
+    }
+}
+
+impl Database {
+    /// Read the database file at `path` as it was right after the commit
+    /// `as_of` of its WAL, see [read_as_of]. Unlike [Database::open], a hot
+    /// journal is left alone.
+    pub fn open_as_of<P: AsRef<Path>>(path: P, as_of: AsOf) -> BinResult<Database> {
+        let bytes = read_as_of(path.as_ref(), as_of)?;
+        Database::read(&mut Cursor::new(bytes))
+    }
+}
+
+// * Helper functions * //
+
+/// Does the database header at the start of `bytes` say it is in WAL mode?
+fn wal_mode(bytes: &[u8]) -> bool {
+    let header = Header::read(&mut Cursor::new(bytes)).ok();
+    header.is_some_and(|h| h.read_format == 2 || h.write_format == 2)
+}
+
+/**
+ * Lock the database file at `path` like a reader: SHARED, and when it is in
+ * WAL mode, the WAL-index like every connection that has it open, and every
+ * reader slot but the first, which keeps checkpoints from moving their read
+ * marks and other connections from starting the WAL over. Returns the locks
+ * and the WAL as of then, which stays readable as long as they are held.
+ */
+pub(crate) fn lock_reader(path: &Path) -> BinResult<(FileLock, Option<ShmLock>, Option<Wal>)> {
+    let mut lock = FileLock::open(path)?;
+    lock.lock(LockLevel::Shared)?;
+    if !is_wal_mode_locked(&mut lock)? && lock.file().metadata()?.len() > 0 {
+        return Ok((lock, None, None));
+    }
+
+    let shm = ShmLock::open(path)?;
+    shm.lock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1, false)?;
+    shm.lock(wal_read_lock(1)..wal_read_lock(WAL_READERS), false)?;
+    let wal = read_wal(path)?;
+    Ok((lock, Some(shm), wal))
+}
+
+/// Read the database file at `path` and its WAL holding the locks of a
+/// reader, see [lock_reader]
+fn read_locked(path: &Path) -> BinResult<(Vec<u8>, Option<Wal>)> {
+    let (mut lock, _shm, wal) = lock_reader(path)?;
+    let mut bytes = vec![];
+    lock.file().seek(SeekFrom::Start(0))?;
+    lock.file().read_to_end(&mut bytes)?;
+    Ok((bytes, wal))
+}
+
+/// Lock `slots` of `shm` exclusively, waiting up to the busy timeout if
+/// `wait`, `false` if another connection still holds one of them
+fn try_lock(shm: &ShmLock, slots: Range<u64>, wait: bool) -> BinResult<bool> {
+    if !wait {
+        return Ok(shm.try_lock(slots, true)?);
+    }
+    match shm.lock(slots, true) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The WAL of the database at `path`, if it exists and has a valid header
+pub(crate) fn read_wal(path: &Path) -> BinResult<Option<Wal>> {
+    match File::open(wal_path(path)) {
+        Ok(mut file) => Wal::read(&mut file),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// A new salt
+fn random() -> u32 {
+    RandomState::new().hash_one(SystemTime::now()) as u32
+}
+
+/**
+ * The checksum SQLite uses for the WAL, continuing from `sum`, of 32-bit
+ * words in pairs. Words are big endian if the magic number says so.
+ */
+pub(crate) fn checksum(big_endian: bool, sum: [u32; 2], bytes: &[u8]) -> [u32; 2] {
+    let word = |b: &[u8]| {
+        let b = [b[0], b[1], b[2], b[3]];
+        match big_endian {
+            true => u32::from_be_bytes(b),
+            false => u32::from_le_bytes(b),
+        }
+    };
+
+    bytes.chunks_exact(8).fold(sum, |[s0, s1], pair| {
+        let s0 = s0.wrapping_add(word(&pair[..4])).wrapping_add(s1);
+        let s1 = s1.wrapping_add(word(&pair[4..])).wrapping_add(s0);
+        [s0, s1]
+    })
+}
+
+// * Tests * //
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{journal::JournalMode, schema::SerialValue, shm::shm_path, testing::temp_copy};
+    use pretty_assertions::assert_eq;
+
+    type Change = Box<dyn Fn(&mut Vec<u8>)>;
+
+    /// A copy of wal.db and its WAL only used by one test
+    fn moons(name: &str) -> PathBuf {
+        let path = temp_copy("data/wal.db", &format!("wal-{}", name));
+        std::fs::copy("data/wal.db-wal", wal_path(&path)).expect("Failed to copy wal.db-wal");
+        path
+    }
+
+    fn read(path: &Path) -> Option<Wal> {
+        Wal::read(&mut File::open(wal_path(path)).expect("Failed to open WAL")).unwrap()
+    }
+
+    /// Names of the moons in wal.db
+    fn names(db: &Database) -> Vec<String> {
+        db.table_rows(2)
+            .iter()
+            .map(|cell| match &cell.record.payload[1] {
+                SerialValue::String(name) => name.clone(),
+                value => panic!("Unexpected name {:?}", value),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn read_frames() {
+        let mut file = File::open("data/wal.db-wal").expect("Failed to open wal.db-wal");
+        let wal = Wal::read(&mut file).unwrap().expect("Invalid WAL header");
+        assert_eq!((wal.header.page_size, wal.header.checkpoint_sequence), (1024, 1));
+
+        // 3 commits of page 2 since the checkpoint, and older frames after them
+        let pages: Vec<(u32, u32)> = wal
+            .frames
+            .iter()
+            .map(|f| (f.header.page_number, f.header.commit_size))
+            .collect();
+        assert_eq!(pages, vec![(2, 2), (2, 2), (2, 2)]);
+        assert_eq!(file.metadata().unwrap().len(), 32 + 6 * (24 + 1024));
+    }
+
+    #[test]
+    fn apply_frames() {
+        // Offset of frame `i`
+        let frame = |i: usize| 32 + i * (24 + 1024);
+        let jupiter = ["Io", "Europa", "Ganymede", "Callisto"];
+        let t: Vec<(&str, Change, Vec<&str>)> = vec![
+            ("as written", Box::new(|_| {}), vec!["Luna", "Phobos", "Deimos"]),
+            (
+                "torn frame",
+                Box::new(move |wal| wal.truncate(frame(2) + 100)),
+                vec!["Moon", "Phobos", "Deimos"],
+            ),
+            (
+                "wrong checksum",
+                Box::new(move |wal| wal[frame(1) + 500] ^= 1),
+                vec!["Moon"],
+            ),
+            ("not a commit", Box::new(move |wal| wal[frame(0) + 7] = 0), vec![]),
+            ("wrong header checksum", Box::new(|wal| wal[5] ^= 1), vec![]),
+        ];
+
+        for (name, change, exp) in t.into_iter() {
+            let mut wal = std::fs::read("data/wal.db-wal").expect("Failed to read wal.db-wal");
+            change(&mut wal);
+            let mut bytes = std::fs::read("data/wal.db").expect("Failed to read wal.db");
+            if let Some(wal) = Wal::read(&mut Cursor::new(wal)).unwrap() {
+                wal.apply(&mut bytes);
+            }
+
+            let db = Database::read(&mut Cursor::new(bytes)).unwrap();
+            let exp: Vec<&str> = jupiter.iter().chain(&exp).copied().collect();
+            assert_eq!(names(&db), exp, "{}", name);
+        }
+    }
+
+    #[test]
+    fn snapshots() {
+        let wal = read(Path::new("data/wal.db")).unwrap();
+        let salt = wal.header.salt;
+        let commits: Vec<(u32, u32, Vec<u32>)> =
+            wal.commits().into_iter().map(|c| (c.frame, c.size, c.pages)).collect();
+        assert_eq!(commits, vec![(1, 2, vec![2]), (2, 2, vec![2]), (3, 2, vec![2])]);
+        assert!(wal.commits().iter().all(|c| c.salt == salt));
+
+        let jupiter = ["Io", "Europa", "Ganymede", "Callisto"];
+        let t = [
+            (AsOf::Frame(1), Ok(vec!["Moon"])),
+            (AsOf::Frame(2), Ok(vec!["Moon", "Phobos", "Deimos"])),
+            (AsOf::Frame(3), Ok(vec!["Luna", "Phobos", "Deimos"])),
+            (AsOf::Salt(salt[0]), Ok(vec!["Luna", "Phobos", "Deimos"])),
+            (AsOf::Frame(0), Err("Frame 0 is not a commit of the WAL".to_string())),
+            (AsOf::Frame(4), Err("Frame 4 is not a commit of the WAL".to_string())),
+            (
+                AsOf::Salt(salt[0] - 1),
+                Err(format!("The WAL was started over since salt {}", salt[0] - 1)),
+            ),
+        ];
+
+        for (as_of, exp) in t.into_iter() {
+            let db = Database::open_as_of("data/wal.db", as_of).map_err(|err| match err {
+                binrw::Error::AssertFail { message, .. } => message,
+                err => panic!("Unexpected error {:?}", err),
+            });
+            let exp = exp.map(|exp| jupiter.iter().chain(&exp).map(|s| s.to_string()).collect());
+            assert_eq!(db.map(|db| names(&db)), exp, "{:?}", as_of);
+        }
+    }
+
+    #[test]
+    fn write_frames() {
+        let path = moons("write");
+        let mut db = Database::open(&path).unwrap();
+        db.execute("CREATE TABLE stars (name TEXT)").unwrap();
+        db.save(&path).unwrap();
+
+        // The stale frames are overwritten by page 1 and the new root page
+        let wal = read(&path).unwrap();
+        let pages: Vec<(u32, u32)> = wal
+            .frames
+            .iter()
+            .map(|f| (f.header.page_number, f.header.commit_size))
+            .collect();
+        assert_eq!(pages, vec![(2, 2), (2, 2), (2, 2), (1, 0), (3, 3)]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * 1024);
+        assert_eq!(Database::open(&path).unwrap().table_rows(1), db.table_rows(1));
+        assert_eq!(crate::shm::check(&path).unwrap(), vec![]);
+
+        // Once every frame is in the database file the WAL starts over
+        assert_eq!(checkpoint(&path, CheckpointMode::Passive).unwrap(), (5, 5));
+        db.execute("DROP TABLE stars").unwrap();
+        db.save(&path).unwrap();
+        let next = read(&path).unwrap();
+        let generation = |h: WalHeader| (h.checkpoint_sequence, h.salt[0]);
+        assert_eq!(generation(next.header), (2, wal.header.salt[0] + 1));
+        assert_eq!(next.frames.len(), 2);
+        assert_eq!(Database::open(&path).unwrap().table_rows(1), db.table_rows(1));
+
+        // Another connection with the WAL-index open doesn't keep checkpoints out
+        let other = ShmLock::open(&path).unwrap();
+        other.lock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1, false).unwrap();
+        db.execute("CREATE TABLE comets (name TEXT)").unwrap();
+        db.save(&path).unwrap();
+        assert_eq!(crate::shm::check(&path).unwrap(), vec![]);
+        assert_eq!(read(&path).unwrap().committed().len(), 4);
+        assert_eq!(checkpoint(&path, CheckpointMode::Passive).unwrap(), (4, 4));
+        drop(other);
+        db.execute("DROP TABLE comets").unwrap();
+        db.save(&path).unwrap();
+
+        // Leaving WAL mode checkpoints and deletes the WAL
+        db.save_with(&path, JournalMode::Delete).unwrap();
+        assert!(!wal_path(&path).exists());
+        assert_eq!(db.db_header.write_format, 1);
+        assert_eq!(Database::open(&path).unwrap().table_rows(1), db.table_rows(1));
+
+        db.save_with(&path, JournalMode::Wal).unwrap();
+        assert!(is_wal_mode(&path).unwrap());
+        assert!(!wal_path(&path).exists());
+        std::fs::remove_file(shm_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_reader() {
+        let path = moons("reader");
+        let db = Database::open(&path).unwrap();
+
+        // Another connection reads the database as of the second commit
+        let other = ShmLock::open(&path).unwrap();
+        let mut index = WalIndex::recover(&read(&path).unwrap());
+        index.checkpoint.read_marks[1] = 2;
+        index.write(&mut other.file()).unwrap();
+        other.lock(WAL_DMS_LOCK..WAL_DMS_LOCK + 1, false).unwrap();
+        other.lock(wal_read_lock(1)..wal_read_lock(2), false).unwrap();
+
+        // So the frames after it stay in the WAL only, and the index stays
+        assert_eq!(checkpoint(&path, CheckpointMode::Passive).unwrap(), (3, 2));
+        let bytes = std::fs::read(&path).unwrap();
+        let jupiter = ["Io", "Europa", "Ganymede", "Callisto"];
+        let exp: Vec<&str> = jupiter.iter().chain(&["Moon", "Phobos", "Deimos"]).copied().collect();
+        assert_eq!(names(&Database::read(&mut Cursor::new(bytes)).unwrap()), exp);
+        let index = WalIndex::read(&mut other.file()).unwrap();
+        assert_eq!((index.headers[0].max_frame, index.checkpoint.backfill), (3, 2));
+        assert_eq!(Database::open(&path).unwrap(), db);
+        assert!(checkpoint(&path, CheckpointMode::Full).is_err());
+
+        drop(other);
+        assert_eq!(checkpoint(&path, CheckpointMode::Passive).unwrap(), (3, 3));
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(Database::read(&mut Cursor::new(bytes)).unwrap(), db);
+        std::fs::remove_file(wal_path(&path)).unwrap();
+        std::fs::remove_file(shm_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_snapshot() {
+        let path = moons("snapshot");
+        let mut a = Database::open(&path).unwrap();
+        let mut b = Database::open(&path).unwrap();
+
+        // Both transactions hold SHARED, which doesn't keep either from the WAL
+        let mut first = a.transaction(&path).unwrap();
+        let mut second = b.transaction(&path).unwrap();
+        first.execute("CREATE TABLE stars (name TEXT)").unwrap();
+        first.commit().unwrap();
+
+        // But the second one began before the first one committed
+        second.execute("CREATE TABLE comets (name TEXT)").unwrap();
+        let err = match second.commit().unwrap_err() {
+            binrw::Error::Io(err) => err.to_string(),
+            err => panic!("Unexpected error {:?}", err),
+        };
+        assert_eq!(err, "database changed since the transaction began");
+        assert_eq!(read(&path).unwrap().committed().len(), 5);
+        assert_eq!(Database::open(&path).unwrap().table_rows(1), a.table_rows(1));
+        std::fs::remove_file(wal_path(&path)).unwrap();
+        std::fs::remove_file(shm_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoints() {
+        let t = [
+            (CheckpointMode::Passive, (3, 3), Some(3)),
+            (CheckpointMode::Full, (3, 3), Some(3)),
+            (CheckpointMode::Restart, (3, 3), Some(0)),
+            (CheckpointMode::Truncate, (0, 0), None),
+        ];
+
+        for (mode, exp, frames) in t.into_iter() {
+            let path = moons(&format!("{:?}", mode));
+            let db = Database::open(&path).unwrap();
+            assert_eq!(checkpoint(&path, mode).unwrap(), exp, "{:?}", mode);
+
+            // The database file alone has every committed page
+            let bytes = std::fs::read(&path).unwrap();
+            assert_eq!(Database::read(&mut Cursor::new(bytes)).unwrap(), db, "{:?}", mode);
+            assert_eq!(read(&path).map(|wal| wal.committed().len()), frames, "{:?}", mode);
+            std::fs::remove_file(wal_path(&path)).unwrap();
+            std::fs::remove_file(shm_path(&path)).unwrap();
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}